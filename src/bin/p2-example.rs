@@ -25,320 +25,10766 @@
 // * Make your program robust: there are 7 errors & multiple blank lines
 //   present in the data.
 
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use activities::contacts::{self, *};
+use activities::query::Query;
+use chrono::{DateTime, Datelike, Local, NaiveDate, Utc};
+use crossterm::cursor::{Hide, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use encoding_rs::Encoding;
+use flate2::write::GzEncoder;
+use indicatif::{ProgressBar, ProgressStyle};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Borders, Clear, Paragraph, Row, Table, TableState};
+use ratatui::{Frame, Terminal};
+use regex::{Regex, RegexBuilder};
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use structopt::StructOpt;
 use thiserror::Error;
+use tracing::{debug, info};
+use uuid::Uuid;
 
-/// A contact record.
-#[derive(Debug)]
-struct Record {
-    /// Unique ID number for this record.
-    id: i64,
-    /// The name of the contact.
-    name: String,
-    /// The email of the contact.
-    email: Option<String>,
+/// Asks the user a yes or no question. Anything other than "y" or "yes" is
+/// treated as "no".
+fn confirm(question: &str) -> std::io::Result<bool> {
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
 }
 
-/// Contains all saved records.
-#[derive(Debug)]
-struct Records {
-    inner: HashMap<i64, Record>,
+/// Asks for the passphrase of an encrypted data file. It can also be given
+/// in the "P2_PASSPHRASE" environment variable, so that scripts can use
+/// encrypted files too. When "confirm" is true, the passphrase is asked for
+/// twice, since a typo would lock the contacts away for good.
+fn read_passphrase(confirm: bool) -> Result<String, AppError> {
+    if let Ok(passphrase) = std::env::var("P2_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    let passphrase = read_hidden("passphrase: ")?;
+    if confirm && read_hidden("repeat passphrase: ")? != passphrase {
+        return Err(AppError::PassphraseMismatch);
+    }
+    Ok(passphrase)
 }
 
-impl Records {
-    /// Create a new records collection.
-    fn new() -> Self {
-        Self {
-            inner: HashMap::new(),
+/// Reads a line from the terminal without showing what is typed. Raw mode
+/// stops the terminal from echoing the keys, so we handle them ourselves.
+/// The prompt goes to stderr, where it won't mix with any output.
+fn read_hidden(prompt: &str) -> std::io::Result<String> {
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+    enable_raw_mode()?;
+    let mut line = String::new();
+    let result = loop {
+        let code = match event::read() {
+            Ok(Event::Key(KeyEvent {
+                code,
+                modifiers,
+                kind: KeyEventKind::Press,
+                ..
+            })) => (code, modifiers),
+            Ok(_) => continue,
+            Err(e) => break Err(e),
+        };
+        match code {
+            (KeyCode::Enter, _) => break Ok(line),
+            (KeyCode::Char('c'), KeyModifiers::CONTROL) => {
+                break Err(std::io::ErrorKind::Interrupted.into())
+            }
+            (KeyCode::Char(c), _) => line.push(c),
+            (KeyCode::Backspace, _) => {
+                line.pop();
+            }
+            _ => (),
         }
-    }
+    };
+    // The terminal is put back to normal even if reading failed.
+    disable_raw_mode()?;
+    eprintln!();
+    result
+}
 
-    /// Edit an existing record. Will insert a new record if the id is not found.
-    fn edit(&mut self, id: i64, name: &str, email: Option<String>) {
-        self.inner.insert(
-            id,
-            Record {
-                id,
-                name: name.to_string(),
-                email,
-            },
-        );
+/// Parses the value of "--delimiter". Tabs are hard to type, so "tab" can be
+/// used instead.
+fn parse_delimiter(value: &str) -> Result<char, String> {
+    let mut chars = value.chars();
+    match (value, chars.next(), chars.next()) {
+        ("tab" | "\\t", _, _) => Ok('\t'),
+        (_, Some(c), None) if !matches!(c, '"' | '\n' | '\r') => Ok(c),
+        _ => Err(format!(
+            "invalid delimiter: {} (expected a single character, or tab)",
+            value
+        )),
     }
+}
 
-    /// Returns the next available record id.
-    fn next_id(&self) -> i64 {
-        // First we just get all the keys (ids).
-        // This vector is just a copy of the ids, so we can throw it
-        // away when done.
-        let mut ids: Vec<_> = self.inner.keys().collect();
-        ids.sort();
-        // "pop" removes the last entry from the vector, so we will have
-        // the largest ID currently in use.
-        // Adding 1 to the largest ID gives us the next ID, and if none
-        // were found, we just start at 1.
-        match ids.pop() {
-            Some(id) => id + 1,
-            None => 1,
-        }
-    }
-
-    /// Adds a new record to the database.
-    fn add(&mut self, record: Record) {
-        self.inner.insert(record.id, record);
-    }
-
-    /// Converts this structure into a vector of Record.
-    /// This is used when saving the data.
-    fn into_vec(mut self) -> Vec<Record> {
-        // "drain" moves the records out of the hashmap, so we can then
-        // move them into a vector. It also returns t (key, value) pair
-        // so we need to access the value with ".1" since it is a tuple.
-        let mut records: Vec<_> = self.inner.drain().map(|kv| kv.1).collect();
-        // We sort the records in order by id before returning them.
-        records.sort_by_key(|rec| rec.id);
-        records
-    }
-
-    /// Searches for all records containing the supplied name.
-    fn search(&self, name: &str) -> Vec<&Record> {
-        // We simple filter through the values here and see if there
-        // are any matches using the ".contains" method on a string.
-        // The search is case-insensitive due to the usage of ".to_lowercase".
-        self.inner
-            .values()
-            .filter(|rec| rec.name.to_lowercase().contains(&name.to_lowercase()))
-            .collect()
+/// Parses the name given to "add" or "edit". Every contact needs a name, and
+/// a file with a blank one couldn't be read back.
+fn parse_name(value: &str) -> Result<String, String> {
+    match value.trim().is_empty() {
+        true => Err("the name can't be empty".to_string()),
+        false => Ok(value.to_string()),
     }
+}
+
+/// Checks whether a path is "-", which stands for stdin or stdout instead
+/// of a real file.
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
 
-    fn remove(&mut self, id: i64) -> Option<Record> {
-        self.inner.remove(&id)
+/// Opens a file for reading, or stdin when the path is "-". Compressed
+/// files are decompressed while they are read.
+fn open_input(path: &Path) -> std::io::Result<Box<dyn BufRead>> {
+    if is_stdio(path) {
+        // "lock" gives us a buffered reader for stdin.
+        decompress(std::io::stdin().lock())
+    } else {
+        decompress(BufReader::new(File::open(path)?))
     }
 }
 
-/// Errors that may occur while parsing the data file.
-#[derive(Error, Debug)]
-enum ParseError {
-    #[error("id must be a number: {0}")]
-    InvalidId(#[from] std::num::ParseIntError),
-    #[error("empty record")]
-    EmptyRecord,
-    #[error("missing field: {0}")]
-    MissingField(String),
-}
-
-/// Parses a single record line.
-fn parse_record(record: &str) -> Result<Record, ParseError> {
-    // We use ".split" on ',' to create a vector of strings.
-    // This vector will contain elements of each field in the record
-    // without the delimiting commas separating them.
-    let fields: Vec<&str> = record.split(',').collect();
-
-    // The id and name fields are required, so a match expression is used
-    // in order to extract the data (if possible) and place it into the
-    // appropriate variables.
-
-    // Here we try to get the "id" portion of the record, which should be
-    // the first entry (which is index 0). We then use "from_str_radix"
-    // to parse the string id into a numeric i64 id. We abort the function
-    // if this conversion fails, or if we do not find the id.
-    let id = match fields.get(0) {
-        Some(id) => i64::from_str_radix(id, 10)?,
-        None => return Err(ParseError::EmptyRecord),
-    };
-
-    // Here we try to get the "name" portion of the record, which should
-    // be the second entry (which is index 1). We also ensure that a name
-    // actually exists by using filter on the name and seeing if it is an
-    // empty string (""). The "fields" vector contains &str type, "get"
-    // references again (&&str) and then filter references a third time (&&&str).
-    // The asterisks (**) remove two of these references so we can compare
-    // to the empty string ("") which is a &str. This is not something to
-    // worry about because the compiler will tell you if asterisks are needed
-    // when you attempt to compile the program.
-    let name = match fields.get(1).filter(|name| **name != "") {
-        Some(name) => name.to_string(),
-        None => return Err(ParseError::MissingField("name".to_owned())),
-    };
-
-    // The email field is the third piece of data (index 2). "Get" returns
-    // an Option, so all we need to do is simply map the email to a
-    // String type with "to_string" and then filter if it is empty.
-    // Map and filter will only run if we actually have data to work
-    // with, since emails are optional.
-    let email = fields
-        .get(2)
-        .map(|email| email.to_string())
-        .filter(|email| email != "");
-
-    Ok(Record { id, name, email })
-}
-
-/// Parses the entire record file.
-fn parse_records(records: String, verbose: bool) -> Records {
-    let mut recs = Records::new();
-    // We use ".split" with '\n' to get each line one at a time.
-    // '\n' means "new line". "Enumerate" provides the data and
-    // the current enumeration index (starting from 0) and we use
-    // this number to report line errors.
-    for (num, record) in records.split('\n').enumerate() {
-        if record != "" {
-            match parse_record(record) {
-                Ok(rec) => recs.add(rec),
-                Err(e) => {
-                    if verbose {
-                        println!(
-                            "error on line number {}: {}\n  > \"{}\"\n",
-                            num + 1,
-                            e,
-                            record
-                        );
-                    }
-                }
+/// Reads new contacts from "name,email" lines, such as when adding many
+/// contacts at once. Several emails may be given, separated by semicolons.
+/// Blank lines are skipped, and so is a first line of "name,email". Lines
+/// that can't be read are returned separately, so that the rest of the
+/// contacts can still be added. The contacts don't have ids yet.
+fn read_batch<R: BufRead>(reader: R) -> std::io::Result<(Vec<Record>, Vec<BadLine>)> {
+    let mut records = vec![];
+    let mut bad_lines = vec![];
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() || (i == 0 && line.trim().eq_ignore_ascii_case("name,email")) {
+            continue;
+        }
+        let fields = parse_fields(&line, ',').and_then(|fields| match fields.first() {
+            Some(name) if !name.trim().is_empty() => Ok(fields),
+            _ => Err(ParseError::MissingField("name".to_owned())),
+        });
+        match fields {
+            Ok(fields) => {
+                let mut record = Record::new(RecordId::default(), fields[0].trim().to_string());
+                record.emails = fields
+                    .get(1)
+                    .map(|emails| split_list(emails))
+                    .unwrap_or_default();
+                records.push(record);
             }
+            Err(error) => bad_lines.push(BadLine {
+                number: i + 1,
+                error,
+                text: line,
+            }),
         }
     }
-    recs
+    Ok((records, bad_lines))
 }
 
-/// Loads the raw records from a file.
-fn load_records(file_name: PathBuf, verbose: bool) -> std::io::Result<Records> {
-    let mut file = File::open(file_name)?;
+/// Loads records that are kept in a CSV file next to the data file, such as
+/// the trash. A missing file just means that there are no records in it.
+///
+/// Records read from stdin have nowhere to keep other files, so these are
+/// always empty. The files of an encrypted data file are encrypted with the
+/// same passphrase.
+fn load_sidecar(
+    data_file: &Path,
+    extension: &str,
+    passphrase: Option<&str>,
+    strict: bool,
+) -> Result<Records, AppError> {
+    if is_stdio(data_file) {
+        return Ok(Records::new());
+    }
+    let file_name = sidecar_path(data_file, extension);
+    let records = match passphrase {
+        Some(passphrase) => load_encrypted(file_name, passphrase, strict),
+        None => load_records(file_name, CsvFormat::default(), strict).map_err(AppError::from),
+    };
+    match records {
+        Err(AppError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(Records::new()),
+        result => result,
+    }
+}
 
-    let mut buffer = String::new();
-    file.read_to_string(&mut buffer)?;
+/// Saves records to a CSV file next to the data file.
+fn save_sidecar(
+    data_file: &Path,
+    extension: &str,
+    passphrase: Option<&str>,
+    records: &Records,
+) -> std::io::Result<()> {
+    if is_stdio(data_file) {
+        return Ok(());
+    }
+    let file_name = sidecar_path(data_file, extension);
+    match passphrase {
+        Some(passphrase) => save_encrypted(file_name, records, passphrase),
+        None => save_records(file_name, records),
+    }
+}
 
-    Ok(parse_records(buffer, verbose))
+/// Loads the records in the trash. Records read from stdin have no trash,
+/// so removing them gets rid of them for good.
+fn load_trash(
+    data_file: &Path,
+    passphrase: Option<&str>,
+    strict: bool,
+) -> Result<Records, AppError> {
+    load_sidecar(data_file, "trash", passphrase, strict)
 }
 
-/// Saves the records to disk.
-fn save_records(file_name: PathBuf, records: Records) -> std::io::Result<()> {
-    // We use OpenOptions to configure how the file should be opened.
-    // This is needed so we can get write access to the file. Additionally,
-    // we "truncate" the file, which deletes all the contents. This is done
-    // because we rewrite the entire contents whenever we save. It is possible
-    // to write to a specific section of the file, but rewriting the entire
-    // file is the simplest method.
-    let mut file = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(file_name)?;
+/// Saves the records in the trash.
+fn save_trash(data_file: &Path, passphrase: Option<&str>, trash: &Records) -> std::io::Result<()> {
+    save_sidecar(data_file, "trash", passphrase, trash)
+}
 
-    // First we write the field names.
-    file.write(b"id,name,email\n")?;
-
-    // Then we iterate through each record and write it to the file.
-    // "Into_iter" creates an iterator that takes ownership of the data
-    // during iteration. We do this so we don't have to make additional
-    // copies of the data before saving it to disk (we can just work with
-    // it directly).
-    for record in records.into_vec().into_iter() {
-        // When we do not have an email, we just use an empty string ("").
-        let email = match record.email {
-            Some(email) => email,
-            None => "".to_string(),
-        };
-        // This creates a new string that is properly formatted to CSV.
-        let line = format!("{},{},{}\n", record.id, record.name, email);
-        // We then write the string to the file. "write" works with bytes,
-        // so we just access the bytes of the string with "as_bytes".
-        file.write(line.as_bytes())?;
-    }
-    // "Flushing" the data ensures that everything is written to disk before
-    // continuing. Without this line, it is possible for the program to
-    // terminate before the system is done writing to the file, and this
-    // can result in corrupted data.
-    file.flush()?;
+/// Puts removed records in the trash, so that "restore" can bring them back.
+/// This is done before the data file is saved, so they can't be lost if
+/// saving fails. A record with the same id as one already in the trash
+/// replaces the older one.
+fn move_to_trash(
+    data_file: &Path,
+    passphrase: Option<&str>,
+    strict: bool,
+    removed: impl IntoIterator<Item = Record>,
+) -> Result<(), AppError> {
+    let mut trash = load_trash(data_file, passphrase, strict)?;
+    for record in removed {
+        trash.add(record);
+    }
+    save_trash(data_file, passphrase, &trash)?;
     Ok(())
 }
 
-#[derive(StructOpt, Debug)]
-#[structopt(about = "project 2: contact manager")]
-struct Opt {
-    #[structopt(short, parse(from_os_str), default_value = "p2_data.csv")]
-    data_file: PathBuf,
-    #[structopt(subcommand)]
-    cmd: Command,
-    #[structopt(short, help = "verbose")]
-    verbose: bool,
+/// Loads the records as they were after the last "sync", or None if the
+/// data file was never synced. The file is only there after a sync, and
+/// "compact" refuses to run while it is, so it isn't made when there's none.
+fn load_synced(
+    data_file: &Path,
+    passphrase: Option<&str>,
+    strict: bool,
+) -> Result<Option<Records>, AppError> {
+    match sidecar_path(data_file, "sync").exists() {
+        true => load_sidecar(data_file, "sync", passphrase, strict).map(Some),
+        false => Ok(None),
+    }
 }
 
-#[derive(StructOpt, Debug)]
-enum Command {
-    Add {
-        name: String,
-        #[structopt(short)]
-        email: Option<String>,
-    },
-    Edit {
-        id: i64,
-        name: String,
-        #[structopt(short)]
-        email: Option<String>,
-    },
-    List {},
-    Remove {
-        id: i64,
-    },
-    Search {
-        query: String,
-    },
+/// A note to follow up with a contact by a certain day.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Reminder {
+    /// The number that "reminders --done" refers to the reminder by.
+    number: u32,
+    contact: RecordId,
+    due: NaiveDate,
+    note: Option<String>,
 }
 
-/// Runs the program. This is so we can utilize the question mark operator.
-fn run(opt: Opt) -> Result<(), std::io::Error> {
-    match opt.cmd {
-        Command::Add { name, email } => {
-            let mut recs = load_records(opt.data_file.clone(), opt.verbose)?;
-            let next_id = recs.next_id();
-            recs.add(Record {
-                id: next_id,
-                name,
-                email,
-            });
-            save_records(opt.data_file, recs)?;
+/// Loads the reminders. They are kept in a JSON file next to the data file,
+/// which is encrypted along with it. Records read from stdin have no
+/// reminders.
+fn load_reminders(data_file: &Path, passphrase: Option<&str>) -> Result<Vec<Reminder>, AppError> {
+    if is_stdio(data_file) {
+        return Ok(vec![]);
+    }
+    let data = match fs::read(sidecar_path(data_file, "reminders")) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let data = match passphrase {
+        Some(passphrase) => crypt::decrypt(&data, passphrase).ok_or(AppError::WrongPassphrase)?,
+        None => data,
+    };
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Saves the reminders.
+fn save_reminders(
+    data_file: &Path,
+    passphrase: Option<&str>,
+    reminders: &[Reminder],
+) -> Result<(), AppError> {
+    let mut data = serde_json::to_vec_pretty(reminders)?;
+    if let Some(passphrase) = passphrase {
+        data = crypt::encrypt(&data, passphrase);
+    }
+    save_file(sidecar_path(data_file, "reminders"), |file| {
+        file.write_all(&data)
+    })?;
+    Ok(())
+}
+
+/// The commands that "--dry-run" works with, as listed in its help and in
+/// the error for other commands. It must match "Command::supports_dry_run".
+macro_rules! dry_run_commands {
+    () => {
+        "add, edit, remove, import, import-vcf, dedup, group add, group remove, link, unlink, touch and compact"
+    };
+}
+
+/// Errors that may occur while running the program.
+#[derive(Error, Debug)]
+enum AppError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("database error: {0}")]
+    Sled(#[from] sled::Error),
+    #[error("invalid search pattern: {0}")]
+    Regex(#[from] regex::Error),
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid YAML: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("failed to start server: {0}")]
+    Server(String),
+    #[error("failed to sync with {0}: {1}")]
+    Sync(String, String),
+    #[error("database is locked: another p2 process is using {}", .0.display())]
+    Locked(PathBuf),
+    #[error(
+        "found {} lines that can't be read (run without --strict to skip them):{}",
+        .0.len(),
+        format_bad_lines(.0)
+    )]
+    Malformed(Vec<BadLine>),
+    #[error(
+        "found {} contacts with ids that were already used (use --on-duplicate to keep them):{}",
+        .0.len(),
+        format_bad_lines(.0)
+    )]
+    DuplicateIds(Vec<BadLine>),
+    #[error("failed to start search threads: {0}")]
+    Threads(#[from] rayon::ThreadPoolBuildError),
+    #[error("failed to watch the data file: {0}")]
+    Watch(#[from] notify::Error),
+    #[error("failed to read the command: {0}")]
+    Readline(#[from] rustyline::error::ReadlineError),
+    #[error("failed to write the spreadsheet: {0}")]
+    Xlsx(#[from] rust_xlsxwriter::XlsxError),
+    #[error("failed to make a QR code: {0} (try a contact with shorter notes)")]
+    Qr(#[from] qrcode::types::QrError),
+    #[error("image error: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("failed to download {0}: {1}")]
+    Download(String, String),
+    #[error("invalid relation: {0}")]
+    InvalidRelation(String),
+    #[error("invalid template: {0}")]
+    Template(String),
+    #[error("no id was given, and there is no terminal to pick a contact on")]
+    NoId,
+    #[error("record {0} not found")]
+    NotFound(RecordId),
+    #[error("no contact matches {0}")]
+    NoMatch(String),
+    #[error("{1} contacts match {0} (give more of the name, or use an id)")]
+    AmbiguousQuery(String, usize),
+    #[error("{0} has no {1}")]
+    MissingField(String, &'static str),
+    #[error("couldn't use the clipboard: {0}")]
+    Clipboard(#[from] arboard::Error),
+    #[error("couldn't open the mail client: {0} (use --print to print the address instead)")]
+    MailClient(String),
+    #[error("record {0} not found in trash")]
+    NotInTrash(RecordId),
+    #[error("reminder {0} not found")]
+    NoSuchReminder(u32),
+    #[error("{0} days from today is too far in the future")]
+    TooFarAhead(u32),
+    #[error("only csv data files can be {0}")]
+    CsvOnly(&'static str),
+    #[error("found {0} problems in the data file")]
+    Invalid(usize),
+    #[error("can't ask before removing contacts read from stdin (use --yes)")]
+    CantConfirm,
+    #[error("{0} is read-only, so it can't be changed")]
+    ReadOnly(PathBuf),
+    #[error("--dry-run only works with {}", dry_run_commands!())]
+    NoDryRun,
+    #[error("contacts read from stdin can't be {0}")]
+    NotWithStdio(&'static str),
+    #[error("{0} databases can't be used with --read-only")]
+    NoReadOnly(&'static str),
+    #[error(
+        "the contacts are synced with a server, which would still use the old ids (remove {} to stop syncing)",
+        .0.display()
+    )]
+    SyncedIds(PathBuf),
+    #[error("git failed: {0}")]
+    Git(String),
+    #[error("no backup was made at {0} (use restore --list to see the backups)")]
+    NoSuchBackup(String),
+    #[error("{1} backups were made at {0} (give more of the time)")]
+    AmbiguousBackup(String, usize),
+    #[error("invalid profile name: {0} (use only letters, digits, - and _)")]
+    BadProfileName(String),
+    #[error("profile {0} doesn't exist (create it with \"profile create {0}\")")]
+    NoSuchProfile(String),
+    #[error("profile {0} already exists")]
+    ProfileExists(String),
+    #[error("{} doesn't exist (create it with \"init\", or use --create)", .0.display())]
+    NoDataFile(PathBuf),
+    #[error("{} already exists", .0.display())]
+    DataFileExists(PathBuf),
+    #[error("the data file is encrypted (use --encrypt, or the decrypt command)")]
+    Encrypted,
+    #[error("the data file isn't encrypted (use the encrypt command first)")]
+    NotEncrypted,
+    #[error("the data file is already encrypted")]
+    AlreadyEncrypted,
+    #[error("encrypted data files can't be {0}")]
+    NotWhenEncrypted(&'static str),
+    #[error("wrong passphrase, or the encrypted file is damaged")]
+    WrongPassphrase,
+    #[error("the passphrases don't match")]
+    PassphraseMismatch,
+    #[error(
+        "{} was changed outside of p2, or is damaged: it doesn't match its checksum (use --ignore-checksum to continue anyway)",
+        .0.display()
+    )]
+    ChecksumMismatch(PathBuf),
+    #[error(
+        "the data file uses schema version {0}, but only version {supported} is supported, so it can't be changed (upgrade p2 first)",
+        supported = SCHEMA_VERSION
+    )]
+    NewerSchema(u32),
+}
+
+impl AppError {
+    /// Returns the exit status for the error, so that scripts can tell
+    /// what kind of problem happened. These are listed in the help text.
+    fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Io(_) | AppError::Sqlite(_) | AppError::Sled(_) => 2,
+            AppError::Malformed(_)
+            | AppError::DuplicateIds(_)
+            | AppError::Invalid(_)
+            | AppError::Json(_)
+            | AppError::Yaml(_)
+            | AppError::ChecksumMismatch(_)
+            | AppError::NewerSchema(_) => 3,
+            AppError::NotFound(_)
+            | AppError::NoMatch(_)
+            | AppError::NotInTrash(_)
+            | AppError::NoSuchReminder(_)
+            | AppError::NoSuchBackup(_)
+            | AppError::NoDataFile(_) => 4,
+            AppError::Locked(_) => 5,
+            _ => 1,
         }
-        Command::Edit { id, name, email } => {
-            let mut recs = load_records(opt.data_file.clone(), opt.verbose)?;
-            recs.edit(id, &name, email);
-            save_records(opt.data_file, recs)?;
+    }
+
+    /// Returns a short name for the kind of error, used by "--errors json".
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::Io(_) => "io",
+            AppError::Sqlite(_) | AppError::Sled(_) => "database",
+            AppError::Regex(_) => "invalid_pattern",
+            AppError::Json(_) => "invalid_json",
+            AppError::Yaml(_) => "invalid_yaml",
+            AppError::Server(_) => "server",
+            AppError::Sync(..) => "sync",
+            AppError::Locked(_) => "locked",
+            AppError::Malformed(_) => "malformed_line",
+            AppError::DuplicateIds(_) => "duplicate_id",
+            AppError::Threads(_) => "threads",
+            AppError::Watch(_) => "watch",
+            AppError::Readline(_) => "readline",
+            AppError::Xlsx(_) => "xlsx",
+            AppError::Qr(_) => "qr",
+            AppError::Image(_) => "image",
+            AppError::Download(..) => "download",
+            AppError::InvalidRelation(_) => "invalid_relation",
+            AppError::Template(_) => "invalid_template",
+            AppError::NoId => "no_id",
+            AppError::NotFound(_) => "not_found",
+            AppError::NoMatch(_) => "no_match",
+            AppError::AmbiguousQuery(..) => "ambiguous_query",
+            AppError::MissingField(..) => "missing_field",
+            AppError::Clipboard(_) => "clipboard",
+            AppError::MailClient(_) => "mail_client",
+            AppError::NotInTrash(_) => "not_in_trash",
+            AppError::NoSuchReminder(_) => "no_such_reminder",
+            AppError::TooFarAhead(_) => "too_far_ahead",
+            AppError::CsvOnly(_) => "csv_only",
+            AppError::Invalid(_) => "invalid_data",
+            AppError::CantConfirm => "cant_confirm",
+            AppError::NoDryRun => "no_dry_run",
+            AppError::NotWithStdio(_) => "not_with_stdio",
+            AppError::NoReadOnly(_) => "no_read_only",
+            AppError::SyncedIds(_) => "synced_ids",
+            AppError::Git(_) => "git",
+            AppError::ReadOnly(_) => "read_only",
+            AppError::NoSuchBackup(_) => "no_such_backup",
+            AppError::AmbiguousBackup(..) => "ambiguous_backup",
+            AppError::BadProfileName(_) => "invalid_profile_name",
+            AppError::NoSuchProfile(_) => "no_such_profile",
+            AppError::ProfileExists(_) => "profile_exists",
+            AppError::NoDataFile(_) => "no_data_file",
+            AppError::DataFileExists(_) => "data_file_exists",
+            AppError::Encrypted => "encrypted",
+            AppError::NotEncrypted => "not_encrypted",
+            AppError::AlreadyEncrypted => "already_encrypted",
+            AppError::NotWhenEncrypted(_) => "not_when_encrypted",
+            AppError::WrongPassphrase => "wrong_passphrase",
+            AppError::PassphraseMismatch => "passphrase_mismatch",
+            AppError::ChecksumMismatch(_) => "checksum_mismatch",
+            AppError::NewerSchema(_) => "newer_schema",
         }
-        Command::List { .. } => {
-            let recs = load_records(opt.data_file, opt.verbose)?;
-            for record in recs.into_vec() {
-                println!("{:?}", record);
-            }
+    }
+}
+
+/// Errors from the contacts library keep their meaning, so that the hints
+/// about "--strict" and "--on-duplicate" are still printed.
+impl From<contacts::Error> for AppError {
+    fn from(error: contacts::Error) -> Self {
+        match error {
+            contacts::Error::Io(e) => AppError::Io(e),
+            contacts::Error::Json(e) => AppError::Json(e),
+            contacts::Error::Yaml(e) => AppError::Yaml(e),
+            contacts::Error::Malformed(lines) => AppError::Malformed(lines),
+            contacts::Error::DuplicateIds(lines) => AppError::DuplicateIds(lines),
         }
-        Command::Remove { id } => {
-            let mut recs = load_records(opt.data_file.clone(), opt.verbose)?;
-            if recs.remove(id).is_some() {
-                save_records(opt.data_file, recs)?;
-                println!("record deleted");
-            } else {
-                println!("record not found");
+    }
+}
+
+/// An error as printed by "--errors json". "line" is only known for lines
+/// that couldn't be parsed.
+#[derive(Debug, Serialize)]
+struct ErrorReport<'a> {
+    code: &'static str,
+    message: String,
+    line: Option<usize>,
+    file: &'a Path,
+}
+
+/// Prints an error to stderr in the requested format. In JSON, every error
+/// is a single object on its own line. Each bad line of a malformed file is
+/// printed as a separate object, so that each one has its line number.
+fn report_error(e: &AppError, data_file: &Path, format: OutputFormat) {
+    let reports = match e {
+        AppError::Malformed(lines) | AppError::DuplicateIds(lines) => lines
+            .iter()
+            .map(|line| ErrorReport {
+                code: e.code(),
+                message: line.error.to_string(),
+                line: Some(line.number),
+                file: data_file,
+            })
+            .collect(),
+        AppError::Locked(file) => vec![ErrorReport {
+            code: e.code(),
+            message: e.to_string(),
+            line: None,
+            file,
+        }],
+        _ => vec![ErrorReport {
+            code: e.code(),
+            message: e.to_string(),
+            line: None,
+            file: data_file,
+        }],
+    };
+    match format {
+        OutputFormat::Text => eprintln!("{}: {}", output::error("an error occurred"), e),
+        OutputFormat::Json => {
+            for report in reports {
+                // Serializing can't fail, since every field is a plain
+                // string or number.
+                if let Ok(json) = serde_json::to_string(&report) {
+                    eprintln!("{}", json);
+                }
             }
         }
-        Command::Search { query } => {
-            let recs = load_records(opt.data_file, opt.verbose)?;
-            let results = recs.search(&query);
-            if results.is_empty() {
-                println!("no records found");
-            } else {
-                for rec in results {
-                    println!("{:?}", rec);
-                }
+    }
+}
+
+/// Returns where the contacts are kept when no data file is given:
+/// "p2/contacts.csv" inside the XDG data directory. That directory is
+/// "$XDG_DATA_HOME" when it is set, and "~/.local/share" otherwise.
+fn default_data_file() -> PathBuf {
+    // The XDG spec says that relative paths in "XDG_DATA_HOME" are invalid
+    // and should be ignored, so we fall back to the usual location instead.
+    let data_dir = match std::env::var_os("XDG_DATA_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => PathBuf::from(std::env::var_os("HOME").unwrap_or_default())
+            .join(".local")
+            .join("share"),
+    };
+    data_dir.join("p2").join("contacts.csv")
+}
+
+/// Creates an empty data file, and the directories it lives in, unless it
+/// already exists. This is done by "init" and --create, and for the default
+/// data file the first time that it is used. Other files are never created
+/// on their own, so that a typo in the name is reported instead of silently
+/// starting a new, empty contact list.
+fn create_data_file(file_name: &Path, backend: Backend, format: CsvFormat) -> Result<(), AppError> {
+    if file_name.exists() {
+        return Ok(());
+    }
+    if let Some(dir) = file_name.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut records = Records::new();
+    records.format = format;
+    let path = file_name.to_path_buf();
+    // Saving no records writes the header of a CSV file, the settings line
+    // of a JSON Lines file, and the tables of a database.
+    match backend {
+        Backend::Csv => save_records(path, &records)?,
+        Backend::Jsonl => JsonlStorage { path, strict: true }.save(&records)?,
+        Backend::Sqlite => SqliteStorage { path }.save(&records)?,
+        Backend::Sled => SledStorage { path }.save(&records)?,
+    }
+    Ok(())
+}
+
+/// Returns the directory that holds the data files of every profile:
+/// "p2/profiles" inside the XDG config directory. That directory is
+/// "$XDG_CONFIG_HOME" when it is set, and "~/.config" otherwise.
+fn profile_dir() -> PathBuf {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => PathBuf::from(std::env::var_os("HOME").unwrap_or_default()).join(".config"),
+    };
+    config_dir.join("p2").join("profiles")
+}
+
+/// Returns the data file of a profile. Profile names become part of a file
+/// name, so they are limited to letters, digits, "-" and "_". This keeps a
+/// name like "../work" from pointing somewhere outside the profile directory.
+fn profile_path(name: &str) -> Result<PathBuf, AppError> {
+    let valid = name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    if name.is_empty() || !valid {
+        return Err(AppError::BadProfileName(name.to_owned()));
+    }
+    Ok(profile_dir().join(format!("{}.csv", name)))
+}
+
+/// Returns the names of every profile, in alphabetical order.
+fn profile_names() -> std::io::Result<Vec<String>> {
+    let entries = match fs::read_dir(profile_dir()) {
+        Ok(entries) => entries,
+        // No profiles have been created yet.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut names = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "csv") {
+            if let Some(name) = path.file_stem() {
+                names.push(name.to_string_lossy().into_owned());
             }
         }
     }
+    names.sort();
+    Ok(names)
+}
+
+/// Returns the path of a file that is kept next to the data file, such as
+/// "p2_data.csv.lock" for the extension "lock".
+fn sidecar_path(file_name: &Path, extension: &str) -> PathBuf {
+    let name = file_name.file_name().unwrap_or_default().to_string_lossy();
+    file_name.with_file_name(format!("{}.{}", name, extension))
+}
+
+/// Locks the data file so that only one program can change it at a time.
+///
+/// The lock is placed on a separate ".lock" file, because saving replaces
+/// the data file with a brand new one and a lock on the old file would no
+/// longer protect anything. Commands that change records take an exclusive
+/// lock, while read-only commands take a shared lock so they can run at the
+/// same time as each other. When "wait" is false and the lock is already
+/// held, an error is returned instead of waiting for the lock.
+///
+/// The lock file can't be created in a directory that we can't write to.
+/// Read-only commands then go on without a lock, which is safe because
+/// saving replaces the data file in one step, so it is never seen half
+/// written. Commands that change records couldn't save there anyway. With
+/// "--read-only", nothing at all may be written, so the lock file is only
+/// used if it already exists, and the command goes on without a lock in
+/// the same way otherwise.
+///
+/// The lock is released when the returned file is dropped.
+fn lock_data_file(
+    file_name: &Path,
+    exclusive: bool,
+    wait: bool,
+    read_only: bool,
+) -> Result<Option<File>, AppError> {
+    let lock_name = sidecar_path(file_name, "lock");
+    let lock = match read_only {
+        true => File::open(&lock_name),
+        false => OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_name),
+    };
+    let lock = match lock {
+        Ok(lock) => lock,
+        Err(e) if read_only && e.kind() == std::io::ErrorKind::NotFound => {
+            debug!(path = %lock_name.display(), "there is no lock file, so reading without it");
+            return Ok(None);
+        }
+        Err(e) if is_read_only_error(&e) && exclusive => {
+            return Err(AppError::ReadOnly(file_name.to_path_buf()))
+        }
+        Err(e) if is_read_only_error(&e) => {
+            debug!(path = %lock_name.display(), "can't create the lock file, so reading without it");
+            return Ok(None);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let result = match (exclusive, wait) {
+        (true, true) => return Ok(lock.lock().map(|_| Some(lock))?),
+        (false, true) => return Ok(lock.lock_shared().map(|_| Some(lock))?),
+        (true, false) => lock.try_lock(),
+        (false, false) => lock.try_lock_shared(),
+    };
+    match result {
+        Ok(()) => Ok(Some(lock)),
+        Err(TryLockError::WouldBlock) => Err(AppError::Locked(file_name.to_path_buf())),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Checks whether an error means that a file can't be written, either
+/// because of its permissions or because the whole filesystem is read-only.
+fn is_read_only_error(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        e.kind(),
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem
+    )
+}
+
+/// Checks whether a data file has been made read-only, such as with
+/// "chmod a-w". Saving would replace the file anyway, since that only needs
+/// the directory to be writable, so p2 checks for it itself.
+fn is_read_only_file(file_name: &Path) -> bool {
+    fs::metadata(file_name)
+        .map(|metadata| metadata.permissions().readonly())
+        .unwrap_or(false)
+}
+
+/// How many backups are kept when no other number is given.
+const BACKUPS_KEPT: usize = 10;
+
+/// Returns the directory that holds the backups of a data file, such as
+/// "p2_data.csv.backups".
+fn backup_dir(data_file: &Path) -> PathBuf {
+    sidecar_path(data_file, "backups")
+}
+
+/// Returns every backup of a data file, oldest first. Each backup is named
+/// after the time it was made, followed by the name of the data file, so
+/// sorting the names also sorts them by time.
+fn list_backups(data_file: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(backup_dir(data_file)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let name = data_file.file_name().unwrap_or_default().to_string_lossy();
+    let suffix = format!("-{}", name);
+    let mut backups = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        // Temporary files from a backup in progress start with a dot.
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if file_name.ends_with(&suffix) && !file_name.starts_with('.') {
+            backups.push(path);
+        }
+    }
+    backups.sort();
+    Ok(backups)
+}
+
+/// Copies the data file into its backup directory, then removes the oldest
+/// backups so that only "keep" of them are left. Returns the new backup.
+fn backup_data_file(data_file: &Path, keep: usize) -> std::io::Result<PathBuf> {
+    let dir = backup_dir(data_file);
+    fs::create_dir_all(&dir)?;
+    // Colons can't be used in file names on every system, so the time is
+    // written without them. The milliseconds keep two quick backups apart.
+    let time = Utc::now().format("%Y%m%dT%H%M%S%.3fZ");
+    let name = data_file.file_name().unwrap_or_default().to_string_lossy();
+    let backup = dir.join(format!("{}-{}", time, name));
+    // Like saving, the copy is renamed into place once it is complete, so a
+    // backup is never only partly written.
+    let tmp_name = temp_path(&backup);
+    if let Err(e) = fs::copy(data_file, &tmp_name) {
+        let _ = fs::remove_file(&tmp_name);
+        return Err(e);
+    }
+    fs::rename(&tmp_name, &backup)?;
+    let backups = list_backups(data_file)?;
+    // The new backup is always kept, even if "keep" is zero.
+    let old = backups.len().saturating_sub(keep.max(1));
+    for old_backup in backups[..old].iter() {
+        fs::remove_file(old_backup)?;
+    }
+    Ok(backup)
+}
+
+/// Returns the time a backup was made, which starts its file name.
+fn backup_time(backup: &Path) -> &str {
+    let name = backup.file_name().and_then(|name| name.to_str());
+    name.and_then(|name| name.split('-').next())
+        .unwrap_or_default()
+}
+
+/// Finds the backup made at a time. Only the start of the time needs to be
+/// given, as long as just one backup matches it.
+fn find_backup(data_file: &Path, time: &str) -> Result<PathBuf, AppError> {
+    let mut matches: Vec<_> = list_backups(data_file)?
+        .into_iter()
+        .filter(|backup| backup_time(backup).starts_with(time))
+        .collect();
+    match matches.len() {
+        1 => Ok(matches.remove(0)),
+        0 => Err(AppError::NoSuchBackup(time.to_owned())),
+        count => Err(AppError::AmbiguousBackup(time.to_owned(), count)),
+    }
+}
+
+/// Loads the records in a backup, which may be compressed or encrypted
+/// just like the data file.
+fn load_backup(backup: &Path, passphrase: Option<&str>) -> Result<Records, AppError> {
+    match passphrase {
+        Some(passphrase) if is_encrypted_file(backup)? => {
+            load_encrypted(backup.to_path_buf(), passphrase, false)
+        }
+        None if is_encrypted_file(backup)? => Err(AppError::Encrypted),
+        _ => Ok(load_records(
+            backup.to_path_buf(),
+            CsvFormat::default(),
+            false,
+        )?),
+    }
+}
+
+/// Describes how many records were added or removed, such as "+2" or "-1".
+fn count_change(before: usize, after: usize) -> String {
+    match after.cmp(&before) {
+        std::cmp::Ordering::Less => format!("-{}", before - after),
+        std::cmp::Ordering::Equal => "same".to_owned(),
+        std::cmp::Ordering::Greater => format!("+{}", after - before),
+    }
+}
+
+/// A place where records can be loaded from and saved to.
+trait Storage {
+    /// Loads every saved record.
+    fn load(&self) -> Result<Records, AppError>;
+
+    /// Saves the records, replacing whatever was previously saved.
+    fn save(&self, records: &Records) -> Result<(), AppError>;
+
+    /// Adds a single new record, giving it the next available id. Returns
+    /// the record with its id.
+    ///
+    /// By default, this loads every record and saves them all again.
+    /// Storages that can add a record more quickly should do so.
+    fn add(&self, record: Record, id_policy: IdPolicy) -> Result<Record, AppError> {
+        add_by_saving(self, record, id_policy)
+    }
+}
+
+/// Adds a record by loading every record and saving them all again. This
+/// works for any storage.
+fn add_by_saving<S: Storage + ?Sized>(
+    storage: &S,
+    mut record: Record,
+    id_policy: IdPolicy,
+) -> Result<Record, AppError> {
+    let mut records = storage.load()?;
+    records.id_policy = id_policy;
+    record.id = records.next_id();
+    records.add(record.clone());
+    storage.save(&records)?;
+    Ok(record)
+}
+
+/// The storage backends that can be selected on the command line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Backend {
+    Csv,
+    Sqlite,
+    Sled,
+    Jsonl,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "sqlite" => Ok(Self::Sqlite),
+            "sled" => Ok(Self::Sled),
+            "jsonl" => Ok(Self::Jsonl),
+            _ => Err(format!(
+                "unknown backend: {} (expected csv, sqlite, sled or jsonl)",
+                s
+            )),
+        }
+    }
+}
+
+/// Creates the storage for the selected backend. A "Box" is used because
+/// each backend is a different type, but they all implement "Storage".
+/// Only CSV files can be encrypted, so a passphrase always means CSV.
+fn open_storage(
+    backend: Backend,
+    path: PathBuf,
+    strict: bool,
+    ignore_checksum: bool,
+    format: CsvFormat,
+    passphrase: Option<String>,
+) -> Box<dyn Storage> {
+    // Records from stdin are always CSV, whatever the backend is.
+    if is_stdio(&path) {
+        return Box::new(StdioStorage {
+            strict,
+            format,
+            loaded: RefCell::new(None),
+        });
+    }
+    if let Some(passphrase) = passphrase {
+        return Box::new(EncryptedStorage {
+            path,
+            strict,
+            passphrase,
+        });
+    }
+    match backend {
+        Backend::Csv => Box::new(CsvStorage {
+            path,
+            strict,
+            ignore_checksum,
+            format,
+        }),
+        Backend::Sqlite => Box::new(SqliteStorage { path }),
+        Backend::Sled => Box::new(SledStorage { path }),
+        Backend::Jsonl => Box::new(JsonlStorage { path, strict }),
+    }
+}
+
+/// One change made to a single record. "before" is None when the record
+/// was added, and "after" is None when the record was removed.
+#[derive(Debug, Serialize, Deserialize)]
+struct Change {
+    id: RecordId,
+    before: Option<Record>,
+    after: Option<Record>,
+}
+
+/// Compares two sets of records and lists every record that is different.
+fn diff_records(before: &Records, after: &Records) -> Vec<Change> {
+    // A BTreeSet keeps each id only once, in order.
+    let ids: BTreeSet<RecordId> = before.ids().chain(after.ids()).collect();
+    ids.into_iter()
+        .filter_map(|id| {
+            let (old, new) = (before.get(id), after.get(id));
+            (old != new).then(|| Change {
+                id,
+                before: old.cloned(),
+                after: new.cloned(),
+            })
+        })
+        .collect()
+}
+
+/// What happened to a record in a single change.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Operation {
+    Added,
+    Edited,
+    Removed,
+}
+
+impl Operation {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Added => "added",
+            Operation::Edited => "edited",
+            Operation::Removed => "removed",
+        }
+    }
+}
+
+impl Change {
+    fn operation(&self) -> Operation {
+        match (&self.before, &self.after) {
+            (None, _) => Operation::Added,
+            (_, None) => Operation::Removed,
+            _ => Operation::Edited,
+        }
+    }
+}
+
+/// One line of the history log, which records when each change happened.
+/// Unlike the journal, the history is never shortened by undoing.
+#[derive(Debug, Serialize, Deserialize)]
+struct HistoryEntry {
+    id: RecordId,
+    time: DateTime<Utc>,
+    operation: Operation,
+}
+
+/// Wraps another storage and writes every change that is saved into a
+/// journal file, so that the change can be undone later. Each change is
+/// also added to the history log along with the time it was made.
+///
+/// The records are remembered when they are loaded, and compared with the
+/// records being saved to find out what changed. Each save becomes one line
+/// of JSON in the journal.
+///
+/// Since every command loads its records through this storage, it is also
+/// where the records are told how to give ids to new records.
+struct JournalStorage {
+    inner: Box<dyn Storage>,
+    id_policy: IdPolicy,
+    // When undoing, changes are only added to the history and not the
+    // journal, so there is no journal path. Records read from stdin have
+    // neither, since nothing is written to the disk.
+    journal: Option<PathBuf>,
+    history: Option<PathBuf>,
+    // With --git, the data file to commit after every save.
+    git: Option<PathBuf>,
+    // "RefCell" lets us update the remembered records even though "load"
+    // and "save" only get a shared reference to the storage.
+    loaded: RefCell<Option<Records>>,
+}
+
+impl JournalStorage {
+    fn new(
+        inner: Box<dyn Storage>,
+        id_policy: IdPolicy,
+        journal: Option<PathBuf>,
+        history: Option<PathBuf>,
+        git: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            inner,
+            id_policy,
+            journal,
+            history,
+            git,
+            loaded: RefCell::new(None),
+        }
+    }
+}
+
+impl JournalStorage {
+    /// Writes the changes to the journal and the history.
+    fn log_changes(&self, changes: &[Change]) -> Result<(), AppError> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        if let Some(journal) = &self.journal {
+            append_line(journal, &serde_json::to_string(changes)?)?;
+        }
+        let history = match &self.history {
+            Some(history) => history,
+            None => return Ok(()),
+        };
+        let time = Utc::now();
+        for change in changes.iter() {
+            let entry = HistoryEntry {
+                id: change.id,
+                time,
+                operation: change.operation(),
+            };
+            append_line(history, &serde_json::to_string(&entry)?)?;
+        }
+        Ok(())
+    }
+
+    /// Commits the data file to git, with a message that describes the
+    /// changes. Saves that didn't change any record, such as after
+    /// sorting, are committed by "run" once the command is done instead.
+    fn commit_changes(&self, changes: &[Change]) {
+        if let Some(data_file) = self.git.as_ref().filter(|_| !changes.is_empty()) {
+            commit_or_warn(data_file, &commit_message(changes));
+        }
+    }
+}
+
+/// Commits the data file with "git_commit". The changes are already saved
+/// by then, so failing to commit them is only a warning.
+fn commit_or_warn(data_file: &Path, message: &str) {
+    if let Err(e) = git_commit(data_file, message) {
+        let warning = format!("warning: couldn't commit the change to git: {}", e);
+        eprintln!("{}", output::error(&warning));
+    }
+}
+
+/// Describes the changes of a single save as a commit message, such as
+/// "remove contact 12".
+fn commit_message(changes: &[Change]) -> String {
+    let verb = |operation| match operation {
+        Operation::Added => "add",
+        Operation::Edited => "edit",
+        Operation::Removed => "remove",
+    };
+    if let [change] = changes {
+        return format!("{} contact {}", verb(change.operation()), change.id);
+    }
+    let counts: Vec<_> = [Operation::Added, Operation::Edited, Operation::Removed]
+        .iter()
+        .filter_map(|operation| {
+            let count = changes
+                .iter()
+                .filter(|change| change.operation() == *operation)
+                .count();
+            (count > 0).then(|| format!("{} {}", count, operation.as_str()))
+        })
+        .collect();
+    format!("change {} contacts: {}", changes.len(), counts.join(", "))
+}
+
+/// Runs git in the directory of the data file, and returns what it printed.
+fn run_git(data_file: &Path, args: &[&str]) -> Result<String, AppError> {
+    let dir = match data_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        let message = String::from_utf8_lossy(&output.stderr);
+        return Err(AppError::Git(message.trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Commits the data file if it is in a git repository and has changed. Its
+/// checksum is committed along with it, so that an older version that is
+/// checked out still matches its checksum. Only these files are committed,
+/// even when other files in the repository were staged.
+fn git_commit(data_file: &Path, message: &str) -> Result<(), AppError> {
+    let name = data_file.file_name().unwrap_or_default().to_string_lossy();
+    let checksum = sidecar_path(data_file, "sha256");
+    let checksum_name = checksum.file_name().unwrap_or_default().to_string_lossy();
+    // A data file outside of a repository simply isn't committed.
+    match run_git(data_file, &["rev-parse", "--is-inside-work-tree"]) {
+        Ok(_) => (),
+        Err(AppError::Git(_)) => {
+            debug!(path = %data_file.display(), "not in a git repository, so not committing");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    }
+    // The checksum is left out when there is none, unless it was committed
+    // before, since removing it is a change too. Encrypted files have none.
+    let mut names = vec![name.as_ref()];
+    if checksum.exists() || !run_git(data_file, &["ls-files", "--", &checksum_name])?.is_empty() {
+        names.push(&checksum_name);
+    }
+    let status = run_git(
+        data_file,
+        &[&["status", "--porcelain", "--"], &names[..]].concat(),
+    )?;
+    if status.trim().is_empty() {
+        debug!(path = %data_file.display(), "the data file hasn't changed, so not committing");
+        return Ok(());
+    }
+    run_git(data_file, &[&["add", "--"], &names[..]].concat())?;
+    run_git(
+        data_file,
+        &[&["commit", "--quiet", "-m", message, "--"], &names[..]].concat(),
+    )?;
+    info!(message, "committed the data file");
     Ok(())
 }
 
-fn main() {
-    let opt = Opt::from_args();
-    if let Err(e) = run(opt) {
-        println!("an error occurred: {}", e);
+/// Adds a line to the end of a file, creating the file if needed.
+fn append_line(file_name: &Path, line: &str) -> std::io::Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_name)?;
+    file.write_all(format!("{}\n", line).as_bytes())
+}
+
+/// Loads the history of a single record, oldest first.
+fn load_history(file_name: &Path, id: RecordId) -> Result<Vec<HistoryEntry>, AppError> {
+    let data = match fs::read_to_string(file_name) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = vec![];
+    for line in data.lines().filter(|line| !line.trim().is_empty()) {
+        let entry: HistoryEntry = serde_json::from_str(line)?;
+        if entry.id == id {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Changes the ids in the history after the records were renumbered. "ids"
+/// has the new id of every record. The entries of contacts that no longer
+/// exist are dropped, since their ids may now belong to other contacts.
+fn renumber_history(file_name: &Path, ids: &HashMap<RecordId, RecordId>) -> Result<(), AppError> {
+    let data = match fs::read_to_string(file_name) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut renumbered = String::new();
+    for line in data.lines().filter(|line| !line.trim().is_empty()) {
+        let mut entry: HistoryEntry = serde_json::from_str(line)?;
+        match ids.get(&entry.id) {
+            Some(id) => entry.id = *id,
+            None => continue,
+        }
+        renumbered.push_str(&serde_json::to_string(&entry)?);
+        renumbered.push('\n');
+    }
+    Ok(save_file(file_name.to_path_buf(), |file| {
+        file.write_all(renumbered.as_bytes())
+    })?)
+}
+
+impl Storage for JournalStorage {
+    fn load(&self) -> Result<Records, AppError> {
+        let mut records = self.inner.load()?;
+        records.id_policy = self.id_policy;
+        *self.loaded.borrow_mut() = Some(records.clone());
+        Ok(records)
+    }
+
+    fn save(&self, records: &Records) -> Result<(), AppError> {
+        let changes = match self.loaded.borrow().as_ref() {
+            Some(before) => diff_records(before, records),
+            None => vec![],
+        };
+        self.inner.save(records)?;
+        self.log_changes(&changes)?;
+        self.commit_changes(&changes);
+        // Later saves are compared against what was just saved.
+        *self.loaded.borrow_mut() = Some(records.clone());
+        Ok(())
+    }
+
+    fn add(&self, record: Record, id_policy: IdPolicy) -> Result<Record, AppError> {
+        let record = self.inner.add(record, id_policy)?;
+        let changes = [Change {
+            id: record.id,
+            before: None,
+            after: Some(record.clone()),
+        }];
+        self.log_changes(&changes)?;
+        self.commit_changes(&changes);
+        Ok(record)
+    }
+}
+
+/// Wraps another storage for "--dry-run". Nothing is ever saved. Instead,
+/// the records are compared with the ones that were loaded, and the
+/// differences are printed.
+struct DryRunStorage {
+    inner: Box<dyn Storage>,
+    loaded: RefCell<Option<Records>>,
+}
+
+impl Storage for DryRunStorage {
+    fn load(&self) -> Result<Records, AppError> {
+        let records = self.inner.load()?;
+        *self.loaded.borrow_mut() = Some(records.clone());
+        Ok(records)
+    }
+
+    fn save(&self, records: &Records) -> Result<(), AppError> {
+        // Later saves are compared against these records, just like they
+        // would be if they had been saved.
+        let before = self
+            .loaded
+            .borrow_mut()
+            .replace(records.clone())
+            .unwrap_or_default();
+        println!("dry run, so these changes were not saved:");
+        print_diff(&diff_files(&before, records));
+        Ok(())
+    }
+}
+
+/// Wraps another storage for "--read-only", so that nothing can ever be
+/// saved, whichever command is run.
+struct ReadOnlyStorage {
+    inner: Box<dyn Storage>,
+    path: PathBuf,
+}
+
+impl Storage for ReadOnlyStorage {
+    fn load(&self) -> Result<Records, AppError> {
+        self.inner.load()
+    }
+
+    fn save(&self, _records: &Records) -> Result<(), AppError> {
+        Err(AppError::ReadOnly(self.path.clone()))
+    }
+
+    fn add(&self, _record: Record, _id_policy: IdPolicy) -> Result<Record, AppError> {
+        Err(AppError::ReadOnly(self.path.clone()))
+    }
+}
+
+/// Reverses the most recent change in the journal and removes it from the
+/// journal. Returns the undone changes, or None if the journal is empty.
+fn undo_change(storage: &dyn Storage, journal: &Path) -> Result<Option<Vec<Change>>, AppError> {
+    let data = match fs::read_to_string(journal) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines: Vec<&str> = data
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    let changes: Vec<Change> = match lines.pop() {
+        Some(line) => serde_json::from_str(line)?,
+        None => return Ok(None),
+    };
+
+    // Each record is put back the way it was before the change.
+    let mut records = storage.load()?;
+    for change in changes.iter() {
+        match &change.before {
+            Some(record) => records.add(record.clone()),
+            None => {
+                records.remove(change.id);
+            }
+        }
+    }
+    storage.save(&records)?;
+
+    // The journal is only shortened once the records are saved, so a failed
+    // save can simply be undone again.
+    let mut remaining = String::new();
+    for line in lines {
+        remaining.push_str(line);
+        remaining.push('\n');
+    }
+    fs::write(journal, remaining)?;
+    Ok(Some(changes))
+}
+
+/// Returns the SHA-256 checksum of a file as hexadecimal.
+fn file_checksum(file_name: &Path) -> std::io::Result<String> {
+    let mut file = File::open(file_name)?;
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        context.update(&buffer[..count]);
+    }
+    let digest = context.finish();
+    Ok(digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Saves the checksum of the data file next to it, so that changes made
+/// outside of p2 can be noticed the next time it is loaded. The file has
+/// the same format as the "sha256sum" program, so "sha256sum -c" can check
+/// it as well. Like the data file, it is written to a temporary file first,
+/// so a crash never leaves half a checksum behind.
+fn write_checksum(data_file: &Path) -> std::io::Result<()> {
+    let name = data_file.file_name().unwrap_or_default().to_string_lossy();
+    let line = format!("{}  {}\n", file_checksum(data_file)?, name);
+    save_file(sidecar_path(data_file, "sha256"), |file| {
+        file.write_all(line.as_bytes())
+    })
+}
+
+/// Checks the data file against its saved checksum. A file that doesn't
+/// match was changed by another program, or was damaged or cut short. Files
+/// without a saved checksum, such as ones that were never saved by p2, are
+/// not checked.
+fn verify_checksum(data_file: &Path, ignore: bool) -> Result<(), AppError> {
+    let saved = match fs::read_to_string(sidecar_path(data_file, "sha256")) {
+        Ok(saved) => saved,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let saved = saved.split_whitespace().next().unwrap_or_default();
+    if saved == file_checksum(data_file)? {
+        return Ok(());
+    }
+    if !ignore {
+        return Err(AppError::ChecksumMismatch(data_file.to_path_buf()));
+    }
+    let warning = format!(
+        "warning: {} doesn't match its checksum, continuing anyway",
+        data_file.display()
+    );
+    eprintln!("{}", output::error(&warning));
+    Ok(())
+}
+
+/// Checks that the records can be saved without losing anything. A data
+/// file written by a newer version of p2 may have fields that we don't know
+/// how to keep.
+fn check_schema(schema: u32) -> Result<(), AppError> {
+    match schema > SCHEMA_VERSION {
+        true => Err(AppError::NewerSchema(schema)),
+        false => Ok(()),
+    }
+}
+
+/// Stores records in a CSV file.
+struct CsvStorage {
+    path: PathBuf,
+    /// Whether lines that can't be parsed are an error instead of being
+    /// skipped.
+    strict: bool,
+    /// Whether to only warn when the checksum doesn't match.
+    ignore_checksum: bool,
+    /// The format to use instead of the one found from the header line.
+    format: CsvFormat,
+}
+
+impl Storage for CsvStorage {
+    fn load(&self) -> Result<Records, AppError> {
+        verify_checksum(&self.path, self.ignore_checksum)?;
+        Ok(load_records(self.path.clone(), self.format, self.strict)?)
+    }
+
+    fn save(&self, records: &Records) -> Result<(), AppError> {
+        check_schema(records.schema)?;
+        save_records(self.path.clone(), records)?;
+        Ok(write_checksum(&self.path)?)
+    }
+
+    fn add(&self, mut record: Record, id_policy: IdPolicy) -> Result<Record, AppError> {
+        // A record can't be added to the end of a compressed file.
+        if is_compressed(&self.path)? {
+            return add_by_saving(self, record, id_policy);
+        }
+        verify_checksum(&self.path, self.ignore_checksum)?;
+        // The records are read one at a time just to find the largest id in
+        // use, so they never all need to be kept in memory. Lines that can't
+        // be parsed are skipped, just like when loading every record.
+        let mut largest = 0;
+        let mut reader =
+            RecordReader::new(BufReader::new(File::open(&self.path)?)).with_format(self.format);
+        let mut bad_lines = vec![];
+        while let Some(result) = reader.next() {
+            match result {
+                Ok(Record {
+                    id: RecordId::Number(id),
+                    ..
+                }) => largest = largest.max(id),
+                Ok(_) => (),
+                Err(ParseError::Io(e)) => return Err(e.into()),
+                Err(e) if self.strict => bad_lines.push(BadLine::new(&reader, e)),
+                Err(_) => (),
+            }
+        }
+        if !bad_lines.is_empty() {
+            return Err(AppError::Malformed(bad_lines));
+        }
+        check_schema(reader.schema)?;
+        // The new record's fields would end up in the wrong columns if the
+        // file has them in another order, and at the end of a file that is
+        // kept sorted it would be out of place. The whole file is saved then.
+        // Older files are saved too, so that they are upgraded before a
+        // record written by this version is added to them.
+        if !reader.has_usual_columns()
+            || reader.format().sort != SortOrder::Id
+            || reader.schema < SCHEMA_VERSION
+        {
+            return add_by_saving(self, record, id_policy);
+        }
+        record.id = id_policy.next_id(largest, reader.saved_next_id);
+        append_record(&self.path, &record, reader.delimiter)?;
+        write_checksum(&self.path)?;
+        Ok(record)
+    }
+}
+
+/// Stores records in a JSON Lines file, with one contact on each line.
+struct JsonlStorage {
+    path: PathBuf,
+    /// Whether lines that can't be parsed are an error instead of being
+    /// skipped.
+    strict: bool,
+}
+
+impl Storage for JsonlStorage {
+    fn load(&self) -> Result<Records, AppError> {
+        let start = std::time::Instant::now();
+        // Like the databases, a file that doesn't exist yet has no records.
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Records::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let recs = read_json_lines(BufReader::new(file), self.strict)?;
+        info!(
+            path = %self.path.display(),
+            records = recs.len(),
+            elapsed = ?start.elapsed(),
+            "loaded the records"
+        );
+        Ok(recs)
+    }
+
+    fn save(&self, records: &Records) -> Result<(), AppError> {
+        check_schema(records.schema)?;
+        let start = std::time::Instant::now();
+        save_file(self.path.clone(), |file| write_json_lines(file, records))?;
+        info!(
+            path = %self.path.display(),
+            records = records.len(),
+            elapsed = ?start.elapsed(),
+            "saved the records"
+        );
+        Ok(())
+    }
+
+    /// The records are still read to find the next id, but the new record
+    /// is simply added as a line at the end, without writing the others.
+    fn add(&self, mut record: Record, id_policy: IdPolicy) -> Result<Record, AppError> {
+        let mut records = self.load()?;
+        check_schema(records.schema)?;
+        records.id_policy = id_policy;
+        record.id = records.next_id();
+        append_line(&self.path, &format_json_line(&record)?)?;
+        Ok(record)
+    }
+}
+
+/// Reads records from stdin and writes them to stdout, so that p2 can be
+/// used as a filter in a shell pipeline without touching the disk.
+struct StdioStorage {
+    strict: bool,
+    format: CsvFormat,
+    // Stdin can only be read once, so the records are remembered in case
+    // they are loaded again.
+    loaded: RefCell<Option<Records>>,
+}
+
+impl Storage for StdioStorage {
+    fn load(&self) -> Result<Records, AppError> {
+        if let Some(records) = self.loaded.borrow().as_ref() {
+            return Ok(records.clone());
+        }
+        let records = read_records(std::io::stdin().lock(), self.format, self.strict)?;
+        info!(records = records.len(), "read the records from stdin");
+        *self.loaded.borrow_mut() = Some(records.clone());
+        Ok(records)
+    }
+
+    fn save(&self, records: &Records) -> Result<(), AppError> {
+        let mut stdout = std::io::stdout().lock();
+        Ok(records.to_writer(&mut stdout, DataFormat::Csv)?)
+    }
+}
+
+/// Encryption of whole files with a passphrase.
+///
+/// The passphrase is turned into a key with PBKDF2, which is deliberately
+/// slow so that guessing passphrases takes a long time. The data is then
+/// encrypted with ChaCha20-Poly1305, which also detects any change to the
+/// encrypted file, including a wrong passphrase.
+///
+/// An encrypted file starts with "MAGIC", followed by the random salt for
+/// the key, the random nonce, and then the encrypted data.
+mod crypt {
+    use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+    use ring::pbkdf2;
+    use ring::rand::{SecureRandom, SystemRandom};
+    use std::num::NonZeroU32;
+
+    /// Marks a file as encrypted, and which version of the format it uses.
+    pub const MAGIC: &[u8] = b"p2-encrypted-v1\n";
+    const SALT_LEN: usize = 16;
+    const PBKDF2_ROUNDS: u32 = 600_000;
+
+    /// Turns a passphrase into the key for ChaCha20-Poly1305.
+    fn key(passphrase: &str, salt: &[u8]) -> LessSafeKey {
+        let mut key = [0; 32];
+        let rounds = NonZeroU32::new(PBKDF2_ROUNDS).unwrap();
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            rounds,
+            salt,
+            passphrase.as_bytes(),
+            &mut key,
+        );
+        LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &key).unwrap())
+    }
+
+    /// Encrypts data with a passphrase.
+    pub fn encrypt(data: &[u8], passphrase: &str) -> Vec<u8> {
+        // A new salt and nonce are used every time, so saving the same
+        // records twice never gives the same encrypted file.
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; NONCE_LEN];
+        let random = SystemRandom::new();
+        random.fill(&mut salt).expect("no random numbers available");
+        random
+            .fill(&mut nonce)
+            .expect("no random numbers available");
+        let mut sealed = data.to_vec();
+        key(passphrase, &salt)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(MAGIC),
+                &mut sealed,
+            )
+            .expect("the data is too large to encrypt");
+        [MAGIC, &salt, &nonce, &sealed].concat()
+    }
+
+    /// Decrypts data that was encrypted with "encrypt". Returns None if the
+    /// passphrase is wrong or the data was changed.
+    pub fn decrypt(data: &[u8], passphrase: &str) -> Option<Vec<u8>> {
+        let data = data.strip_prefix(MAGIC)?;
+        if data.len() < SALT_LEN + NONCE_LEN {
+            return None;
+        }
+        let (salt, data) = data.split_at(SALT_LEN);
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut sealed = sealed.to_vec();
+        let opened = key(passphrase, salt)
+            .open_in_place(nonce, Aad::from(MAGIC), &mut sealed)
+            .ok()?;
+        Some(opened.to_vec())
+    }
+}
+
+/// Checks whether a file was encrypted by p2. A missing file isn't.
+fn is_encrypted_file(file_name: &Path) -> std::io::Result<bool> {
+    let mut start = vec![];
+    match File::open(file_name) {
+        Ok(file) => file
+            .take(crypt::MAGIC.len() as u64)
+            .read_to_end(&mut start)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    Ok(start == crypt::MAGIC)
+}
+
+/// Loads records from an encrypted file. The whole file is decrypted in
+/// memory, so the records are never written to disk unencrypted.
+fn load_encrypted(file_name: PathBuf, passphrase: &str, strict: bool) -> Result<Records, AppError> {
+    let start = std::time::Instant::now();
+    let data = fs::read(&file_name)?;
+    let data = crypt::decrypt(&data, passphrase).ok_or(AppError::WrongPassphrase)?;
+    let recs = read_records(data.as_slice(), CsvFormat::default(), strict)?;
+    info!(
+        path = %file_name.display(),
+        records = recs.len(),
+        elapsed = ?start.elapsed(),
+        "loaded the encrypted records"
+    );
+    Ok(recs)
+}
+
+/// Saves records to an encrypted file.
+fn save_encrypted(file_name: PathBuf, records: &Records, passphrase: &str) -> std::io::Result<()> {
+    let start = std::time::Instant::now();
+    let path = file_name.display().to_string();
+    let mut data = vec![];
+    records.to_writer(&mut data, DataFormat::Csv)?;
+    let data = crypt::encrypt(&data, passphrase);
+    save_file(file_name, |file| file.write_all(&data))?;
+    info!(
+        path,
+        records = records.len(),
+        elapsed = ?start.elapsed(),
+        "saved the encrypted records"
+    );
+    Ok(())
+}
+
+/// Stores records in a CSV file that is encrypted with a passphrase.
+///
+/// Adding a record can't just append to the end of the file like
+/// "CsvStorage" does, so every record is loaded and saved again instead.
+struct EncryptedStorage {
+    path: PathBuf,
+    strict: bool,
+    passphrase: String,
+}
+
+impl Storage for EncryptedStorage {
+    fn load(&self) -> Result<Records, AppError> {
+        load_encrypted(self.path.clone(), &self.passphrase, self.strict)
+    }
+
+    fn save(&self, records: &Records) -> Result<(), AppError> {
+        check_schema(records.schema)?;
+        Ok(save_encrypted(
+            self.path.clone(),
+            records,
+            &self.passphrase,
+        )?)
+    }
+}
+
+/// Stores records in an SQLite database.
+///
+/// Unlike the CSV file, only the rows that actually changed are written
+/// when saving, which keeps saves fast for large numbers of contacts.
+struct SqliteStorage {
+    path: PathBuf,
+}
+
+/// The columns of the contacts table other than the id, along with their
+/// SQL types. Columns missing from an existing database are added when it
+/// is opened, so older databases keep working as new fields are added.
+const SQLITE_COLUMNS: [(&str, &str); 16] = [
+    ("name", "TEXT NOT NULL DEFAULT ''"),
+    ("emails", "TEXT NOT NULL DEFAULT ''"),
+    ("phone", "TEXT"),
+    ("tags", "TEXT NOT NULL DEFAULT ''"),
+    ("created_at", "TEXT NOT NULL DEFAULT ''"),
+    ("updated_at", "TEXT NOT NULL DEFAULT ''"),
+    ("address", "TEXT"),
+    ("birthday", "TEXT"),
+    ("notes", "TEXT"),
+    ("relations", "TEXT NOT NULL DEFAULT ''"),
+    ("avatar", "TEXT"),
+    ("company", "TEXT"),
+    ("title", "TEXT"),
+    ("nickname", "TEXT"),
+    ("last_contacted", "TEXT"),
+    ("extra", "TEXT NOT NULL DEFAULT '{}'"),
+];
+
+/// Converts a record into the values of the "SQLITE_COLUMNS", in the same
+/// order. Lists are stored semicolon-delimited, just like in the CSV file.
+/// The values of unknown columns are stored as a JSON object, sorted by
+/// column name so that an unchanged record always has the same text.
+fn sqlite_values(record: &Record) -> Vec<rusqlite::types::Value> {
+    use rusqlite::types::Value;
+    vec![
+        Value::Text(record.name.clone()),
+        Value::Text(record.emails.join(";")),
+        record.phone.clone().map(Value::Text).unwrap_or(Value::Null),
+        Value::Text(record.tags.join(";")),
+        Value::Text(format_timestamp(record.created_at)),
+        Value::Text(format_timestamp(record.updated_at)),
+        record
+            .address
+            .clone()
+            .map(Value::Text)
+            .unwrap_or(Value::Null),
+        record
+            .birthday
+            .map(|date| Value::Text(date.to_string()))
+            .unwrap_or(Value::Null),
+        record.notes.clone().map(Value::Text).unwrap_or(Value::Null),
+        Value::Text(
+            record
+                .relations
+                .iter()
+                .map(|relation| relation.to_string())
+                .collect::<Vec<_>>()
+                .join(";"),
+        ),
+        record
+            .avatar
+            .clone()
+            .map(Value::Text)
+            .unwrap_or(Value::Null),
+        record
+            .company
+            .clone()
+            .map(Value::Text)
+            .unwrap_or(Value::Null),
+        record.title.clone().map(Value::Text).unwrap_or(Value::Null),
+        record
+            .nickname
+            .clone()
+            .map(Value::Text)
+            .unwrap_or(Value::Null),
+        record
+            .last_contacted
+            .map(|date| Value::Text(date.to_string()))
+            .unwrap_or(Value::Null),
+        Value::Text(
+            serde_json::to_string(&record.extra.iter().collect::<BTreeMap<_, _>>())
+                .expect("a map of strings can always be written as JSON"),
+        ),
+    ]
+}
+
+/// Ids are stored as integers, or as text for UUIDs.
+fn sqlite_id(id: RecordId) -> rusqlite::types::Value {
+    match id {
+        RecordId::Number(id) => rusqlite::types::Value::Integer(id),
+        RecordId::Uuid(id) => rusqlite::types::Value::Text(id.to_string()),
+    }
+}
+
+/// Reads an id column, stored by "sqlite_id".
+fn sqlite_id_column(row: &rusqlite::Row, column: &str) -> rusqlite::Result<RecordId> {
+    use rusqlite::types::{Type, Value};
+    let index = row.as_ref().column_index(column)?;
+    match row.get::<_, Value>(index)? {
+        Value::Integer(id) => Ok(RecordId::Number(id)),
+        Value::Text(id) => Uuid::parse_str(&id)
+            .map(RecordId::Uuid)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(e))),
+        value => Err(rusqlite::Error::InvalidColumnType(
+            index,
+            column.to_string(),
+            value.data_type(),
+        )),
+    }
+}
+
+/// Builds a record from a row of the contacts table.
+fn sqlite_record(row: &rusqlite::Row) -> rusqlite::Result<Record> {
+    Ok(Record {
+        id: sqlite_id_column(row, "id")?,
+        name: row.get("name")?,
+        emails: split_list(&row.get::<_, String>("emails")?),
+        phone: row.get("phone")?,
+        tags: split_list(&row.get::<_, String>("tags")?),
+        created_at: sqlite_timestamp(row, "created_at")?,
+        updated_at: sqlite_timestamp(row, "updated_at")?,
+        address: row.get("address")?,
+        birthday: row.get("birthday")?,
+        notes: row.get("notes")?,
+        relations: parse_relations(&row.get::<_, String>("relations")?).map_err(|e| {
+            let index = row.as_ref().column_index("relations").unwrap_or_default();
+            rusqlite::Error::FromSqlConversionFailure(
+                index,
+                rusqlite::types::Type::Text,
+                Box::new(e),
+            )
+        })?,
+        avatar: row.get("avatar")?,
+        company: row.get("company")?,
+        title: row.get("title")?,
+        nickname: row.get("nickname")?,
+        last_contacted: row.get("last_contacted")?,
+        extra: serde_json::from_str(&row.get::<_, String>("extra")?).map_err(|e| {
+            let index = row.as_ref().column_index("extra").unwrap_or_default();
+            rusqlite::Error::FromSqlConversionFailure(
+                index,
+                rusqlite::types::Type::Text,
+                Box::new(e),
+            )
+        })?,
+    })
+}
+
+/// Reads a timestamp column, which is stored as text.
+fn sqlite_timestamp(row: &rusqlite::Row, column: &str) -> rusqlite::Result<Option<DateTime<Utc>>> {
+    let timestamp = row.get::<_, String>(column)?;
+    parse_timestamp(&timestamp).map_err(|e| {
+        let index = row.as_ref().column_index(column).unwrap_or_default();
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+impl SqliteStorage {
+    /// Opens the database, creating the contacts table and any missing
+    /// columns if needed.
+    fn connect(&self) -> rusqlite::Result<rusqlite::Connection> {
+        let conn = rusqlite::Connection::open(&self.path)?;
+        // The id column has no type, so that it can hold both numbers and
+        // UUIDs. Databases created before UUIDs were supported have an
+        // "INTEGER" id column, and can only hold numeric ids.
+        conn.execute_batch("CREATE TABLE IF NOT EXISTS contacts (id PRIMARY KEY);")?;
+        // Settings, such as the next id, are stored in their own table.
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS settings (name TEXT PRIMARY KEY, value TEXT NOT NULL);",
+        )?;
+        let existing = {
+            let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('contacts')")?;
+            let names = stmt.query_map([], |row| row.get::<_, String>(0))?;
+            names.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        for (name, kind) in SQLITE_COLUMNS.iter() {
+            if !existing.iter().any(|column| column == name) {
+                conn.execute_batch(&format!(
+                    "ALTER TABLE contacts ADD COLUMN {} {};",
+                    name, kind
+                ))?;
+            }
+        }
+        Ok(conn)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self) -> Result<Records, AppError> {
+        let start = std::time::Instant::now();
+        let conn = self.connect()?;
+        let mut stmt = conn.prepare("SELECT * FROM contacts")?;
+        let rows = stmt.query_map([], sqlite_record)?;
+        let mut recs = Records::new();
+        for record in rows {
+            recs.add(record?);
+        }
+        let next_id: Option<String> = conn
+            .query_row(
+                "SELECT value FROM settings WHERE name = 'next_id'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(next_id) = next_id.and_then(|id| id.parse().ok()) {
+            recs.saved_next_id = next_id;
+        }
+        info!(
+            path = %self.path.display(),
+            records = recs.len(),
+            elapsed = ?start.elapsed(),
+            "loaded the records from the database"
+        );
+        Ok(recs)
+    }
+
+    fn save(&self, records: &Records) -> Result<(), AppError> {
+        let start = std::time::Instant::now();
+        let mut conn = self.connect()?;
+        // A transaction makes sure that either every change is saved, or
+        // none of them are.
+        let tx = conn.transaction()?;
+        {
+            // Rows that are no longer present in the records were removed.
+            let mut stmt = tx.prepare("SELECT id FROM contacts")?;
+            let ids = stmt.query_map([], |row| sqlite_id_column(row, "id"))?;
+            let mut delete = tx.prepare("DELETE FROM contacts WHERE id = ?1")?;
+            for id in ids {
+                let id = id?;
+                if records.get(id).is_none() {
+                    delete.execute([sqlite_id(id)])?;
+                    debug!(%id, "deleted a row");
+                }
+            }
+
+            // New rows are inserted, and existing rows are only updated
+            // when at least one of their fields has changed. The statement
+            // is built from "SQLITE_COLUMNS", so that it always includes
+            // every column.
+            let names: Vec<_> = SQLITE_COLUMNS.iter().map(|(name, _)| *name).collect();
+            let placeholders: Vec<_> = (1..=names.len() + 1).map(|i| format!("?{}", i)).collect();
+            let updates: Vec<_> = names
+                .iter()
+                .map(|name| format!("{0} = excluded.{0}", name))
+                .collect();
+            let changes: Vec<_> = names
+                .iter()
+                .map(|name| format!("{0} IS NOT excluded.{0}", name))
+                .collect();
+            let mut upsert = tx.prepare(&format!(
+                "INSERT INTO contacts (id, {}) VALUES ({})
+                ON CONFLICT(id) DO UPDATE SET {} WHERE {}",
+                names.join(", "),
+                placeholders.join(", "),
+                updates.join(", "),
+                changes.join(" OR "),
+            ))?;
+            for record in records.sorted() {
+                let mut values = vec![sqlite_id(record.id)];
+                values.extend(sqlite_values(record));
+                if upsert.execute(rusqlite::params_from_iter(values))? > 0 {
+                    debug!(id = %record.id, "wrote a row");
+                }
+            }
+            tx.execute(
+                "INSERT OR REPLACE INTO settings (name, value) VALUES ('next_id', ?1)",
+                [records.next_unused_id().to_string()],
+            )?;
+        }
+        tx.commit()?;
+        info!(
+            path = %self.path.display(),
+            records = records.len(),
+            elapsed = ?start.elapsed(),
+            "saved the records to the database"
+        );
+        Ok(())
+    }
+}
+
+/// Stores records in a sled database, which is a directory of files.
+///
+/// Each record is kept under its own key as JSON, so only the records that
+/// changed are written when saving. Every change of a save is applied as
+/// one batch, so a crash can't leave the database with only some of them.
+struct SledStorage {
+    path: PathBuf,
+}
+
+/// The keys of records are this followed by the id. Keys without it hold
+/// settings, such as the next id.
+const SLED_RECORD_PREFIX: &str = "contact/";
+
+/// The key that holds the smallest id that has never been used.
+const SLED_NEXT_ID: &str = "next_id";
+
+/// Opens a sled database. Sled holds a lock on the database until its
+/// background thread stops, which can be a moment after the database is
+/// dropped, so opening it again in the same process could fail, such as
+/// right after "--create" made it. Each database is only opened once and
+/// then shared instead.
+fn open_sled(path: &Path) -> Result<sled::Db, AppError> {
+    static OPENED: std::sync::Mutex<BTreeMap<PathBuf, sled::Db>> =
+        std::sync::Mutex::new(BTreeMap::new());
+    let mut opened = OPENED.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(db) = opened.get(path) {
+        return Ok(db.clone());
+    }
+    let db = sled::open(path)?;
+    opened.insert(path.to_path_buf(), db.clone());
+    Ok(db)
+}
+
+/// Returns the key of a record in the sled database.
+fn sled_key(id: RecordId) -> String {
+    format!("{}{}", SLED_RECORD_PREFIX, id)
+}
+
+impl Storage for SledStorage {
+    fn load(&self) -> Result<Records, AppError> {
+        let start = std::time::Instant::now();
+        let db = open_sled(&self.path)?;
+        let mut recs = Records::new();
+        for entry in db.scan_prefix(SLED_RECORD_PREFIX) {
+            let (_, value) = entry?;
+            recs.add(serde_json::from_slice(&value)?);
+        }
+        let next_id = db.get(SLED_NEXT_ID)?;
+        let next_id = next_id
+            .as_deref()
+            .and_then(|id| std::str::from_utf8(id).ok())
+            .and_then(|id| id.parse().ok());
+        if let Some(next_id) = next_id {
+            recs.saved_next_id = next_id;
+        }
+        info!(
+            path = %self.path.display(),
+            records = recs.len(),
+            elapsed = ?start.elapsed(),
+            "loaded the records from the database"
+        );
+        Ok(recs)
+    }
+
+    fn save(&self, records: &Records) -> Result<(), AppError> {
+        let start = std::time::Instant::now();
+        let db = open_sled(&self.path)?;
+        let mut batch = sled::Batch::default();
+        // Keys that no longer belong to any of the records were removed.
+        let keep: HashSet<String> = records.ids().map(sled_key).collect();
+        for key in db.scan_prefix(SLED_RECORD_PREFIX).keys() {
+            let key = key?;
+            if !keep.contains(String::from_utf8_lossy(&key).as_ref()) {
+                debug!(key = %String::from_utf8_lossy(&key), "deleted a key");
+                batch.remove(key);
+            }
+        }
+        // Records are only written when they are new or have changed.
+        for record in records.sorted() {
+            let key = sled_key(record.id);
+            let value = serde_json::to_vec(record)?;
+            if db.get(&key)?.as_deref() != Some(value.as_slice()) {
+                debug!(id = %record.id, "wrote a key");
+                batch.insert(key.as_bytes(), value);
+            }
+        }
+        batch.insert(
+            SLED_NEXT_ID,
+            records.next_unused_id().to_string().as_bytes(),
+        );
+        db.apply_batch(batch)?;
+        // Sled writes to the disk in the background, so the changes are
+        // flushed before saying that they were saved.
+        db.flush()?;
+        info!(
+            path = %self.path.display(),
+            records = records.len(),
+            elapsed = ?start.elapsed(),
+            "saved the records to the database"
+        );
+        Ok(())
+    }
+}
+
+/// An input field in the add/edit form of the interactive interface.
+const FORM_FIELDS: [&str; 3] = ["Name", "Emails (separated by ;)", "Phone"];
+
+/// A form used to add a new record or edit an existing one.
+struct Form {
+    /// The id of the record being edited, or None when adding a new record.
+    id: Option<RecordId>,
+    /// The text entered for each of the "FORM_FIELDS".
+    values: [String; 3],
+    /// The index of the field currently being typed in.
+    focus: usize,
+}
+
+/// What the interactive interface is currently doing with key presses.
+enum Mode {
+    /// Moving through the list of contacts.
+    Browse,
+    /// Typing into the search box.
+    Search,
+    /// Filling out the add/edit form.
+    Form(Form),
+    /// Waiting for the user to confirm deleting the record with this id.
+    ConfirmDelete(RecordId),
+}
+
+/// State of the interactive interface.
+struct Tui<'a> {
+    storage: &'a dyn Storage,
+    /// Where deleted records are put in the trash, next to the data file.
+    data_file: &'a Path,
+    passphrase: Option<&'a str>,
+    strict: bool,
+    records: Records,
+    query: String,
+    mode: Mode,
+    /// Tracks which row of the table is selected and how far it is scrolled.
+    table: TableState,
+    /// Message shown at the bottom of the screen.
+    status: String,
+}
+
+impl<'a> Tui<'a> {
+    /// The records matching the search box, in order by id.
+    fn visible(&self) -> Vec<&Record> {
+        if self.query.is_empty() {
+            self.records.sorted()
+        } else {
+            self.records.search(&self.query)
+        }
+    }
+
+    /// The id of the selected record, if there is one.
+    fn selected_id(&self) -> Option<RecordId> {
+        let index = self.table.selected()?;
+        self.visible().get(index).map(|rec| rec.id)
+    }
+
+    /// Moves the selection by "amount" rows, staying within the table.
+    fn move_selection(&mut self, amount: isize) {
+        let count = self.visible().len();
+        if count == 0 {
+            self.table.select(None);
+            return;
+        }
+        let current = self.table.selected().unwrap_or(0) as isize;
+        let next = (current + amount).clamp(0, count as isize - 1);
+        self.table.select(Some(next as usize));
+    }
+
+    /// Saves the records and shows a message about what happened.
+    fn save(&mut self, message: String) {
+        self.status = match self.storage.save(&self.records) {
+            Ok(()) => message,
+            Err(e) => format!("failed to save: {}", e),
+        };
+    }
+
+    /// Moves a record to the trash and saves the others, just like
+    /// "p2 remove" does.
+    fn delete(&mut self, id: RecordId) {
+        let Some(record) = self.records.get(id).cloned() else {
+            return;
+        };
+        match move_to_trash(self.data_file, self.passphrase, self.strict, [record]) {
+            Ok(()) => {
+                self.records.remove(id);
+                self.save(format!("moved record {} to trash", id));
+            }
+            Err(e) => self.status = format!("failed to move to trash: {}", e),
+        }
+    }
+
+    /// Handles a key press. Returns false when the user wants to quit.
+    fn handle_key(&mut self, key: KeyEvent) -> bool {
+        // "mem::replace" temporarily takes the mode out of "self" so that we
+        // can move the form out of it. Each branch puts a mode back.
+        match std::mem::replace(&mut self.mode, Mode::Browse) {
+            Mode::Browse => return self.handle_browse_key(key),
+            Mode::Search => match key.code {
+                KeyCode::Esc => self.query.clear(),
+                KeyCode::Enter => (),
+                KeyCode::Backspace => {
+                    self.query.pop();
+                    self.mode = Mode::Search;
+                }
+                KeyCode::Char(c) => {
+                    self.query.push(c);
+                    self.mode = Mode::Search;
+                }
+                _ => self.mode = Mode::Search,
+            },
+            Mode::Form(mut form) => match key.code {
+                KeyCode::Esc => self.status = "cancelled".to_string(),
+                KeyCode::Enter => self.submit(form),
+                KeyCode::Tab | KeyCode::Down => {
+                    form.focus = (form.focus + 1) % FORM_FIELDS.len();
+                    self.mode = Mode::Form(form);
+                }
+                KeyCode::BackTab | KeyCode::Up => {
+                    form.focus = (form.focus + FORM_FIELDS.len() - 1) % FORM_FIELDS.len();
+                    self.mode = Mode::Form(form);
+                }
+                KeyCode::Backspace => {
+                    form.values[form.focus].pop();
+                    self.mode = Mode::Form(form);
+                }
+                KeyCode::Char(c) => {
+                    form.values[form.focus].push(c);
+                    self.mode = Mode::Form(form);
+                }
+                _ => self.mode = Mode::Form(form),
+            },
+            Mode::ConfirmDelete(id) => {
+                if key.code == KeyCode::Char('y') {
+                    self.delete(id);
+                } else {
+                    self.status = "cancelled".to_string();
+                }
+            }
+        }
+        self.move_selection(0);
+        true
+    }
+
+    /// Handles a key press while browsing the list of contacts.
+    fn handle_browse_key(&mut self, key: KeyEvent) -> bool {
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+            KeyCode::Up | KeyCode::Char('k') => self.move_selection(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.move_selection(1),
+            KeyCode::PageUp => self.move_selection(-10),
+            KeyCode::PageDown => self.move_selection(10),
+            KeyCode::Char('/') => self.mode = Mode::Search,
+            KeyCode::Char('a') => {
+                self.mode = Mode::Form(Form {
+                    id: None,
+                    values: Default::default(),
+                    focus: 0,
+                })
+            }
+            KeyCode::Char('e') | KeyCode::Enter => {
+                let record = self.selected_id().and_then(|id| self.records.get(id));
+                if let Some(record) = record {
+                    self.mode = Mode::Form(Form {
+                        id: Some(record.id),
+                        values: [
+                            record.name.clone(),
+                            record.emails.join(";"),
+                            record.phone.clone().unwrap_or_default(),
+                        ],
+                        focus: 0,
+                    });
+                }
+            }
+            KeyCode::Char('d') | KeyCode::Delete => {
+                if let Some(id) = self.selected_id() {
+                    self.mode = Mode::ConfirmDelete(id);
+                }
+            }
+            _ => (),
+        }
+        true
+    }
+
+    /// Adds or edits a record using the values entered in the form.
+    fn submit(&mut self, form: Form) {
+        let [name, emails, phone] = form.values;
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            // The form stays open so the user can fix the problem.
+            self.status = "a name is required".to_string();
+            self.mode = Mode::Form(Form {
+                values: [name, emails, phone],
+                ..form
+            });
+            return;
+        }
+        let emails = split_list(&emails);
+        let phone = Some(phone.trim().to_string()).filter(|phone| !phone.is_empty());
+        match form.id {
+            Some(id) => {
+                self.records.edit(id, |record| {
+                    record.name = name;
+                    record.emails = emails;
+                    record.phone = phone;
+                });
+                self.save(format!("updated record {}", id));
+            }
+            None => {
+                let id = self.records.next_id();
+                self.records.add(Record {
+                    emails,
+                    phone,
+                    ..Record::new(id, name)
+                });
+                self.save(format!("added record {}", id));
+            }
+        }
+    }
+
+    /// Draws the whole interface.
+    fn draw(&mut self, frame: &mut Frame) {
+        let areas = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(3),
+                Constraint::Min(1),
+                Constraint::Length(1),
+            ])
+            .split(frame.size());
+
+        let search = Paragraph::new(self.query.as_str())
+            .block(Block::default().borders(Borders::ALL).title("Search (/)"));
+        frame.render_widget(search, areas[0]);
+        if let Mode::Search = self.mode {
+            let x = areas[0].x + 1 + self.query.chars().count() as u16;
+            frame.set_cursor(x, areas[0].y + 1);
+        }
+
+        let rows: Vec<_> = self
+            .visible()
+            .into_iter()
+            .map(|rec| {
+                Row::new(vec![
+                    rec.id.to_string(),
+                    rec.name.clone(),
+                    rec.emails.join(", "),
+                    rec.phone.clone().unwrap_or_default(),
+                ])
+            })
+            .collect();
+        let count = rows.len();
+        let widths = [
+            Constraint::Length(6),
+            Constraint::Percentage(30),
+            Constraint::Percentage(45),
+            Constraint::Percentage(25),
+        ];
+        let table = Table::new(rows, widths)
+            .header(
+                Row::new(vec!["id", "name", "emails", "phone"])
+                    .style(Style::default().add_modifier(Modifier::BOLD)),
+            )
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!("Contacts ({})", count)),
+            )
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        frame.render_stateful_widget(table, areas[1], &mut self.table);
+
+        let help = match self.mode {
+            Mode::Browse => "j/k: move  /: search  a: add  e: edit  d: delete  q: quit",
+            Mode::Search => "type to filter  enter: done  esc: clear",
+            Mode::Form(_) => "tab: next field  enter: save  esc: cancel",
+            Mode::ConfirmDelete(_) => "y: delete  any other key: cancel",
+        };
+        let status = if self.status.is_empty() {
+            help.to_string()
+        } else {
+            format!("{}  |  {}", self.status, help)
+        };
+        frame.render_widget(Paragraph::new(status), areas[2]);
+
+        match &self.mode {
+            Mode::Form(form) => draw_form(frame, form),
+            Mode::ConfirmDelete(id) => {
+                let area = centered_rect(50, 3, frame.size());
+                let name = self.records.get(*id).map(|rec| rec.name.as_str());
+                let text = format!("Delete {}? (y/n)", name.unwrap_or_default());
+                frame.render_widget(Clear, area);
+                frame.render_widget(
+                    Paragraph::new(text).block(Block::default().borders(Borders::ALL)),
+                    area,
+                );
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Draws the add/edit form in a box over the contact list.
+fn draw_form(frame: &mut Frame, form: &Form) {
+    let height = FORM_FIELDS.len() as u16 * 3 + 2;
+    let area = centered_rect(60, height, frame.size());
+    let title = match form.id {
+        Some(id) => format!("Edit record {}", id),
+        None => "Add record".to_string(),
+    };
+    frame.render_widget(Clear, area);
+    frame.render_widget(Block::default().borders(Borders::ALL).title(title), area);
+
+    let inner = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(1)
+        .constraints(vec![Constraint::Length(3); FORM_FIELDS.len()])
+        .split(area);
+    for (i, label) in FORM_FIELDS.iter().enumerate() {
+        let mut block = Block::default().borders(Borders::ALL).title(*label);
+        if i == form.focus {
+            block = block.border_style(Style::default().add_modifier(Modifier::BOLD));
+            let x = inner[i].x + 1 + form.values[i].chars().count() as u16;
+            frame.set_cursor(x, inner[i].y + 1);
+        }
+        frame.render_widget(
+            Paragraph::new(form.values[i].as_str()).block(block),
+            inner[i],
+        );
+    }
+}
+
+/// Returns an area in the middle of "area" that is "percent_x" percent of
+/// the width and "height" rows tall.
+fn centered_rect(percent_x: u16, height: u16, area: Rect) -> Rect {
+    let width = area.width * percent_x / 100;
+    Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + area.height.saturating_sub(height) / 2,
+        width,
+        height: height.min(area.height),
+    }
+}
+
+/// Keeps the terminal in raw mode on the alternate screen, which full-screen
+/// interfaces draw on, until it is dropped. Dropping it always puts the
+/// terminal back, even when something fails or panics. Otherwise the shell
+/// would be left in raw mode after we exit.
+struct AlternateScreen<W: Write> {
+    out: W,
+}
+
+impl<W: Write> AlternateScreen<W> {
+    /// Switches the terminal that "out" writes to over to the alternate
+    /// screen, and hides the cursor.
+    fn enter(out: W) -> std::io::Result<Self> {
+        enable_raw_mode()?;
+        // The guard is made first, so that raw mode is turned off again if
+        // switching screens fails.
+        let mut screen = Self { out };
+        execute!(screen.out, EnterAlternateScreen, Hide)?;
+        Ok(screen)
+    }
+}
+
+impl<W: Write> Drop for AlternateScreen<W> {
+    fn drop(&mut self) {
+        // Errors can't be returned from here, and there is nothing else to
+        // try if the terminal can't be put back.
+        let _ = execute!(self.out, Show, LeaveAlternateScreen);
+        let _ = disable_raw_mode();
+    }
+}
+
+/// Runs the interactive interface until the user quits. Every change is
+/// saved as soon as it is made.
+fn run_tui(
+    storage: &dyn Storage,
+    data_file: &Path,
+    passphrase: Option<&str>,
+    strict: bool,
+    records: Records,
+) -> Result<(), AppError> {
+    let _screen = AlternateScreen::enter(std::io::stdout())?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
+
+    let mut tui = Tui {
+        storage,
+        data_file,
+        passphrase,
+        strict,
+        records,
+        query: String::new(),
+        mode: Mode::Browse,
+        table: TableState::default(),
+        status: String::new(),
+    };
+    tui.move_selection(0);
+    tui_loop(&mut terminal, &mut tui)
+}
+
+/// Draws the interface and handles key presses until the user quits.
+fn tui_loop(
+    terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>,
+    tui: &mut Tui,
+) -> Result<(), AppError> {
+    loop {
+        terminal.draw(|frame| tui.draw(frame))?;
+        if let Event::Key(key) = event::read()? {
+            // Some terminals also report key releases, which we ignore.
+            if key.kind == KeyEventKind::Press && !tui.handle_key(key) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The JSON body accepted when creating or replacing a contact.
+#[derive(Debug, Deserialize)]
+struct ContactInput {
+    name: String,
+    #[serde(default)]
+    emails: Vec<String>,
+    #[serde(default)]
+    phone: Option<String>,
+    /// When replacing a contact, leaving out the tags keeps the existing ones.
+    #[serde(default)]
+    tags: Option<Vec<String>>,
+    #[serde(default)]
+    address: Option<String>,
+    /// Birthdays are written as "YYYY-MM-DD".
+    #[serde(default)]
+    birthday: Option<NaiveDate>,
+    #[serde(default)]
+    notes: Option<String>,
+    /// The path or URL of a picture of the contact.
+    #[serde(default)]
+    avatar: Option<String>,
+    #[serde(default)]
+    company: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    nickname: Option<String>,
+    /// Written as "YYYY-MM-DD", like birthdays.
+    #[serde(default)]
+    last_contacted: Option<NaiveDate>,
+}
+
+/// An HTTP response: the status code and the JSON body.
+type ApiResponse = (u16, String);
+
+/// Creates a JSON error body, like {"error": "not found"}.
+fn json_error(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
+
+/// Decodes a percent-encoded URL component, such as "john%20smith".
+fn percent_decode(value: &str) -> String {
+    let mut bytes = vec![];
+    let mut input = value.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex: Vec<u8> = input.by_ref().take(2).collect();
+                let decoded = std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match decoded {
+                    Some(b) => bytes.push(b),
+                    None => {
+                        bytes.push(b'%');
+                        bytes.extend(hex);
+                    }
+                }
+            }
+            _ => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Percent-encodes a URL component, keeping only the characters that never
+/// need it, so "john smith" becomes "john%20smith".
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Finds the value of a parameter in a query string like "q=smith&x=1".
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+/// Reads a contact from the body of a request. If the body is not a valid
+/// contact, the error response is returned instead.
+fn read_contact(request: &mut tiny_http::Request) -> Result<ContactInput, ApiResponse> {
+    let mut body = String::new();
+    if let Err(e) = request.as_reader().read_to_string(&mut body) {
+        return Err((400, json_error(&e.to_string())));
+    }
+    match serde_json::from_str::<ContactInput>(&body) {
+        Ok(input) if input.name.trim().is_empty() => Err((400, json_error("missing field: name"))),
+        Ok(input) => Ok(input),
+        Err(e) => Err((400, json_error(&e.to_string()))),
+    }
+}
+
+/// Runs the HTTP server until the program is stopped.
+///
+/// The server provides these routes, which all use JSON:
+/// * GET /contacts (optionally with ?q=query to search)
+/// * GET /contacts/{id}
+/// * POST /contacts
+/// * PUT /contacts/{id}
+/// * DELETE /contacts/{id} (moves the contact to the trash)
+/// * POST /sync (used by the sync command)
+fn serve(
+    storage: &dyn Storage,
+    data_file: &Path,
+    passphrase: Option<&str>,
+    strict: bool,
+    addr: &str,
+    read_only: bool,
+) -> Result<(), AppError> {
+    let server = tiny_http::Server::http(addr).map_err(|e| AppError::Server(e.to_string()))?;
+    println!("listening on http://{}", addr);
+    for mut request in server.incoming_requests() {
+        // Only reading is allowed when the data file is read-only.
+        let allowed = !read_only || *request.method() == tiny_http::Method::Get;
+        let response = match allowed {
+            true => handle_request(
+                storage,
+                data_file,
+                passphrase,
+                strict,
+                read_only,
+                &mut request,
+            ),
+            false => Err(AppError::ReadOnly(data_file.to_path_buf())),
+        };
+        let (status, body) = match response {
+            Ok(response) => response,
+            Err(e @ AppError::ReadOnly(_)) => (403, json_error(&e.to_string())),
+            Err(e) => (500, json_error(&e.to_string())),
+        };
+        info!(method = %request.method(), url = request.url(), status, "answered a request");
+        let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+            .expect("content type header is valid");
+        let response = tiny_http::Response::from_string(body)
+            .with_status_code(status)
+            .with_header(header);
+        if let Err(e) = request.respond(response) {
+            eprintln!("failed to send response: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// Shows the records with "show", and shows them again every time the data
+/// file changes, until the program is stopped. Saving replaces the data
+/// file with a new one, so it is the directory that is watched, since a
+/// watch on the file itself would end with the old file.
+fn watch<F>(
+    storage: &dyn Storage,
+    data_file: &Path,
+    read_only: bool,
+    show: F,
+) -> Result<(), AppError>
+where
+    F: Fn(&Records) -> std::io::Result<()>,
+{
+    use notify::{EventKind, RecursiveMode, Watcher};
+    use std::time::Duration;
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    let dir = match data_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    loop {
+        // The lock is only held while reading, so that other commands can
+        // change the file in between. A file that can't be loaded may be
+        // fixed by the next change, so the error is printed and the watch
+        // goes on.
+        let recs = {
+            let _lock = lock_data_file(data_file, false, true, read_only)?;
+            storage.load()
+        };
+        match recs {
+            Ok(recs) => show(&recs)?,
+            Err(e) => eprintln!("{}", output::error(&e.to_string())),
+        }
+        // Other files in the directory change too, such as the lock file.
+        // Reading the data file is also an event, which is ignored so that
+        // loading it doesn't cause it to be loaded again.
+        for event in receiver.iter() {
+            let event = event?;
+            let changed = !matches!(event.kind, EventKind::Access(_))
+                && event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name() == data_file.file_name());
+            if changed {
+                break;
+            }
+        }
+        // Saving causes several events in a row, so they are all taken
+        // before loading the file again.
+        while receiver.recv_timeout(Duration::from_millis(100)).is_ok() {}
+        println!(
+            "\n{} changed at {}",
+            data_file.display(),
+            Local::now().format("%H:%M:%S")
+        );
+    }
+}
+
+/// Replaces a contact with the one sent in a PUT request. The tags are
+/// replaced too, unless they were left out.
+fn replace_contact(recs: &mut Records, id: RecordId, input: ContactInput) {
+    recs.edit(id, |record| {
+        record.name = input.name;
+        record.emails = input.emails;
+        record.phone = input.phone;
+        record.address = input.address;
+        record.birthday = input.birthday;
+        record.notes = input.notes;
+        record.avatar = input.avatar;
+        record.company = input.company;
+        record.title = input.title;
+        record.nickname = input.nickname;
+        record.last_contacted = input.last_contacted;
+        if let Some(tags) = input.tags {
+            // Tags are compared without case, like "tag" does, so the
+            // same tag is only kept once.
+            record.tags.clear();
+            for tag in tags {
+                if !record.has_tag(&tag) {
+                    record.tags.push(tag);
+                }
+            }
+        }
+    });
+}
+
+/// Handles a single HTTP request.
+fn handle_request(
+    storage: &dyn Storage,
+    data_file: &Path,
+    passphrase: Option<&str>,
+    strict: bool,
+    read_only: bool,
+    request: &mut tiny_http::Request,
+) -> Result<ApiResponse, AppError> {
+    use tiny_http::Method;
+
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments: Vec<_> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let method = request.method().clone();
+
+    // Each request loads the records again, so changes made by other p2
+    // commands while the server is running are always visible. The data
+    // file is locked until the request is finished.
+    let _lock = lock_data_file(data_file, method != Method::Get, true, read_only)?;
+    let mut recs = storage.load()?;
+
+    // Ids come from the URL, so they need to be checked before use.
+    let id = match segments.get(1).map(|id| id.parse::<RecordId>()) {
+        Some(Ok(id)) => Some(id),
+        Some(Err(_)) => return Ok((400, json_error("id must be a number or UUID"))),
+        None => None,
+    };
+    let response = match (method, segments.first(), id) {
+        (Method::Get, Some(&"contacts"), None) => {
+            let results = match query_param(query, "q") {
+                Some(query) => recs.search(&query),
+                None => recs.sorted(),
+            };
+            (200, serde_json::to_string(&results)?)
+        }
+        (Method::Get, Some(&"contacts"), Some(id)) => match recs.get(id) {
+            Some(record) => (200, serde_json::to_string(record)?),
+            None => (404, json_error("record not found")),
+        },
+        (Method::Post, Some(&"contacts"), None) => match read_contact(request) {
+            Ok(input) => {
+                let record = Record {
+                    emails: input.emails,
+                    phone: input.phone,
+                    tags: input.tags.unwrap_or_default(),
+                    address: input.address,
+                    birthday: input.birthday,
+                    notes: input.notes,
+                    avatar: input.avatar,
+                    company: input.company,
+                    title: input.title,
+                    nickname: input.nickname,
+                    last_contacted: input.last_contacted,
+                    ..Record::new(recs.next_id(), input.name)
+                };
+                recs.add(record.clone());
+                storage.save(&recs)?;
+                (201, serde_json::to_string(&record)?)
+            }
+            Err(response) => response,
+        },
+        (Method::Put, Some(&"contacts"), Some(id)) => match read_contact(request) {
+            Ok(input) => {
+                replace_contact(&mut recs, id, input);
+                storage.save(&recs)?;
+                (200, serde_json::to_string(&recs.get(id))?)
+            }
+            Err(response) => response,
+        },
+        (Method::Delete, Some(&"contacts"), Some(id)) => match recs.remove(id) {
+            Some(record) => {
+                move_to_trash(data_file, passphrase, strict, [record])?;
+                storage.save(&recs)?;
+                (204, String::new())
+            }
+            None => (404, json_error("record not found")),
+        },
+        (Method::Post, Some(&"sync"), None) => {
+            // "p2 sync" sends the version of the records it fetched. If
+            // they have changed since then, applying its changes could undo
+            // the new ones, so it has to fetch them again.
+            let expected = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("If-Match"))
+                .map(|header| header.value.as_str().trim_matches('"').to_string());
+            let current = records_version(&serde_json::to_string(&recs.sorted())?);
+            match serde_json::from_reader::<_, SyncRequest>(request.as_reader()) {
+                _ if expected.is_some_and(|expected| expected != current) => (
+                    412,
+                    json_error("the contacts changed since they were fetched"),
+                ),
+                Ok(changes) => {
+                    apply_sync(&mut recs, &changes);
+                    storage.save(&recs)?;
+                    (200, serde_json::to_string(&recs.sorted())?)
+                }
+                Err(e) => (400, json_error(&e.to_string())),
+            }
+        }
+        (_, Some(&"contacts"), _) if segments.len() <= 2 => (405, json_error("method not allowed")),
+        _ => (404, json_error("not found")),
+    };
+    Ok(response)
+}
+
+/// The changes that "p2 sync" sends to the server. Unlike the other
+/// routes, whole records are sent, so their ids and timestamps are kept.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SyncRequest {
+    /// Records to add, or to replace the records with the same ids.
+    put: Vec<Record>,
+    /// Ids of records to remove.
+    remove: Vec<RecordId>,
+}
+
+/// Applies the changes sent by "p2 sync".
+fn apply_sync(recs: &mut Records, changes: &SyncRequest) {
+    for record in &changes.put {
+        recs.add(record.clone());
+    }
+    for &id in &changes.remove {
+        recs.remove(id);
+    }
+}
+
+/// Returns the version of a list of records sent by the server: the
+/// SHA-256 checksum of the JSON, as hexadecimal. "p2 sync" sends it back
+/// with its changes, so that the server can tell whether anything changed
+/// in between.
+fn records_version(json: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, json.as_bytes());
+    digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// A record that was changed on both sides since the last sync.
+#[derive(Debug, PartialEq)]
+struct SyncConflict {
+    id: RecordId,
+    /// True if the remote version was kept, false if the local one was.
+    kept_remote: bool,
+}
+
+/// What syncing needs to do to make both sides the same.
+#[derive(Debug, Default)]
+struct SyncPlan {
+    /// Changes to send to the remote.
+    push: SyncRequest,
+    /// How many remote changes were taken.
+    pulled: usize,
+    conflicts: Vec<SyncConflict>,
+    /// Records that were added on both sides with the same id. The remote
+    /// record keeps the id, and the local one is given a new one, so both
+    /// are kept. Each entry is the old id and the new one.
+    renumbered: Vec<(RecordId, RecordId)>,
+}
+
+/// Works out how to sync the local and remote records.
+///
+/// "base" is how the records looked after the last sync. Comparing each side
+/// with it tells us which side changed a record: a record that only changed
+/// on one side is copied to the other. When both sides changed the same
+/// record, it is a conflict, and the version that was updated most recently
+/// wins. Changing a record wins over removing it, so nothing that someone
+/// was working on gets lost. Records that were added on both sides with the
+/// same id are different contacts, so the local one gets a new id.
+///
+/// "local" is changed to hold the remote changes that were pulled.
+fn plan_sync(base: &Records, local: &mut Records, remote: &Records) -> SyncPlan {
+    let ids: BTreeSet<RecordId> = base.ids().chain(local.ids()).chain(remote.ids()).collect();
+    let mut plan = SyncPlan::default();
+    let mut added_both = vec![];
+    for id in ids {
+        let (old, ours, theirs) = (base.get(id), local.get(id), remote.get(id));
+        if ours == theirs {
+            continue;
+        }
+        let take_remote = if let (None, Some(ours), Some(_)) = (old, ours, theirs) {
+            added_both.push(ours.clone());
+            true
+        } else if ours == old {
+            true
+        } else if theirs == old {
+            false
+        } else {
+            let kept_remote = match (ours, theirs) {
+                (Some(ours), Some(theirs)) => theirs.updated_at > ours.updated_at,
+                (None, _) => true,
+                (_, None) => false,
+            };
+            plan.conflicts.push(SyncConflict { id, kept_remote });
+            kept_remote
+        };
+        match (take_remote, theirs.cloned(), ours.cloned()) {
+            (true, Some(theirs), _) => {
+                local.add(theirs);
+                plan.pulled += 1;
+            }
+            (true, None, _) => {
+                local.remove(id);
+                plan.pulled += 1;
+            }
+            (false, _, Some(ours)) => plan.push.put.push(ours),
+            (false, _, None) => plan.push.remove.push(id),
+        }
+    }
+    // New ids are only given out once every remote record has been pulled,
+    // so that they can't be the same as any of their ids.
+    for mut record in added_both {
+        let id = record.id;
+        record.id = local.next_id();
+        plan.renumbered.push((id, record.id));
+        local.add(record.clone());
+        plan.push.put.push(record);
+    }
+    plan
+}
+
+/// Gets every record from a server started with "p2 serve", along with
+/// their version, made by "records_version".
+fn fetch_remote(remote: &str) -> Result<(Records, String), AppError> {
+    let sync_error = |e: &dyn std::fmt::Display| AppError::Sync(remote.to_owned(), e.to_string());
+    let json = ureq::get(&format!("{}/contacts", remote))
+        .call()
+        .map_err(|e| sync_error(&e))?
+        .into_string()
+        .map_err(|e| sync_error(&e))?;
+    let records: Vec<Record> = serde_json::from_str(&json).map_err(|e| sync_error(&e))?;
+    let mut recs = Records::new();
+    for record in records {
+        recs.add(record);
+    }
+    Ok((recs, records_version(&json)))
+}
+
+/// Sends changes to a server started with "p2 serve". "version" is the
+/// version of the records that the changes were made against, and the
+/// server refuses them if its records have changed since.
+fn push_remote(remote: &str, changes: &SyncRequest, version: &str) -> Result<(), AppError> {
+    let sync_error = |e: &dyn std::fmt::Display| AppError::Sync(remote.to_owned(), e.to_string());
+    match ureq::post(&format!("{}/sync", remote))
+        .set("If-Match", &format!("\"{}\"", version))
+        .send_json(changes)
+    {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(412, _)) => Err(sync_error(
+            &"the contacts on the server changed during the sync; run sync again",
+        )),
+        Err(e) => Err(sync_error(&e)),
+    }
+}
+
+/// Escapes a value for use in a vCard. Backslashes, commas, semicolons, and
+/// line breaks all have special meaning inside of vCard values.
+fn escape_vcard(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ',' => escaped.push_str("\\,"),
+            ';' => escaped.push_str("\\;"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => (),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Splits a vCard value on each unescaped semicolon and removes the escapes
+/// from each component. Structured values such as "N" use semicolons to
+/// separate their components.
+fn split_vcard(value: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        // "parts" always has at least one entry, so "last_mut" never fails.
+        let part = parts.last_mut().expect("always at least one part");
+        match c {
+            '\\' => match chars.next() {
+                Some('n') | Some('N') => part.push('\n'),
+                Some(c) => part.push(c),
+                None => (),
+            },
+            ';' => parts.push(String::new()),
+            _ => part.push(c),
+        }
+    }
+    parts
+}
+
+/// Removes the escapes from a vCard value.
+fn unescape_vcard(value: &str) -> String {
+    split_vcard(value).join(";")
+}
+
+/// Terminates a vCard line, splitting it up if needed. Lines in a vCard are
+/// limited to 75 bytes, and continuation lines begin with a single space.
+fn fold_vcard_line(line: &str) -> String {
+    let mut folded = String::new();
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            len = 1;
+        }
+        folded.push(c);
+        len += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+/// Formats a single record as a vCard.
+fn format_vcard(record: &Record) -> String {
+    let mut lines = vec!["BEGIN:VCARD".to_string(), "VERSION:3.0".to_string()];
+    lines.extend(vcard_lines(record));
+    lines.push("END:VCARD".to_string());
+    lines.iter().map(|line| fold_vcard_line(line)).collect()
+}
+
+/// Returns the next birthday on or after "today". People born on February 29
+/// celebrate on February 28 when it isn't a leap year.
+fn next_birthday(birthday: NaiveDate, today: NaiveDate) -> NaiveDate {
+    let in_year = |year| {
+        birthday
+            .with_year(year)
+            .or_else(|| NaiveDate::from_ymd_opt(year, 2, 28))
+            .expect("February 28 exists in every year")
+    };
+    let this_year = in_year(today.year());
+    match this_year < today {
+        true => in_year(today.year() + 1),
+        false => this_year,
+    }
+}
+
+/// Formats a line of "birthdays" for someone whose next birthday is "date",
+/// "days" from today. The age is left out when there isn't one yet, such as
+/// for a birthday that was entered with the wrong year.
+fn birthday_line(name: &str, birthday: NaiveDate, date: NaiveDate, days: i64) -> String {
+    let when = match days {
+        0 => "today".to_string(),
+        1 => "tomorrow".to_string(),
+        _ => format!("in {} days", days),
+    };
+    let line = format!("{}  {:<11}  {}", date.format("%a %b %e"), when, name);
+    match date.year() - birthday.year() {
+        age if age > 0 => format!("{} turns {}", line, age),
+        _ => line,
+    }
+}
+
+/// Formats the birthdays of the records as an iCalendar file, with an event
+/// that repeats every year for each contact that has a birthday.
+///
+/// Calendar values are escaped and folded just like vCard values.
+fn format_birthday_calendar(records: &[&Record], stamp: DateTime<Utc>) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//p2//birthdays//EN".to_string(),
+    ];
+    for record in records {
+        let birthday = match record.birthday {
+            Some(birthday) => birthday,
+            None => continue,
+        };
+        // A yearly event that starts on February 29 would only happen in
+        // leap years, so it is moved to the last day of February instead.
+        let rule = match (birthday.month(), birthday.day()) {
+            (2, 29) => "RRULE:FREQ=YEARLY;BYMONTH=2;BYMONTHDAY=-1",
+            _ => "RRULE:FREQ=YEARLY",
+        };
+        lines.extend([
+            "BEGIN:VEVENT".to_string(),
+            // The id keeps the event the same when the file is imported
+            // again, so the calendar doesn't end up with copies of it.
+            format!("UID:birthday-{}@p2", record.id),
+            format!("DTSTAMP:{}", stamp.format("%Y%m%dT%H%M%SZ")),
+            format!("DTSTART;VALUE=DATE:{}", birthday.format("%Y%m%d")),
+            rule.to_string(),
+            format!(
+                "SUMMARY:{}",
+                escape_vcard(&format!("{}'s birthday", record.name))
+            ),
+            "TRANSP:TRANSPARENT".to_string(),
+            "END:VEVENT".to_string(),
+        ]);
+    }
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|line| fold_vcard_line(line)).collect()
+}
+
+/// Draws a QR code with half-height blocks, so that two rows of the code fit
+/// in one line of the terminal and it stays roughly square.
+///
+/// Most terminals have a dark background, so by default the light parts of
+/// the code are the ones drawn with blocks. Scanners need the quiet zone
+/// around the code to find it, so it is kept.
+fn render_qr(code: &qrcode::QrCode, light_background: bool) -> String {
+    use qrcode::render::unicode::Dense1x2;
+    let (dark, light) = match light_background {
+        true => (Dense1x2::Dark, Dense1x2::Light),
+        false => (Dense1x2::Light, Dense1x2::Dark),
+    };
+    code.render::<Dense1x2>()
+        .dark_color(dark)
+        .light_color(light)
+        .quiet_zone(true)
+        .build()
+}
+
+/// Returns the lines of a vCard that hold the fields of a record, leaving
+/// out the lines that begin and end the card. The lines aren't folded.
+fn vcard_lines(record: &Record) -> Vec<String> {
+    // vCard 3.0 requires a structured name (N) in addition to the formatted
+    // name (FN). We treat the last word of the name as the family name.
+    let (given, family) = match record.name.rsplit_once(' ') {
+        Some((given, family)) => (given, family),
+        None => ("", record.name.as_str()),
+    };
+    let mut lines = vec![
+        format!("FN:{}", escape_vcard(&record.name)),
+        format!("N:{};{};;;", escape_vcard(family), escape_vcard(given)),
+    ];
+    if let Some(nickname) = &record.nickname {
+        lines.push(format!("NICKNAME:{}", escape_vcard(nickname)));
+    }
+    for email in &record.emails {
+        lines.push(format!("EMAIL;TYPE=INTERNET:{}", escape_vcard(email)));
+    }
+    if let Some(phone) = &record.phone {
+        lines.push(format!("TEL:{}", escape_vcard(phone)));
+    }
+    // Tags are stored as categories, which are separated by commas.
+    if !record.tags.is_empty() {
+        let tags: Vec<_> = record.tags.iter().map(|tag| escape_vcard(tag)).collect();
+        lines.push(format!("CATEGORIES:{}", tags.join(",")));
+    }
+    // Addresses are structured, but ours is free-form, so the whole address
+    // goes in the "street" component.
+    if let Some(address) = &record.address {
+        lines.push(format!("ADR:;;{};;;;", escape_vcard(address)));
+    }
+    if let Some(birthday) = &record.birthday {
+        lines.push(format!("BDAY:{}", birthday));
+    }
+    if let Some(notes) = &record.notes {
+        lines.push(format!("NOTE:{}", escape_vcard(notes)));
+    }
+    // The photo is linked rather than included in the card. Paths of local
+    // files become "file://" URLs.
+    if let Some(avatar) = &record.avatar {
+        let url = match avatar.contains("://") {
+            true => avatar.clone(),
+            false => format!("file://{}", avatar),
+        };
+        lines.push(format!("PHOTO;VALUE=uri:{}", url));
+    }
+    // ORG is structured like the name, with the company first, followed by
+    // its departments. We only have the company.
+    if let Some(company) = &record.company {
+        lines.push(format!("ORG:{}", escape_vcard(company)));
+    }
+    if let Some(title) = &record.title {
+        lines.push(format!("TITLE:{}", escape_vcard(title)));
+    }
+    lines
+}
+
+/// Joins lines that were folded back together. Lines that begin with a
+/// space or tab continue the previous line. Each line keeps its line number
+/// for error reporting.
+fn unfold_vcard(data: &str) -> Vec<(usize, String)> {
+    let mut lines: Vec<(usize, String)> = vec![];
+    for (num, line) in data.lines().enumerate() {
+        let continuation = line.strip_prefix(' ').or_else(|| line.strip_prefix('\t'));
+        match (continuation, lines.last_mut()) {
+            (Some(rest), Some(last)) => last.1.push_str(rest),
+            _ => lines.push((num + 1, line.to_string())),
+        }
+    }
+    lines
+}
+
+/// Splits a vCard line into the name of its property, in uppercase, and
+/// its value.
+fn vcard_property(line: &str) -> Option<(String, &str)> {
+    // Each line looks like "NAME;PARAM=VALUE:value". Property names may
+    // also have a group prefix, such as "item1.EMAIL", which we ignore.
+    let (property, value) = line.split_once(':')?;
+    let property = property.split(';').next().unwrap_or_default();
+    let property = property.rsplit('.').next().unwrap_or_default();
+    Some((property.to_uppercase(), value))
+}
+
+/// Parses every vCard found in the supplied data. Since vCards do not have
+/// our ids, each record is given an id of 0 and a real id must be assigned
+/// before adding it to the database.
+fn parse_vcards(data: &str) -> Vec<Record> {
+    // Long lines may be "folded" onto multiple lines, so we join them back
+    // together first.
+    let lines = unfold_vcard(data);
+
+    let mut records = vec![];
+    // The card currently being read, along with the line it started on and
+    // the name built from the structured "N" property. "N" is only used
+    // when a card does not have a formatted name ("FN").
+    let mut card: Option<(usize, Record, String)> = None;
+    for (num, line) in lines {
+        let (property, value) = match vcard_property(&line) {
+            Some(parts) => parts,
+            None => continue,
+        };
+        match (property.as_str(), card.as_mut()) {
+            ("BEGIN", _) if value.eq_ignore_ascii_case("VCARD") => {
+                if let Some((start, _, _)) = card.take() {
+                    report_vcard_error(start, ParseError::UnterminatedCard);
+                }
+                let record = Record::new(RecordId::default(), String::new());
+                card = Some((num, record, String::new()));
+            }
+            ("END", Some(_)) if value.eq_ignore_ascii_case("VCARD") => {
+                if let Some((start, mut record, structured_name)) = card.take() {
+                    if record.name.is_empty() {
+                        record.name = structured_name;
+                    }
+                    if record.name.is_empty() {
+                        let e = ParseError::MissingField("name".to_owned());
+                        report_vcard_error(start, e);
+                    } else {
+                        records.push(record);
+                    }
+                }
+            }
+            ("FN", Some((_, record, _))) => record.name = unescape_vcard(value).trim().to_string(),
+            ("N", Some((_, _, structured_name))) => {
+                // The components are ordered family, given, additional,
+                // prefix, and suffix. We put the name back together in the
+                // order that people normally write it.
+                let parts = split_vcard(value);
+                let order = [3, 1, 2, 0, 4];
+                let parts: Vec<_> = order
+                    .iter()
+                    .filter_map(|i| parts.get(*i))
+                    .map(|part| part.trim())
+                    .filter(|part| !part.is_empty())
+                    .collect();
+                *structured_name = parts.join(" ");
+            }
+            ("EMAIL", Some((_, record, _))) => {
+                let email = unescape_vcard(value).trim().to_string();
+                if !email.is_empty() {
+                    record.emails.push(email);
+                }
+            }
+            ("CATEGORIES", Some((_, record, _))) => {
+                // Categories are separated by unescaped commas.
+                let mut tag = String::new();
+                let mut chars = value.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => tag.extend(chars.next()),
+                        ',' => record.tags.push(std::mem::take(&mut tag)),
+                        _ => tag.push(c),
+                    }
+                }
+                record.tags.push(tag);
+                record.tags.retain(|tag| !tag.trim().is_empty());
+            }
+            ("ADR", Some((_, record, _))) if record.address.is_none() => {
+                // The non-empty components are joined back into a single
+                // line, such as "1 Main St, Springfield".
+                let parts: Vec<_> = split_vcard(value)
+                    .into_iter()
+                    .map(|part| part.trim().to_string())
+                    .filter(|part| !part.is_empty())
+                    .collect();
+                record.address = Some(parts.join(", ")).filter(|address| !address.is_empty());
+            }
+            ("BDAY", Some((start, record, _))) => match parse_date(value) {
+                Ok(birthday) => record.birthday = Some(birthday),
+                Err(e) => report_vcard_error(*start, e),
+            },
+            ("NOTE", Some((_, record, _))) => {
+                let notes = unescape_vcard(value).trim().to_string();
+                record.notes = Some(notes).filter(|notes| !notes.is_empty());
+            }
+            // Photos included in the card itself can't be kept, since the
+            // avatar is only a path or URL.
+            ("PHOTO", Some((_, record, _))) if !line.to_uppercase().contains("ENCODING=") => {
+                let url = value.trim();
+                let avatar = url.strip_prefix("file://").unwrap_or(url);
+                record.avatar = Some(avatar.to_string()).filter(|avatar| !avatar.is_empty());
+            }
+            ("ORG", Some((_, record, _))) => {
+                let company = split_vcard(value).into_iter().next().unwrap_or_default();
+                record.company = Some(company.trim().to_string()).filter(|c| !c.is_empty());
+            }
+            // NICKNAME may be a list separated by commas. Only the first
+            // one is kept.
+            ("NICKNAME", Some((_, record, _))) => {
+                let nickname = unescape_vcard(value.split(',').next().unwrap_or_default());
+                record.nickname = Some(nickname.trim().to_string()).filter(|n| !n.is_empty());
+            }
+            ("TITLE", Some((_, record, _))) => {
+                let title = unescape_vcard(value).trim().to_string();
+                record.title = Some(title).filter(|title| !title.is_empty());
+            }
+            ("TEL", Some((_, record, _))) if record.phone.is_none() => {
+                let phone = unescape_vcard(value).trim().to_string();
+                record.phone = Some(phone).filter(|phone| !phone.is_empty());
+            }
+            _ => (),
+        }
+    }
+    if let Some((start, _, _)) = card {
+        report_vcard_error(start, ParseError::UnterminatedCard);
+    }
+    records
+}
+
+/// Logs an error found while reading a vCard file. The card is skipped.
+fn report_vcard_error(line: usize, e: ParseError) {
+    info!("skipped the vcard starting on line number {}: {}", line, e);
+}
+
+/// The vCard properties that hold the fields of a record.
+const VCARD_PROPERTIES: [&str; 12] = [
+    "FN",
+    "N",
+    "NICKNAME",
+    "EMAIL",
+    "TEL",
+    "CATEGORIES",
+    "ADR",
+    "BDAY",
+    "NOTE",
+    "PHOTO",
+    "ORG",
+    "TITLE",
+];
+
+/// Changes a vCard to hold the fields of a record. "base" is the record as
+/// it was when the card was last synced. Properties whose fields haven't
+/// changed since then are left exactly as they were, so that anything we
+/// don't read, like a second phone number, isn't lost.
+fn update_vcard(card: &str, base: Option<&Record>, record: &Record) -> String {
+    let old_lines = base.map(vcard_lines).unwrap_or_default();
+    let new_lines = vcard_lines(record);
+    let with_property = |lines: &[String], property: &str| -> Vec<String> {
+        lines
+            .iter()
+            .filter(|line| vcard_property(line).is_some_and(|(name, _)| name == property))
+            .cloned()
+            .collect()
+    };
+    let changed: Vec<&str> = VCARD_PROPERTIES
+        .iter()
+        .copied()
+        .filter(|property| {
+            base.is_none()
+                || with_property(&old_lines, property) != with_property(&new_lines, property)
+        })
+        .collect();
+    let is_changed =
+        |line: &str| vcard_property(line).is_some_and(|(name, _)| changed.contains(&name.as_str()));
+    let mut lines = vec![];
+    for (_, line) in unfold_vcard(card) {
+        match vcard_property(&line) {
+            // The new values go at the end of the card.
+            Some((name, value)) if name == "END" && value.eq_ignore_ascii_case("VCARD") => {
+                lines.extend(new_lines.iter().filter(|line| is_changed(line)).cloned());
+            }
+            _ if is_changed(&line) => continue,
+            _ => (),
+        }
+        lines.push(line);
+    }
+    lines.iter().map(|line| fold_vcard_line(line)).collect()
+}
+
+/// Copies the fields that a vCard holds onto a record.
+fn apply_vcard_fields(record: &mut Record, card: Record) {
+    record.name = card.name;
+    record.emails = card.emails;
+    record.phone = card.phone;
+    record.tags = card.tags;
+    record.address = card.address;
+    record.birthday = card.birthday;
+    record.notes = card.notes;
+    record.avatar = card.avatar;
+    record.company = card.company;
+    record.title = card.title;
+    record.nickname = card.nickname;
+    record.touch();
+}
+
+/// A card in a CardDAV address book, as listed by the server.
+#[derive(Debug, PartialEq)]
+struct CardInfo {
+    /// Where the card is, usually as a path on the server.
+    href: String,
+    /// Changes every time the card is changed.
+    etag: String,
+}
+
+/// Reads the list of cards from the answer to a PROPFIND request. The
+/// address book itself is listed as well, but it is a collection rather
+/// than a card, so it is left out.
+fn parse_multistatus(xml: &str) -> Result<Vec<CardInfo>, roxmltree::Error> {
+    let doc = roxmltree::Document::parse(xml)?;
+    let cards = doc
+        .descendants()
+        .filter(|node| node.has_tag_name(("DAV:", "response")))
+        .filter(|response| {
+            !response
+                .descendants()
+                .any(|node| node.has_tag_name(("DAV:", "collection")))
+        })
+        .filter_map(|response| {
+            let text = |name: &str| {
+                response
+                    .descendants()
+                    .find(|node| node.has_tag_name(("DAV:", name)))
+                    .and_then(|node| node.text())
+                    .map(|text| text.trim().to_string())
+            };
+            Some(CardInfo {
+                href: text("href")?,
+                etag: text("getetag").unwrap_or_default(),
+            })
+        })
+        .collect();
+    Ok(cards)
+}
+
+/// An address book on a CardDAV server.
+struct CardDav {
+    /// The URL of the address book, ending with a slash.
+    url: String,
+    /// The "Authorization" header to send, if a user name was given.
+    auth: Option<String>,
+}
+
+impl CardDav {
+    fn new(url: &str, user: Option<&str>, password: &str) -> Self {
+        use base64::Engine;
+
+        let auth = user.map(|user| {
+            let login = format!("{}:{}", user, password);
+            format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD.encode(login)
+            )
+        });
+        Self {
+            url: format!("{}/", url.trim_end_matches('/')),
+            auth,
+        }
+    }
+
+    fn error(&self, e: impl std::fmt::Display) -> AppError {
+        AppError::Sync(self.url.clone(), e.to_string())
+    }
+
+    /// Returns the start of the address book's URL, up to the path, such as
+    /// "https://example.com".
+    fn origin(&self) -> &str {
+        let start = self.url.find("://").map_or(0, |i| i + 3);
+        match self.url[start..].find('/') {
+            Some(end) => &self.url[..start + end],
+            None => &self.url,
+        }
+    }
+
+    /// Turns an href from the server into a full URL. Hrefs are normally
+    /// just a path, which is on the same server as the address book.
+    fn resolve(&self, href: &str) -> String {
+        match href.contains("://") {
+            true => href.to_owned(),
+            false => format!("{}{}", self.origin(), href),
+        }
+    }
+
+    /// Returns the href for a new card, in the same form that the server
+    /// lists cards in.
+    fn new_href(&self, uid: Uuid) -> String {
+        format!("{}{}.vcf", &self.url[self.origin().len()..], uid)
+    }
+
+    fn request(&self, method: &str, href: &str) -> ureq::Request {
+        let request = ureq::request(method, &self.resolve(href));
+        match &self.auth {
+            Some(auth) => request.set("Authorization", auth),
+            None => request,
+        }
+    }
+
+    /// Lists every card in the address book.
+    fn list(&self) -> Result<Vec<CardInfo>, AppError> {
+        let body = concat!(
+            r#"<?xml version="1.0" encoding="utf-8"?>"#,
+            r#"<d:propfind xmlns:d="DAV:"><d:prop><d:getetag/><d:resourcetype/></d:prop></d:propfind>"#
+        );
+        let xml = self
+            .request("PROPFIND", &self.url)
+            .set("Depth", "1")
+            .set("Content-Type", "application/xml; charset=utf-8")
+            .send_string(body)
+            .map_err(|e| self.error(e))?
+            .into_string()
+            .map_err(|e| self.error(e))?;
+        parse_multistatus(&xml).map_err(|e| self.error(e))
+    }
+
+    /// Downloads a card, along with its ETag.
+    fn get(&self, href: &str) -> Result<(String, String), AppError> {
+        let response = self
+            .request("GET", href)
+            .call()
+            .map_err(|e| self.error(e))?;
+        let etag = response.header("ETag").unwrap_or_default().to_string();
+        let card = response.into_string().map_err(|e| self.error(e))?;
+        Ok((card, etag))
+    }
+
+    /// Uploads a card and returns its new ETag, which is empty if the server
+    /// didn't send one.
+    ///
+    /// When "etag" is given, the server only replaces the card if it still
+    /// has that ETag. Otherwise, the card is only created if it doesn't
+    /// exist yet. Either way, nothing is changed if someone else got there
+    /// first, and None is returned.
+    fn put(&self, href: &str, card: &str, etag: Option<&str>) -> Result<Option<String>, AppError> {
+        let request = self
+            .request("PUT", href)
+            .set("Content-Type", "text/vcard; charset=utf-8");
+        let request = match etag {
+            Some(etag) => request.set("If-Match", etag),
+            None => request.set("If-None-Match", "*"),
+        };
+        match request.send_string(card) {
+            Ok(response) => Ok(Some(
+                response.header("ETag").unwrap_or_default().to_string(),
+            )),
+            Err(ureq::Error::Status(412, _)) => Ok(None),
+            Err(e) => Err(self.error(e)),
+        }
+    }
+
+    /// Removes a card if it still has the given ETag. Returns false if the
+    /// card was changed by someone else.
+    fn delete(&self, href: &str, etag: &str) -> Result<bool, AppError> {
+        match self.request("DELETE", href).set("If-Match", etag).call() {
+            Ok(_) | Err(ureq::Error::Status(404, _)) => Ok(true),
+            Err(ureq::Error::Status(412, _)) => Ok(false),
+            Err(e) => Err(self.error(e)),
+        }
+    }
+}
+
+/// A card that was the same on both sides after the last CardDAV sync.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SyncedCard {
+    id: RecordId,
+    href: String,
+    etag: String,
+    /// The card as it is on the server.
+    card: String,
+    /// The record as it was after syncing.
+    record: Record,
+}
+
+/// What is remembered between CardDAV syncs: the cards of each address
+/// book, by URL. It is kept in a JSON file next to the data file.
+type CardDavState = BTreeMap<String, Vec<SyncedCard>>;
+
+/// Loads what was remembered about the last syncs.
+fn load_carddav_state(data_file: &Path) -> Result<CardDavState, AppError> {
+    match File::open(sidecar_path(data_file, "carddav")) {
+        Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(CardDavState::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Saves what is remembered about the last syncs.
+fn save_carddav_state(data_file: &Path, state: &CardDavState) -> std::io::Result<()> {
+    save_file(sidecar_path(data_file, "carddav"), |file| {
+        serde_json::to_writer_pretty(&mut *file, state)?;
+        Ok(())
+    })
+}
+
+/// Works out the day a reminder is due from "--on" or "--in". structopt
+/// makes sure that exactly one of them is given. Days past the last one
+/// that dates can hold are an error.
+fn due_date(
+    on: Option<NaiveDate>,
+    after: Option<u32>,
+    today: NaiveDate,
+) -> Result<NaiveDate, AppError> {
+    match (on, after.unwrap_or_default()) {
+        (Some(day), _) => Ok(day),
+        (None, days) => today
+            .checked_add_days(chrono::Days::new(days.into()))
+            .ok_or(AppError::TooFarAhead(days)),
+    }
+}
+
+/// Formats a reminder as a row of the "reminders" table.
+fn reminder_row(reminder: &Reminder, recs: &Records, today: NaiveDate) -> Vec<String> {
+    let when = match reminder.due.cmp(&today) {
+        std::cmp::Ordering::Less => format!("{} (overdue)", reminder.due),
+        std::cmp::Ordering::Equal => format!("{} (today)", reminder.due),
+        std::cmp::Ordering::Greater => reminder.due.to_string(),
+    };
+    // The contact may have been removed since.
+    let contact = match recs.get(reminder.contact) {
+        Some(record) => format!("{} ({})", record.name, record.id),
+        None => format!("record {}", reminder.contact),
+    };
+    vec![
+        reminder.number.to_string(),
+        when,
+        contact,
+        reminder.note.clone().unwrap_or_default(),
+    ]
+}
+
+/// Changes the ids of the contacts that reminders are for after the records
+/// were renumbered or merged. Reminders for contacts that were removed are kept, since
+/// the contact may still be restored from the trash.
+fn renumber_reminders(
+    data_file: &Path,
+    passphrase: Option<&str>,
+    ids: &HashMap<RecordId, RecordId>,
+) -> Result<(), AppError> {
+    let mut reminders = load_reminders(data_file, passphrase)?;
+    if reminders.is_empty() {
+        return Ok(());
+    }
+    for reminder in reminders.iter_mut() {
+        if let Some(id) = ids.get(&reminder.contact) {
+            reminder.contact = *id;
+        }
+    }
+    save_reminders(data_file, passphrase, &reminders)
+}
+
+/// Changes the ids of the synced cards after the records were renumbered.
+/// Cards of contacts that were removed since the last sync are forgotten,
+/// so the next sync takes them from the server again instead of mixing
+/// them up with the contacts that were given their ids.
+fn renumber_carddav_state(
+    data_file: &Path,
+    ids: &HashMap<RecordId, RecordId>,
+) -> Result<(), AppError> {
+    if !sidecar_path(data_file, "carddav").exists() {
+        return Ok(());
+    }
+    let mut state = load_carddav_state(data_file)?;
+    for cards in state.values_mut() {
+        cards.retain_mut(|card| match ids.get(&card.id) {
+            Some(id) => {
+                card.id = *id;
+                card.record.id = *id;
+                true
+            }
+            None => false,
+        });
+    }
+    Ok(save_carddav_state(data_file, &state)?)
+}
+
+/// Returns the UID of a vCard, which stays the same however the card is
+/// changed.
+fn vcard_uid(card: &str) -> Option<String> {
+    unfold_vcard(card)
+        .into_iter()
+        .find_map(|(_, line)| match vcard_property(&line) {
+            Some((name, value)) if name == "UID" => Some(value.trim().to_string()),
+            _ => None,
+        })
+}
+
+/// Finds the record that a card from the server is for, when neither has
+/// been synced before. Records that are already synced, whose ids are in
+/// "known", are skipped. A card with the UID of a record's UUID is for that
+/// record, and otherwise one with the same name and an email in common.
+fn matching_record(
+    recs: &Records,
+    known: &HashSet<RecordId>,
+    card: &str,
+    theirs: &Record,
+) -> Option<RecordId> {
+    let uid = vcard_uid(card).and_then(|uid| Uuid::parse_str(&uid).ok());
+    let unsynced = || {
+        recs.sorted()
+            .into_iter()
+            .filter(|record| !known.contains(&record.id))
+    };
+    if let Some(record) =
+        unsynced().find(|record| uid.is_some_and(|uid| record.id == RecordId::Uuid(uid)))
+    {
+        return Some(record.id);
+    }
+    let name = search_key(&theirs.name, false);
+    unsynced()
+        .find(|record| {
+            search_key(&record.name, false) == name
+                && record.emails.iter().any(|email| {
+                    theirs
+                        .emails
+                        .iter()
+                        .any(|theirs| theirs.eq_ignore_ascii_case(email))
+                })
+        })
+        .map(|record| record.id)
+}
+
+/// How many changes a CardDAV sync made.
+#[derive(Debug, Default)]
+struct CardDavCounts {
+    pushed: usize,
+    pulled: usize,
+    conflicts: usize,
+}
+
+/// Prints a warning about a record that was changed on both sides.
+fn warn_conflict(counts: &mut CardDavCounts, message: String) {
+    counts.conflicts += 1;
+    eprintln!("{}", output::error(&message));
+}
+
+/// Syncs the records with an address book on a CardDAV server. When "push"
+/// is false, the changes made on the server are taken, but local changes
+/// are kept until the next time.
+///
+/// "cards" are the cards as they were after the last sync. A card that was
+/// changed on the server has a different ETag than the one we remember,
+/// and a record that was changed here is different from the one we
+/// remember. When both happen to the same contact, the server's version
+/// wins, except that changing a contact always wins over removing it. Cards
+/// are only replaced or removed if they still have the ETag we know about,
+/// so a change made on the server while we are syncing is never
+/// overwritten.
+///
+/// Cards and records that haven't been synced before, such as on the first
+/// sync with an address book that already has contacts, are matched up by
+/// "matching_record" first, so that the same contact isn't added to both
+/// sides again.
+fn sync_carddav(
+    dav: &CardDav,
+    cards: &mut Vec<SyncedCard>,
+    recs: &mut Records,
+    trash: &mut Records,
+    push: bool,
+) -> Result<CardDavCounts, AppError> {
+    let mut counts = CardDavCounts::default();
+    let mut listed: HashMap<String, String> = dav
+        .list()?
+        .into_iter()
+        .map(|info| (info.href, info.etag))
+        .collect();
+    // A card is only taken off "pending" once it has been synced. If
+    // something goes wrong, the cards that are left are remembered as they
+    // were, and they are synced again next time.
+    let mut pending = std::mem::take(cards);
+    let result = (|| -> Result<(), AppError> {
+        while let Some(mut old) = pending.last().cloned() {
+            let local = recs.get(old.id).cloned();
+            let local_changed = local.as_ref() != Some(&old.record);
+            // Work out what happened on the server. None means that the card
+            // was removed, and Some(None) that it wasn't changed.
+            let remote = match listed.remove(&old.href) {
+                None => None,
+                Some(etag) if etag == old.etag && !etag.is_empty() => Some(None),
+                Some(_) => {
+                    let (card, etag) = dav.get(&old.href)?;
+                    match parse_vcards(&card).into_iter().next() {
+                        // Servers may change a card without changing any of our
+                        // fields, such as when they add a timestamp.
+                        Some(theirs) if vcard_lines(&theirs) == vcard_lines(&old.record) => {
+                            old.etag = etag;
+                            old.card = card;
+                            Some(None)
+                        }
+                        Some(theirs) => Some(Some((card, etag, theirs))),
+                        None => {
+                            eprintln!("{}", output::error(&format!("can't read {}", old.href)));
+                            Some(None)
+                        }
+                    }
+                }
+            };
+            match (local, remote) {
+                (_, Some(None)) if !local_changed || !push => cards.push(old),
+                (Some(record), Some(None)) => {
+                    let card = update_vcard(&old.card, Some(&old.record), &record);
+                    match dav.put(&old.href, &card, Some(&old.etag))? {
+                        Some(etag) => {
+                            counts.pushed += 1;
+                            cards.push(SyncedCard {
+                                etag,
+                                card,
+                                record,
+                                ..old
+                            });
+                        }
+                        None => {
+                            let message = format!(
+                                "record {} was changed on the server while syncing; sync again",
+                                old.id
+                            );
+                            warn_conflict(&mut counts, message);
+                            cards.push(old);
+                        }
+                    }
+                }
+                (None, Some(None)) => match dav.delete(&old.href, &old.etag)? {
+                    true => counts.pushed += 1,
+                    false => {
+                        let message = format!(
+                            "record {} was changed on the server while syncing; sync again",
+                            old.id
+                        );
+                        warn_conflict(&mut counts, message);
+                        cards.push(old);
+                    }
+                },
+                (local, Some(Some((card, etag, theirs)))) => {
+                    if local_changed {
+                        let message = format!(
+                            "record {} was changed here and on the server; kept the server's version",
+                            old.id
+                        );
+                        warn_conflict(&mut counts, message);
+                    }
+                    // A record that was removed here comes back, with a new id
+                    // if its old one has been given to another record since.
+                    let mut record = match local {
+                        Some(record) => record,
+                        None if recs.get(old.id).is_none() => Record::new(old.id, String::new()),
+                        None => Record::new(recs.next_id(), String::new()),
+                    };
+                    apply_vcard_fields(&mut record, theirs);
+                    recs.add(record.clone());
+                    counts.pulled += 1;
+                    cards.push(SyncedCard {
+                        id: record.id,
+                        href: old.href,
+                        etag,
+                        card,
+                        record,
+                    });
+                }
+                (Some(record), None) if local_changed => {
+                    // The record is sent again as a new card below.
+                    let message = format!(
+                        "record {} was changed here but removed from the server; kept it",
+                        record.id
+                    );
+                    warn_conflict(&mut counts, message);
+                }
+                (Some(record), None) => {
+                    if let Some(record) = recs.remove(record.id) {
+                        trash.add(record);
+                    }
+                    counts.pulled += 1;
+                }
+                (None, None) => (),
+            }
+            pending.pop();
+        }
+        Ok(())
+    })();
+    cards.append(&mut pending);
+    result?;
+    // Cards that we haven't seen before were added on the server, unless
+    // they are for a record that hasn't been synced yet either.
+    let mut known: HashSet<RecordId> = cards.iter().map(|card| card.id).collect();
+    for (href, _) in listed {
+        let (card, etag) = dav.get(&href)?;
+        let theirs = match parse_vcards(&card).into_iter().next() {
+            Some(theirs) => theirs,
+            None => {
+                eprintln!("{}", output::error(&format!("can't read {}", href)));
+                continue;
+            }
+        };
+        let record = match matching_record(recs, &known, &card, &theirs) {
+            Some(id) if vcard_lines(&recs[id]) == vcard_lines(&theirs) => recs[id].clone(),
+            Some(id) => {
+                let mut record = recs[id].clone();
+                let message = format!(
+                    "record {} is different on the server; kept the server's version",
+                    id
+                );
+                warn_conflict(&mut counts, message);
+                apply_vcard_fields(&mut record, theirs);
+                recs.add(record.clone());
+                counts.pulled += 1;
+                record
+            }
+            None => {
+                let record = Record {
+                    id: recs.next_id(),
+                    ..theirs
+                };
+                recs.add(record.clone());
+                counts.pulled += 1;
+                record
+            }
+        };
+        known.insert(record.id);
+        cards.push(SyncedCard {
+            id: record.id,
+            href,
+            etag,
+            card,
+            record,
+        });
+    }
+    // Records that don't have a card yet were added here.
+    if push {
+        let known: HashSet<RecordId> = cards.iter().map(|card| card.id).collect();
+        let new: Vec<Record> = recs
+            .sorted()
+            .into_iter()
+            .filter(|record| !known.contains(&record.id))
+            .cloned()
+            .collect();
+        for record in new {
+            let uid = match record.id {
+                RecordId::Uuid(uid) => uid,
+                RecordId::Number(_) => Uuid::new_v4(),
+            };
+            let href = dav.new_href(uid);
+            let template = format!("BEGIN:VCARD\r\nVERSION:3.0\r\nUID:{}\r\nEND:VCARD\r\n", uid);
+            let card = update_vcard(&template, None, &record);
+            match dav.put(&href, &card, None)? {
+                Some(etag) => {
+                    counts.pushed += 1;
+                    cards.push(SyncedCard {
+                        id: record.id,
+                        href,
+                        etag,
+                        card,
+                        record,
+                    });
+                }
+                None => {
+                    let message = format!(
+                        "a card for record {} already exists on the server",
+                        record.id
+                    );
+                    warn_conflict(&mut counts, message);
+                }
+            }
+        }
+    }
+    Ok(counts)
+}
+
+/// Escapes a value for use in an LDAP distinguished name (DN), where
+/// commas and a few other characters separate its parts.
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::new();
+    for (i, c) in value.chars().enumerate() {
+        let edge = i == 0 || i == value.chars().count() - 1;
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => escaped.push('\\'),
+            '#' if i == 0 => escaped.push('\\'),
+            ' ' if edge => escaped.push('\\'),
+            _ => (),
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Formats an attribute of an LDIF entry, ending with a line break.
+///
+/// Values can only be written as they are if they are plain ASCII without
+/// line breaks, and don't start with a character that means something else
+/// in LDIF. Any other value is written in base64, after a double colon.
+/// Long lines are folded, with each continuation line starting with a space.
+fn ldif_line(attribute: &str, value: &str) -> String {
+    use base64::Engine;
+
+    let safe = value
+        .bytes()
+        .all(|b| b.is_ascii() && !matches!(b, b'\0' | b'\n' | b'\r'))
+        && !value.starts_with([' ', ':', '<'])
+        && !value.ends_with(' ');
+    let line = match safe {
+        true => format!("{}: {}", attribute, value),
+        false => format!(
+            "{}:: {}",
+            attribute,
+            base64::engine::general_purpose::STANDARD.encode(value)
+        ),
+    };
+    // The line is plain ASCII by now, so it can be split at any byte.
+    let mut folded = String::new();
+    for (i, chunk) in line.as_bytes().chunks(76).enumerate() {
+        if i > 0 {
+            folded.push(' ');
+        }
+        folded.push_str(std::str::from_utf8(chunk).expect("LDIF lines are ASCII"));
+        folded.push('\n');
+    }
+    folded
+}
+
+/// Formats a single record as an LDIF entry, using the standard
+/// "inetOrgPerson" object class for contacts. "duplicate" must be the same
+/// as for "ldif_dn", since every attribute in the DN has to be in the entry
+/// as well.
+fn format_ldif(record: &Record, dn: &str, duplicate: bool) -> String {
+    let mut entry = ldif_line("dn", dn);
+    for class in ["top", "person", "organizationalPerson", "inetOrgPerson"] {
+        entry.push_str(&ldif_line("objectClass", class));
+    }
+    entry.push_str(&ldif_line("cn", &record.name));
+    if duplicate {
+        entry.push_str(&ldif_line("uid", &record.id.to_string()));
+    }
+    // A person must have a surname (sn). Just like with vCards, the last
+    // word of the name is treated as the surname.
+    match record.name.rsplit_once(' ') {
+        Some((given, family)) => {
+            entry.push_str(&ldif_line("givenName", given));
+            entry.push_str(&ldif_line("sn", family));
+        }
+        None => entry.push_str(&ldif_line("sn", &record.name)),
+    }
+    for email in &record.emails {
+        entry.push_str(&ldif_line("mail", email));
+    }
+    if let Some(phone) = &record.phone {
+        entry.push_str(&ldif_line("telephoneNumber", phone));
+    }
+    if let Some(address) = &record.address {
+        entry.push_str(&ldif_line("postalAddress", address));
+    }
+    if let Some(notes) = &record.notes {
+        entry.push_str(&ldif_line("description", notes));
+    }
+    if let Some(company) = &record.company {
+        entry.push_str(&ldif_line("o", company));
+    }
+    if let Some(title) = &record.title {
+        entry.push_str(&ldif_line("title", title));
+    }
+    entry.push('\n');
+    entry
+}
+
+/// Returns the DN of a contact. Without a base DN, the contact is named by
+/// its name and email, which is what mail clients like Thunderbird expect.
+/// Contacts that have the same name as an earlier one also get their id,
+/// since every DN in a directory has to be different.
+fn ldif_dn(record: &Record, base_dn: Option<&str>, duplicate: bool) -> String {
+    let mut rdn = format!("cn={}", escape_dn_value(&record.name));
+    if duplicate {
+        rdn.push_str(&format!("+uid={}", record.id));
+    }
+    match (base_dn, record.emails.first()) {
+        (Some(base_dn), _) => format!("{},{}", rdn, base_dn),
+        (None, Some(email)) => format!("{},mail={}", rdn, escape_dn_value(email)),
+        (None, None) => rdn,
+    }
+}
+
+/// The formats that the export command can write.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ExportFormat {
+    /// Records that p2 can read back in: a data file, or JSON or YAML.
+    Data(DataFormat),
+    /// An alias file for mutt and other terminal mail clients.
+    Mutt,
+    /// An Excel spreadsheet, which can't be read back in.
+    Xlsx,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Data(DataFormat::Csv)),
+            "json" => Ok(Self::Data(DataFormat::Json)),
+            "jsonl" => Ok(Self::Data(DataFormat::JsonLines)),
+            "yaml" => Ok(Self::Data(DataFormat::Yaml)),
+            "mutt" => Ok(Self::Mutt),
+            "xlsx" => Ok(Self::Xlsx),
+            _ => Err(format!(
+                "unknown format: {} (expected csv, json, jsonl, yaml, xlsx or mutt)",
+                s
+            )),
+        }
+    }
+}
+
+/// Writes records to an Excel spreadsheet, with a bold header row that has
+/// the same columns as the data file.
+///
+/// Unlike CSV, the cells have types: numeric ids are numbers, and the dates
+/// are real dates, so they can be sorted and filtered in Excel. Excel's
+/// dates start in 1900, so earlier ones are written as text instead. Emails
+/// and tags are joined with semicolons, like in the data file.
+fn write_xlsx(
+    records: &[&Record],
+    extra_columns: &[String],
+    progress: &ProgressBar,
+) -> Result<Vec<u8>, AppError> {
+    use rust_xlsxwriter::{ExcelDateTime, Format, Workbook};
+
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    sheet.set_name("Contacts")?;
+    let bold = Format::new().set_bold();
+    let date = Format::new().set_num_format("yyyy-mm-dd");
+    let timestamp = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+
+    let columns = CSV_COLUMNS
+        .iter()
+        .copied()
+        .chain(extra_columns.iter().map(|column| column.as_str()));
+    for (col, column) in columns.enumerate() {
+        sheet.write_string_with_format(0, col as u16, column, &bold)?;
+    }
+
+    for (row, record) in progress.wrap_iter(records.iter()).enumerate() {
+        // The header is in the first row, so the records start after it.
+        let row = row as u32 + 1;
+        match record.id {
+            RecordId::Number(id) => sheet.write_number(row, 0, id as f64)?,
+            RecordId::Uuid(id) => sheet.write_string(row, 0, id.to_string())?,
+        };
+        sheet.write_string(row, 1, &record.name)?;
+        sheet.write_string(row, 2, record.emails.join(";"))?;
+        if let Some(phone) = &record.phone {
+            // Phone numbers are text, so that leading zeros and "+" are kept.
+            sheet.write_string(row, 3, phone)?;
+        }
+        sheet.write_string(row, 4, record.tags.join(";"))?;
+        for (col, time) in [(5, record.created_at), (6, record.updated_at)] {
+            if let Some(time) = time {
+                let time = ExcelDateTime::from_timestamp(time.timestamp())?;
+                sheet.write_datetime_with_format(row, col, time, &timestamp)?;
+            }
+        }
+        if let Some(address) = &record.address {
+            sheet.write_string(row, 7, address)?;
+        }
+        for (col, day) in [(8, record.birthday), (15, record.last_contacted)] {
+            match day {
+                Some(day) if (1900..=9999).contains(&day.year()) => {
+                    let day = ExcelDateTime::from_ymd(
+                        day.year() as u16,
+                        day.month() as u8,
+                        day.day() as u8,
+                    )?;
+                    sheet.write_datetime_with_format(row, col, day, &date)?;
+                }
+                Some(day) => {
+                    sheet.write_string(row, col, day.format("%Y-%m-%d").to_string())?;
+                }
+                None => (),
+            }
+        }
+        if let Some(notes) = &record.notes {
+            sheet.write_string(row, 9, notes)?;
+        }
+        let relations: Vec<_> = record.relations.iter().map(|r| r.to_string()).collect();
+        sheet.write_string(row, 10, relations.join(";"))?;
+        if let Some(avatar) = &record.avatar {
+            sheet.write_string(row, 11, avatar)?;
+        }
+        if let Some(company) = &record.company {
+            sheet.write_string(row, 12, company)?;
+        }
+        if let Some(title) = &record.title {
+            sheet.write_string(row, 13, title)?;
+        }
+        if let Some(nickname) = &record.nickname {
+            sheet.write_string(row, 14, nickname)?;
+        }
+        for (col, column) in extra_columns.iter().enumerate() {
+            if let Some(value) = record.extra.get(column) {
+                sheet.write_string(row, (CSV_COLUMNS.len() + col) as u16, value)?;
+            }
+        }
+    }
+
+    // The header stays in view while scrolling, and has buttons for
+    // sorting and filtering each column.
+    sheet.set_freeze_panes(1, 0)?;
+    let last_col = (CSV_COLUMNS.len() + extra_columns.len() - 1) as u16;
+    sheet.autofilter(0, 0, records.len() as u32, last_col)?;
+    sheet.autofit();
+    Ok(workbook.save_to_buffer()?)
+}
+
+/// Creates a short nickname for a contact, to be typed in place of their
+/// email address. "taken" holds the nicknames that were already given out.
+///
+/// The contact's own nickname is tried first, then the first name, then the
+/// first name with the initial of the last name, then the whole name. If
+/// all of those are taken, a number is added to the end of the whole name.
+fn nickname(record: &Record, taken: &HashSet<String>) -> String {
+    // Only lowercase letters and digits are kept, so that the nickname is
+    // easy to type.
+    let clean = |word: &str| -> String {
+        word.to_lowercase()
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect()
+    };
+    let words: Vec<String> = record
+        .name
+        .split_whitespace()
+        .map(clean)
+        .filter(|word| !word.is_empty())
+        .collect();
+    let mut candidates: Vec<String> = record
+        .nickname
+        .iter()
+        .map(|nickname| clean(nickname))
+        .filter(|nickname| !nickname.is_empty())
+        .collect();
+    match words.as_slice() {
+        [] => {
+            // Names without any usable letters fall back to the email.
+            let user = record
+                .emails
+                .first()
+                .map(|email| clean(email.split('@').next().unwrap_or_default()))
+                .filter(|user| !user.is_empty());
+            candidates.push(user.unwrap_or_else(|| "contact".to_string()));
+        }
+        [word] => candidates.push(word.clone()),
+        [first, .., last] => {
+            candidates.push(first.clone());
+            candidates.push(format!("{}{}", first, &last[..1]));
+            candidates.push(words.join("-"));
+        }
+    }
+    let last = candidates
+        .last()
+        .expect("always at least one candidate")
+        .clone();
+    candidates
+        .into_iter()
+        .chain((2..).map(|number| format!("{}{}", last, number)))
+        .find(|candidate| !taken.contains(candidate))
+        .expect("numbered nicknames never run out")
+}
+
+/// Formats a contact as a mutt alias, such as
+/// "alias ann Ann Lee <ann@example.com>". Only the first email is used.
+/// Names with characters that mean something in an address are quoted.
+fn format_mutt_alias(nickname: &str, record: &Record, email: &str) -> String {
+    let name = match record.name.contains(|c| "()<>@,;:\\\".[]".contains(c)) {
+        true => format!(
+            "\"{}\"",
+            record.name.replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+        false => record.name.clone(),
+    };
+    format!("alias {} {} <{}>", nickname, name, email)
+}
+
+/// Where a file being imported came from. Other programs export contacts
+/// with their own columns, which an import profile translates into records.
+#[derive(Debug, Clone, Copy)]
+enum ImportSource {
+    /// A data file written by p2.
+    P2,
+    /// A CSV file exported by another program.
+    Csv(&'static dyn ImportProfile),
+}
+
+impl FromStr for ImportSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "p2" {
+            return Ok(Self::P2);
+        }
+        match IMPORT_PROFILES.iter().find(|profile| profile.name() == s) {
+            Some(profile) => Ok(Self::Csv(*profile)),
+            None => Err(format!(
+                "unknown source: {} (expected p2, {})",
+                s,
+                import_profile_names().join(", ")
+            )),
+        }
+    }
+}
+
+/// Parses the value of "import --encoding". Any name that web browsers
+/// understand works, such as "latin1" or "shift_jis". "utf16" is accepted
+/// as well, since it is what most people would type.
+fn parse_encoding(value: &str) -> Result<&'static Encoding, String> {
+    match value.to_lowercase().as_str() {
+        "utf16" | "utf-16" => Ok(encoding_rs::UTF_16LE),
+        "utf16be" => Ok(encoding_rs::UTF_16BE),
+        label => Encoding::for_label(label.as_bytes())
+            .ok_or_else(|| format!("unknown encoding: {} (try utf8, latin1 or utf16)", value)),
+    }
+}
+
+/// Imports and exports of fewer records than this finish too quickly for a
+/// progress bar to be useful.
+const PROGRESS_MIN_RECORDS: usize = 1000;
+
+/// Returns whether to show a progress bar for "len" records. The bar is
+/// drawn on stderr, so it is hidden when stderr isn't a terminal, so that it
+/// doesn't end up in logs.
+fn shows_progress(len: usize, terminal: bool) -> bool {
+    len >= PROGRESS_MIN_RECORDS && terminal
+}
+
+/// Creates a progress bar on stderr for importing or exporting "len"
+/// records, which is hidden unless "shows_progress" says otherwise.
+fn progress_bar(len: usize, action: &str) -> ProgressBar {
+    use std::io::IsTerminal;
+    if !shows_progress(len, std::io::stderr().is_terminal()) {
+        return ProgressBar::hidden();
+    }
+    let style = ProgressStyle::with_template("{msg} [{bar:30}] {pos}/{len} records, {eta} left")
+        .expect("the progress template is valid")
+        .progress_chars("=> ");
+    ProgressBar::new(len as u64)
+        .with_style(style)
+        .with_message(action.to_string())
+}
+
+/// Opens a file to import. The rest of p2 only works with UTF-8, so files
+/// in another encoding are converted while they are read. A byte order mark
+/// at the start of the file overrides the encoding, just like in browsers.
+fn open_import(
+    file: &Path,
+    encoding: Option<&'static Encoding>,
+) -> Result<Box<dyn BufRead>, AppError> {
+    let mut reader = decompress(BufReader::new(File::open(file)?))?;
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return Ok(reader),
+    };
+    // The whole file is converted at once. Imports are loaded into memory
+    // anyway, so this doesn't cost much.
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    let (text, used, had_errors) = encoding.decode(&bytes);
+    if had_errors {
+        eprintln!(
+            "warning: some characters in {} aren't valid {}, and were replaced with \u{fffd}",
+            file.display(),
+            used.name()
+        );
+    }
+    Ok(Box::new(std::io::Cursor::new(
+        text.into_owned().into_bytes(),
+    )))
+}
+
+/// Translates the rows of a CSV file exported by another program into
+/// records. Each program gets its own profile, and they are all read by
+/// "read_foreign_csv".
+trait ImportProfile: std::fmt::Debug + Sync {
+    /// The name that picks the profile with "import --source".
+    fn name(&self) -> &'static str;
+
+    /// Turns a row of the file into a record.
+    fn convert(&self, row: &CsvRow) -> Result<Record, ParseError>;
+}
+
+/// Every import profile. Adding a profile to this list is all it takes to
+/// make it available to "import --source".
+static IMPORT_PROFILES: [&dyn ImportProfile; 3] =
+    [&GoogleProfile, &OutlookProfile, &ThunderbirdProfile];
+
+/// Returns the names of the import profiles, for help and error messages.
+fn import_profile_names() -> Vec<&'static str> {
+    IMPORT_PROFILES
+        .iter()
+        .map(|profile| profile.name())
+        .collect()
+}
+
+/// A row of a CSV file exported by another program. Its values are looked
+/// up by the name of their column, since every program orders its columns
+/// differently.
+struct CsvRow<'a> {
+    columns: &'a [String],
+    fields: Vec<String>,
+    /// The positions of the columns that have been looked at, so that we
+    /// can tell which columns weren't imported.
+    used: &'a RefCell<BTreeSet<usize>>,
+}
+
+impl CsvRow<'_> {
+    /// Returns the value of a column, unless it is missing or empty.
+    fn get(&self, column: &str) -> Option<&str> {
+        let index = self.columns.iter().position(|name| name == column)?;
+        self.used.borrow_mut().insert(index);
+        self.fields
+            .get(index)
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+    }
+
+    /// Returns the values of several columns joined together, such as the
+    /// parts of a name. None is returned if none of them have a value.
+    fn join(&self, columns: &[&str], separator: &str) -> Option<String> {
+        let parts: Vec<_> = columns
+            .iter()
+            .filter_map(|column| self.get(column))
+            .collect();
+        Some(parts.join(separator)).filter(|joined| !joined.is_empty())
+    }
+
+    /// Returns the first of the columns that has a value.
+    fn first(&self, columns: &[&str]) -> Option<String> {
+        columns
+            .iter()
+            .find_map(|column| self.get(column))
+            .map(|value| value.to_string())
+    }
+
+    /// Returns the values of numbered columns, such as "E-mail 1 - Value"
+    /// and "E-mail 2 - Value". "{}" in the pattern stands for the number.
+    fn numbered(&self, pattern: &str) -> Vec<&str> {
+        let mut values = vec![];
+        for number in 1.. {
+            let column = pattern.replace("{}", &number.to_string());
+            if !self.columns.contains(&column) {
+                break;
+            }
+            values.extend(self.get(&column));
+        }
+        values
+    }
+}
+
+/// Reads a CSV file that was exported by another program. The first line
+/// names the columns, and the profile turns each of the other rows into a
+/// record. The records have an id of 0, so real ids must be assigned before
+/// adding them.
+///
+/// Rows that can't be converted are handled just like bad lines of a data
+/// file. The names of the columns that had values but weren't used are
+/// returned along with the records.
+fn read_foreign_csv<R: BufRead>(
+    reader: R,
+    profile: &dyn ImportProfile,
+    strict: bool,
+) -> Result<(Vec<Record>, Vec<String>), AppError> {
+    let mut reader = RecordReader::new(reader);
+    if !reader.read_record()? {
+        return Ok((vec![], vec![]));
+    }
+    let header = reader.record.as_str();
+    // Exports made with some locales separate fields with semicolons, and
+    // some programs use tabs. Whichever appears most in the header wins.
+    let delimiter = [',', ';', '\t']
+        .iter()
+        .copied()
+        .rev()
+        .max_by_key(|c| header.matches(*c).count())
+        .unwrap_or(',');
+    let columns: Vec<String> = parse_fields(header, delimiter)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|column| column.trim().to_string())
+        .collect();
+    let used = RefCell::new(BTreeSet::new());
+    let mut with_values = BTreeSet::new();
+    let (mut records, mut bad_lines) = (vec![], vec![]);
+    while reader.read_record()? {
+        if reader.record.trim().is_empty() {
+            continue;
+        }
+        let result = parse_fields(&reader.record, delimiter).and_then(|fields| {
+            for (index, field) in fields.iter().enumerate() {
+                if !field.trim().is_empty() {
+                    with_values.insert(index);
+                }
+            }
+            profile.convert(&CsvRow {
+                columns: &columns,
+                fields,
+                used: &used,
+            })
+        });
+        match result {
+            Ok(record) => records.push(record),
+            Err(e) => {
+                let bad_line = BadLine::new(&reader, e);
+                info!("skipped a contact: {}", bad_line);
+                if strict {
+                    bad_lines.push(bad_line);
+                }
+            }
+        }
+    }
+    if !bad_lines.is_empty() {
+        return Err(AppError::Malformed(bad_lines));
+    }
+    let skipped = with_values
+        .difference(&used.borrow())
+        .filter_map(|&index| columns.get(index).cloned())
+        .collect();
+    Ok((records, skipped))
+}
+
+/// Splits a value from a Google Contacts export. Columns that hold several
+/// values, such as the labels of a contact, separate them with " ::: ".
+fn google_values(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(" ::: ")
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+}
+
+/// Google Contacts exports. Google has changed its columns over the years,
+/// so both the older names (such as "Given Name" and "Group Membership")
+/// and the newer ones (such as "First Name" and "Labels") are understood.
+#[derive(Debug)]
+struct GoogleProfile;
+
+impl ImportProfile for GoogleProfile {
+    fn name(&self) -> &'static str {
+        "google"
+    }
+
+    fn convert(&self, row: &CsvRow) -> Result<Record, ParseError> {
+        // The parts of the name are read even when there is a full name, so
+        // that they aren't reported as skipped. Contacts without a name,
+        // such as businesses, are named after their organization instead.
+        let name = row
+            .first(&["Name"])
+            .or(row.join(&["First Name", "Middle Name", "Last Name"], " "))
+            .or(row.join(&["Given Name", "Additional Name", "Family Name"], " "))
+            .or_else(|| row.first(&["Organization Name", "Organization 1 - Name"]))
+            .ok_or_else(|| ParseError::MissingField("name".to_owned()))?;
+        let emails = row
+            .numbered("E-mail {} - Value")
+            .into_iter()
+            .flat_map(google_values)
+            .map(|email| email.to_string())
+            .collect();
+        let phone = row
+            .numbered("Phone {} - Value")
+            .into_iter()
+            .flat_map(google_values)
+            .next()
+            .map(|phone| phone.to_string());
+        // Labels that begin with "*", like "* myContacts", are added by
+        // Google itself rather than by the user.
+        let tags = row
+            .first(&["Labels", "Group Membership"])
+            .map(|labels| {
+                google_values(&labels)
+                    .filter(|label| !label.starts_with('*'))
+                    .map(|label| label.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        // Formatted addresses are spread over several lines, but ours are
+        // kept on one.
+        let address = row.get("Address 1 - Formatted").map(|address| {
+            let lines: Vec<_> = address
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty())
+                .collect();
+            lines.join(", ")
+        });
+        // Birthdays without a year are written like "--05-12", and can't be
+        // kept, since ours always have a year.
+        let birthday = match row.get("Birthday") {
+            Some(birthday) if !birthday.starts_with("--") => Some(parse_date(birthday)?),
+            _ => None,
+        };
+        Ok(Record {
+            emails,
+            phone,
+            tags,
+            address,
+            birthday,
+            notes: row.first(&["Notes"]),
+            nickname: row.first(&["Nickname"]),
+            company: row.first(&["Organization Name", "Organization 1 - Name"]),
+            title: row.first(&["Organization Title", "Organization 1 - Title"]),
+            ..Record::new(RecordId::default(), name)
+        })
+    }
+}
+
+/// Returns the full year for a year that may have been written with only two
+/// digits, such as "90" in "3/14/90". Birthdays aren't in the future, so
+/// two-digit years up to this year's are in this century, and the others
+/// are in the last one. Years with more digits are returned unchanged.
+fn full_year(year: i32, today: NaiveDate) -> i32 {
+    if !(0..100).contains(&year) {
+        return year;
+    }
+    let century = today.year() - today.year() % 100;
+    match century + year > today.year() {
+        true => century - 100 + year,
+        false => century + year,
+    }
+}
+
+/// Moves a date that was written with a two-digit year into the right
+/// century, using "full_year".
+fn with_full_year(date: NaiveDate, today: NaiveDate) -> Result<NaiveDate, ParseError> {
+    let year = full_year(date.year(), today);
+    date.with_year(year)
+        .ok_or_else(|| ParseError::InvalidDate(format!("{}-{}-{}", year, date.month(), date.day())))
+}
+
+/// Outlook exports, which are also written by Outlook.com and many other
+/// programs that want Outlook to be able to read their files.
+#[derive(Debug)]
+struct OutlookProfile;
+
+impl ImportProfile for OutlookProfile {
+    fn name(&self) -> &'static str {
+        "outlook"
+    }
+
+    fn convert(&self, row: &CsvRow) -> Result<Record, ParseError> {
+        let name = row
+            .join(&["First Name", "Middle Name", "Last Name"], " ")
+            .or_else(|| row.first(&["Nickname", "Company"]))
+            .ok_or_else(|| ParseError::MissingField("name".to_owned()))?;
+        let emails = ["E-mail Address", "E-mail 2 Address", "E-mail 3 Address"]
+            .iter()
+            .filter_map(|column| row.get(column))
+            .map(|email| email.to_string())
+            .collect();
+        let phone = row.first(&[
+            "Mobile Phone",
+            "Primary Phone",
+            "Home Phone",
+            "Business Phone",
+        ]);
+        // Categories are separated by semicolons, just like our tags.
+        let tags = row.get("Categories").map(split_list).unwrap_or_default();
+        let home = [
+            "Home Street",
+            "Home City",
+            "Home State",
+            "Home Postal Code",
+            "Home Country/Region",
+        ];
+        let business = [
+            "Business Street",
+            "Business City",
+            "Business State",
+            "Business Postal Code",
+            "Business Country/Region",
+        ];
+        let address = row.join(&home, ", ").or_else(|| row.join(&business, ", "));
+        // Outlook writes dates like "3/14/1990", and "0/0/00" when there
+        // isn't one. Older versions only write two digits of the year.
+        let birthday = match row.get("Birthday") {
+            Some(birthday) if birthday.starts_with("0/0/") => None,
+            Some(birthday) => match NaiveDate::parse_from_str(birthday, "%m/%d/%Y") {
+                Ok(birthday) => Some(with_full_year(birthday, Local::now().date_naive())?),
+                Err(_) => Some(parse_date(birthday)?),
+            },
+            None => None,
+        };
+        Ok(Record {
+            emails,
+            phone,
+            tags,
+            address,
+            birthday,
+            notes: row.first(&["Notes"]),
+            nickname: row.first(&["Nickname"]),
+            company: row.first(&["Company"]),
+            title: row.first(&["Job Title"]),
+            ..Record::new(RecordId::default(), name)
+        })
+    }
+}
+
+/// Thunderbird address book exports.
+#[derive(Debug)]
+struct ThunderbirdProfile;
+
+impl ImportProfile for ThunderbirdProfile {
+    fn name(&self) -> &'static str {
+        "thunderbird"
+    }
+
+    fn convert(&self, row: &CsvRow) -> Result<Record, ParseError> {
+        let name = row
+            .first(&["Display Name"])
+            .or(row.join(&["First Name", "Last Name"], " "))
+            .or_else(|| row.first(&["Nickname", "Organization"]))
+            .ok_or_else(|| ParseError::MissingField("name".to_owned()))?;
+        let emails = ["Primary Email", "Secondary Email"]
+            .iter()
+            .filter_map(|column| row.get(column))
+            .map(|email| email.to_string())
+            .collect();
+        let phone = row.first(&["Mobile Number", "Home Phone", "Work Phone"]);
+        let address = row.join(
+            &[
+                "Home Address",
+                "Home Address 2",
+                "Home City",
+                "Home State",
+                "Home ZipCode",
+                "Home Country",
+            ],
+            ", ",
+        );
+        // The birthday is split into three columns. Without a year, it
+        // can't be kept. The year may only have two digits.
+        let part = |column: &str| row.get(column).and_then(|value| value.parse().ok());
+        let birthday = match (part("Birth Year"), part("Birth Month"), part("Birth Day")) {
+            (Some(year), Some(month), Some(day)) => {
+                let year = full_year(year as i32, Local::now().date_naive());
+                let date = NaiveDate::from_ymd_opt(year, month, day);
+                Some(date.ok_or_else(|| {
+                    ParseError::InvalidDate(format!("{}-{}-{}", year, month, day))
+                })?)
+            }
+            _ => None,
+        };
+        Ok(Record {
+            emails,
+            phone,
+            address,
+            birthday,
+            notes: row.first(&["Notes"]),
+            nickname: row.first(&["Nickname"]),
+            company: row.first(&["Organization"]),
+            title: row.first(&["Job Title"]),
+            ..Record::new(RecordId::default(), name)
+        })
+    }
+}
+
+/// The formats that records can be printed in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    /// Debug output, one record per line.
+    Text,
+    /// A JSON array of records, useful for piping into other tools.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown format: {} (expected text or json)", s)),
+        }
+    }
+}
+
+/// The widest that a column of a table can be. Longer values are cut short.
+const MAX_COLUMN_WIDTH: usize = 40;
+
+/// The names of the columns used when printing records as a table.
+const TABLE_COLUMNS: [&str; 5] = ["ID", "NAME", "EMAIL", "PHONE", "TAGS"];
+
+/// The widths of the columns when records are printed while the file is
+/// still being read. The widest values aren't known yet, so these are used
+/// instead.
+const STREAM_COLUMN_WIDTHS: [usize; 5] = [6, 24, 32, 16, 24];
+
+/// Colors for terminal output. Whether color is used is decided once when
+/// the program starts, so the functions that print don't need to be told.
+mod output {
+    use crossterm::style::Stylize;
+    use regex::Regex;
+    use std::io::IsTerminal;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// When to print in color.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum ColorChoice {
+        /// Only when printing to a terminal.
+        Auto,
+        Always,
+        Never,
+    }
+
+    impl FromStr for ColorChoice {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "auto" => Ok(Self::Auto),
+                "always" => Ok(Self::Always),
+                "never" => Ok(Self::Never),
+                _ => Err(format!(
+                    "unknown color choice: {} (expected auto, always, or never)",
+                    s
+                )),
+            }
+        }
+    }
+
+    // Stdout and stderr are decided separately, since only one of them may
+    // be a terminal. For example, when the output is piped into another
+    // program, errors are still shown in the terminal.
+    static STDOUT_COLOR: AtomicBool = AtomicBool::new(false);
+    static STDERR_COLOR: AtomicBool = AtomicBool::new(false);
+
+    /// Decides whether to use color. "Auto" never uses color when the
+    /// NO_COLOR environment variable is set.
+    pub fn set_color(choice: ColorChoice) {
+        let (stdout, stderr) = match choice {
+            ColorChoice::Always => (true, true),
+            ColorChoice::Never => (false, false),
+            ColorChoice::Auto => {
+                let allowed = std::env::var_os("NO_COLOR").is_none();
+                (
+                    allowed && std::io::stdout().is_terminal(),
+                    allowed && std::io::stderr().is_terminal(),
+                )
+            }
+        };
+        STDOUT_COLOR.store(stdout, Ordering::Relaxed);
+        STDERR_COLOR.store(stderr, Ordering::Relaxed);
+    }
+
+    /// Checks whether messages on stderr should be colored.
+    pub fn stderr_color() -> bool {
+        STDERR_COLOR.load(Ordering::Relaxed)
+    }
+
+    static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+    /// Sends status messages to stderr, for when stdout is being used for
+    /// the records themselves.
+    pub fn status_to_stderr() {
+        STATUS_TO_STDERR.store(true, Ordering::Relaxed);
+    }
+
+    /// Checks whether status messages go to stderr.
+    pub fn status_on_stderr() -> bool {
+        STATUS_TO_STDERR.load(Ordering::Relaxed)
+    }
+
+    fn stdout_color() -> bool {
+        STDOUT_COLOR.load(Ordering::Relaxed)
+    }
+
+    /// Colors a record id.
+    pub fn id(text: &str) -> String {
+        match stdout_color() {
+            true => text.cyan().to_string(),
+            false => text.to_string(),
+        }
+    }
+
+    /// Makes column names stand out from the values below them.
+    pub fn header(text: &str) -> String {
+        match stdout_color() {
+            true => text.bold().to_string(),
+            false => text.to_string(),
+        }
+    }
+
+    /// Colors an error message, which is printed to stderr.
+    pub fn error(text: &str) -> String {
+        match STDERR_COLOR.load(Ordering::Relaxed) {
+            true => text.red().bold().to_string(),
+            false => text.to_string(),
+        }
+    }
+
+    /// Highlights every part of the text that the pattern matches.
+    pub fn highlight(text: &str, pattern: Option<&Regex>) -> String {
+        let pattern = match pattern {
+            Some(pattern) if stdout_color() => pattern,
+            _ => return text.to_string(),
+        };
+        let mut highlighted = String::new();
+        let mut end = 0;
+        for found in pattern.find_iter(text).filter(|found| !found.is_empty()) {
+            highlighted.push_str(&text[end..found.start()]);
+            highlighted.push_str(&found.as_str().yellow().bold().to_string());
+            end = found.end();
+        }
+        highlighted.push_str(&text[end..]);
+        highlighted
+    }
+}
+
+/// A list that is narrowed down by typing, like fzf, for choosing one item
+/// out of many. It is drawn on stderr, so that it never ends up in output
+/// that was redirected to a file.
+mod picker {
+    use crossterm::cursor::MoveTo;
+    use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+    use crossterm::queue;
+    use crossterm::style::{Attribute, Print, SetAttribute};
+    use crossterm::terminal::{self, Clear, ClearType};
+    use std::io::{IsTerminal, Write};
+
+    /// Returns whether there is a terminal to show the picker on.
+    pub fn available() -> bool {
+        std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+    }
+
+    /// Scores how well the query matches the text, or returns None if the
+    /// letters of the query aren't all in the text in the same order. Case
+    /// and spaces in the query are ignored. Higher scores are better: each
+    /// letter right after the one before it, or at the start of a word,
+    /// counts for more.
+    pub fn score(query: &str, text: &str) -> Option<i64> {
+        let query: Vec<char> = query
+            .to_lowercase()
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        let text: Vec<char> = text.to_lowercase().chars().collect();
+        if query.is_empty() {
+            return Some(0);
+        }
+        let letter = |j: usize| match j == 0 || !text[j - 1].is_alphanumeric() {
+            true => 4,
+            false => 1,
+        };
+        // The same letter may be in the text more than once, and the first
+        // one isn't always the best. "best[j]" is the highest score for the
+        // letters of the query so far, with the last one at "j" in the text.
+        let mut best: Vec<Option<i64>> = text
+            .iter()
+            .enumerate()
+            .map(|(j, t)| (*t == query[0]).then(|| letter(j)))
+            .collect();
+        for c in &query[1..] {
+            let mut next = vec![None; text.len()];
+            // The best score with the previous letter anywhere before "j".
+            let mut before = None;
+            for j in 0..text.len() {
+                if text[j] == *c {
+                    let after_gap = before;
+                    let adjacent = j.checked_sub(1).and_then(|k| best[k]).map(|s| s + 5);
+                    next[j] = after_gap.max(adjacent).map(|s| s + letter(j));
+                }
+                before = before.max(best[j]);
+            }
+            best = next;
+        }
+        best.into_iter().flatten().max()
+    }
+
+    /// Returns the positions of the items that match the query, best match
+    /// first. Items that match equally well stay in their original order.
+    pub fn matches(query: &str, items: &[String]) -> Vec<usize> {
+        let mut found: Vec<(usize, i64)> = items
+            .iter()
+            .enumerate()
+            .filter_map(|(i, item)| Some((i, score(query, item)?)))
+            .collect();
+        found.sort_by_key(|(_, score)| std::cmp::Reverse(*score));
+        found.into_iter().map(|(i, _)| i).collect()
+    }
+
+    /// Lets the user pick one of the items, and returns its position. None
+    /// means that they changed their mind and pressed escape.
+    pub fn pick(prompt: &str, items: &[String]) -> std::io::Result<Option<usize>> {
+        // The terminal is put back when the screen is dropped.
+        let mut screen = super::AlternateScreen::enter(std::io::stderr())?;
+        run(&mut screen.out, prompt, items)
+    }
+
+    /// Handles key presses until an item is picked.
+    fn run<W: Write>(
+        out: &mut W,
+        prompt: &str,
+        items: &[String],
+    ) -> std::io::Result<Option<usize>> {
+        let mut query = String::new();
+        let mut selected = 0;
+        loop {
+            let found = matches(&query, items);
+            selected = selected.min(found.len().saturating_sub(1));
+            draw(out, prompt, &query, items, &found, selected)?;
+            let (code, modifiers) = match event::read()? {
+                Event::Key(KeyEvent {
+                    code,
+                    modifiers,
+                    kind: KeyEventKind::Press,
+                    ..
+                }) => (code, modifiers),
+                _ => continue,
+            };
+            let control = modifiers.contains(KeyModifiers::CONTROL);
+            match code {
+                KeyCode::Enter => return Ok(found.get(selected).copied()),
+                KeyCode::Esc => return Ok(None),
+                KeyCode::Char('c') if control => return Ok(None),
+                KeyCode::Up => selected = selected.saturating_sub(1),
+                KeyCode::Char('p') if control => selected = selected.saturating_sub(1),
+                KeyCode::Down => selected += 1,
+                KeyCode::Char('n') if control => selected += 1,
+                KeyCode::Backspace => {
+                    query.pop();
+                    selected = 0;
+                }
+                KeyCode::Char(c) if !control => {
+                    query.push(c);
+                    selected = 0;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Draws the prompt, how many items match, and as many of the matching
+    /// items as fit, scrolled so that the selected one can be seen.
+    fn draw<W: Write>(
+        out: &mut W,
+        prompt: &str,
+        query: &str,
+        items: &[String],
+        found: &[usize],
+        selected: usize,
+    ) -> std::io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let (width, rows) = (width as usize, height.saturating_sub(2) as usize);
+        let offset = (selected + 1).saturating_sub(rows);
+        queue!(
+            out,
+            Clear(ClearType::All),
+            MoveTo(0, 0),
+            Print(format!("{}{}", prompt, query)),
+            MoveTo(0, 1),
+            Print(format!("  {}/{}", found.len(), items.len())),
+        )?;
+        for (row, i) in found.iter().enumerate().skip(offset).take(rows) {
+            let text: String = items[*i].chars().take(width.saturating_sub(2)).collect();
+            queue!(out, MoveTo(0, (row - offset + 2) as u16))?;
+            match row == selected {
+                true => queue!(
+                    out,
+                    SetAttribute(Attribute::Reverse),
+                    Print(format!("> {}", text)),
+                    SetAttribute(Attribute::Reset),
+                )?,
+                false => queue!(out, Print(format!("  {}", text)))?,
+            }
+        }
+        out.flush()
+    }
+}
+
+/// Lets the user pick a contact when they didn't give an id. Returns None
+/// if they didn't pick one.
+fn pick_record(recs: &Records, prompt: &str) -> Result<Option<RecordId>, AppError> {
+    if !picker::available() {
+        return Err(AppError::NoId);
+    }
+    let records = recs.sorted();
+    Ok(pick_from(&records, prompt)?.map(|i| records[i].id))
+}
+
+/// Shows the records in the picker, and returns the index of the one that
+/// was picked.
+fn pick_from(records: &[&Record], prompt: &str) -> Result<Option<usize>, AppError> {
+    let items: Vec<_> = records
+        .iter()
+        .map(|record| {
+            let mut item = format!("{}  {}", record.id, record.name);
+            for value in record.emails.iter().chain(record.phone.iter()) {
+                item.push_str("  ");
+                item.push_str(value);
+            }
+            item
+        })
+        .collect();
+    Ok(picker::pick(prompt, &items)?)
+}
+
+/// Finds the one contact named by an id or a query, for commands like
+/// "email" that act on a single contact. When a query matches several
+/// contacts, the user picks one of them, so this returns None if they
+/// didn't pick any.
+fn find_one<'a>(
+    recs: &'a Records,
+    target: &str,
+    prompt: &str,
+) -> Result<Option<&'a Record>, AppError> {
+    if let Ok(id) = target.parse::<RecordId>() {
+        return recs.get(id).map(Some).ok_or(AppError::NotFound(id));
+    }
+    let found = recs.search(target);
+    match found.as_slice() {
+        [] => Err(AppError::NoMatch(target.to_owned())),
+        [record] => Ok(Some(record)),
+        _ if picker::available() => Ok(pick_from(&found, prompt)?.map(|i| found[i])),
+        _ => Err(AppError::AmbiguousQuery(target.to_owned(), found.len())),
+    }
+}
+
+/// Returns the "mailto:" link for an email address. The "@" is left as it
+/// is, but anything else that isn't plain, like a "+", is percent-encoded.
+fn mailto_url(address: &str) -> String {
+    match address.rsplit_once('@') {
+        Some((user, domain)) => {
+            format!("mailto:{}@{}", percent_encode(user), percent_encode(domain))
+        }
+        None => format!("mailto:{}", percent_encode(address)),
+    }
+}
+
+/// Opens a link, such as a "mailto:" link, with the program the system
+/// uses for it.
+fn open_url(url: &str) -> std::io::Result<std::process::ExitStatus> {
+    let mut command = if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else if cfg!(windows) {
+        // The empty argument is the window title, which "start" would
+        // otherwise take from the link.
+        let mut command = std::process::Command::new("cmd");
+        command.args(["/C", "start", ""]);
+        command
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    command.arg(url).status()
+}
+
+/// The fields that "copy" can put on the clipboard.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CopyField {
+    Name,
+    Email,
+    Phone,
+    Address,
+    /// The name, job, email addresses, phone number and address, one on each
+    /// line, like on a business card.
+    All,
+}
+
+impl CopyField {
+    /// The name of the field, as used in messages.
+    fn as_str(self) -> &'static str {
+        match self {
+            CopyField::Name => "name",
+            CopyField::Email => "email address",
+            CopyField::Phone => "phone number",
+            CopyField::Address => "address",
+            CopyField::All => "details",
+        }
+    }
+
+    /// Returns the text to copy for a record, if it has the field. Like
+    /// "email", only the first of several email addresses is copied.
+    fn value(self, record: &Record) -> Option<String> {
+        match self {
+            CopyField::Name => Some(record.name.clone()),
+            CopyField::Email => record.emails.first().cloned(),
+            CopyField::Phone => record.phone.clone(),
+            CopyField::Address => record.address.clone(),
+            CopyField::All => {
+                let mut lines = vec![record.name.clone()];
+                let job: Vec<_> = record
+                    .title
+                    .iter()
+                    .chain(&record.company)
+                    .cloned()
+                    .collect();
+                if !job.is_empty() {
+                    lines.push(job.join(", "));
+                }
+                lines.extend(record.emails.iter().cloned());
+                lines.extend(record.phone.iter().cloned());
+                lines.extend(record.address.iter().cloned());
+                Some(lines.join("\n"))
+            }
+        }
+    }
+}
+
+impl FromStr for CopyField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(Self::Name),
+            "email" => Ok(Self::Email),
+            "phone" => Ok(Self::Phone),
+            "address" => Ok(Self::Address),
+            "all" => Ok(Self::All),
+            _ => Err(format!(
+                "unknown field: {} (expected name, email, phone, address, or all)",
+                s
+            )),
+        }
+    }
+}
+
+/// Puts text on the system clipboard. With "wait", on Linux, this doesn't
+/// return until something else is copied.
+fn copy_to_clipboard(text: String, wait: bool) -> Result<(), AppError> {
+    let mut clipboard = arboard::Clipboard::new()?;
+    // On Linux the text is only on the clipboard while a program holds it.
+    // Waiting keeps p2 running to hold it until another program takes the
+    // clipboard over.
+    #[cfg(target_os = "linux")]
+    if wait {
+        use arboard::SetExtLinux;
+        eprintln!("keeping the text on the clipboard until something else is copied");
+        clipboard.set().wait().text(text)?;
+        return Ok(());
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = wait;
+    clipboard.set_text(text)?;
+    // Otherwise, dropping the clipboard hands the text to the clipboard
+    // manager. Without one, the text is gone as soon as p2 exits, and
+    // there is no way to tell whether one is running.
+    #[cfg(target_os = "linux")]
+    eprintln!(
+        "note: without a clipboard manager, the text can only be pasted until p2 exits (use --wait to keep it)"
+    );
+    drop(clipboard);
+    Ok(())
+}
+
+/// Prints a status message, such as what a command changed. These go to
+/// stdout, unless the records themselves are being written there.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if output::status_on_stderr() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Rows of text that are printed with each column lined up.
+struct TextTable {
+    /// The column names, which are printed first unless they were left out.
+    header: Option<Vec<String>>,
+    rows: Vec<Vec<String>>,
+}
+
+impl TextTable {
+    /// Creates a table, starting with a row of column names if "header" is
+    /// true.
+    fn new(columns: &[&str], header: bool) -> Self {
+        let header = header.then(|| columns.iter().map(|column| column.to_string()).collect());
+        Self {
+            header,
+            rows: vec![],
+        }
+    }
+
+    /// Adds a row to the end of the table.
+    fn add_row(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+
+    /// Returns the width of each column, which is the width of its widest
+    /// value, up to "MAX_COLUMN_WIDTH".
+    fn widths(&self) -> Vec<usize> {
+        let mut widths = vec![];
+        for row in self.header.iter().chain(self.rows.iter()) {
+            widths.resize(widths.len().max(row.len()), 0);
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(value.chars().count().min(MAX_COLUMN_WIDTH));
+            }
+        }
+        widths
+    }
+
+    /// Prints every row of the table. Any text matching "highlight" is
+    /// highlighted.
+    fn print(&self, highlight: Option<&Regex>) {
+        let widths = self.widths();
+        if let Some(header) = &self.header {
+            println!(
+                "{}",
+                format_row(header, &widths, |_, text| output::header(text))
+            );
+        }
+        for row in self.rows.iter() {
+            println!("{}", format_row(row, &widths, record_style(highlight)));
+        }
+    }
+}
+
+/// Returns the colors for the cells of a record's row: the id is colored,
+/// and so is any text that matches "highlight".
+fn record_style(highlight: Option<&Regex>) -> impl Fn(usize, &str) -> String + '_ {
+    move |column, text| match column {
+        0 => output::id(text),
+        _ => output::highlight(text, highlight),
+    }
+}
+
+/// Formats a row of a table, padding each value to the width of its column
+/// and separating the columns with two spaces. Values that are too wide are
+/// truncated. "style" is called with the column number and text of each
+/// value, and returns it with any colors added.
+fn format_row<F>(row: &[String], widths: &[usize], style: F) -> String
+where
+    F: Fn(usize, &str) -> String,
+{
+    // Empty values at the end of the row are left out, so that the line
+    // doesn't end with spaces.
+    let len = row
+        .iter()
+        .rposition(|value| !value.is_empty())
+        .map_or(0, |i| i + 1);
+    let mut line = String::new();
+    for (column, (value, width)) in row[..len].iter().zip(widths).enumerate() {
+        if column > 0 {
+            line.push_str("  ");
+        }
+        // The padding is worked out before adding colors, since colors
+        // add characters that don't take up any space.
+        let text = truncate(value, *width);
+        line.push_str(&style(column, &text));
+        if column + 1 < len {
+            line.push_str(&" ".repeat(width.saturating_sub(text.chars().count())));
+        }
+    }
+    line
+}
+
+/// Cuts a value down to at most "width" characters. An ellipsis replaces
+/// the last character that fits, to show that something was cut off.
+fn truncate(value: &str, width: usize) -> String {
+    // Characters are counted instead of bytes, since a single character
+    // may take up several bytes.
+    if value.chars().count() <= width {
+        return value.to_string();
+    }
+    let mut truncated: String = value.chars().take(width.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+/// Returns the values of a record shown in each of the "TABLE_COLUMNS".
+fn table_row(record: &Record) -> Vec<String> {
+    vec![
+        record.id.to_string(),
+        record.name.clone(),
+        record.emails.join(", "),
+        record.phone.clone().unwrap_or_default(),
+        record.tags.join(", "),
+    ]
+}
+
+/// A function that returns the value of one of a record's fields as text.
+type FieldValue = fn(&Record) -> String;
+
+/// The placeholders that can be used in a template, along with functions
+/// that return the value of each one for a record. "email" is the first
+/// email address, while "emails" is every one of them.
+const TEMPLATE_FIELDS: [(&str, FieldValue); 16] = [
+    ("id", |record| record.id.to_string()),
+    ("name", |record| record.name.clone()),
+    ("nickname", |record| {
+        record.nickname.clone().unwrap_or_default()
+    }),
+    ("email", |record| {
+        record.emails.first().cloned().unwrap_or_default()
+    }),
+    ("emails", |record| record.emails.join(", ")),
+    ("phone", |record| record.phone.clone().unwrap_or_default()),
+    ("tags", |record| record.tags.join(", ")),
+    ("address", |record| {
+        record.address.clone().unwrap_or_default()
+    }),
+    ("birthday", |record| {
+        record
+            .birthday
+            .map(|date| date.to_string())
+            .unwrap_or_default()
+    }),
+    ("notes", |record| record.notes.clone().unwrap_or_default()),
+    ("avatar", |record| record.avatar.clone().unwrap_or_default()),
+    ("company", |record| {
+        record.company.clone().unwrap_or_default()
+    }),
+    ("title", |record| record.title.clone().unwrap_or_default()),
+    ("last_contacted", |record| {
+        record
+            .last_contacted
+            .map(|date| date.to_string())
+            .unwrap_or_default()
+    }),
+    ("created_at", |record| format_timestamp(record.created_at)),
+    ("updated_at", |record| format_timestamp(record.updated_at)),
+];
+
+/// A piece of a template: either text that is printed as-is, or the
+/// function for one of the "TEMPLATE_FIELDS".
+#[derive(Debug, Clone)]
+enum TemplatePart {
+    Text(String),
+    Field(FieldValue),
+}
+
+/// A custom format for printing records, such as "{name} <{email}>". Each
+/// placeholder in braces is replaced with the value of that field. "{{" and
+/// "}}" are used for braces that aren't part of a placeholder.
+#[derive(Debug, Clone)]
+struct Template {
+    parts: Vec<TemplatePart>,
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    /// Templates are checked as soon as they are parsed, so a misspelled
+    /// placeholder is reported before anything is printed.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    // "by_ref" lets us take the placeholder's name from the
+                    // iterator without giving up ownership of it.
+                    let mut name = String::new();
+                    let mut closed = false;
+                    for c in chars.by_ref() {
+                        if c == '}' {
+                            closed = true;
+                            break;
+                        }
+                        name.push(c);
+                    }
+                    if !closed {
+                        return Err(format!(
+                            "unterminated placeholder {{{} in template (use {{{{ for a literal {{)",
+                            name
+                        ));
+                    }
+                    let field = template_field(&name)?;
+                    if !text.is_empty() {
+                        parts.push(TemplatePart::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(TemplatePart::Field(field));
+                }
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(TemplatePart::Text(text));
+        }
+        Ok(Self { parts })
+    }
+}
+
+/// Finds the function for a placeholder of a template.
+fn template_field(name: &str) -> Result<FieldValue, String> {
+    TEMPLATE_FIELDS
+        .iter()
+        .find(|(field, _)| *field == name)
+        .map(|(_, value)| *value)
+        .ok_or_else(|| {
+            let names: Vec<_> = TEMPLATE_FIELDS.iter().map(|(n, _)| *n).collect();
+            format!(
+                "unknown placeholder {{{}}} in template (expected one of: {})",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+impl Template {
+    /// Parses a document for "merge", such as a letter. Its placeholders
+    /// are in double braces, like "Dear {{name}},", so that any single
+    /// braces in the document are left as they are.
+    fn from_document(document: &str) -> Result<Self, String> {
+        let mut parts = vec![];
+        let mut rest = document;
+        while let Some(start) = rest.find("{{") {
+            let end = rest[start..]
+                .find("}}")
+                .map(|end| start + end)
+                .ok_or_else(|| "a placeholder in the template is missing its \"}}\"".to_string())?;
+            // Spaces are allowed inside the braces, as in "{{ name }}".
+            let field = template_field(rest[start + 2..end].trim())?;
+            if start > 0 {
+                parts.push(TemplatePart::Text(rest[..start].to_string()));
+            }
+            parts.push(TemplatePart::Field(field));
+            rest = &rest[end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Text(rest.to_string()));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Fills in the template with the fields of a record.
+    fn render(&self, record: &Record) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Text(text) => text.clone(),
+                TemplatePart::Field(value) => value(record),
+            })
+            .collect()
+    }
+}
+
+/// A field that has a different value in two versions of a record.
+#[derive(Debug, PartialEq, Serialize)]
+struct FieldChange {
+    field: String,
+    before: String,
+    after: String,
+}
+
+/// A record that is in both files, but with different values.
+#[derive(Debug, Serialize)]
+struct ChangedRecord {
+    id: RecordId,
+    name: String,
+    fields: Vec<FieldChange>,
+}
+
+/// The differences between two contact files. Records are matched up by
+/// their ids.
+#[derive(Debug, Default, Serialize)]
+struct FileDiff {
+    added: Vec<Record>,
+    removed: Vec<Record>,
+    changed: Vec<ChangedRecord>,
+}
+
+/// Lists the fields that differ between two versions of a record. Fields
+/// are compared as they are printed by templates, and unknown columns are
+/// compared as well.
+fn diff_fields(before: &Record, after: &Record) -> Vec<FieldChange> {
+    let mut fields: Vec<(String, String, String)> = TEMPLATE_FIELDS
+        .iter()
+        // "email" is only the first of the "emails".
+        .filter(|(field, _)| *field != "email")
+        .map(|(field, value)| (field.to_string(), value(before), value(after)))
+        .collect();
+    let columns: BTreeSet<&String> = before.extra.keys().chain(after.extra.keys()).collect();
+    for column in columns {
+        let value = |record: &Record| record.extra.get(column).cloned().unwrap_or_default();
+        fields.push((column.clone(), value(before), value(after)));
+    }
+    fields
+        .into_iter()
+        .filter(|(_, before, after)| before != after)
+        .map(|(field, before, after)| FieldChange {
+            field,
+            before,
+            after,
+        })
+        .collect()
+}
+
+/// Compares the records of two files.
+fn diff_files(before: &Records, after: &Records) -> FileDiff {
+    let mut diff = FileDiff::default();
+    for change in diff_records(before, after) {
+        match (change.before, change.after) {
+            (None, Some(record)) => diff.added.push(record),
+            (Some(record), None) => diff.removed.push(record),
+            (Some(old), Some(new)) => diff.changed.push(ChangedRecord {
+                id: change.id,
+                fields: diff_fields(&old, &new),
+                name: new.name,
+            }),
+            (None, None) => (),
+        }
+    }
+    diff
+}
+
+/// Prints the differences between two files: "+" for added records, "-"
+/// for removed ones, and "~" for changed ones, followed by their changes.
+fn print_diff(diff: &FileDiff) {
+    for record in diff.added.iter() {
+        println!("+ {} {}", output::id(&record.id.to_string()), record.name);
+    }
+    for record in diff.removed.iter() {
+        println!("- {} {}", output::id(&record.id.to_string()), record.name);
+    }
+    for record in diff.changed.iter() {
+        println!("~ {} {}", output::id(&record.id.to_string()), record.name);
+        for change in record.fields.iter() {
+            println!(
+                "    {}: {:?} -> {:?}",
+                change.field, change.before, change.after
+            );
+        }
+    }
+    println!(
+        "{} added, {} removed, {} changed",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len()
+    );
+}
+
+/// Prints every field of a record, one per line. Fields that are empty are
+/// still shown, so that every record looks the same.
+fn print_details(record: &Record, recs: &Records) {
+    // Times are saved in UTC, but shown in the local time zone.
+    let time = |time: Option<DateTime<Utc>>| {
+        time.map(|time| {
+            let time = time.with_timezone(&Local);
+            time.format("%Y-%m-%d %H:%M:%S").to_string()
+        })
+    };
+    let tags = match record.tags.is_empty() {
+        true => vec![],
+        false => vec![record.tags.join(", ")],
+    };
+    let mut fields = vec![
+        ("id", vec![output::id(&record.id.to_string())]),
+        ("name", vec![record.name.clone()]),
+        ("nickname", record.nickname.iter().cloned().collect()),
+        ("company", record.company.iter().cloned().collect()),
+        ("title", record.title.iter().cloned().collect()),
+        // Each email address goes on its own line.
+        ("email", record.emails.clone()),
+        ("phone", record.phone.iter().cloned().collect()),
+        ("tags", tags),
+        ("address", record.address.iter().cloned().collect()),
+        (
+            "birthday",
+            record.birthday.iter().map(|d| d.to_string()).collect(),
+        ),
+        ("notes", record.notes.iter().cloned().collect()),
+        ("related", related(record, recs)),
+        ("avatar", record.avatar.iter().cloned().collect()),
+        (
+            "last contacted",
+            record
+                .last_contacted
+                .iter()
+                .map(|d| d.to_string())
+                .collect(),
+        ),
+        ("created", time(record.created_at).into_iter().collect()),
+        ("updated", time(record.updated_at).into_iter().collect()),
+    ];
+    // Columns that were added by someone else are shown after ours, sorted
+    // by name so that they always come out in the same order.
+    let mut extra: Vec<_> = record.extra.iter().collect();
+    extra.sort();
+    for (name, value) in extra {
+        fields.push((name.as_str(), vec![value.clone()]));
+    }
+    let width = fields.iter().map(|(name, _)| name.len()).max().unwrap_or(0) + 1;
+    for (name, values) in fields {
+        let label = format!("{:<width$}", format!("{}:", name), width = width);
+        match values.split_first() {
+            Some((first, rest)) => {
+                println!("{} {}", output::header(&label), first);
+                for value in rest {
+                    println!("{:width$} {}", "", value, width = width);
+                }
+            }
+            None => println!("{}", output::header(label.trim_end())),
+        }
+    }
+}
+
+/// Describes the links between a record and other records, both the ones it
+/// has and the ones that other records have to it, such as "spouse: Ann (3)"
+/// and "manager of: Bob (7)".
+fn related(record: &Record, recs: &Records) -> Vec<String> {
+    let name = |id: RecordId| match recs.get(id) {
+        Some(other) => format!("{} ({})", other.name, output::id(&id.to_string())),
+        None => format!("{} (not found)", output::id(&id.to_string())),
+    };
+    let mut lines: Vec<_> = record
+        .relations
+        .iter()
+        .map(|relation| format!("{}: {}", relation.kind, name(relation.id)))
+        .collect();
+    for (other, kind) in recs.linked_from(record.id) {
+        lines.push(format!("{} of: {}", kind, name(other.id)));
+    }
+    lines
+}
+
+/// The ways that terminals can be sent an image to draw.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ImageProtocol {
+    /// The graphics protocol of kitty, which Ghostty supports too.
+    Kitty,
+    /// The inline images of iTerm2, which WezTerm supports too.
+    Iterm,
+}
+
+impl ImageProtocol {
+    /// Guesses which protocol the terminal supports from the environment
+    /// variables that it sets. Most terminals don't support either of them,
+    /// and there is no image to draw when stdout isn't a terminal.
+    fn detect() -> Option<Self> {
+        use std::io::IsTerminal;
+        if !std::io::stdout().is_terminal() {
+            return None;
+        }
+        let var = |name| std::env::var(name).unwrap_or_default();
+        if std::env::var_os("KITTY_WINDOW_ID").is_some()
+            || var("TERM").contains("kitty")
+            || var("TERM").contains("ghostty")
+        {
+            return Some(Self::Kitty);
+        }
+        match var("TERM_PROGRAM").as_str() {
+            "iTerm.app" | "WezTerm" => Some(Self::Iterm),
+            _ => None,
+        }
+    }
+
+    /// Draws an image below the cursor, 20 columns wide.
+    fn draw(self, image: &[u8]) -> Result<(), AppError> {
+        use base64::Engine;
+        let engine = base64::engine::general_purpose::STANDARD;
+        let mut out = std::io::stdout().lock();
+        match self {
+            ImageProtocol::Iterm => {
+                let data = engine.encode(image);
+                write!(out, "\x1b]1337;File=inline=1;width=20:{}\x07", data)?;
+            }
+            ImageProtocol::Kitty => {
+                // kitty only reads PNG images itself, so anything else is
+                // converted first. The image is sent in pieces of at most
+                // 4096 bytes, and "m=1" means that more pieces follow.
+                let mut png = std::io::Cursor::new(vec![]);
+                image::load_from_memory(image)?.write_to(&mut png, image::ImageFormat::Png)?;
+                let data = engine.encode(png.get_ref());
+                let chunks: Vec<_> = data.as_bytes().chunks(4096).collect();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let more = (i + 1 < chunks.len()) as u8;
+                    let chunk = String::from_utf8_lossy(chunk);
+                    match i {
+                        0 => write!(out, "\x1b_Gf=100,a=T,c=20,m={};{}\x1b\\", more, chunk)?,
+                        _ => write!(out, "\x1b_Gm={};{}\x1b\\", more, chunk)?,
+                    }
+                }
+            }
+        }
+        writeln!(out)?;
+        Ok(())
+    }
+}
+
+/// Reads the picture of a contact, from a file or by downloading it.
+fn read_avatar(avatar: &str) -> Result<Vec<u8>, AppError> {
+    if avatar.starts_with("http://") || avatar.starts_with("https://") {
+        let mut image = vec![];
+        ureq::get(avatar)
+            .call()
+            .map_err(|e| AppError::Download(avatar.to_string(), e.to_string()))?
+            .into_reader()
+            .read_to_end(&mut image)?;
+        return Ok(image);
+    }
+    Ok(fs::read(avatar.strip_prefix("file://").unwrap_or(avatar))?)
+}
+
+/// Returns the name of the document that "merge" writes for a contact, such
+/// as "7-ann-lee.txt". The id keeps contacts with the same name apart, and
+/// "extension" is the one the template has.
+fn merge_file_name(record: &Record, extension: Option<&str>) -> String {
+    let mut name = record.id.to_string();
+    let lowercase = record.name.to_lowercase();
+    for word in lowercase.split(|c: char| !c.is_alphanumeric()) {
+        if !word.is_empty() {
+            name.push('-');
+            name.push_str(word);
+        }
+    }
+    match extension {
+        Some(extension) => format!("{}.{}", name, extension),
+        None => name,
+    }
+}
+
+/// Prints each record using a template. Nothing is printed when there are
+/// no records, since the output is usually meant for another program.
+fn print_template(records: &[&Record], template: &Template) {
+    for record in records {
+        println!("{}", template.render(record));
+    }
+}
+
+/// Returns whether a record matches the query given with "--where". Every
+/// record matches when there isn't one.
+fn matches_filter(filter: &Option<Query>, record: &Record) -> bool {
+    filter.as_ref().is_none_or(|query| query.matches(record))
+}
+
+/// Returns whether it has been more than "days" days since a record was last
+/// contacted. Records that were never contacted have been waiting longest
+/// of all. Every record matches when no number of days was given.
+fn is_stale(record: &Record, days: Option<u32>, today: NaiveDate) -> bool {
+    match (days, record.last_contacted) {
+        (None, _) | (Some(_), None) => true,
+        (Some(days), Some(date)) => (today - date).num_days() > i64::from(days),
+    }
+}
+
+/// Parses the value of "list --stale", such as "90d", and returns it in
+/// days. A month is counted as 30 days and a year as 365. A number on its
+/// own is a number of days.
+fn parse_age(value: &str) -> Result<u32, String> {
+    let error = || {
+        format!(
+            "invalid age: {} (expected days, weeks, months or years, such as 90d, 6w, 3m or 1y)",
+            value
+        )
+    };
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let number: u32 = number.parse().map_err(|_| error())?;
+    let days = match unit {
+        "" | "d" => 1,
+        "w" => 7,
+        "m" => 30,
+        "y" => 365,
+        _ => return Err(error()),
+    };
+    number.checked_mul(days).ok_or_else(error)
+}
+
+/// Prints the records in the requested format. Tables start with a row of
+/// column names, unless "header" is false.
+fn print_records(records: &[&Record], format: OutputFormat, header: bool) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Text => {
+            // The message would get in the way of other programs reading
+            // the output, which is why the header was turned off.
+            if records.is_empty() && header {
+                println!("no records found");
+                return Ok(());
+            }
+            let mut table = TextTable::new(&TABLE_COLUMNS, header);
+            for record in records {
+                table.add_row(table_row(record));
+            }
+            table.print(None);
+        }
+        // An empty JSON array is printed when there are no records, so
+        // tools reading the output always receive valid JSON.
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(records)?),
+    }
+    Ok(())
+}
+
+/// The state of "p2 shell". The records are loaded once, and every command
+/// changes them in memory until they are saved.
+struct Shell<'a> {
+    storage: &'a dyn Storage,
+    data_file: &'a Path,
+    passphrase: Option<&'a str>,
+    strict: bool,
+    recs: Records,
+    /// The records that were removed since the last save. They are put in
+    /// the trash when the changes are saved.
+    removed: Vec<Record>,
+    /// Whether anything changed since the last save.
+    changed: bool,
+}
+
+impl<'a> Shell<'a> {
+    fn new(
+        storage: &'a dyn Storage,
+        data_file: &'a Path,
+        passphrase: Option<&'a str>,
+        strict: bool,
+    ) -> Result<Self, AppError> {
+        Ok(Self {
+            storage,
+            data_file,
+            passphrase,
+            strict,
+            recs: storage.load()?,
+            removed: vec![],
+            changed: false,
+        })
+    }
+
+    /// Returns the file that keeps the lines typed into the shell. It is
+    /// next to the data file, so that every data file and profile has its
+    /// own history.
+    fn history_file(&self) -> PathBuf {
+        sidecar_path(self.data_file, "shell_history")
+    }
+
+    /// Reads and runs commands until "exit". A command that fails only
+    /// prints its error, so that a typo doesn't lose the unsaved changes.
+    /// The lines that were typed are kept in "history_file", so they can be
+    /// brought back with the arrow keys the next time too.
+    fn run(&mut self) -> Result<(), AppError> {
+        use rustyline::error::ReadlineError;
+
+        let mut editor = rustyline::DefaultEditor::new()?;
+        let history = self.history_file();
+        // There is no history file until the shell has been used once.
+        let _ = editor.load_history(&history);
+        println!(
+            "loaded {} records from {} (type help to see the commands)",
+            self.recs.len(),
+            self.data_file.display()
+        );
+        loop {
+            let line = match editor.readline("p2> ") {
+                Ok(line) => line,
+                // Ctrl-C only clears the line, while Ctrl-D is the same as
+                // typing "exit".
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => "exit".to_string(),
+                Err(e) => return Err(e.into()),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            editor.add_history_entry(line.as_str())?;
+            let words = match shell_words::split(&line) {
+                Ok(words) => words,
+                Err(e) => {
+                    eprintln!("{}", output::error(&e.to_string()));
+                    continue;
+                }
+            };
+            // "help" is also returned as an error, with the help text as
+            // its message.
+            let command = match ShellCommand::from_iter_safe(words) {
+                Ok(command) => command,
+                Err(e) => {
+                    println!("{}", e.message);
+                    continue;
+                }
+            };
+            match command {
+                ShellCommand::Exit {} if self.changed => {
+                    let answer = editor.readline("save the changes before leaving? [y/N] ")?;
+                    if matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                        self.save()?;
+                    }
+                    break;
+                }
+                ShellCommand::Exit {} => break,
+                command => {
+                    if let Err(e) = self.run_command(command) {
+                        eprintln!("{}", output::error(&e.to_string()));
+                    }
+                }
+            }
+        }
+        if let Err(e) = editor.save_history(&history) {
+            eprintln!("warning: couldn't save the shell history: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Runs a single command other than "exit".
+    fn run_command(&mut self, command: ShellCommand) -> Result<(), AppError> {
+        match command {
+            ShellCommand::Add(AddArgs {
+                batch: Some(batch),
+                tag,
+                ..
+            }) => {
+                let (records, bad_lines) = read_batch(open_input(&batch)?)?;
+                for bad_line in bad_lines.iter() {
+                    info!("skipped a contact: {}", bad_line);
+                }
+                if self.strict && !bad_lines.is_empty() {
+                    return Err(AppError::Malformed(bad_lines));
+                }
+                for mut record in records.iter().cloned() {
+                    record.id = self.recs.next_id();
+                    record.tags = tag.clone();
+                    self.recs.add(record);
+                }
+                self.changed = true;
+                println!(
+                    "added {} records, skipped {} lines",
+                    records.len(),
+                    bad_lines.len()
+                );
+            }
+            ShellCommand::Add(add) => {
+                let mut record = add.into_record();
+                record.id = self.recs.next_id();
+                let id = record.id;
+                self.recs.add(record);
+                self.changed = true;
+                println!("added record {}", output::id(&id.to_string()));
+            }
+            ShellCommand::Edit(edit) => {
+                if edit.apply(&mut self.recs)? {
+                    self.changed = true;
+                }
+            }
+            ShellCommand::List { filter } => {
+                let mut records = self.recs.sorted();
+                records.retain(|rec| matches_filter(&filter, rec));
+                print_records(&records, OutputFormat::Text, true)?;
+            }
+            ShellCommand::Search { query } => {
+                print_records(&self.recs.search(&query), OutputFormat::Text, true)?;
+            }
+            ShellCommand::Show { id } => {
+                let record = self.recs.get(id).ok_or(AppError::NotFound(id))?;
+                print_details(record, &self.recs);
+            }
+            ShellCommand::Remove { id } => {
+                let id = match id {
+                    Some(id) => id,
+                    None => match pick_record(&self.recs, "remove: ")? {
+                        Some(id) => id,
+                        None => return Ok(()),
+                    },
+                };
+                let record = self.recs.remove(id).ok_or(AppError::NotFound(id))?;
+                self.removed.push(record);
+                self.changed = true;
+                println!("removed record {}", output::id(&id.to_string()));
+            }
+            ShellCommand::Save {} => {
+                self.save()?;
+                println!("saved {} records", self.recs.len());
+            }
+            ShellCommand::Exit {} => (),
+        }
+        Ok(())
+    }
+
+    /// Writes the changes to the data file. Removed records are put in the
+    /// trash first, just like "p2 remove" does, so they can't be lost if
+    /// saving fails.
+    fn save(&mut self) -> Result<(), AppError> {
+        if !self.removed.is_empty() {
+            let removed = self.removed.drain(..);
+            move_to_trash(self.data_file, self.passphrase, self.strict, removed)?;
+        }
+        self.storage.save(&self.recs)?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+/// Prints search results in the requested format, including which fields
+/// of each record matched the search. Text matching "highlight" is
+/// highlighted when printing in color.
+fn print_matches(
+    matches: &[SearchMatch],
+    format: OutputFormat,
+    header: bool,
+    highlight: Option<&Regex>,
+) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Text => {
+            if matches.is_empty() && header {
+                println!("no records found");
+                return Ok(());
+            }
+            let mut columns = TABLE_COLUMNS.to_vec();
+            columns.push("MATCHED");
+            let mut table = TextTable::new(&columns, header);
+            for found in matches {
+                let fields: Vec<_> = found.matched.iter().map(|field| field.as_str()).collect();
+                let mut row = table_row(found.record);
+                row.push(fields.join(", "));
+                table.add_row(row);
+            }
+            table.print(highlight);
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(matches)?),
+    }
+    Ok(())
+}
+
+/// The orders that records can be listed in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SortKey {
+    Id,
+    Name,
+    /// The first email address. Records without one come last.
+    Email,
+    Created,
+    Updated,
+    /// The day the contact was last contacted. Records that never were
+    /// come first.
+    Contacted,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(Self::Id),
+            "name" => Ok(Self::Name),
+            "email" => Ok(Self::Email),
+            "created" => Ok(Self::Created),
+            "updated" => Ok(Self::Updated),
+            "contacted" => Ok(Self::Contacted),
+            _ => Err(format!(
+                "unknown sort order: {} (expected id, name, email, created, updated, or contacted)",
+                s
+            )),
+        }
+    }
+}
+
+/// The fields that "list --group-by" can group contacts by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GroupKey {
+    Company,
+    Title,
+}
+
+impl GroupKey {
+    /// The name of the field, as used on the command line.
+    fn as_str(self) -> &'static str {
+        match self {
+            GroupKey::Company => "company",
+            GroupKey::Title => "title",
+        }
+    }
+
+    /// Returns the value of the field for a record, if it has one.
+    fn value(self, record: &Record) -> Option<&str> {
+        let value = match self {
+            GroupKey::Company => record.company.as_deref(),
+            GroupKey::Title => record.title.as_deref(),
+        };
+        value
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+    }
+}
+
+impl FromStr for GroupKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "company" => Ok(Self::Company),
+            "title" => Ok(Self::Title),
+            _ => Err(format!("unknown field: {} (expected company or title)", s)),
+        }
+    }
+}
+
+/// Contacts that have the same value for the field they were grouped by.
+/// The contacts without a value are in a group without a name.
+#[derive(Debug, Serialize)]
+struct RecordGroup<'a> {
+    name: Option<String>,
+    records: Vec<&'a Record>,
+}
+
+/// Splits records into groups by the value of a field. The groups are in
+/// order by name, ignoring case, and the contacts without a value come
+/// last. Each group keeps its records in the order they were given.
+fn group_records(records: Vec<&Record>, key: GroupKey) -> Vec<RecordGroup<'_>> {
+    let mut groups: BTreeMap<(bool, String), RecordGroup> = BTreeMap::new();
+    for record in records {
+        let name = key.value(record);
+        // Names that only differ in case, like "ACME" and "Acme", end up in
+        // the same group, which is named after the first one found.
+        let order = (name.is_none(), name.unwrap_or_default().to_lowercase());
+        groups
+            .entry(order)
+            .or_insert_with(|| RecordGroup {
+                name: name.map(|name| name.to_string()),
+                records: vec![],
+            })
+            .records
+            .push(record);
+    }
+    groups.into_values().collect()
+}
+
+/// Prints the groups made by "list --group-by" and "list --grouped". As
+/// text, each group's name is printed above its contacts, which are printed
+/// just like "list" prints them. "unnamed" is printed above the group
+/// without a name.
+fn print_groups(
+    groups: &[RecordGroup],
+    unnamed: &str,
+    format: OutputFormat,
+    template: Option<&Template>,
+    header: bool,
+) -> std::io::Result<()> {
+    if format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(groups)?);
+        return Ok(());
+    }
+    if groups.is_empty() && header {
+        println!("no records found");
+    }
+    for (i, group) in groups.iter().enumerate() {
+        // A blank line separates each group from the one before it.
+        if i > 0 {
+            println!();
+        }
+        let name = group.name.as_deref().unwrap_or(unnamed);
+        println!("{}", output::header(name));
+        match template {
+            Some(template) => print_template(&group.records, template),
+            None => print_records(&group.records, OutputFormat::Text, header)?,
+        }
+    }
+    Ok(())
+}
+
+/// Returns the language of the user's locale, such as "sv" for
+/// "sv_SE.UTF-8". The variables are checked in the same order that other
+/// programs check them when sorting text.
+fn locale_language() -> Option<String> {
+    ["LC_ALL", "LC_COLLATE", "LANG"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.is_empty())
+        .and_then(|value| parse_language(&value).ok())
+}
+
+/// Parses the value of "--locale", which is a language like "sv", or a
+/// whole locale like "sv_SE.UTF-8", and returns the language.
+fn parse_language(value: &str) -> Result<String, String> {
+    let language = value.split(['_', '-', '.', '@']).next().unwrap_or_default();
+    match language.len() {
+        2 | 3 if language.chars().all(|c| c.is_ascii_alphabetic()) => Ok(language.to_lowercase()),
+        _ => Err(format!(
+            "invalid locale: {} (expected a language such as sv or de_DE)",
+            value
+        )),
+    }
+}
+
+/// Returns the letter that a name is listed under by "list --grouped": its
+/// first letter in upper case, without any accents, so that "élodie" is
+/// listed under "E". Letters listed in "LOCALE_LETTERS" for the language
+/// keep their accents. Names that begin with a digit or have no letters
+/// at all return None.
+fn first_letter(name: &str, language: Option<&str>) -> Option<char> {
+    let c = name.chars().find(|c| c.is_alphanumeric())?;
+    if !c.is_alphabetic() {
+        return None;
+    }
+    let upper = c.to_uppercase().next().unwrap_or(c);
+    let own_letter = locale_letters(language)
+        .iter()
+        .any(|(letter, _)| *letter == upper);
+    match own_letter {
+        true => Some(upper),
+        false => strip_accents(&upper.to_string()).chars().next(),
+    }
+}
+
+/// Splits records into a group for each letter that their names begin
+/// with, using "first_letter". The records are sorted by name, and the
+/// groups by letter, with names that don't begin with a letter last.
+fn group_by_letter<'a>(
+    mut records: Vec<&'a Record>,
+    language: Option<&str>,
+) -> Vec<RecordGroup<'a>> {
+    records.sort_by_cached_key(|record| collation_key(&record.name, language));
+    // Letters from "LOCALE_LETTERS" are ordered by the letter they come
+    // after, and then by their place in the list.
+    let letters = locale_letters(language);
+    let order = |letter: char| match letters.iter().position(|(own, _)| *own == letter) {
+        Some(i) => (letters[i].1, i + 1),
+        None => (letter, 0),
+    };
+    let mut groups: BTreeMap<(bool, (char, usize)), RecordGroup> = BTreeMap::new();
+    for record in records {
+        let letter = first_letter(&record.name, language);
+        groups
+            .entry((letter.is_none(), letter.map_or(('#', 0), order)))
+            .or_insert_with(|| RecordGroup {
+                name: Some(letter.map_or("#".to_string(), String::from)),
+                records: vec![],
+            })
+            .records
+            .push(record);
+    }
+    groups.into_values().collect()
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    about = "project 2: contact manager",
+    after_help = "EXIT STATUS:
+    0  success
+    1  any other error
+    2  the data file couldn't be read or written
+    3  the data file has problems that can't be skipped
+    4  the record, backup or data file was not found
+    5  another p2 process is using the data file"
+)]
+struct Opt {
+    #[structopt(
+        short,
+        parse(from_os_str),
+        env = "CONTACTS_FILE",
+        help = "the contacts file, or - to read them from stdin and write any changes to stdout [default: ~/.local/share/p2/contacts.csv]"
+    )]
+    data_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        conflicts_with = "data-file",
+        help = "use the contacts file of a profile created with \"profile create\""
+    )]
+    profile: Option<String>,
+    #[structopt(
+        long,
+        default_value = "csv",
+        help = "csv, sqlite, sled or jsonl (the default for files ending in .jsonl)"
+    )]
+    backend: Backend,
+    #[structopt(
+        long,
+        help = "the data file is encrypted: ask for its passphrase, or read it from P2_PASSPHRASE"
+    )]
+    encrypt: bool,
+    #[structopt(
+        long,
+        default_value = "number",
+        help = "how to create ids for new contacts: number or uuid"
+    )]
+    id_scheme: IdScheme,
+    #[structopt(
+        long,
+        help = "allow the ids of removed contacts to be given to new contacts"
+    )]
+    reuse_ids: bool,
+    #[structopt(subcommand)]
+    cmd: Command,
+    #[structopt(
+        short,
+        parse(from_occurrences),
+        help = "log what is happening to stderr: -v for files, counts and timing, -vv for more detail, -vvv for every record"
+    )]
+    verbose: u8,
+    #[structopt(long, help = "wait for other p2 processes instead of failing")]
+    wait: bool,
+    #[structopt(
+        long,
+        help = concat!("show what ", dry_run_commands!(), " would change, without saving")
+    )]
+    dry_run: bool,
+    #[structopt(
+        long,
+        help = "commit the data file after every change, if it is in a git repository"
+    )]
+    git: bool,
+    #[structopt(
+        long,
+        conflicts_with = "read-only",
+        help = "create the data file if it doesn't exist yet, instead of failing"
+    )]
+    create: bool,
+    #[structopt(
+        long,
+        conflicts_with = "dry-run",
+        help = "never change the data file; commands that would change it fail instead"
+    )]
+    read_only: bool,
+    #[structopt(
+        short,
+        long,
+        help = "the most threads to use when searching (default: one per CPU)"
+    )]
+    jobs: Option<usize>,
+    #[structopt(
+        long,
+        help = "refuse to continue if the data file has lines that can't be read"
+    )]
+    strict: bool,
+    #[structopt(
+        long,
+        help = "only warn if the data file was changed outside of p2 instead of stopping"
+    )]
+    ignore_checksum: bool,
+    #[structopt(
+        long,
+        parse(try_from_str = parse_delimiter),
+        help = "the character between fields of the data file, such as ; or tab (default: found from the header line, or tab for .tsv files)"
+    )]
+    delimiter: Option<char>,
+    #[structopt(
+        long,
+        help = "the data file has no header line, so every line is a contact with its fields in the usual order"
+    )]
+    no_header: bool,
+    #[structopt(
+        long,
+        default_value = "keep-last",
+        help = "what to do when two contacts in the data file have the same id: error, keep-first, keep-last, or renumber"
+    )]
+    on_duplicate: DuplicatePolicy,
+    #[structopt(
+        long,
+        help = "back up the data file before any command that changes it"
+    )]
+    auto_backup: bool,
+    #[structopt(
+        long,
+        default_value = "text",
+        help = "how to print errors: text, or json objects with a code, message, line, and file"
+    )]
+    errors: OutputFormat,
+    #[structopt(
+        long,
+        default_value = "auto",
+        help = "when to print in color: auto, always, or never"
+    )]
+    color: output::ColorChoice,
+}
+
+/// Adds a new contact.
+#[derive(StructOpt, Debug)]
+struct AddArgs {
+    #[structopt(required_unless = "batch", parse(try_from_str = parse_name))]
+    name: Option<String>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &[
+            "name", "email", "phone", "address", "birthday", "notes", "avatar", "company", "title",
+            "nickname", "last-contacted"
+        ],
+        help = "add a contact for each \"name,email\" line of a file, or of stdin for \"-\""
+    )]
+    batch: Option<PathBuf>,
+    #[structopt(short, number_of_values = 1, help = "may be repeated")]
+    email: Vec<String>,
+    #[structopt(short, long)]
+    phone: Option<String>,
+    #[structopt(short, long, number_of_values = 1, help = "may be repeated")]
+    tag: Vec<String>,
+    #[structopt(long)]
+    address: Option<String>,
+    #[structopt(long, parse(try_from_str = parse_date), help = "YYYY-MM-DD")]
+    birthday: Option<NaiveDate>,
+    #[structopt(long)]
+    notes: Option<String>,
+    #[structopt(long, parse(from_str = parse_avatar), help = "the path or URL of a picture")]
+    avatar: Option<String>,
+    #[structopt(long, help = "the company or organization the contact works for")]
+    company: Option<String>,
+    #[structopt(long, help = "the job title, such as \"Sales Manager\"")]
+    title: Option<String>,
+    #[structopt(long, help = "what the contact likes to be called, such as \"Bob\"")]
+    nickname: Option<String>,
+    #[structopt(long, parse(try_from_str = parse_date), help = "YYYY-MM-DD")]
+    last_contacted: Option<NaiveDate>,
+}
+
+/// Changes the fields of a contact.
+#[derive(StructOpt, Debug)]
+struct EditArgs {
+    #[structopt(help = "the contact to change (picked from a list if left out)")]
+    id: Option<RecordId>,
+    #[structopt(
+        parse(try_from_str = parse_name),
+        help = "the new name (unchanged if left out)"
+    )]
+    name: Option<String>,
+    #[structopt(
+        short,
+        number_of_values = 1,
+        help = "replaces every email address; may be repeated"
+    )]
+    email: Vec<String>,
+    #[structopt(long, conflicts_with = "email", help = "remove every email address")]
+    clear_email: bool,
+    #[structopt(short, long)]
+    phone: Option<String>,
+    #[structopt(long, conflicts_with = "phone")]
+    clear_phone: bool,
+    #[structopt(long)]
+    address: Option<String>,
+    #[structopt(long, conflicts_with = "address")]
+    clear_address: bool,
+    #[structopt(long, parse(try_from_str = parse_date), help = "YYYY-MM-DD")]
+    birthday: Option<NaiveDate>,
+    #[structopt(long, conflicts_with = "birthday")]
+    clear_birthday: bool,
+    #[structopt(long)]
+    notes: Option<String>,
+    #[structopt(long, conflicts_with = "notes")]
+    clear_notes: bool,
+    #[structopt(long, parse(from_str = parse_avatar), help = "the path or URL of a picture")]
+    avatar: Option<String>,
+    #[structopt(long, conflicts_with = "avatar")]
+    clear_avatar: bool,
+    #[structopt(long, help = "the company or organization the contact works for")]
+    company: Option<String>,
+    #[structopt(long, conflicts_with = "company")]
+    clear_company: bool,
+    #[structopt(long, help = "the job title, such as \"Sales Manager\"")]
+    title: Option<String>,
+    #[structopt(long, conflicts_with = "title")]
+    clear_title: bool,
+    #[structopt(long, help = "what the contact likes to be called, such as \"Bob\"")]
+    nickname: Option<String>,
+    #[structopt(long, conflicts_with = "nickname")]
+    clear_nickname: bool,
+    #[structopt(long, parse(try_from_str = parse_date), help = "YYYY-MM-DD")]
+    last_contacted: Option<NaiveDate>,
+    #[structopt(long, conflicts_with = "last-contacted")]
+    clear_last_contacted: bool,
+}
+
+/// Reads the avatar option. Paths of local files are made absolute, so the
+/// picture can be found whichever directory p2 is run from.
+fn parse_avatar(avatar: &str) -> String {
+    match avatar.contains("://") {
+        true => avatar.to_string(),
+        false => std::path::absolute(avatar)
+            .map(|path| path.display().to_string())
+            .unwrap_or_else(|_| avatar.to_string()),
+    }
+}
+
+impl AddArgs {
+    /// Creates the new record. The id is filled in when it is added, and
+    /// the name is always given when there is no batch file, since
+    /// structopt requires it.
+    fn into_record(self) -> Record {
+        Record {
+            emails: self.email,
+            phone: self.phone,
+            tags: self.tag,
+            address: self.address,
+            birthday: self.birthday,
+            notes: self.notes,
+            avatar: self.avatar,
+            company: self.company,
+            title: self.title,
+            nickname: self.nickname,
+            last_contacted: self.last_contacted,
+            ..Record::new(RecordId::default(), self.name.unwrap_or_default())
+        }
+    }
+}
+
+impl EditArgs {
+    /// Changes the record. Fields that weren't given are left as they are.
+    /// The "clear" options can't be used along with a new value, so the
+    /// value is always empty (None) when clearing. Returns false if nothing
+    /// was changed, such as when no id was given and the user didn't pick a
+    /// contact either, so that there is nothing to save.
+    fn apply(self, recs: &mut Records) -> Result<bool, AppError> {
+        let id = match self.id {
+            Some(id) => id,
+            None => match pick_record(recs, "edit: ")? {
+                Some(id) => id,
+                None => return Ok(false),
+            },
+        };
+        // Only existing contacts can be edited, since a new contact would be
+        // left without a name when it isn't given.
+        let before = recs.get(id).ok_or(AppError::NotFound(id))?;
+        let mut record = before.clone();
+        if let Some(name) = self.name {
+            record.name = name;
+        }
+        if !self.email.is_empty() || self.clear_email {
+            record.emails = self.email;
+        }
+        if self.phone.is_some() || self.clear_phone {
+            record.phone = self.phone;
+        }
+        if self.address.is_some() || self.clear_address {
+            record.address = self.address;
+        }
+        if self.birthday.is_some() || self.clear_birthday {
+            record.birthday = self.birthday;
+        }
+        if self.notes.is_some() || self.clear_notes {
+            record.notes = self.notes;
+        }
+        if self.avatar.is_some() || self.clear_avatar {
+            record.avatar = self.avatar;
+        }
+        if self.company.is_some() || self.clear_company {
+            record.company = self.company;
+        }
+        if self.title.is_some() || self.clear_title {
+            record.title = self.title;
+        }
+        if self.nickname.is_some() || self.clear_nickname {
+            record.nickname = self.nickname;
+        }
+        if self.last_contacted.is_some() || self.clear_last_contacted {
+            record.last_contacted = self.last_contacted;
+        }
+        // Without any changes, the contact isn't marked as updated.
+        if record == *before {
+            return Ok(false);
+        }
+        recs.edit(id, |old| *old = record);
+        Ok(true)
+    }
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    Add(AddArgs),
+    Edit(EditArgs),
+    List {
+        #[structopt(long, number_of_values = 1, help = "only show contacts with this tag")]
+        tag: Vec<String>,
+        #[structopt(
+            long,
+            default_value = "id",
+            help = "id, name, email, created, updated, or contacted"
+        )]
+        sort: SortKey,
+        #[structopt(long, help = "list in the opposite order")]
+        reverse: bool,
+        #[structopt(long, help = "show at most this many contacts")]
+        limit: Option<usize>,
+        #[structopt(
+            long,
+            default_value = "0",
+            help = "skip this many contacts before showing any"
+        )]
+        offset: usize,
+        #[structopt(
+            long = "where",
+            value_name = "query",
+            help = "only show contacts that match a query, such as 'name ~ smith and has email'"
+        )]
+        filter: Option<Query>,
+        #[structopt(long, default_value = "text", help = "text or json")]
+        format: OutputFormat,
+        #[structopt(
+            long,
+            conflicts_with_all = &[
+                "sort", "reverse", "limit", "offset", "format", "group-by", "grouped"
+            ],
+            help = "print contacts in file order while reading the file (csv only)"
+        )]
+        stream: bool,
+        #[structopt(long, help = "leave out the row of column names")]
+        no_header: bool,
+        #[structopt(
+            long,
+            conflicts_with = "format",
+            help = "print each contact using a template such as \"{name} <{email}>\""
+        )]
+        template: Option<Template>,
+        #[structopt(
+            long,
+            value_name = "field",
+            help = "list the contacts under a heading for each company or title"
+        )]
+        group_by: Option<GroupKey>,
+        #[structopt(
+            long,
+            conflicts_with_all = &["group-by", "sort", "reverse"],
+            help = "list the contacts by name under a heading for each letter, like a phone's address book"
+        )]
+        grouped: bool,
+        #[structopt(
+            long,
+            parse(try_from_str = parse_language),
+            help = "sort names in the order of this language, such as sv (default: from LC_COLLATE or LANG)"
+        )]
+        locale: Option<String>,
+        #[structopt(
+            long,
+            value_name = "age",
+            parse(try_from_str = parse_age),
+            help = "only show contacts that haven't been contacted for this long, such as 90d, 6w, 3m or 1y"
+        )]
+        stale: Option<u32>,
+    },
+    Show {
+        id: RecordId,
+        #[structopt(long, default_value = "text", help = "text or json")]
+        format: OutputFormat,
+        #[structopt(
+            long,
+            conflicts_with = "format",
+            help = "also draw the avatar, in terminals that can show images (kitty, iTerm2, WezTerm)"
+        )]
+        avatar: bool,
+    },
+    Remove {
+        #[structopt(help = "the contact to remove (picked from a list if left out)")]
+        id: Option<RecordId>,
+        #[structopt(
+            long,
+            conflicts_with = "id",
+            help = "remove every contact whose name or email match"
+        )]
+        query: Option<String>,
+        #[structopt(
+            long = "where",
+            value_name = "query",
+            conflicts_with_all = &["id", "query"],
+            help = "remove every contact that matches a query, such as 'tag = old'"
+        )]
+        filter: Option<Query>,
+        #[structopt(short, long, help = "remove without asking first")]
+        yes: bool,
+        #[structopt(long, help = "only show what would be removed")]
+        dry_run: bool,
+    },
+    Restore {
+        #[structopt(
+            required_unless_one = &["list", "from"],
+            help = "id of a contact in the trash"
+        )]
+        id: Option<RecordId>,
+        #[structopt(long, conflicts_with_all = &["id", "from"], help = "list the backups")]
+        list: bool,
+        #[structopt(
+            long,
+            conflicts_with = "id",
+            help = "replace the data file with the backup made at this time (the start is enough)"
+        )]
+        from: Option<String>,
+        #[structopt(short, long, help = "restore the backup without asking first")]
+        yes: bool,
+    },
+    Trash(TrashCommand),
+    /// Lists the contacts that match a query, such as
+    /// 'name ~ "smith" and email ends "@gmail.com"'. A field is compared
+    /// with =, != , ~ (contains), !~, starts, ends, <, <=, >, or >=, and
+    /// "has phone" matches contacts with a phone number. Comparisons are
+    /// combined with and, or, not, and parentheses. Case is ignored.
+    Query {
+        query: Query,
+        #[structopt(long, default_value = "text", help = "text or json")]
+        format: OutputFormat,
+        #[structopt(long, help = "leave out the row of column names")]
+        no_header: bool,
+    },
+    /// Lists the contacts that match, and lists them again whenever the data
+    /// file changes, such as when another p2 command or a sync updates it.
+    Watch {
+        #[structopt(long, help = "only show contacts whose name or email match")]
+        query: Option<String>,
+        #[structopt(
+            long = "where",
+            value_name = "query",
+            help = "only show contacts that match a query, such as 'name ~ smith and has email'"
+        )]
+        filter: Option<Query>,
+        #[structopt(long, default_value = "text", help = "text or json")]
+        format: OutputFormat,
+    },
+    Search {
+        query: String,
+        #[structopt(
+            long,
+            use_delimiter = true,
+            default_value = "name,nickname,email",
+            help = "comma-separated fields to search: id, name, nickname, email, phone, company, title"
+        )]
+        fields: Vec<Field>,
+        #[structopt(long, help = "search phone numbers only (same as --fields phone)")]
+        phone: bool,
+        #[structopt(
+            long,
+            help = "treat the query as a case-insensitive regular expression"
+        )]
+        regex: bool,
+        #[structopt(
+            long,
+            conflicts_with = "regex",
+            help = "match letters whatever their accents, so muller finds Müller"
+        )]
+        ignore_accents: bool,
+        #[structopt(
+            long,
+            conflicts_with = "regex",
+            help = "only find names spelled like the query, so bob doesn't find Robert and kathrine doesn't find Katherine"
+        )]
+        no_aliases: bool,
+        #[structopt(
+            long,
+            conflicts_with_all = &["regex", "fields", "phone"],
+            help = "find names that sound like the query, so Jon Smyth finds John Smith"
+        )]
+        phonetic: bool,
+        #[structopt(long, number_of_values = 1, help = "only show contacts with this tag")]
+        tag: Vec<String>,
+        #[structopt(long, default_value = "text", help = "text or json")]
+        format: OutputFormat,
+        #[structopt(long, help = "leave out the row of column names")]
+        no_header: bool,
+        #[structopt(
+            long,
+            conflicts_with = "format",
+            help = "print each contact using a template such as \"{name} <{email}>\""
+        )]
+        template: Option<Template>,
+    },
+    /// Lists the birthdays coming up soon, or writes every birthday to a
+    /// calendar file.
+    Birthdays {
+        #[structopt(
+            long,
+            default_value = "30",
+            value_name = "days",
+            help = "how many days ahead to look"
+        )]
+        next: u32,
+        #[structopt(
+            long,
+            parse(from_os_str),
+            value_name = "file",
+            help = "write the birthdays to an .ics file as events that repeat every year"
+        )]
+        ics: Option<PathBuf>,
+    },
+    /// Shows a contact as a QR code, which a phone camera can scan to add
+    /// the contact.
+    Qr {
+        id: RecordId,
+        #[structopt(
+            long,
+            parse(from_os_str),
+            value_name = "file",
+            help = "also save the QR code as a PNG image"
+        )]
+        png: Option<PathBuf>,
+        #[structopt(long, help = "draw the code for a terminal with a light background")]
+        light: bool,
+    },
+    ExportVcf {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    ImportVcf {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Writes the contacts as LDIF, which LDAP directories and some mail
+    /// clients can load.
+    ExportLdif {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        #[structopt(
+            long,
+            help = "put the entries under this DN, such as ou=contacts,dc=example,dc=com"
+        )]
+        base_dn: Option<String>,
+    },
+    Import {
+        #[structopt(
+            parse(from_os_str),
+            help = "another contacts file: csv, or json, jsonl or yaml from export"
+        )]
+        file: PathBuf,
+        #[structopt(
+            long,
+            default_value = "p2",
+            help = "the program that wrote the file: p2, google, outlook or thunderbird"
+        )]
+        source: ImportSource,
+        #[structopt(
+            long,
+            parse(try_from_str = parse_encoding),
+            help = "the encoding of the file, such as latin1 or utf16 (default: utf8)"
+        )]
+        encoding: Option<&'static Encoding>,
+        #[structopt(
+            long,
+            help = "copy the contacts exactly, keeping their ids, such as to move them to another backend"
+        )]
+        keep_ids: bool,
+    },
+    Export {
+        #[structopt(long, help = "only export contacts whose name or email match")]
+        query: Option<String>,
+        #[structopt(
+            long = "where",
+            value_name = "query",
+            help = "only export contacts that match a query, such as 'name ~ smith and has email'"
+        )]
+        filter: Option<Query>,
+        #[structopt(long, parse(from_os_str), help = "file to write")]
+        out: PathBuf,
+        #[structopt(
+            long,
+            default_value = "csv",
+            help = "csv, json, jsonl, yaml, xlsx for an Excel spreadsheet, or mutt for a mutt alias file"
+        )]
+        format: ExportFormat,
+    },
+    /// Fills in a template file, such as a letter, for each contact, and
+    /// writes one document per contact. Placeholders are in double braces,
+    /// like "Dear {{name}},".
+    Merge {
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "the template file, with placeholders like {{name}} and {{email}}"
+        )]
+        template: PathBuf,
+        #[structopt(long, help = "only use contacts whose name or email match")]
+        query: Option<String>,
+        #[structopt(
+            long = "where",
+            value_name = "query",
+            help = "only use contacts that match a query, such as 'tag = clients'"
+        )]
+        filter: Option<Query>,
+        #[structopt(
+            long,
+            parse(from_os_str),
+            default_value = ".",
+            help = "the directory to write the documents to"
+        )]
+        out_dir: PathBuf,
+    },
+    /// Notes that contacts were called, written to, or met today, so that
+    /// "list --stale" can find the people it has been a while since.
+    Touch {
+        #[structopt(required = true, help = "the contacts that were contacted")]
+        ids: Vec<RecordId>,
+        #[structopt(
+            long,
+            parse(try_from_str = parse_date),
+            help = "the day they were contacted instead of today, as YYYY-MM-DD"
+        )]
+        date: Option<NaiveDate>,
+    },
+    /// Sets a reminder to follow up with a contact, such as
+    /// "remind 7 --in 2w --note 'send the proposal'".
+    // The group makes exactly one of --in and --on required, and the usage
+    // error names both of them.
+    #[structopt(group = structopt::clap::ArgGroup::with_name("when").required(true))]
+    Remind {
+        id: RecordId,
+        #[structopt(
+            long = "in",
+            value_name = "age",
+            parse(try_from_str = parse_age),
+            group = "when",
+            help = "how long from today, such as 3d, 2w or 1m"
+        )]
+        after: Option<u32>,
+        #[structopt(
+            long,
+            parse(try_from_str = parse_date),
+            group = "when",
+            help = "the day, as YYYY-MM-DD"
+        )]
+        on: Option<NaiveDate>,
+        #[structopt(long, help = "what to follow up about")]
+        note: Option<String>,
+    },
+    /// Lists the reminders, the soonest first.
+    Reminders {
+        #[structopt(long, help = "only list the reminders that are due today or overdue")]
+        due: bool,
+        #[structopt(
+            long,
+            value_name = "number",
+            conflicts_with_all = &["due", "format"],
+            help = "remove a reminder that was taken care of"
+        )]
+        done: Option<u32>,
+        #[structopt(long, default_value = "text", help = "text or json")]
+        format: OutputFormat,
+    },
+    /// Starts writing an email to a contact in the mail client, by opening a
+    /// "mailto:" link. Contacts with several addresses are written to at the
+    /// first one.
+    Email {
+        #[structopt(help = "the id of the contact, or a query that matches their name or email")]
+        target: String,
+        #[structopt(long, help = "only print the address, without opening the mail client")]
+        print: bool,
+    },
+    /// Copies a contact's details to the clipboard, so that they can be
+    /// pasted without selecting them in the terminal.
+    Copy {
+        id: RecordId,
+        #[structopt(
+            long,
+            default_value = "email",
+            help = "name, email, phone, address, or all"
+        )]
+        field: CopyField,
+        #[structopt(
+            long,
+            help = "on Linux, keep running until something else is copied, for desktops without a clipboard manager"
+        )]
+        wait: bool,
+    },
+    Group(GroupCommand),
+    Profile(ProfileCommand),
+    /// Creates a new, empty data file, so that contacts can be added to it.
+    /// The backend is picked like for any other command, from --backend or
+    /// the file name.
+    Init {},
+    Carddav(CarddavCommand),
+    GenMan {},
+    /// Shows the contacts that were added, removed, or changed between two
+    /// contact files.
+    Diff {
+        #[structopt(parse(from_os_str), help = "the older contacts file")]
+        before: PathBuf,
+        #[structopt(parse(from_os_str), help = "the newer contacts file")]
+        after: PathBuf,
+        #[structopt(long, default_value = "text", help = "text or json")]
+        format: OutputFormat,
+    },
+    /// Encrypts the data file and its trash with a new passphrase.
+    Encrypt {},
+    /// Decrypts the data file and its trash, so --encrypt is no longer needed.
+    Decrypt {},
+    /// Compresses the data file with gzip. It stays compressed from then on.
+    Compress {},
+    /// Links a contact to another one, such as "link 3 7 --relation spouse"
+    /// when contact 7 is the spouse of contact 3. Both show up in "show".
+    Link {
+        id: RecordId,
+        other: RecordId,
+        #[structopt(
+            long,
+            default_value = "related",
+            help = "how the second contact is related to the first, such as spouse or manager"
+        )]
+        relation: String,
+    },
+    /// Removes the links between two contacts.
+    Unlink {
+        id: RecordId,
+        other: RecordId,
+    },
+    /// Upgrades the data file to the current schema version, after making a
+    /// backup. Other commands also upgrade it whenever they save it.
+    Migrate {},
+    /// Renumbers the contacts from 1, so that the ids of removed contacts
+    /// are no longer skipped. The history is updated to the new ids, but
+    /// the undo journal is cleared since its changes use the old ones. With
+    /// --dry-run, it only shows which ids would change.
+    Compact {},
+    /// Sorts the data file, so that it is easier to browse in an editor. It
+    /// stays sorted as contacts are added and changed.
+    Sort {
+        #[structopt(long, default_value = "id", help = "name, email, or id")]
+        by: SortOrder,
+        #[structopt(
+            long,
+            parse(try_from_str = parse_language),
+            help = "sort names in the order of this language, such as sv; it is saved in the data file, so the order doesn't depend on who adds contacts"
+        )]
+        locale: Option<String>,
+    },
+    /// Sends local changes to a server started with "p2 serve", and takes
+    /// the changes made there.
+    Sync {
+        #[structopt(long, help = "address of the server, such as https://example.com:8080")]
+        remote: String,
+    },
+    /// Copies the data file into a directory of backups next to it.
+    Backup {
+        #[structopt(long, default_value = "10", help = "how many backups to keep")]
+        keep: usize,
+    },
+    Completions {
+        #[structopt(
+            possible_values = &structopt::clap::Shell::variants(),
+            help = "the shell to write a completion script for"
+        )]
+        shell: structopt::clap::Shell,
+    },
+    Undo {},
+    History {
+        id: RecordId,
+    },
+    /// Shows the most recent git commits of the data file, such as the ones
+    /// made by --git.
+    Log {
+        #[structopt(
+            short = "n",
+            long,
+            default_value = "10",
+            help = "how many commits to show"
+        )]
+        count: usize,
+    },
+    Dedup {
+        #[structopt(
+            long,
+            default_value = "1",
+            help = "how many characters two names may differ by"
+        )]
+        max_distance: usize,
+        #[structopt(long, help = "merge every group of duplicates without asking")]
+        merge: bool,
+        #[structopt(
+            short,
+            long,
+            conflicts_with = "merge",
+            help = "ask before merging each group"
+        )]
+        interactive: bool,
+    },
+    Repair {
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "where to write the repaired copy (default: <data file>.repaired)"
+        )]
+        out: Option<PathBuf>,
+        #[structopt(
+            long,
+            parse(from_os_str),
+            help = "where to write the lines that couldn't be repaired (default: <data file>.rejects)"
+        )]
+        rejects: Option<PathBuf>,
+    },
+    Validate {
+        #[structopt(long, default_value = "text", help = "text or json")]
+        format: OutputFormat,
+    },
+    Tui {},
+    /// Loads the contacts once and reads commands such as add, edit, and
+    /// list from the keyboard. Changes are only written to the data file
+    /// by "save", and the data file is locked until the shell is left.
+    Shell {},
+    Serve {
+        #[structopt(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+    },
+}
+
+/// Commands for organizing contacts into groups. A group is simply every
+/// contact that has a particular tag.
+#[derive(StructOpt, Debug)]
+enum GroupCommand {
+    /// Adds contacts to a group.
+    Add { group: String, ids: Vec<RecordId> },
+    /// Removes contacts from a group.
+    Remove { group: String, ids: Vec<RecordId> },
+    /// Lists every group and how many contacts are in it.
+    List {},
+    /// Lists the contacts in a group.
+    Members {
+        group: String,
+        #[structopt(long, default_value = "text", help = "text or json")]
+        format: OutputFormat,
+    },
+}
+
+/// Commands for the trash, which holds removed contacts until they are
+/// restored or the trash is emptied.
+#[derive(StructOpt, Debug)]
+enum TrashCommand {
+    /// Lists the contacts in the trash.
+    List {
+        #[structopt(long, default_value = "text", help = "text or json")]
+        format: OutputFormat,
+    },
+    /// Permanently deletes every contact in the trash.
+    Empty {},
+}
+
+/// Commands for keeping the contacts in sync with an address book on a
+/// CardDAV server, such as Nextcloud or Fastmail.
+#[derive(StructOpt, Debug)]
+enum CarddavCommand {
+    /// Sends the local changes to the server and takes the changes made
+    /// there.
+    Sync {
+        #[structopt(flatten)]
+        server: CarddavServer,
+    },
+    /// Takes the changes made on the server, without sending any.
+    Pull {
+        #[structopt(flatten)]
+        server: CarddavServer,
+    },
+}
+
+/// Where to find an address book on a CardDAV server.
+#[derive(StructOpt, Debug)]
+struct CarddavServer {
+    #[structopt(
+        long,
+        help = "URL of the address book, such as https://example.com/dav/addressbooks/me/contacts/"
+    )]
+    url: String,
+    #[structopt(
+        long,
+        help = "user name to log in with (the password is asked for, or read from P2_CARDDAV_PASSWORD)"
+    )]
+    user: Option<String>,
+}
+
+/// Commands for profiles, which are separate contact files that can be
+/// picked by name with "--profile" instead of by path.
+#[derive(StructOpt, Debug)]
+enum ProfileCommand {
+    /// Lists every profile.
+    List {},
+    /// Creates a new profile with no contacts.
+    Create { name: String },
+}
+
+impl Command {
+    /// Returns the name of the command as it is typed, such as
+    /// "import-vcf". It is worked out from the name of the variant, which
+    /// structopt turns into the command's name in the same way.
+    fn name(&self) -> String {
+        let mut name = String::new();
+        let variant = format!("{:?}", self);
+        for (i, c) in variant
+            .chars()
+            .take_while(|c| c.is_alphanumeric())
+            .enumerate()
+        {
+            if c.is_uppercase() && i > 0 {
+                name.push('-');
+            }
+            name.push(c.to_ascii_lowercase());
+        }
+        name
+    }
+
+    /// Returns true if the command changes the saved records.
+    fn modifies_records(&self) -> bool {
+        match self {
+            Command::Add(_)
+            | Command::Edit(_)
+            | Command::Remove { .. }
+            // Listing the backups doesn't restore anything.
+            | Command::Restore { list: false, .. }
+            | Command::Trash(TrashCommand::Empty { .. })
+            | Command::ImportVcf { .. }
+            | Command::Import { .. }
+            | Command::Group(GroupCommand::Add { .. })
+            | Command::Group(GroupCommand::Remove { .. })
+            | Command::Undo { .. }
+            | Command::Encrypt { .. }
+            | Command::Decrypt { .. }
+            | Command::Compress { .. }
+            | Command::Sort { .. }
+            | Command::Compact { .. }
+            | Command::Migrate { .. }
+            | Command::Init { .. }
+            | Command::Link { .. }
+            | Command::Unlink { .. }
+            | Command::Touch { .. }
+            | Command::Remind { .. }
+            | Command::Reminders { done: Some(_), .. }
+            | Command::Sync { .. }
+            | Command::Carddav(_)
+            | Command::Tui { .. }
+            | Command::Shell { .. } => true,
+            // Without these options, duplicates are only reported.
+            Command::Dedup {
+                merge, interactive, ..
+            } => *merge || *interactive,
+            _ => false,
+        }
+    }
+
+    /// Returns true if "--dry-run" can be used with the command. These
+    /// commands only change the records by saving them through the
+    /// storage, which shows the changes instead of saving them during a dry
+    /// run. Other commands also write to files like the trash directly.
+    fn supports_dry_run(&self) -> bool {
+        match self {
+            Command::Add(_)
+            | Command::Edit(_)
+            | Command::Remove { .. }
+            | Command::ImportVcf { .. }
+            | Command::Import { .. }
+            | Command::Dedup { .. }
+            | Command::Group(GroupCommand::Add { .. })
+            | Command::Group(GroupCommand::Remove { .. })
+            | Command::Link { .. }
+            | Command::Unlink { .. }
+            | Command::Touch { .. }
+            // Compacting checks for a dry run itself, before it saves the
+            // records or renumbers the other files.
+            | Command::Compact { .. } => true,
+            command => !command.modifies_records(),
+        }
+    }
+}
+
+// The commands of "p2 shell". The doc comment is printed by "help".
+/// Type one command on each line. Quotes work like they do in a Unix shell,
+/// so "add 'Ann Lee' -e ann@example.com" adds one contact named Ann Lee.
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "",
+    no_version,
+    setting = structopt::clap::AppSettings::NoBinaryName,
+    setting = structopt::clap::AppSettings::DisableVersion
+)]
+enum ShellCommand {
+    Add(AddArgs),
+    Edit(EditArgs),
+    /// Lists the contacts.
+    List {
+        #[structopt(
+            long = "where",
+            value_name = "query",
+            help = "only show contacts that match a query, such as 'name ~ smith and has email'"
+        )]
+        filter: Option<Query>,
+    },
+    /// Lists the contacts whose name or email match.
+    Search {
+        query: String,
+    },
+    /// Shows every field of a contact.
+    Show {
+        id: RecordId,
+    },
+    /// Removes a contact. It is moved to the trash when the changes are
+    /// saved.
+    Remove {
+        #[structopt(help = "the contact to remove (picked from a list if left out)")]
+        id: Option<RecordId>,
+    },
+    /// Writes the changes to the data file.
+    Save {},
+    /// Leaves the shell, asking first whether to save any changes.
+    #[structopt(alias = "quit")]
+    Exit {},
+}
+
+/// Returns the name that the program was run as, such as "p2".
+fn bin_name() -> String {
+    std::env::args_os()
+        .next()
+        .map(PathBuf::from)
+        .and_then(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| "p2".to_owned())
+}
+
+/// The completions that are added to bash's generated completion script.
+/// After "show", "edit", "remove" and "history", the ids of the contacts in
+/// the data file are offered. Any other word is left to the "_{bin}"
+/// function that clap generated. "{bin}" is replaced with the program name.
+const BASH_ID_COMPLETIONS: &str = r#"
+_{bin}_ids() {
+    local i
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case "${COMP_WORDS[i]}" in
+            show|edit|remove|history)
+                if [[ $i -eq $((COMP_CWORD - 1)) && ${COMP_WORDS[COMP_CWORD]} != -* ]]; then
+                    COMPREPLY=($(compgen -W "$({bin} list --no-header --template '{id}' 2>/dev/null)" -- "${COMP_WORDS[COMP_CWORD]}"))
+                    return 0
+                fi
+                break
+                ;;
+        esac
+    done
+    _{bin} "$@"
+}
+
+complete -F _{bin}_ids -o bashdefault -o default {bin}
+"#;
+
+/// The same as "BASH_ID_COMPLETIONS", for fish. Fish shows the text after
+/// the tab next to each id, so the names are listed as well.
+const FISH_ID_COMPLETIONS: &str = r#"
+complete -c {bin} -n "__fish_seen_subcommand_from show edit remove history" -f -a "({bin} list --no-header --template '{id}'\t'{name}' 2>/dev/null)"
+"#;
+
+/// Writes a completion script for a shell, for the program name "bin". The
+/// script is generated by clap from the options in "Opt", so it always
+/// matches the current commands. Clap only knows about the options
+/// themselves, so completing contact ids is added by hand for the shells
+/// where that is simple to do.
+fn write_completions(
+    out: &mut impl Write,
+    shell: structopt::clap::Shell,
+    bin: &str,
+) -> std::io::Result<()> {
+    Opt::clap().gen_completions_to(bin, shell, out);
+    let extra = match shell {
+        structopt::clap::Shell::Bash => BASH_ID_COMPLETIONS,
+        structopt::clap::Shell::Fish => FISH_ID_COMPLETIONS,
+        _ => return Ok(()),
+    };
+    write!(out, "{}", extra.replace("{bin}", bin))
+}
+
+/// Prints a completion script for the name that the program was run as,
+/// since the completions only work for that name.
+fn print_completions(shell: structopt::clap::Shell) -> std::io::Result<()> {
+    write_completions(&mut std::io::stdout().lock(), shell, &bin_name())
+}
+
+/// The examples at the end of the man page. "{bin}" is replaced with the
+/// program name.
+const MAN_EXAMPLES: [(&str, &str); 6] = [
+    (
+        "{bin} add \"Ada Lovelace\" -e ada@example.com -t friends",
+        "Add a contact with an email address and a tag.",
+    ),
+    (
+        "{bin} list --sort name --limit 10",
+        "Show the first ten contacts in alphabetical order.",
+    ),
+    (
+        "{bin} search ada --fields name,email",
+        "Find contacts whose name or email contain \"ada\".",
+    ),
+    (
+        "{bin} edit 3 --phone 555-0100",
+        "Change the phone number of contact 3.",
+    ),
+    (
+        "{bin} remove 3 --yes",
+        "Move contact 3 to the trash without asking.",
+    ),
+    (
+        "{bin} --profile work list",
+        "List the contacts in the \"work\" profile.",
+    ),
+];
+
+/// Escapes text so that roff prints it as it is. Backslashes start roff
+/// escapes, and lines that start with "." or "'" are roff requests.
+fn roff_escape(text: &str) -> String {
+    text.replace('\\', "\\e")
+        .replace('-', "\\-")
+        .lines()
+        .map(|line| {
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The help of a command, split up into the parts that clap prints.
+struct CommandHelp {
+    /// The description of the command, from its doc comment.
+    about: String,
+    /// Each section of the help, such as "USAGE" or "OPTIONS", with its
+    /// lines. Blank lines and the indentation are left out.
+    sections: Vec<(String, Vec<String>)>,
+}
+
+impl CommandHelp {
+    /// Returns the lines of a section, which are empty if the command
+    /// doesn't have it.
+    fn section(&self, heading: &str) -> &[String] {
+        self.sections
+            .iter()
+            .find(|(name, _)| name == heading)
+            .map_or(&[], |(_, lines)| lines)
+    }
+
+    /// Returns the names of the subcommands, without the "help" command
+    /// that clap adds to every command that has subcommands.
+    fn subcommands(&self) -> Vec<String> {
+        self.section("SUBCOMMANDS")
+            .iter()
+            .filter_map(|line| line.split_whitespace().next())
+            .filter(|name| *name != "help")
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+/// Returns the help that "--help" prints for a command, such as
+/// ["group", "add"]. Clap 2 has no public way to look at the options of an
+/// app, so the man page is made from the same help that users see. No
+/// terminal width is set, so that every option stays on one line.
+fn command_help(bin: &str, path: &[String]) -> CommandHelp {
+    let mut args = vec![bin];
+    args.extend(path.iter().map(String::as_str));
+    args.push("--help");
+    let text = match Opt::clap().set_term_width(0).get_matches_from_safe(args) {
+        Err(e) if e.kind == structopt::clap::ErrorKind::HelpDisplayed => e.message,
+        _ => String::new(),
+    };
+    let mut about = vec![];
+    let mut sections: Vec<(String, Vec<String>)> = vec![];
+    // The first line has the name of the command, which we already know.
+    for line in text.lines().skip(1) {
+        match line.strip_suffix(':') {
+            Some(heading) if !line.starts_with(' ') && heading == heading.to_uppercase() => {
+                sections.push((heading.to_owned(), vec![]))
+            }
+            _ => match sections.last_mut() {
+                Some((_, lines)) if !line.trim().is_empty() => lines.push(line.trim().to_owned()),
+                Some(_) => (),
+                None => about.push(line),
+            },
+        }
+    }
+    CommandHelp {
+        about: about.join("\n").trim().to_owned(),
+        sections,
+    }
+}
+
+/// Formats the name of an option or argument from the help, such as
+/// "-j, --jobs <jobs>", with the options in bold and the values in italics.
+fn man_name(name: &str) -> String {
+    let words: Vec<_> = name
+        .split(' ')
+        .map(|word| match word.strip_prefix('<') {
+            Some(value) => format!("\\fI{}\\fR", roff_escape(&value.replace('>', ""))),
+            None => match word.strip_suffix(',') {
+                Some(word) => format!("\\fB{}\\fR,", roff_escape(word)),
+                None => format!("\\fB{}\\fR", roff_escape(word)),
+            },
+        })
+        .collect();
+    words.join(" ")
+}
+
+/// Writes lines from the help as a roff list, in alphabetical order. Each
+/// line is an option or argument, followed by its help after two or more
+/// spaces, like "-e <email>...    may be repeated".
+fn write_man_entries(out: &mut impl Write, lines: &[&String]) -> std::io::Result<()> {
+    let mut entries: Vec<_> = lines
+        .iter()
+        .map(|line| {
+            line.split_once("  ")
+                .map_or((line.as_str(), ""), |(name, help)| (name, help.trim()))
+        })
+        // Every command has these, so they would only be noise.
+        .filter(|(name, _)| !matches!(*name, "-h, --help" | "-V, --version"))
+        .collect();
+    // Options are sorted by their long name when they have one, like
+    // "-j, --jobs" under "jobs".
+    entries.sort_by_key(|(name, _)| {
+        let long = name.split(' ').find_map(|word| word.strip_prefix("--"));
+        long.unwrap_or(name.trim_start_matches('-')).to_owned()
+    });
+    for (name, help) in entries {
+        writeln!(out, ".TP\n{}", man_name(name))?;
+        // The help shows the current value of an environment variable, as
+        // in "[env: CONTACTS_FILE=x]", but it has no place in the man page.
+        let help = match help.split_once("[env: ") {
+            Some((before, env)) => match env.split_once('=') {
+                Some((name, rest)) => {
+                    let after = rest.split_once(']').map_or("", |(_, after)| after);
+                    format!("{}[env: {}]{}", before, name, after)
+                }
+                None => help.to_owned(),
+            },
+            None => help.to_owned(),
+        };
+        if !help.is_empty() {
+            writeln!(out, "{}", roff_escape(&help))?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a section of the man page for every subcommand, including the
+/// subcommands of commands like "group" and "trash".
+fn write_man_commands(out: &mut impl Write, bin: &str, path: &[String]) -> std::io::Result<()> {
+    for name in command_help(bin, path).subcommands() {
+        let mut path = path.to_vec();
+        path.push(name);
+        let help = command_help(bin, &path);
+        // Commands that only hold other commands are described by those.
+        if help.subcommands().is_empty() {
+            let usage = help.section("USAGE").first().cloned().unwrap_or_default();
+            writeln!(out, ".SS \"{}\"", roff_escape(&usage))?;
+            if !help.about.is_empty() {
+                writeln!(out, "{}", roff_escape(&help.about))?;
+            }
+            let options = help.section("FLAGS").iter().chain(help.section("OPTIONS"));
+            write_man_entries(out, &options.collect::<Vec<_>>())?;
+            write_man_entries(out, &help.section("ARGS").iter().collect::<Vec<_>>())?;
+        }
+        write_man_commands(out, bin, &path)?;
+    }
+    Ok(())
+}
+
+/// Writes a man page in roff format, which can be viewed with
+/// "man ./p2.1" or installed into a man directory. Like the completions,
+/// it is generated from "Opt" so that it never gets out of date.
+fn write_man_page(out: &mut impl Write, bin: &str) -> std::io::Result<()> {
+    let help = command_help(bin, &[]);
+    writeln!(
+        out,
+        ".TH {} 1 \"\" \"{} {}\" \"User Commands\"",
+        roff_escape(&bin.to_uppercase()),
+        roff_escape(bin),
+        env!("CARGO_PKG_VERSION")
+    )?;
+    writeln!(out, ".SH NAME")?;
+    writeln!(out, "{} \\- {}", roff_escape(bin), roff_escape(&help.about))?;
+    writeln!(out, ".SH SYNOPSIS")?;
+    let usage = help.section("USAGE").first().cloned().unwrap_or_default();
+    writeln!(out, "{}", roff_escape(&usage))?;
+    writeln!(out, ".SH OPTIONS")?;
+    let options = help.section("FLAGS").iter().chain(help.section("OPTIONS"));
+    write_man_entries(out, &options.collect::<Vec<_>>())?;
+    writeln!(out, ".SH COMMANDS")?;
+    write_man_commands(out, bin, &[])?;
+    writeln!(out, ".SH ENVIRONMENT")?;
+    writeln!(out, ".TP\n.B CONTACTS_FILE")?;
+    writeln!(
+        out,
+        "The contacts file to use when \\fB\\-d\\fR is not given."
+    )?;
+    writeln!(out, ".TP\n.B P2_PASSPHRASE")?;
+    writeln!(
+        out,
+        "The passphrase of an encrypted data file, instead of asking for it."
+    )?;
+    writeln!(out, ".TP\n.B P2_CARDDAV_PASSWORD")?;
+    writeln!(
+        out,
+        "The password for \\fBcarddav\\fR commands, instead of asking for it."
+    )?;
+    writeln!(out, ".SH FILES")?;
+    writeln!(out, ".TP\n.I ~/.local/share/p2/contacts.csv")?;
+    writeln!(
+        out,
+        "The contacts file when neither \\fB\\-d\\fR nor CONTACTS_FILE is given."
+    )?;
+    writeln!(out, ".TP\n.I ~/.config/p2/profiles/")?;
+    writeln!(out, "The contacts files of every profile.")?;
+    // The exit statuses are listed at the end of the help, one per line.
+    writeln!(out, ".SH EXIT STATUS")?;
+    for line in help.section("EXIT STATUS") {
+        if let Some((status, meaning)) = line.split_once(char::is_whitespace) {
+            writeln!(out, ".TP\n.B {}\n{}", status, roff_escape(meaning.trim()))?;
+        }
+    }
+    writeln!(out, ".SH EXAMPLES")?;
+    for (example, description) in MAN_EXAMPLES.iter() {
+        // ".B" would drop the quotes in the examples, so "\\fB" is used.
+        let example = example.replace("{bin}", bin);
+        writeln!(
+            out,
+            ".TP\n\\fB{}\\fR\n{}",
+            roff_escape(&example),
+            roff_escape(description)
+        )?;
+    }
+    Ok(())
+}
+
+/// Prints the man page for the name that the program was run as.
+fn print_man_page() -> std::io::Result<()> {
+    write_man_page(&mut std::io::stdout().lock(), &bin_name())
+}
+
+/// Asks for the passphrase if the command needs one. "encrypted" is whether
+/// the data file is encrypted already.
+fn command_passphrase(opt: &Opt, encrypted: bool, stdio: bool) -> Result<Option<String>, AppError> {
+    let encrypting = matches!(opt.cmd, Command::Encrypt {} | Command::Decrypt {});
+    if (opt.encrypt || encrypting) && (stdio || opt.backend != Backend::Csv) {
+        return Err(AppError::CsvOnly("encrypted"));
+    }
+    match opt.cmd {
+        Command::Encrypt {} if encrypted => Err(AppError::AlreadyEncrypted),
+        Command::Encrypt {} => Ok(Some(read_passphrase(true)?)),
+        Command::Decrypt {} if !encrypted => Err(AppError::NotEncrypted),
+        Command::Decrypt {} => Ok(Some(read_passphrase(false)?)),
+        _ if encrypted && !opt.encrypt => Err(AppError::Encrypted),
+        _ if opt.encrypt && !encrypted => Err(AppError::NotEncrypted),
+        // Without the passphrase, the contacts can't even be listed.
+        _ if encrypted => Ok(Some(read_passphrase(false)?)),
+        _ => Ok(None),
+    }
+}
+
+/// Runs the program. With "--git", changes to records are committed as
+/// they are saved, but commands such as "sort" and "encrypt" change the data
+/// file without changing any record, so anything left is committed once the
+/// command is done.
+fn run(opt: Opt, data_file: PathBuf) -> Result<(), AppError> {
+    let commit = opt.git && opt.cmd.modifies_records() && !opt.dry_run && !is_stdio(&data_file);
+    let message = format!("p2 {}", opt.cmd.name());
+    run_command(opt, data_file.clone())?;
+    if commit {
+        commit_or_warn(&data_file, &message);
+    }
+    Ok(())
+}
+
+/// Runs a command. This is so we can utilize the question mark operator.
+fn run_command(mut opt: Opt, data_file: PathBuf) -> Result<(), AppError> {
+    // Data files named like "contacts.jsonl" are JSON Lines, unless another
+    // backend was chosen.
+    if opt.backend == Backend::Csv && DataFormat::from_path(&data_file) == DataFormat::JsonLines {
+        opt.backend = Backend::Jsonl;
+    }
+    // Searches use rayon's global thread pool, which has one thread per CPU
+    // unless it is told otherwise before the first search.
+    if let Some(jobs) = opt.jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build_global()?;
+    }
+    // Profiles are managed separately from the contacts in them, the
+    // completions and man page only depend on the options, and diff compares
+    // two other files, so these commands don't need a data file.
+    match &opt.cmd {
+        Command::Profile(ProfileCommand::List {}) => {
+            let names = profile_names()?;
+            if names.is_empty() {
+                println!("no profiles found");
+            }
+            for name in names {
+                println!("{}", name);
+            }
+            return Ok(());
+        }
+        Command::Profile(ProfileCommand::Create { name }) => {
+            let path = profile_path(name)?;
+            if path.exists() {
+                return Err(AppError::ProfileExists(name.clone()));
+            }
+            fs::create_dir_all(profile_dir())?;
+            save_records(path, &Records::new())?;
+            println!("created profile {}", name);
+            return Ok(());
+        }
+        Command::Completions { shell } => {
+            print_completions(*shell)?;
+            return Ok(());
+        }
+        Command::GenMan {} => {
+            print_man_page()?;
+            return Ok(());
+        }
+        Command::Diff {
+            before,
+            after,
+            format,
+        } => {
+            let before = load_records(before.clone(), CsvFormat::default(), opt.strict)?;
+            let after = load_records(after.clone(), CsvFormat::default(), opt.strict)?;
+            let diff = diff_files(&before, &after);
+            match format {
+                OutputFormat::Text => print_diff(&diff),
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+            }
+            return Ok(());
+        }
+        _ => (),
+    }
+    // Files named like "contacts.tsv" are separated by tabs unless another
+    // delimiter was given. Otherwise, the delimiter is found from the header.
+    let csv_format = CsvFormat {
+        delimiter: opt.delimiter.or_else(|| default_delimiter(&data_file)),
+        no_header: opt.no_header,
+        on_duplicate: opt.on_duplicate,
+        ..CsvFormat::default()
+    };
+    // The default data file is created below, so "init" checks whether the
+    // file existed before that.
+    let existed = data_file.exists();
+    match &opt.profile {
+        Some(name) if !data_file.exists() => return Err(AppError::NoSuchProfile(name.clone())),
+        Some(_) => (),
+        None if opt.data_file.is_none() && !opt.read_only => {
+            create_data_file(&data_file, opt.backend, csv_format)?
+        }
+        None => (),
+    }
+    let missing = !is_stdio(&data_file) && !data_file.exists();
+    match opt.cmd {
+        Command::Init {} if opt.read_only => return Err(AppError::ReadOnly(data_file)),
+        Command::Init {} if is_stdio(&data_file) => return Err(AppError::NotWithStdio("created")),
+        Command::Init {} if existed => return Err(AppError::DataFileExists(data_file)),
+        Command::Init {} => {
+            create_data_file(&data_file, opt.backend, csv_format)?;
+            println!("created {}", data_file.display());
+            return Ok(());
+        }
+        // Restoring a backup is a way to get back a data file that was
+        // deleted, so it doesn't need to exist.
+        Command::Restore { from: Some(_), .. } => (),
+        _ if missing && opt.create => create_data_file(&data_file, opt.backend, csv_format)?,
+        _ if missing => return Err(AppError::NoDataFile(data_file)),
+        _ => (),
+    }
+    // The lock is held until "_lock" goes out of scope at the end of this
+    // function, which covers both loading and saving the records. The
+    // server locks the file separately for every request instead, so that
+    // other p2 commands can still be used while it is running.
+    //
+    // Records read from stdin can't be used by anyone else, so they don't
+    // need a lock. When they are written to stdout, status messages go to
+    // stderr instead, so that they don't end up mixed in with the records.
+    let stdio = is_stdio(&data_file);
+    if opt.dry_run && !opt.cmd.supports_dry_run() {
+        return Err(AppError::NoDryRun);
+    }
+    // A dry run never writes anything, so it is treated like any command
+    // that only reads the records.
+    let modifies_records = opt.cmd.modifies_records() && !opt.dry_run;
+    // A data file that can't be written to is treated as if --read-only
+    // was given, so that commands fail before doing anything, rather than
+    // when they try to save.
+    let read_only = opt.read_only || (!stdio && is_read_only_file(&data_file));
+    if read_only && modifies_records {
+        return Err(AppError::ReadOnly(data_file));
+    }
+    // Opening a sled database always writes to it, even just to read it.
+    if read_only && opt.backend == Backend::Sled {
+        return Err(AppError::NoReadOnly("sled"));
+    }
+    if stdio && modifies_records {
+        output::status_to_stderr();
+    }
+    // The passphrase is asked for before locking, so that other p2
+    // processes don't have to wait while it is being typed.
+    let encrypted = !stdio && opt.backend == Backend::Csv && is_encrypted_file(&data_file)?;
+    let passphrase = command_passphrase(&opt, encrypted, stdio)?;
+    // The server and "watch" keep running, so they only lock the data file
+    // while they are reading it.
+    let _lock = match opt.cmd {
+        Command::Serve { .. } | Command::Watch { .. } => None,
+        // Waiting for the clipboard to be taken can go on for hours. The
+        // records are only read, and saving replaces the data file in one
+        // step, so they can't be seen half-written anyway.
+        Command::Copy { wait: true, .. } => None,
+        _ if stdio => None,
+        _ => lock_data_file(&data_file, modifies_records, opt.wait, read_only)?,
+    };
+    // The backup is made while holding the lock, so no other p2 process
+    // can be in the middle of changing the file.
+    if opt.auto_backup && modifies_records && !stdio && data_file.exists() {
+        backup_data_file(&data_file, BACKUPS_KEPT)?;
+    }
+    // Every saved change is written to the journal, except when undoing,
+    // which removes changes from the journal instead. Either way, the
+    // changes are added to the history. The journal and history would hold
+    // unencrypted copies of the records, so encrypted files don't keep them.
+    let id_policy = IdPolicy {
+        scheme: opt.id_scheme,
+        reuse: opt.reuse_ids,
+    };
+    let journal = sidecar_path(&data_file, "journal");
+    let history = sidecar_path(&data_file, "history");
+    let mut inner = open_storage(
+        opt.backend,
+        data_file.clone(),
+        opt.strict,
+        opt.ignore_checksum,
+        csv_format,
+        passphrase.clone().filter(|_| encrypted),
+    );
+    if opt.dry_run {
+        inner = Box::new(DryRunStorage {
+            inner,
+            loaded: RefCell::new(None),
+        });
+    }
+    if read_only {
+        inner = Box::new(ReadOnlyStorage {
+            inner,
+            path: data_file.clone(),
+        });
+    }
+    let storage = JournalStorage::new(
+        inner,
+        id_policy,
+        match opt.cmd {
+            _ if stdio || passphrase.is_some() || opt.dry_run => None,
+            Command::Undo { .. } | Command::Compact { .. } => None,
+            _ => Some(journal.clone()),
+        },
+        // Renumbering every record isn't a change to any contact, and the
+        // history is rewritten to the new ids instead.
+        match opt.cmd {
+            Command::Compact { .. } => None,
+            _ => (!stdio && passphrase.is_none() && !opt.dry_run).then(|| history.clone()),
+        },
+        (opt.git && !stdio && !opt.dry_run).then(|| data_file.clone()),
+    );
+    match opt.cmd {
+        Command::Add(AddArgs {
+            batch: Some(batch),
+            tag,
+            ..
+        }) => {
+            let (records, bad_lines) = read_batch(open_input(&batch)?)?;
+            for bad_line in bad_lines.iter() {
+                info!("skipped a contact: {}", bad_line);
+            }
+            if opt.strict && !bad_lines.is_empty() {
+                return Err(AppError::Malformed(bad_lines));
+            }
+            // Every contact is added before saving, so the records are only
+            // loaded and saved once. Tags are given to every one of them.
+            let mut recs = storage.load()?;
+            let count = records.len();
+            for mut record in records {
+                record.id = recs.next_id();
+                record.tags = tag.clone();
+                recs.add(record);
+            }
+            storage.save(&recs)?;
+            status!("added {} records, skipped {} lines", count, bad_lines.len());
+        }
+        Command::Add(add) => {
+            storage.add(add.into_record(), id_policy)?;
+        }
+        Command::Edit(edit) => {
+            let mut recs = storage.load()?;
+            match edit.apply(&mut recs)? {
+                true => storage.save(&recs)?,
+                false => status!("nothing was changed"),
+            }
+        }
+        Command::List {
+            tag,
+            filter,
+            stream,
+            no_header,
+            template,
+            stale,
+            ..
+        } if stream && opt.backend == Backend::Csv && passphrase.is_none() => {
+            // Each record is printed as soon as it is read, so even huge
+            // files never need to fit in memory. The checksum is worked out
+            // a piece at a time as well.
+            if !stdio {
+                verify_checksum(&data_file, opt.ignore_checksum)?;
+            }
+            let file = open_input(&data_file)?;
+            let mut reader = RecordReader::new(file).with_format(csv_format);
+            let today = Local::now().date_naive();
+            let mut found = false;
+            let mut bad_lines = vec![];
+            let columns = TABLE_COLUMNS.map(|column| column.to_string());
+            if !no_header && template.is_none() {
+                let header = format_row(&columns, &STREAM_COLUMN_WIDTHS, |_, text| {
+                    output::header(text)
+                });
+                println!("{}", header);
+            }
+            while let Some(result) = reader.next() {
+                match result {
+                    Ok(rec)
+                        if rec.has_tags(&tag)
+                            && matches_filter(&filter, &rec)
+                            && is_stale(&rec, stale, today) =>
+                    {
+                        if let Some(template) = &template {
+                            println!("{}", template.render(&rec));
+                        } else {
+                            let row = table_row(&rec);
+                            let style = record_style(None);
+                            println!("{}", format_row(&row, &STREAM_COLUMN_WIDTHS, style));
+                        }
+                        found = true;
+                    }
+                    Ok(_) => (),
+                    Err(ParseError::Io(e)) => return Err(e.into()),
+                    Err(e) => {
+                        let bad_line = BadLine::new(&reader, e);
+                        info!("skipped a record: {}", bad_line);
+                        if opt.strict {
+                            bad_lines.push(bad_line);
+                        }
+                    }
+                }
+            }
+            // The records were already printed by the time we know about
+            // the bad lines, but the error still tells the user about them.
+            if !bad_lines.is_empty() {
+                return Err(AppError::Malformed(bad_lines));
+            }
+            if !found && !no_header && template.is_none() {
+                println!("no records found");
+            }
+        }
+        Command::List {
+            tag,
+            sort,
+            reverse,
+            limit,
+            offset,
+            filter,
+            format,
+            no_header,
+            template,
+            group_by,
+            grouped,
+            stale,
+            locale,
+            ..
+        } => {
+            let language = locale.or_else(locale_language);
+            let language = language.as_deref();
+            let recs = storage.load()?;
+            let mut records = recs.sorted();
+            let today = Local::now().date_naive();
+            records.retain(|rec| {
+                rec.has_tags(&tag) && matches_filter(&filter, rec) && is_stale(rec, stale, today)
+            });
+            // The records are already sorted by id. "sort_by_key" is stable,
+            // so records with the same name, email, or timestamp stay in
+            // order by id. "sort_by_cached_key" only makes each lowercase
+            // copy once, instead of every time two records are compared.
+            match sort {
+                SortKey::Id => (),
+                SortKey::Name => {
+                    records.sort_by_cached_key(|rec| collation_key(&rec.name, language))
+                }
+                SortKey::Email => records.sort_by_cached_key(|rec| {
+                    let email = rec.emails.first().map(|email| email.to_lowercase());
+                    (email.is_none(), email)
+                }),
+                SortKey::Created => records.sort_by_key(|rec| rec.created_at),
+                SortKey::Updated => records.sort_by_key(|rec| rec.updated_at),
+                SortKey::Contacted => records.sort_by_key(|rec| rec.last_contacted),
+            }
+            if reverse {
+                records.reverse();
+            }
+            // Without a limit, every remaining record is shown.
+            let records: Vec<_> = records
+                .into_iter()
+                .skip(offset)
+                .take(limit.unwrap_or(usize::MAX))
+                .collect();
+            match (grouped, group_by, template) {
+                (true, _, template) => {
+                    let groups = group_by_letter(records, language);
+                    print_groups(&groups, "#", format, template.as_ref(), !no_header)?
+                }
+                (false, Some(key), template) => {
+                    let groups = group_records(records, key);
+                    let unnamed = format!("(no {})", key.as_str());
+                    print_groups(&groups, &unnamed, format, template.as_ref(), !no_header)?
+                }
+                (false, None, Some(template)) => print_template(&records, &template),
+                (false, None, None) => print_records(&records, format, !no_header)?,
+            }
+        }
+        Command::Show { id, format, avatar } => {
+            let recs = storage.load()?;
+            let record = recs.get(id).ok_or(AppError::NotFound(id))?;
+            match format {
+                OutputFormat::Text => print_details(record, &recs),
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(record)?),
+            }
+            match (avatar, &record.avatar, ImageProtocol::detect()) {
+                (false, ..) => (),
+                (true, None, _) => status!("{} has no avatar", record.name),
+                (true, Some(_), None) => status!("this terminal can't show images"),
+                (true, Some(avatar), Some(protocol)) => {
+                    protocol.draw(&read_avatar(avatar)?)?;
+                }
+            }
+        }
+        Command::Remove {
+            id,
+            query,
+            filter,
+            yes,
+            dry_run,
+        } => {
+            // The records would also be moved to the trash, so removing
+            // has its own dry run that only shows them.
+            let dry_run = dry_run || opt.dry_run;
+            let mut recs = storage.load()?;
+            // Without an id, a query, or "--where", the user picks the
+            // contact from a list.
+            let ids: Vec<_> = match (id, query, filter) {
+                (Some(id), _, _) => vec![recs.get(id).ok_or(AppError::NotFound(id))?.id],
+                (None, Some(query), _) => recs.search(&query).iter().map(|rec| rec.id).collect(),
+                (None, None, Some(filter)) => recs
+                    .sorted()
+                    .into_iter()
+                    .filter(|rec| filter.matches(rec))
+                    .map(|rec| rec.id)
+                    .collect(),
+                (None, None, None) => match pick_record(&recs, "remove: ")? {
+                    Some(id) => vec![id],
+                    None => {
+                        status!("nothing was removed");
+                        return Ok(());
+                    }
+                },
+            };
+            if ids.is_empty() {
+                status!("no records found");
+                return Ok(());
+            }
+            // Stdin is already used up by the records, so there is no way to
+            // ask the user anything.
+            if stdio && !yes && !dry_run {
+                return Err(AppError::CantConfirm);
+            }
+            // The records are shown first, so that the user can tell whether
+            // they typed the right id or query.
+            if !yes || dry_run {
+                match ids.as_slice() {
+                    [id] => print_details(&recs[*id], &recs),
+                    _ => {
+                        let records: Vec<_> = ids.iter().map(|id| &recs[*id]).collect();
+                        print_records(&records, OutputFormat::Text, true)?;
+                    }
+                }
+            }
+            let question = match ids.len() {
+                1 => "remove this contact?".to_string(),
+                count => format!("remove these {} contacts?", count),
+            };
+            if dry_run || (!yes && !confirm(&question)?) {
+                status!("nothing was removed");
+                return Ok(());
+            }
+            let removed: Vec<_> = ids.iter().filter_map(|id| recs.remove(*id)).collect();
+            move_to_trash(&data_file, passphrase.as_deref(), opt.strict, removed)?;
+            storage.save(&recs)?;
+            match ids.as_slice() {
+                [id] => status!("record {} moved to trash", output::id(&id.to_string())),
+                _ => status!("moved {} records to trash", ids.len()),
+            }
+        }
+        Command::Restore { list: true, .. } => {
+            let current = storage.load()?.len();
+            let mut table = TextTable::new(&["BACKUP", "RECORDS", "CHANGE"], true);
+            for backup in list_backups(&data_file)? {
+                let count = load_backup(&backup, passphrase.as_deref()).map(|recs| recs.len());
+                table.add_row(vec![
+                    backup_time(&backup).to_owned(),
+                    count
+                        .as_ref()
+                        .map_or("?".to_owned(), |count| count.to_string()),
+                    count.map_or("?".to_owned(), |count| count_change(current, count)),
+                ]);
+            }
+            match table.rows.is_empty() {
+                true => println!("no backups found"),
+                false => table.print(None),
+            }
+        }
+        Command::Restore { from: Some(_), .. } if stdio => {
+            return Err(AppError::NotWithStdio("restored from a backup"));
+        }
+        Command::Restore {
+            from: Some(time),
+            yes,
+            ..
+        } => {
+            let backup = find_backup(&data_file, &time)?;
+            let current = storage.load()?.len();
+            let count = load_backup(&backup, passphrase.as_deref())?.len();
+            println!(
+                "the backup from {} has {} records, and the data file has {} ({})",
+                backup_time(&backup),
+                count,
+                current,
+                count_change(current, count)
+            );
+            if !yes && !confirm("replace the data file with this backup?")? {
+                return Ok(());
+            }
+            // The current file is backed up too, so that restoring can be
+            // undone by restoring that backup.
+            let saved = backup_data_file(&data_file, BACKUPS_KEPT)?;
+            save_file(data_file.clone(), |file| {
+                std::io::copy(&mut File::open(&backup)?, file).map(|_| ())
+            })?;
+            if !is_encrypted_file(&data_file)? {
+                write_checksum(&data_file)?;
+            }
+            // The changes in the journal were made to the old file, so they
+            // can't be undone any more.
+            match fs::remove_file(&journal) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+            status!(
+                "restored the backup from {} (the old data file was backed up to {})",
+                backup_time(&backup),
+                saved.display()
+            );
+        }
+        Command::Restore { id: None, .. } => unreachable!(),
+        Command::Restore { id: Some(id), .. } => {
+            let mut recs = storage.load()?;
+            let mut trash = load_trash(&data_file, passphrase.as_deref(), opt.strict)?;
+            match trash.remove(id) {
+                Some(mut record) => {
+                    // Another contact may have been given the same id since
+                    // this one was removed.
+                    if recs.get(id).is_some() {
+                        record.id = recs.next_id();
+                    }
+                    let new_id = record.id;
+                    recs.add(record);
+                    storage.save(&recs)?;
+                    save_trash(&data_file, passphrase.as_deref(), &trash)?;
+                    status!("restored record {}", new_id);
+                }
+                None => return Err(AppError::NotInTrash(id)),
+            }
+        }
+        Command::Trash(TrashCommand::List { format }) => {
+            let trash = load_trash(&data_file, passphrase.as_deref(), opt.strict)?;
+            print_records(&trash.sorted(), format, true)?;
+        }
+        Command::Trash(TrashCommand::Empty {}) => {
+            let trash = load_trash(&data_file, passphrase.as_deref(), opt.strict)?;
+            let count = trash.sorted().len();
+            save_trash(&data_file, passphrase.as_deref(), &Records::new())?;
+            status!("permanently deleted {} records", count);
+        }
+        Command::Query {
+            query,
+            format,
+            no_header,
+        } => {
+            let recs = storage.load()?;
+            let mut records = recs.sorted();
+            records.retain(|rec| query.matches(rec));
+            print_records(&records, format, !no_header)?;
+        }
+        Command::Search {
+            query,
+            mut fields,
+            phone,
+            regex,
+            ignore_accents,
+            no_aliases,
+            phonetic,
+            tag,
+            format,
+            no_header,
+            template,
+        } => {
+            let recs = storage.load()?;
+            if phone {
+                fields = vec![Field::Phone];
+            }
+            // The pattern is checked before anything is searched, so an
+            // invalid pattern is reported as an error instead of a panic.
+            // Plain queries are escaped, so that the pattern matches them
+            // exactly. Either way, it is used to highlight the matches.
+            // Aliases of the name are highlighted too, as whole words.
+            let aliases = match regex || no_aliases {
+                true => vec![],
+                false => name_aliases(&search_key(&query, ignore_accents)),
+            };
+            let pattern = match (regex, aliases.is_empty()) {
+                (true, _) => query.clone(),
+                (false, true) => regex::escape(&query),
+                (false, false) => {
+                    let aliases: Vec<_> =
+                        aliases.iter().map(|alias| regex::escape(alias)).collect();
+                    format!(r"{}|\b(?:{})\b", regex::escape(&query), aliases.join("|"))
+                }
+            };
+            let pattern = RegexBuilder::new(&pattern).case_insensitive(true).build()?;
+            let mut results = if phonetic {
+                recs.search_phonetic(&query)
+            } else if regex {
+                recs.search_regex(&pattern, &fields)
+            } else if no_aliases {
+                recs.search_in(&query, &fields, ignore_accents)
+            } else {
+                recs.search_similar(&query, &fields, ignore_accents)
+            };
+            results.retain(|found| found.record.has_tags(&tag));
+            // The best matches are printed first. Sorting is stable, so
+            // matches that are just as good stay in order by id.
+            results.sort_by_key(|found| found.kind);
+            // The parts of the values that matched are highlighted as well,
+            // since they may not be spelled like the query, such as John for
+            // kathrine or Müller for muller.
+            let mut matched: Vec<_> = results
+                .iter()
+                .flat_map(|found| {
+                    found.positions.iter().filter_map(move |position| {
+                        let values = found.record.field_values(position.field);
+                        let text = values
+                            .get(position.value)?
+                            .get(position.start..position.end)?;
+                        (!text.is_empty()).then(|| regex::escape(text))
+                    })
+                })
+                .collect();
+            matched.sort();
+            matched.dedup();
+            let pattern = match (regex, matched.is_empty()) {
+                (false, false) => RegexBuilder::new(&format!("{}|{}", pattern, matched.join("|")))
+                    .case_insensitive(true)
+                    .build()?,
+                _ => pattern,
+            };
+            match template {
+                Some(template) => {
+                    let records: Vec<_> = results.iter().map(|found| found.record).collect();
+                    print_template(&records, &template);
+                }
+                None => print_matches(&results, format, !no_header, Some(&pattern))?,
+            }
+        }
+        Command::Birthdays { ics: Some(out), .. } => {
+            let recs = storage.load()?;
+            let mut found = recs.sorted();
+            found.retain(|record| record.birthday.is_some());
+            let calendar = format_birthday_calendar(&found, now());
+            save_file(out, |file| file.write_all(calendar.as_bytes()))?;
+            println!("exported {} birthdays", found.len());
+        }
+        Command::Birthdays { next, ics: None } => {
+            let recs = storage.load()?;
+            let today = Local::now().date_naive();
+            let mut upcoming: Vec<_> = recs
+                .sorted()
+                .into_iter()
+                .filter_map(|record| {
+                    let birthday = record.birthday?;
+                    let date = next_birthday(birthday, today);
+                    let days = (date - today).num_days();
+                    (days <= i64::from(next)).then_some((date, days, birthday, record))
+                })
+                .collect();
+            if upcoming.is_empty() {
+                status!("no birthdays in the next {} days", next);
+                return Ok(());
+            }
+            // Sorting is stable, so people who share a birthday stay in the
+            // same order as the other listings.
+            upcoming.sort_by_key(|(date, ..)| *date);
+            for (date, days, birthday, record) in upcoming {
+                println!("{}", birthday_line(&record.name, birthday, date, days));
+            }
+        }
+        Command::Qr { id, png, light } => {
+            let recs = storage.load()?;
+            let record = recs.get(id).ok_or(AppError::NotFound(id))?;
+            // Phones read the same vCard that export-vcf writes.
+            let code = qrcode::QrCode::new(format_vcard(record))?;
+            print!("{}", render_qr(&code, light));
+            println!();
+            if let Some(png) = png {
+                let image = code
+                    .render::<image::Luma<u8>>()
+                    .min_dimensions(400, 400)
+                    .build();
+                let mut buffer = std::io::Cursor::new(vec![]);
+                image.write_to(&mut buffer, image::ImageFormat::Png)?;
+                save_file(png.clone(), |file| file.write_all(buffer.get_ref()))?;
+                status!("saved the QR code to {}", png.display());
+            }
+        }
+        Command::ExportVcf { file } => {
+            let recs = storage.load()?;
+            let records = recs.into_vec();
+            let mut out = File::create(file)?;
+            let progress = progress_bar(records.len(), "exporting");
+            for record in progress.wrap_iter(records.iter()) {
+                out.write_all(format_vcard(record).as_bytes())?;
+            }
+            out.flush()?;
+            progress.finish_and_clear();
+            println!("exported {} records", records.len());
+        }
+        Command::ExportLdif { file, base_dn } => {
+            let recs = storage.load()?;
+            let records = recs.into_vec();
+            let mut out = File::create(file)?;
+            writeln!(out, "version: 1\n")?;
+            let mut names = HashSet::new();
+            let progress = progress_bar(records.len(), "exporting");
+            for record in progress.wrap_iter(records.iter()) {
+                let duplicate = !names.insert(record.name.to_lowercase());
+                let dn = ldif_dn(record, base_dn.as_deref(), duplicate);
+                out.write_all(format_ldif(record, &dn, duplicate).as_bytes())?;
+            }
+            out.flush()?;
+            progress.finish_and_clear();
+            println!("exported {} records", records.len());
+        }
+        Command::ImportVcf { file } => {
+            let mut recs = storage.load()?;
+            let mut buffer = String::new();
+            File::open(file)?.read_to_string(&mut buffer)?;
+            let cards = parse_vcards(&buffer);
+            let count = cards.len();
+            let progress = progress_bar(count, "importing");
+            // Each imported card is given the next available id.
+            for mut record in progress.wrap_iter(cards.into_iter()) {
+                record.id = recs.next_id();
+                recs.add(record);
+            }
+            progress.finish_and_clear();
+            storage.save(&recs)?;
+            status!("imported {} records", count);
+        }
+        Command::Import {
+            file,
+            source,
+            encoding,
+            keep_ids,
+        } => {
+            let mut recs = storage.load()?;
+            // Only p2 files remember which ids were already used.
+            let mut next_id = 1;
+            let reader = open_import(&file, encoding)?;
+            let other = match source {
+                ImportSource::P2 => {
+                    let other = match DataFormat::from_path(&file) {
+                        DataFormat::Csv => read_records(reader, CsvFormat::default(), opt.strict)?,
+                        format => Records::from_reader(reader, format)?,
+                    };
+                    next_id = other.next_unused_id();
+                    other.into_vec()
+                }
+                ImportSource::Csv(profile) => {
+                    let (records, columns) = read_foreign_csv(reader, profile, opt.strict)?;
+                    if !columns.is_empty() {
+                        status!(
+                            "skipped columns that p2 can't import: {}",
+                            columns.join(", ")
+                        );
+                    }
+                    records
+                }
+            };
+            let (mut imported, mut skipped) = (0, 0);
+            let progress = progress_bar(other.len(), "importing");
+            for mut record in progress.wrap_iter(other.into_iter()) {
+                // When keeping the ids, every record is copied as it is, and
+                // replaces any record here with the same id.
+                if keep_ids {
+                    recs.add(record);
+                    imported += 1;
+                    continue;
+                }
+                // Records that already exist with exactly the same details
+                // are skipped, whatever their id is.
+                if recs.sorted().iter().any(|rec| rec.same_contact(&record)) {
+                    skipped += 1;
+                    continue;
+                }
+                // The ids of the other file may already be used here, so
+                // imported records are given the next available id. UUIDs
+                // are kept, since they are unique unless the same record is
+                // imported twice.
+                if matches!(record.id, RecordId::Number(_)) || recs.get(record.id).is_some() {
+                    record.id = recs.next_id();
+                }
+                recs.add(record);
+                imported += 1;
+            }
+            progress.finish_and_clear();
+            // The ids of contacts removed from the other file aren't given
+            // out again either.
+            if keep_ids {
+                recs.saved_next_id = recs.saved_next_id.max(next_id);
+            }
+            storage.save(&recs)?;
+            status!(
+                "imported {} records, skipped {} duplicates",
+                imported,
+                skipped
+            );
+        }
+        Command::Merge {
+            template,
+            query,
+            filter,
+            out_dir,
+        } => {
+            let document = fs::read_to_string(&template)?;
+            let document = Template::from_document(&document).map_err(AppError::Template)?;
+            let recs = storage.load()?;
+            let mut found = match query {
+                Some(query) => recs.search(&query),
+                None => recs.sorted(),
+            };
+            found.retain(|record| matches_filter(&filter, record));
+            let extension = template
+                .extension()
+                .and_then(|extension| extension.to_str());
+            fs::create_dir_all(&out_dir)?;
+            let progress = progress_bar(found.len(), "merging");
+            for record in progress.wrap_iter(found.iter()) {
+                let path = out_dir.join(merge_file_name(record, extension));
+                save_file(path, |file| {
+                    file.write_all(document.render(record).as_bytes())
+                })?;
+            }
+            progress.finish_and_clear();
+            println!("wrote {} documents to {}", found.len(), out_dir.display());
+        }
+        Command::Touch { ids, date } => {
+            let mut recs = storage.load()?;
+            // Every id is checked first, so that a mistyped one doesn't
+            // leave only some of the contacts changed.
+            for id in ids.iter() {
+                recs.get(*id).ok_or(AppError::NotFound(*id))?;
+            }
+            let date = date.unwrap_or_else(|| Local::now().date_naive());
+            for id in ids.iter() {
+                recs.edit(*id, |record| record.last_contacted = Some(date));
+            }
+            storage.save(&recs)?;
+            match ids.as_slice() {
+                [id] => status!(
+                    "record {} was contacted on {}",
+                    output::id(&id.to_string()),
+                    date
+                ),
+                _ => status!("{} records were contacted on {}", ids.len(), date),
+            }
+        }
+        Command::Remind { .. } if stdio => return Err(AppError::NotWithStdio("given reminders")),
+        Command::Remind {
+            id,
+            after,
+            on,
+            note,
+        } => {
+            let recs = storage.load()?;
+            let record = recs.get(id).ok_or(AppError::NotFound(id))?;
+            let due = due_date(on, after, Local::now().date_naive())?;
+            let mut reminders = load_reminders(&data_file, passphrase.as_deref())?;
+            let number = reminders.iter().map(|r| r.number).max().unwrap_or(0) + 1;
+            reminders.push(Reminder {
+                number,
+                contact: id,
+                due,
+                note,
+            });
+            save_reminders(&data_file, passphrase.as_deref(), &reminders)?;
+            status!("reminder {} set for {} on {}", number, record.name, due);
+        }
+        Command::Reminders {
+            done: Some(number), ..
+        } => {
+            let mut reminders = load_reminders(&data_file, passphrase.as_deref())?;
+            let count = reminders.len();
+            reminders.retain(|reminder| reminder.number != number);
+            if reminders.len() == count {
+                return Err(AppError::NoSuchReminder(number));
+            }
+            save_reminders(&data_file, passphrase.as_deref(), &reminders)?;
+            status!("reminder {} is done", number);
+        }
+        Command::Reminders { due, format, .. } => {
+            let recs = storage.load()?;
+            let mut reminders = load_reminders(&data_file, passphrase.as_deref())?;
+            let today = Local::now().date_naive();
+            reminders.retain(|reminder| !due || reminder.due <= today);
+            reminders.sort_by_key(|reminder| (reminder.due, reminder.number));
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&reminders)?),
+                OutputFormat::Text if reminders.is_empty() => match due {
+                    true => status!("no reminders are due"),
+                    false => status!("no reminders"),
+                },
+                OutputFormat::Text => {
+                    let mut table = TextTable::new(&["NUMBER", "DUE", "CONTACT", "NOTE"], true);
+                    for reminder in &reminders {
+                        table.add_row(reminder_row(reminder, &recs, today));
+                    }
+                    table.print(None);
+                }
+            }
+        }
+        Command::Email { target, print } => {
+            let recs = storage.load()?;
+            let record = match find_one(&recs, &target, "email: ")? {
+                Some(record) => record,
+                None => {
+                    status!("no contact was picked");
+                    return Ok(());
+                }
+            };
+            let address = record
+                .emails
+                .first()
+                .ok_or_else(|| AppError::MissingField(record.name.clone(), "email address"))?;
+            if print {
+                println!("{}", address);
+                return Ok(());
+            }
+            match open_url(&mailto_url(address)) {
+                Ok(exit) if exit.success() => status!("writing to {} <{}>", record.name, address),
+                Ok(exit) => return Err(AppError::MailClient(exit.to_string())),
+                Err(e) => return Err(AppError::MailClient(e.to_string())),
+            }
+        }
+        Command::Copy { id, field, wait } => {
+            let recs = storage.load()?;
+            let record = recs.get(id).ok_or(AppError::NotFound(id))?;
+            let text = field
+                .value(record)
+                .ok_or_else(|| AppError::MissingField(record.name.clone(), field.as_str()))?;
+            copy_to_clipboard(text, wait)?;
+            status!(
+                "copied the {} of {} to the clipboard",
+                field.as_str(),
+                record.name
+            );
+        }
+        Command::Export {
+            query,
+            filter,
+            out,
+            format: ExportFormat::Mutt,
+        } => {
+            let recs = storage.load()?;
+            let mut found = match query {
+                Some(query) => recs.search(&query),
+                None => recs.sorted(),
+            };
+            found.retain(|record| matches_filter(&filter, record));
+            let mut file = File::create(out)?;
+            let mut taken = HashSet::new();
+            let mut count = 0;
+            let progress = progress_bar(found.len(), "exporting");
+            // An alias needs an email address, so contacts without one are
+            // left out.
+            for record in progress.wrap_iter(found.into_iter()) {
+                if let Some(email) = record.emails.first() {
+                    let nickname = nickname(record, &taken);
+                    writeln!(file, "{}", format_mutt_alias(&nickname, record, email))?;
+                    taken.insert(nickname);
+                    count += 1;
+                }
+            }
+            file.flush()?;
+            progress.finish_and_clear();
+            println!("exported {} aliases", count);
+        }
+        Command::Export {
+            query,
+            filter,
+            out,
+            format: ExportFormat::Xlsx,
+        } => {
+            let recs = storage.load()?;
+            let mut found = match query {
+                Some(query) => recs.search(&query),
+                None => recs.sorted(),
+            };
+            found.retain(|record| matches_filter(&filter, record));
+            let progress = progress_bar(found.len(), "exporting");
+            let spreadsheet = write_xlsx(&found, recs.extra_columns(), &progress)?;
+            progress.finish_and_clear();
+            save_file(out, |file| file.write_all(&spreadsheet))?;
+            println!("exported {} records", found.len());
+        }
+        Command::Export {
+            query,
+            filter,
+            out,
+            format: ExportFormat::Data(format),
+        } => {
+            let recs = storage.load()?;
+            // The matching records are copied into a separate set of records,
+            // so the usual save function can write them to the new file.
+            let mut subset = Records::new();
+            let mut found = match query {
+                Some(query) => recs.search(&query),
+                None => recs.sorted(),
+            };
+            found.retain(|record| matches_filter(&filter, record));
+            // The bar stays full while the file is written, which is quick
+            // compared to gathering the records.
+            let progress = progress_bar(found.len(), "exporting");
+            for record in progress.wrap_iter(found.into_iter()) {
+                subset.add(record.clone());
+            }
+            // CSV files are saved like the data file, so that names ending
+            // with ".gz" or ".tsv" work the same way.
+            match format {
+                DataFormat::Csv => save_records(out, &subset)?,
+                _ => save_file(out, |file| subset.to_writer(file, format))?,
+            }
+            progress.finish_and_clear();
+            println!("exported {} records", subset.sorted().len());
+        }
+        Command::Group(GroupCommand::Add { group, ids }) => {
+            let mut recs = storage.load()?;
+            // Every id is checked first, so that nothing is changed when
+            // one of them doesn't exist.
+            if let Some(id) = ids.iter().find(|id| recs.get(**id).is_none()) {
+                return Err(AppError::NotFound(*id));
+            }
+            for id in ids {
+                recs.tag(id, &group);
+                status!("added record {} to {}", id, group);
+            }
+            storage.save(&recs)?;
+        }
+        Command::Group(GroupCommand::Remove { group, ids }) => {
+            let mut recs = storage.load()?;
+            if let Some(id) = ids.iter().find(|id| recs.get(**id).is_none()) {
+                return Err(AppError::NotFound(*id));
+            }
+            for id in ids {
+                recs.untag(id, &group);
+                status!("removed record {} from {}", id, group);
+            }
+            storage.save(&recs)?;
+        }
+        Command::Group(GroupCommand::List {}) => {
+            let recs = storage.load()?;
+            let groups = recs.groups();
+            if groups.is_empty() {
+                println!("no groups found");
+            }
+            for (group, count) in groups {
+                println!("{} ({})", group, count);
+            }
+        }
+        Command::Group(GroupCommand::Members { group, format }) => {
+            let recs = storage.load()?;
+            let mut records = recs.sorted();
+            records.retain(|rec| rec.has_tag(&group));
+            print_records(&records, format, true)?;
+        }
+        Command::Dedup {
+            max_distance,
+            merge,
+            interactive,
+        } => {
+            let mut recs = storage.load()?;
+            let groups = recs.find_duplicates(max_distance);
+            if groups.is_empty() {
+                status!("no duplicates found");
+            }
+            let mut merged = 0;
+            // The id that each of the merged records now has.
+            let mut merged_ids = HashMap::new();
+            for ids in groups {
+                status!("possible duplicates:");
+                for id in ids.iter() {
+                    if let Some(record) = recs.get(*id) {
+                        status!("  {:?}", record);
+                    }
+                }
+                if merge || (interactive && confirm("merge these records?")?) {
+                    if let Some(record) = recs.merge(&ids) {
+                        status!("merged into {:?}", record);
+                        merged += 1;
+                        merged_ids.extend(ids.iter().map(|id| (*id, record.id)));
+                    }
+                }
+                status!();
+            }
+            if merged > 0 {
+                storage.save(&recs)?;
+                // Reminders for the duplicates are now for the merged record.
+                if !opt.dry_run {
+                    renumber_reminders(&data_file, passphrase.as_deref(), &merged_ids)?;
+                }
+                status!("merged {} groups of duplicates", merged);
+            }
+        }
+        Command::Undo {} => match undo_change(&storage, &journal)? {
+            Some(changes) => {
+                // The trash is kept in step with the undone changes: removed
+                // records come back out of the trash, and records that are no
+                // longer added go into it.
+                let mut trash = load_trash(&data_file, passphrase.as_deref(), opt.strict)?;
+                for change in changes.iter() {
+                    match (&change.before, &change.after) {
+                        (Some(_), None) => {
+                            trash.remove(change.id);
+                        }
+                        (None, Some(record)) => trash.add(record.clone()),
+                        _ => (),
+                    }
+                }
+                save_trash(&data_file, passphrase.as_deref(), &trash)?;
+                for change in changes {
+                    match (change.before, change.after) {
+                        (Some(_), None) => status!("restored removed record {}", change.id),
+                        (None, Some(_)) => status!("removed added record {}", change.id),
+                        _ => status!("reverted changes to record {}", change.id),
+                    }
+                }
+            }
+            None => status!("nothing to undo"),
+        },
+        Command::History { id } => {
+            let entries = load_history(&history, id)?;
+            if entries.is_empty() {
+                println!("no history for record {}", id);
+            }
+            for entry in entries {
+                // Times are saved in UTC, but shown in the local time zone.
+                let time = entry.time.with_timezone(&Local);
+                let time = time.format("%Y-%m-%d %H:%M:%S");
+                println!("{} {}", time, entry.operation.as_str());
+            }
+        }
+        Command::Log { count } => {
+            let name = data_file.file_name().unwrap_or_default().to_string_lossy();
+            let log = run_git(
+                &data_file,
+                &[
+                    "log",
+                    &format!("--max-count={}", count),
+                    "--date=format-local:%Y-%m-%d %H:%M",
+                    "--format=%h  %ad  %s",
+                    "--",
+                    &name,
+                ],
+            )?;
+            if log.is_empty() {
+                println!("no commits of {} found", data_file.display());
+            }
+            print!("{}", log);
+        }
+        Command::Repair { .. } if opt.backend != Backend::Csv => {
+            return Err(AppError::CsvOnly("repaired"));
+        }
+        Command::Repair { .. } if passphrase.is_some() => {
+            return Err(AppError::NotWhenEncrypted("repaired"));
+        }
+        Command::Validate { .. } if opt.backend != Backend::Csv => {
+            return Err(AppError::CsvOnly("validated"));
+        }
+        Command::Validate { .. } if passphrase.is_some() => {
+            return Err(AppError::NotWhenEncrypted("validated"));
+        }
+        // Validating and repairing are meant for files that were damaged or
+        // changed by hand, so a file that doesn't match its checksum is
+        // only warned about.
+        Command::Validate { format } => {
+            if !stdio {
+                verify_checksum(&data_file, true)?;
+            }
+            let file = open_input(&data_file)?;
+            let problems = validate_records(file, csv_format)?;
+            match format {
+                OutputFormat::Text => {
+                    for problem in problems.iter() {
+                        println!("line {}: {}", problem.line, problem.message);
+                    }
+                    match problems.len() {
+                        0 => println!("no problems found"),
+                        count => println!("found {} problems", count),
+                    }
+                }
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&problems)?),
+            }
+            // Scripts can check the exit status instead of reading the
+            // output to find out whether the data file is valid.
+            if !problems.is_empty() {
+                return Err(AppError::Invalid(problems.len()));
+            }
+        }
+        Command::Repair { out, rejects } => {
+            // The data file itself is never changed. Instead, the repaired
+            // records are written to a copy that can be checked first.
+            if !stdio {
+                verify_checksum(&data_file, true)?;
+            }
+            let file = open_input(&data_file)?;
+            let repair = repair_records(file, csv_format, id_policy)?;
+            for fix in repair.fixes.iter() {
+                println!("{}", fix);
+            }
+            for reject in repair.rejects.iter() {
+                println!("{}", reject);
+            }
+            if repair.blank_lines > 0 {
+                println!("dropped {} blank lines", repair.blank_lines);
+            }
+            let out = out.unwrap_or_else(|| sidecar_path(&data_file, "repaired"));
+            save_records(out.clone(), &repair.records)?;
+            println!(
+                "wrote {} records to {}",
+                repair.records.sorted().len(),
+                out.display()
+            );
+            // Rejected lines are written exactly as they were, so they can
+            // be fixed by hand and imported.
+            if !repair.rejects.is_empty() {
+                let rejects = rejects.unwrap_or_else(|| sidecar_path(&data_file, "rejects"));
+                let mut file = File::create(&rejects)?;
+                for reject in repair.rejects.iter() {
+                    writeln!(file, "{}", reject.text)?;
+                }
+                println!(
+                    "wrote {} lines that couldn't be repaired to {}",
+                    repair.rejects.len(),
+                    rejects.display()
+                );
+            }
+        }
+        Command::Tui { .. } => {
+            let recs = storage.load()?;
+            run_tui(
+                &storage,
+                &data_file,
+                passphrase.as_deref(),
+                opt.strict,
+                recs,
+            )?;
+        }
+        Command::Shell {} if stdio => return Err(AppError::NotWithStdio("edited in the shell")),
+        Command::Shell {} => {
+            Shell::new(&storage, &data_file, passphrase.as_deref(), opt.strict)?.run()?;
+        }
+        Command::Watch { .. } if stdio => return Err(AppError::NotWithStdio("watched")),
+        Command::Watch {
+            query,
+            filter,
+            format,
+        } => {
+            watch(&storage, &data_file, read_only, |recs| {
+                let mut records = match &query {
+                    Some(query) => recs.search(query),
+                    None => recs.sorted(),
+                };
+                records.retain(|rec| matches_filter(&filter, rec));
+                print_records(&records, format, true)
+            })?;
+        }
+        Command::Serve { addr } => {
+            serve(
+                &storage,
+                &data_file,
+                passphrase.as_deref(),
+                opt.strict,
+                &addr,
+                read_only,
+            )?;
+        }
+        Command::Encrypt {} => {
+            let passphrase = passphrase.expect("asked for before encrypting");
+            let records = storage.load()?;
+            check_schema(records.schema)?;
+            let trash = load_trash(&data_file, None, opt.strict)?;
+            let reminders = load_reminders(&data_file, None)?;
+            let synced = load_synced(&data_file, None, opt.strict)?;
+            save_encrypted(data_file.clone(), &records, &passphrase)?;
+            save_trash(&data_file, Some(&passphrase), &trash)?;
+            if !reminders.is_empty() {
+                save_reminders(&data_file, Some(&passphrase), &reminders)?;
+            }
+            if let Some(synced) = synced {
+                save_sidecar(&data_file, "sync", Some(&passphrase), &synced)?;
+            }
+            // The journal and history can't be encrypted, and they hold
+            // copies of the records, so they are removed. The checksum isn't
+            // needed either, since decrypting checks for changes already.
+            let checksum = sidecar_path(&data_file, "sha256");
+            for file_name in [&journal, &history, &checksum] {
+                match fs::remove_file(file_name) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => (),
+                }
+            }
+            status!("encrypted {}", data_file.display());
+        }
+        Command::Sync { .. } if stdio => return Err(AppError::NotWithStdio("synced")),
+        Command::Sync { remote } => {
+            let remote = remote.trim_end_matches('/');
+            // The records as they were after the last sync are kept next to
+            // the data file, so that we can tell which side changed what.
+            let base = load_sidecar(&data_file, "sync", passphrase.as_deref(), opt.strict)?;
+            let mut recs = storage.load()?;
+            let (theirs, version) = fetch_remote(remote)?;
+            let plan = plan_sync(&base, &mut recs, &theirs);
+            // Changes are pushed first. If that fails, nothing has been
+            // saved locally, and the next sync will try again.
+            if !plan.push.put.is_empty() || !plan.push.remove.is_empty() {
+                push_remote(remote, &plan.push, &version)?;
+            }
+            if plan.pulled > 0 || !plan.renumbered.is_empty() {
+                storage.save(&recs)?;
+            }
+            save_sidecar(&data_file, "sync", passphrase.as_deref(), &recs)?;
+            for conflict in &plan.conflicts {
+                let kept = match conflict.kept_remote {
+                    true => "remote",
+                    false => "local",
+                };
+                let warning = format!(
+                    "record {} was changed on both sides; kept the {} version",
+                    conflict.id, kept
+                );
+                eprintln!("{}", output::error(&warning));
+            }
+            for (old, new) in &plan.renumbered {
+                let warning = format!(
+                    "record {} was added on both sides; the local one is now record {}",
+                    old, new
+                );
+                eprintln!("{}", output::error(&warning));
+            }
+            status!(
+                "pushed {} changes and pulled {} changes ({} conflicts)",
+                plan.push.put.len() + plan.push.remove.len(),
+                plan.pulled,
+                plan.conflicts.len()
+            );
+        }
+        Command::Carddav(_) if stdio => return Err(AppError::NotWithStdio("synced")),
+        // The sync state holds copies of the records, which can't be
+        // encrypted.
+        Command::Carddav(_) if encrypted => return Err(AppError::NotWhenEncrypted("synced")),
+        Command::Carddav(command) => {
+            let (server, push) = match command {
+                CarddavCommand::Sync { server } => (server, true),
+                CarddavCommand::Pull { server } => (server, false),
+            };
+            let password = match server.user {
+                Some(_) => match std::env::var("P2_CARDDAV_PASSWORD") {
+                    Ok(password) => password,
+                    Err(_) => read_hidden("password: ")?,
+                },
+                None => String::new(),
+            };
+            let dav = CardDav::new(&server.url, server.user.as_deref(), &password);
+            let mut state = load_carddav_state(&data_file)?;
+            let mut recs = storage.load()?;
+            let mut trash = load_trash(&data_file, None, opt.strict)?;
+            let cards = state.entry(dav.url.clone()).or_default();
+            let result = sync_carddav(&dav, cards, &mut recs, &mut trash, push);
+            // Whatever was done before an error is saved, so that the next
+            // sync knows about the cards that were already sent.
+            storage.save(&recs)?;
+            save_trash(&data_file, None, &trash)?;
+            save_carddav_state(&data_file, &state)?;
+            let counts = result?;
+            status!(
+                "pushed {} changes and pulled {} changes ({} conflicts)",
+                counts.pushed,
+                counts.pulled,
+                counts.conflicts
+            );
+        }
+        Command::Backup { .. } if stdio => return Err(AppError::NotWithStdio("backed up")),
+        Command::Backup { keep } => {
+            let backup = backup_data_file(&data_file, keep)?;
+            println!("backed up {} to {}", data_file.display(), backup.display());
+        }
+        Command::Compress {} if stdio || opt.backend != Backend::Csv => {
+            return Err(AppError::CsvOnly("compressed"));
+        }
+        Command::Compress {} if passphrase.is_some() => {
+            return Err(AppError::NotWhenEncrypted("compressed"));
+        }
+        Command::Compress {} if is_compressed(&data_file)? => {
+            status!("{} is already compressed", data_file.display());
+        }
+        Command::Compress {} => {
+            // The file is compressed exactly as it is, instead of loading
+            // and saving the records, so lines that can't be read are kept.
+            let mut data = vec![];
+            File::open(&data_file)?.read_to_end(&mut data)?;
+            save_file(data_file.clone(), |file| {
+                let mut encoder = GzEncoder::new(file, flate2::Compression::default());
+                encoder.write_all(&data)?;
+                encoder.finish()?;
+                Ok(())
+            })?;
+            write_checksum(&data_file)?;
+            let size = fs::metadata(&data_file)?.len();
+            status!(
+                "compressed {} from {} to {} bytes",
+                data_file.display(),
+                data.len(),
+                size
+            );
+        }
+        Command::Sort { .. } if opt.backend != Backend::Csv => {
+            return Err(AppError::CsvOnly("sorted"));
+        }
+        Command::Sort { by, locale } => {
+            let mut recs = storage.load()?;
+            recs.format.sort = by;
+            recs.format.locale = locale.as_deref().and_then(known_language);
+            storage.save(&recs)?;
+            status!("sorted {} records by {}", recs.len(), by.as_str());
+        }
+        Command::Link {
+            id,
+            other,
+            relation,
+        } => {
+            // Semicolons separate the relations in the data file.
+            let relation = relation.trim();
+            if relation.is_empty() || relation.contains(';') {
+                return Err(AppError::InvalidRelation(relation.to_string()));
+            }
+            if id == other {
+                return Err(AppError::InvalidRelation(format!("{} to itself", id)));
+            }
+            let mut recs = storage.load()?;
+            for id in [id, other] {
+                recs.get(id).ok_or(AppError::NotFound(id))?;
+            }
+            recs.link(id, other, relation);
+            storage.save(&recs)?;
+            status!(
+                "{} is now the {} of {}",
+                recs[other].name,
+                relation,
+                recs[id].name
+            );
+        }
+        Command::Unlink { id, other } => {
+            let mut recs = storage.load()?;
+            match recs.unlink(id, other) {
+                0 => status!("records {} and {} aren't linked", id, other),
+                count => {
+                    storage.save(&recs)?;
+                    match count {
+                        1 => status!("removed the link"),
+                        _ => status!("removed {} links", count),
+                    }
+                }
+            }
+        }
+        Command::Migrate {} => {
+            let recs = storage.load()?;
+            check_schema(recs.schema)?;
+            if recs.schema == SCHEMA_VERSION {
+                status!(
+                    "{} already uses schema version {}",
+                    data_file.display(),
+                    SCHEMA_VERSION
+                );
+                return Ok(());
+            }
+            // --auto-backup has made a backup already.
+            if !opt.auto_backup && !stdio {
+                backup_data_file(&data_file, BACKUPS_KEPT)?;
+            }
+            let changes = SCHEMA_CHANGES
+                .iter()
+                .filter(|change| change.version > recs.schema);
+            for change in changes {
+                let mut steps = vec![];
+                if !change.added.is_empty() {
+                    steps.push(format!("added {}", change.added.join(", ")));
+                }
+                for (old, new) in change.renamed.iter() {
+                    steps.push(format!("renamed {} to {}", old, new));
+                }
+                steps.extend(change.note.map(String::from));
+                status!("version {}: {}", change.version, steps.join("; "));
+            }
+            storage.save(&recs)?;
+            status!(
+                "upgraded {} from schema version {} to {}",
+                data_file.display(),
+                recs.schema,
+                SCHEMA_VERSION
+            );
+        }
+        // The server that the contacts are synced with would still use the
+        // old ids, so the next sync would mix up every renumbered contact.
+        Command::Compact {} if sidecar_path(&data_file, "sync").exists() => {
+            return Err(AppError::SyncedIds(sidecar_path(&data_file, "sync")));
+        }
+        Command::Compact {} => {
+            let mut recs = storage.load()?;
+            let next_id = recs.next_unused_id();
+            // Every id that is still in use, and what it becomes.
+            let mut ids: HashMap<_, _> = recs.ids().map(|id| (id, id)).collect();
+            let changes = recs.compact();
+            ids.extend(changes.iter().copied());
+            for (old, new) in changes.iter() {
+                status!("{} -> {}", old, new);
+            }
+            if changes.is_empty() && recs.next_unused_id() == next_id {
+                status!("the ids have no gaps");
+                return Ok(());
+            }
+            if opt.dry_run {
+                status!("nothing was renumbered");
+                return Ok(());
+            }
+            storage.save(&recs)?;
+            // The other files next to the data file that refer to records
+            // by id are updated, so they still point at the same contacts.
+            if !stdio {
+                renumber_history(&history, &ids)?;
+                renumber_carddav_state(&data_file, &ids)?;
+                renumber_reminders(&data_file, passphrase.as_deref(), &ids)?;
+                match fs::remove_file(&journal) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => (),
+                }
+            }
+            status!(
+                "renumbered {} records, the next id is {}",
+                changes.len(),
+                recs.next_id()
+            );
+        }
+        Command::Decrypt {} => {
+            let records = storage.load()?;
+            check_schema(records.schema)?;
+            let trash = load_trash(&data_file, passphrase.as_deref(), opt.strict)?;
+            let reminders = load_reminders(&data_file, passphrase.as_deref())?;
+            let synced = load_synced(&data_file, passphrase.as_deref(), opt.strict)?;
+            save_records(data_file.clone(), &records)?;
+            write_checksum(&data_file)?;
+            save_trash(&data_file, None, &trash)?;
+            if !reminders.is_empty() {
+                save_reminders(&data_file, None, &reminders)?;
+            }
+            if let Some(synced) = synced {
+                save_sidecar(&data_file, "sync", None, &synced)?;
+            }
+            status!("decrypted {}", data_file.display());
+        }
+        // These are handled before the data file is opened.
+        Command::Profile(_)
+        | Command::Completions { .. }
+        | Command::GenMan { .. }
+        | Command::Diff { .. }
+        | Command::Init { .. } => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Returns which log messages to show. Warnings are always shown, and each
+/// "-v" shows more detail.
+fn log_targets(verbose: u8) -> tracing_subscriber::filter::Targets {
+    use tracing_subscriber::filter::{LevelFilter, Targets};
+    let level = match verbose {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+    // Libraries such as sled log a lot about their own workings, which
+    // isn't useful here, so only their warnings are shown.
+    Targets::new()
+        .with_target("p2_example", level)
+        .with_target("activities", level)
+        .with_default(LevelFilter::WARN)
+}
+
+/// Sends log messages to stderr.
+fn init_logging(verbose: u8) {
+    use tracing_subscriber::prelude::*;
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(output::stderr_color())
+        .with_target(false)
+        .without_time();
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(log_targets(verbose))
+        .init();
+}
+
+/// Returns the data file that the options point to: the one of the
+/// profile, "-d" or "CONTACTS_FILE", or else the default one.
+fn chosen_data_file(opt: &Opt) -> Result<PathBuf, AppError> {
+    // "-d" and "--profile" can't be used together, but "--profile" still
+    // needs to take priority over the data file from "CONTACTS_FILE".
+    match (&opt.data_file, &opt.profile) {
+        (_, Some(profile)) => profile_path(profile),
+        (Some(data_file), None) => Ok(data_file.clone()),
+        (None, None) => Ok(default_data_file()),
+    }
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    output::set_color(opt.color);
+    init_logging(opt.verbose);
+    // "run" takes ownership of the options, so these are kept for reporting
+    // any error that it returns.
+    let errors = opt.errors;
+    let data_file = match chosen_data_file(&opt) {
+        Ok(data_file) => data_file,
+        Err(e) => {
+            let profile = opt.profile.as_deref().unwrap_or_default();
+            report_error(&e, Path::new(profile), errors);
+            std::process::exit(e.exit_code());
+        }
+    };
+    if let Err(e) = run(opt, data_file.clone()) {
+        report_error(&e, &data_file, errors);
+        std::process::exit(e.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::*;
+
+    /// Makes a new, empty directory for a test to keep its files in.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("p2-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Runs p2 with the given arguments on a data file, like the command
+    /// line would.
+    fn run_p2(data_file: &Path, args: &[&str]) -> Result<(), AppError> {
+        let mut all = vec!["p2", "-d", data_file.to_str().unwrap()];
+        all.extend(args);
+        run(Opt::from_iter(all), data_file.to_path_buf())
+    }
+
+    /// Returns the name and contents of every file in a directory, so that
+    /// tests can check what a command wrote.
+    fn dir_contents(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.is_file())
+            .map(|path| {
+                let contents = fs::read(&path).unwrap();
+                (path, contents)
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn reads_stdin_only_once() {
+        let data = "id,name\n1,Ann\n";
+        let records = read_records(data.as_bytes(), CsvFormat::default(), false).unwrap();
+        let storage = StdioStorage {
+            strict: false,
+            format: CsvFormat::default(),
+            loaded: RefCell::new(Some(records)),
+        };
+        for _ in 0..2 {
+            assert_eq!(storage.load().unwrap().get(1.into()).unwrap().name, "Ann");
+        }
+        // Commands that need files next to the data file are refused
+        // before anything is read.
+        let stdio = Path::new("-");
+        for args in [
+            &["init"][..],
+            &["backup"],
+            &["shell"],
+            &["remind", "1", "--in", "1d"],
+        ] {
+            assert!(
+                matches!(run_p2(stdio, args), Err(AppError::NotWithStdio(_))),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn finds_the_data_file() {
+        std::env::set_var("XDG_DATA_HOME", "/data");
+        assert_eq!(default_data_file(), Path::new("/data/p2/contacts.csv"));
+        // Relative directories are ignored, as the XDG spec says.
+        std::env::set_var("XDG_DATA_HOME", "data");
+        assert!(default_data_file().ends_with(".local/share/p2/contacts.csv"));
+        let data_file = |args: &[&str]| {
+            let mut all = vec!["p2"];
+            all.extend(args);
+            all.push("list");
+            chosen_data_file(&Opt::from_iter(all)).unwrap()
+        };
+        std::env::remove_var("CONTACTS_FILE");
+        assert_eq!(data_file(&[]), default_data_file());
+        std::env::set_var("CONTACTS_FILE", "/home/c.csv");
+        assert_eq!(data_file(&[]), Path::new("/home/c.csv"));
+        assert_eq!(data_file(&["-d", "d.csv"]), Path::new("d.csv"));
+        std::env::remove_var("CONTACTS_FILE");
+    }
+
+    #[test]
+    fn keeps_profiles_in_the_config_directory() {
+        let dir = test_dir("profiles");
+        std::env::set_var("XDG_CONFIG_HOME", &dir);
+        let unused = dir.join("unused.csv");
+        run_p2(&unused, &["profile", "create", "work"]).unwrap();
+        run_p2(&unused, &["profile", "create", "home"]).unwrap();
+        assert!(matches!(
+            run_p2(&unused, &["profile", "create", "work"]),
+            Err(AppError::ProfileExists(_))
+        ));
+        assert_eq!(profile_names().unwrap(), vec!["home", "work"]);
+        let opt = Opt::from_iter(["p2", "--profile", "work", "list"]);
+        let work = chosen_data_file(&opt).unwrap();
+        assert_eq!(work, dir.join("p2").join("profiles").join("work.csv"));
+        run_p2(&work, &["add", "Ann"]).unwrap();
+        assert!(matches!(
+            profile_path("../work"),
+            Err(AppError::BadProfileName(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_compressed_files_compressed() {
+        // Every gzip file starts with these bytes.
+        let gzip = [0x1f, 0x8b];
+        let dir = test_dir("gzip");
+        let data_file = dir.join("c.csv.gz");
+        run_p2(&data_file, &["--create", "add", "Ann"]).unwrap();
+        run_p2(&data_file, &["add", "Bob"]).unwrap();
+        assert!(fs::read(&data_file).unwrap().starts_with(&gzip));
+        let recs = load_records(data_file, CsvFormat::default(), true).unwrap();
+        assert_eq!(recs.len(), 2);
+
+        // A plain file stays compressed once it has been compressed, even
+        // though its name doesn't end with ".gz".
+        let data_file = dir.join("c.csv");
+        run_p2(&data_file, &["--create", "add", "Ann"]).unwrap();
+        let plain = fs::read(&data_file).unwrap();
+        run_p2(&data_file, &["compress"]).unwrap();
+        let compressed = fs::read(&data_file).unwrap();
+        assert!(compressed.starts_with(&gzip));
+        let mut data = vec![];
+        decompress(compressed.as_slice())
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, plain);
+        run_p2(&data_file, &["add", "Bob"]).unwrap();
+        assert!(fs::read(&data_file).unwrap().starts_with(&gzip));
+        let recs = load_records(data_file, CsvFormat::default(), true).unwrap();
+        assert_eq!(recs.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_backups() {
+        let dir = test_dir("backups");
+        let data_file = dir.join("c.csv");
+        run_p2(&data_file, &["--create", "add", "Ann"]).unwrap();
+        let mut made = vec![];
+        for _ in 0..3 {
+            made.push(backup_data_file(&data_file, 2).unwrap());
+            // Backups made in the same millisecond would have the same name.
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        assert_eq!(list_backups(&data_file).unwrap(), made[1..]);
+        assert_eq!(fs::read(&made[2]).unwrap(), fs::read(&data_file).unwrap());
+
+        // --auto-backup keeps what the file was like before the change.
+        let before = fs::read(&data_file).unwrap();
+        run_p2(&data_file, &["--auto-backup", "add", "Bob"]).unwrap();
+        let backups = list_backups(&data_file).unwrap();
+        assert_eq!(backups.len(), 3);
+        assert_eq!(fs::read(backups.last().unwrap()).unwrap(), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn imports_other_encodings() {
+        let dir = test_dir("encodings");
+        let latin1 = dir.join("latin1.csv");
+        fs::write(&latin1, b"id,name\n1,J\xfcrgen\n").unwrap();
+        let mut utf16 = vec![0xff, 0xfe];
+        for unit in "id,name\n1,Zo\u{eb}\n".encode_utf16() {
+            utf16.extend(unit.to_le_bytes());
+        }
+        let utf16_file = dir.join("utf16.csv");
+        fs::write(&utf16_file, utf16).unwrap();
+
+        let read = |file: &Path, encoding| {
+            let mut text = String::new();
+            let encoding = Some(parse_encoding(encoding).unwrap());
+            open_import(file, encoding)
+                .unwrap()
+                .read_to_string(&mut text)
+                .unwrap();
+            text
+        };
+        assert_eq!(read(&latin1, "latin1"), "id,name\n1,J\u{fc}rgen\n");
+        assert_eq!(read(&utf16_file, "utf16"), "id,name\n1,Zo\u{eb}\n");
+        // The byte order mark wins over the encoding that was given.
+        assert_eq!(read(&utf16_file, "latin1"), "id,name\n1,Zo\u{eb}\n");
+        assert!(parse_encoding("klingon").is_err());
+
+        let data_file = dir.join("c.csv");
+        let latin1 = latin1.to_str().unwrap();
+        run_p2(
+            &data_file,
+            &["--create", "import", latin1, "--encoding", "latin1"],
+        )
+        .unwrap();
+        let recs = load_records(data_file, CsvFormat::default(), true).unwrap();
+        assert_eq!(recs.search("j\u{fc}rgen").len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shows_contacts_again_when_the_data_file_changes() {
+        let dir = test_dir("watch");
+        let data_file = dir.join("c.csv");
+        run_p2(&data_file, &["--create", "add", "Ann"]).unwrap();
+        let storage = open_storage(
+            Backend::Csv,
+            data_file.clone(),
+            true,
+            false,
+            CsvFormat::default(),
+            None,
+        );
+
+        let shown = std::cell::RefCell::new(Vec::new());
+        let result = watch(storage.as_ref(), &data_file, false, |recs| {
+            let names: Vec<_> = recs.sorted().iter().map(|rec| rec.name.clone()).collect();
+            shown.borrow_mut().push(names);
+            if shown.borrow().len() > 1 {
+                return Err(std::io::Error::other("seen"));
+            }
+            // The watch has started, so the changes are made now. The other
+            // file is written first and must not cause the records to be
+            // shown again.
+            let dir = dir.clone();
+            let data_file = data_file.clone();
+            std::thread::spawn(move || {
+                fs::write(dir.join("other.txt"), "not contacts").unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(300));
+                run_p2(&data_file, &["add", "Bob"]).unwrap();
+            });
+            Ok(())
+        });
+
+        assert!(matches!(result, Err(AppError::Io(_))));
+        assert_eq!(
+            shown.into_inner(),
+            vec![
+                vec!["Ann".to_string()],
+                vec!["Ann".to_string(), "Bob".to_string()]
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn draws_qr_codes() {
+        let code = qrcode::QrCode::new("BEGIN:VCARD").unwrap();
+        // Two rows of the code and its quiet zone of four modules on each
+        // side are drawn in each line.
+        let size = code.width() + 8;
+        let dark = render_qr(&code, false);
+        let light = render_qr(&code, true);
+        assert_eq!(dark.lines().count(), size.div_ceil(2));
+        assert!(dark.lines().all(|line| line.chars().count() == size));
+        // The quiet zone is light, so it is drawn with blocks only on a dark
+        // background.
+        assert!(dark
+            .lines()
+            .next()
+            .unwrap()
+            .chars()
+            .all(|c| c == '\u{2588}'));
+        assert!(light.lines().next().unwrap().chars().all(|c| c == ' '));
+        // The codes have an odd number of rows, so the last line only has
+        // the top half of the blocks. The rest is the same code inverted.
+        let (dark_rows, dark_last) = dark.rsplit_once('\n').unwrap();
+        let (light_rows, light_last) = light.rsplit_once('\n').unwrap();
+        let inverted: String = dark_rows
+            .chars()
+            .map(|c| match c {
+                '\u{2588}' => ' ',
+                ' ' => '\u{2588}',
+                '\u{2580}' => '\u{2584}',
+                '\u{2584}' => '\u{2580}',
+                c => c,
+            })
+            .collect();
+        assert_eq!(inverted, light_rows);
+        assert!(dark_last.chars().all(|c| c == '\u{2580}'));
+        assert!(light_last.chars().all(|c| c == ' '));
+
+        let dir = test_dir("qr");
+        let data_file = dir.join("c.csv");
+        let png = dir.join("ann.png");
+        run_p2(&data_file, &["--create", "add", "Ann"]).unwrap();
+        run_p2(&data_file, &["qr", "1", "--png", png.to_str().unwrap()]).unwrap();
+        let image = image::open(&png).unwrap();
+        assert!(image.width() >= 400 && image.height() >= 400);
+        assert!(matches!(
+            run_p2(&data_file, &["qr", "2"]),
+            Err(AppError::NotFound(RecordId::Number(2)))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_avatars() {
+        let dir = test_dir("avatars");
+        let data_file = dir.join("c.csv");
+        let picture = dir.join("ann.png");
+        fs::write(&picture, b"not really a picture").unwrap();
+        let picture = picture.to_str().unwrap();
+
+        // Local paths are made absolute, and URLs are kept as they are.
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(
+            parse_avatar("ann.png"),
+            cwd.join("ann.png").display().to_string()
+        );
+        assert_eq!(parse_avatar(picture), picture);
+        assert_eq!(
+            parse_avatar("https://example.com/ann.png"),
+            "https://example.com/ann.png"
+        );
+        assert_eq!(read_avatar(picture).unwrap(), b"not really a picture");
+        let url = format!("file://{}", picture);
+        assert_eq!(read_avatar(&url).unwrap(), b"not really a picture");
+        assert!(matches!(
+            read_avatar("/no/such/picture.png"),
+            Err(AppError::Io(_))
+        ));
+
+        run_p2(&data_file, &["--create", "add", "Ann", "--avatar", picture]).unwrap();
+        let recs = load_records(data_file.clone(), CsvFormat::default(), true).unwrap();
+        assert_eq!(recs.get(1.into()).unwrap().avatar.as_deref(), Some(picture));
+
+        // Pictures in vCards are links, and ones included in the card itself
+        // are left out.
+        let card = format_vcard(recs.get(1.into()).unwrap());
+        assert!(card.contains(&format!("PHOTO;VALUE=uri:file://{}\r\n", picture)));
+        assert_eq!(parse_vcards(&card)[0].avatar.as_deref(), Some(picture));
+        let embedded = "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Bob\r\nPHOTO;ENCODING=b;TYPE=JPEG:/9j/4AAQ\r\nEND:VCARD\r\n";
+        assert_eq!(parse_vcards(embedded)[0].avatar, None);
+
+        run_p2(&data_file, &["edit", "1", "--clear-avatar"]).unwrap();
+        let recs = load_records(data_file, CsvFormat::default(), true).unwrap();
+        assert_eq!(recs.get(1.into()).unwrap().avatar, None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shows_more_log_messages_with_each_v() {
+        use tracing::Level;
+        let verbose = |args: &[&str]| {
+            let args = ["p2"].iter().chain(args).chain(&["list"]);
+            Opt::from_iter_safe(args).unwrap().verbose
+        };
+        assert_eq!(verbose(&[]), 0);
+        assert_eq!(verbose(&["-v"]), 1);
+        assert_eq!(verbose(&["-vvv"]), 3);
+
+        let shown = |verbose, target, level| log_targets(verbose).would_enable(target, &level);
+        assert!(shown(0, "p2_example", Level::WARN));
+        assert!(!shown(0, "p2_example", Level::INFO));
+        assert!(shown(1, "p2_example", Level::INFO));
+        assert!(!shown(1, "activities::contacts", Level::DEBUG));
+        assert!(shown(2, "activities::contacts", Level::DEBUG));
+        assert!(!shown(2, "p2_example", Level::TRACE));
+        assert!(shown(5, "p2_example", Level::TRACE));
+        // Other libraries only ever show their warnings.
+        assert!(shown(3, "sled::pagecache", Level::WARN));
+        assert!(!shown(3, "sled::pagecache", Level::INFO));
+    }
+
+    #[test]
+    fn shows_progress_only_for_large_files_in_a_terminal() {
+        assert!(!shows_progress(10, true));
+        assert!(!shows_progress(PROGRESS_MIN_RECORDS - 1, true));
+        assert!(shows_progress(PROGRESS_MIN_RECORDS, true));
+        assert!(!shows_progress(50_000, false));
+        assert!(progress_bar(10, "importing").is_hidden());
+
+        // Every record is still imported and exported when there are enough
+        // of them for a progress bar.
+        let dir = test_dir("progress");
+        let data_file = dir.join("c.csv");
+        let other = dir.join("other.csv");
+        let count = PROGRESS_MIN_RECORDS + 200;
+        let mut csv = "id,name\n".to_string();
+        for i in 1..=count {
+            csv.push_str(&format!("{},Person {}\n", i, i));
+        }
+        fs::write(&other, csv).unwrap();
+        run_p2(&data_file, &["--create", "import", other.to_str().unwrap()]).unwrap();
+        let recs = load_records(data_file.clone(), CsvFormat::default(), true).unwrap();
+        assert_eq!(recs.sorted().len(), count);
+        let vcf = dir.join("c.vcf");
+        run_p2(&data_file, &["export-vcf", vcf.to_str().unwrap()]).unwrap();
+        let cards = fs::read_to_string(&vcf).unwrap();
+        assert_eq!(cards.matches("BEGIN:VCARD").count(), count);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn moves_contacts_to_a_sled_database() {
+        assert_eq!(sled_key(7.into()), "contact/7");
+
+        let dir = test_dir("sled");
+        let csv = dir.join("c.csv");
+        let db = dir.join("contacts.sled");
+        run_p2(&csv, &["--create", "add", "Ann", "-e", "ann@example.com"]).unwrap();
+        run_p2(&csv, &["add", "Bob"]).unwrap();
+        run_p2(&csv, &["add", "Cid"]).unwrap();
+        run_p2(&csv, &["remove", "--yes", "2"]).unwrap();
+        let sled = |args: &[&str]| {
+            let args: Vec<_> = ["--backend", "sled"].iter().chain(args).copied().collect();
+            run_p2(&db, &args)
+        };
+        let csv = csv.to_str().unwrap();
+        sled(&["--create", "import", csv, "--keep-ids"]).unwrap();
+
+        let storage = open_storage(
+            Backend::Sled,
+            db.clone(),
+            true,
+            false,
+            CsvFormat::default(),
+            None,
+        );
+        let recs = storage.load().unwrap();
+        let names: Vec<_> = recs
+            .sorted()
+            .iter()
+            .map(|rec| (rec.id, rec.name.clone()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![(1.into(), "Ann".to_string()), (3.into(), "Cid".to_string())]
+        );
+        assert_eq!(recs.get(1.into()).unwrap().emails, vec!["ann@example.com"]);
+
+        // The id of the contact removed before moving isn't given out again.
+        sled(&["add", "Dee"]).unwrap();
+        sled(&["remove", "--yes", "1"]).unwrap();
+        let recs = storage.load().unwrap();
+        assert!(recs.get(4.into()).is_some_and(|rec| rec.name == "Dee"));
+        assert!(recs.get(1.into()).is_none());
+        let keys: Vec<_> = open_sled(&db)
+            .unwrap()
+            .scan_prefix(SLED_RECORD_PREFIX)
+            .keys()
+            .map(|key| String::from_utf8(key.unwrap().to_vec()).unwrap())
+            .collect();
+        assert_eq!(keys, vec!["contact/3", "contact/4"]);
+
+        // Opening the database writes to it, so it can't be read-only.
+        assert!(matches!(
+            sled(&["--read-only", "list"]),
+            Err(AppError::NoReadOnly("sled"))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encrypts_the_records_of_the_last_sync() {
+        let dir = test_dir("encrypt-sync");
+        let data_file = dir.join("l.csv");
+        let remote_file = dir.join("remote.csv");
+        run_p2(&remote_file, &["--create", "add", "Bob"]).unwrap();
+        run_p2(&data_file, &["--create", "add", "Ann"]).unwrap();
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        std::thread::spawn(move || {
+            let storage = open_storage(
+                Backend::Csv,
+                remote_file.clone(),
+                true,
+                false,
+                CsvFormat::default(),
+                None,
+            );
+            let addr = addr.to_string();
+            serve(storage.as_ref(), &remote_file, None, true, &addr, false).unwrap();
+        });
+        while std::net::TcpStream::connect(addr).is_err() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let remote = format!("http://{}", addr);
+        let synced = sidecar_path(&data_file, "sync");
+        run_p2(&data_file, &["sync", "--remote", &remote]).unwrap();
+        assert!(String::from_utf8_lossy(&fs::read(&synced).unwrap()).contains("Ann"));
+
+        std::env::set_var("P2_PASSPHRASE", "secret");
+        run_p2(&data_file, &["encrypt"]).unwrap();
+        assert!(is_encrypted_file(&synced).unwrap());
+        run_p2(&data_file, &["--encrypt", "add", "Cid"]).unwrap();
+        run_p2(&data_file, &["--encrypt", "sync", "--remote", &remote]).unwrap();
+        run_p2(&data_file, &["decrypt"]).unwrap();
+        std::env::remove_var("P2_PASSPHRASE");
+
+        let synced = load_records(synced, CsvFormat::default(), true).unwrap();
+        assert_eq!(synced.sorted().len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deleting_contacts_moves_them_to_the_trash() {
+        let dir = test_dir("delete");
+        let data_file = dir.join("c.csv");
+        run_p2(&data_file, &["--create", "add", "Ann"]).unwrap();
+        run_p2(&data_file, &["add", "Bob"]).unwrap();
+        let storage = open_storage(
+            Backend::Csv,
+            data_file.clone(),
+            true,
+            false,
+            CsvFormat::default(),
+            None,
+        );
+
+        let mut tui = Tui {
+            storage: storage.as_ref(),
+            data_file: &data_file,
+            passphrase: None,
+            strict: true,
+            records: storage.load().unwrap(),
+            query: String::new(),
+            mode: Mode::ConfirmDelete(1.into()),
+            table: TableState::default(),
+            status: String::new(),
+        };
+        tui.handle_key(KeyEvent::from(KeyCode::Char('y')));
+        assert_eq!(tui.status, "moved record 1 to trash");
+
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server_file = data_file.clone();
+        std::thread::spawn(move || {
+            let storage = open_storage(
+                Backend::Csv,
+                server_file.clone(),
+                true,
+                false,
+                CsvFormat::default(),
+                None,
+            );
+            let addr = addr.to_string();
+            serve(storage.as_ref(), &server_file, None, true, &addr, false).unwrap();
+        });
+        while std::net::TcpStream::connect(addr).is_err() {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let response = ureq::delete(&format!("http://{}/contacts/2", addr))
+            .call()
+            .unwrap();
+        assert_eq!(response.status(), 204);
+
+        let trash = load_trash(&data_file, None, true).unwrap();
+        let names: Vec<_> = trash.sorted().iter().map(|rec| rec.name.clone()).collect();
+        assert_eq!(names, vec!["Ann", "Bob"]);
+        run_p2(&data_file, &["restore", "1"]).unwrap();
+        run_p2(&data_file, &["restore", "2"]).unwrap();
+        let recs = load_records(data_file, CsvFormat::default(), true).unwrap();
+        assert_eq!(recs.sorted().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_unknown_columns_in_sqlite() {
+        let dir = test_dir("sqlite-extra");
+        let csv = dir.join("c.csv");
+        let db = dir.join("c.sqlite");
+        fs::write(&csv, "id,name,pronouns,pager\n1,Ann,she/her,123\n2,Bob,,\n").unwrap();
+        let csv = csv.to_str().unwrap();
+        let args = [
+            "--backend",
+            "sqlite",
+            "--create",
+            "import",
+            csv,
+            "--keep-ids",
+        ];
+        run_p2(&db, &args).unwrap();
+
+        let storage = open_storage(
+            Backend::Sqlite,
+            db.clone(),
+            true,
+            false,
+            CsvFormat::default(),
+            None,
+        );
+        let recs = storage.load().unwrap();
+        let ann = &recs.get(1.into()).unwrap().extra;
+        assert_eq!(ann.get("pronouns").map(String::as_str), Some("she/her"));
+        assert_eq!(ann.get("pager").map(String::as_str), Some("123"));
+        // Saving the same records again leaves them as they were.
+        storage.save(&recs).unwrap();
+        assert_eq!(storage.load().unwrap().get(1.into()).unwrap().extra, *ann);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn describes_changes_in_commit_messages() {
+        let ann = Record::new(1.into(), "Ann".to_string());
+        let bob = Record::new(2.into(), "Bob".to_string());
+        let added = Change {
+            id: 1.into(),
+            before: None,
+            after: Some(ann.clone()),
+        };
+        let edited = Change {
+            id: 2.into(),
+            before: Some(bob.clone()),
+            after: Some(Record {
+                name: "Robert".to_string(),
+                ..bob.clone()
+            }),
+        };
+        let removed = Change {
+            id: 3.into(),
+            before: Some(bob),
+            after: None,
+        };
+        let changes = [added, edited, removed];
+        assert_eq!(commit_message(&changes[..1]), "add contact 1");
+        assert_eq!(commit_message(&changes[2..]), "remove contact 3");
+        assert_eq!(
+            commit_message(&changes),
+            "change 3 contacts: 1 added, 1 edited, 1 removed"
+        );
+        let opt = Opt::from_iter(["p2", "import-vcf", "cards.vcf"]);
+        assert_eq!(opt.cmd.name(), "import-vcf");
+    }
+
+    #[test]
+    fn skips_bad_batch_lines() {
+        let dir = test_dir("batch");
+        let data_file = dir.join("c.csv");
+        let batch = dir.join("new.txt");
+        fs::write(&batch, "name,email\nAnn,ann@x.com\n,nobody@x.com\n").unwrap();
+        let batch = batch.to_str().unwrap();
+        run_p2(&data_file, &["--create", "add", "--batch", batch]).unwrap();
+        let before = fs::read(&data_file).unwrap();
+        assert!(matches!(
+            run_p2(&data_file, &["--strict", "add", "--batch", batch]),
+            Err(AppError::Malformed(bad_lines)) if bad_lines.len() == 1
+        ));
+        assert_eq!(fs::read(&data_file).unwrap(), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_nothing_on_a_dry_run() {
+        let dir = test_dir("dry-run");
+        let data_file = dir.join("c.csv");
+        run_p2(&data_file, &["--create", "add", "Ann"]).unwrap();
+        run_p2(&data_file, &["add", "Ann", "-e", "ann@x.com"]).unwrap();
+        run_p2(&data_file, &["add", "Bob"]).unwrap();
+        run_p2(&data_file, &["add", "Cy"]).unwrap();
+        // Compacting has something to renumber, and dedup something to
+        // merge.
+        run_p2(&data_file, &["remove", "--yes", "3"]).unwrap();
+        let cards = dir.join("cards.vcf");
+        fs::write(&cards, "BEGIN:VCARD\r\nFN:Di\r\nEND:VCARD\r\n").unwrap();
+        let cards = cards.to_str().unwrap();
+        let before = dir_contents(&dir);
+        let commands: [&[&str]; 12] = [
+            &["add", "Cy"],
+            &["edit", "1", "Anne"],
+            &["remove", "--yes", "1"],
+            &["import-vcf", cards],
+            &["import", "--source", "p2", "-"],
+            &["dedup", "--merge"],
+            &["group", "add", "work", "1"],
+            &["group", "remove", "work", "1"],
+            &["link", "1", "4"],
+            &["unlink", "1", "4"],
+            &["touch", "1"],
+            &["compact"],
+        ];
+        for args in commands {
+            let mut all = vec!["p2"];
+            all.extend(args);
+            assert!(Opt::from_iter(all).cmd.supports_dry_run(), "{:?}", args);
+            if args[0] != "import" {
+                let mut all = vec!["--dry-run"];
+                all.extend(args);
+                run_p2(&data_file, &all).unwrap();
+            }
+        }
+        assert_eq!(dir_contents(&dir), before);
+        assert!(matches!(
+            run_p2(&data_file, &["--dry-run", "sort"]),
+            Err(AppError::NoDryRun)
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn migrates_old_data_files() {
+        let dir = test_dir("migrate");
+        let data_file = dir.join("c.csv");
+        fs::write(&data_file, "id,name,email\n1,Ann,ann@x.com\n").unwrap();
+        run_p2(&data_file, &["migrate"]).unwrap();
+        let data = fs::read_to_string(&data_file).unwrap();
+        let header = data.lines().next().unwrap();
+        assert!(header.contains(&format!("#schema={}", SCHEMA_VERSION)));
+        assert!(data.contains("ann@x.com"));
+        assert_eq!(dir_contents(&dir.join("c.csv.backups")).len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_empty_names() {
+        for args in [&["p2", "add", " "][..], &["p2", "edit", "1", ""]] {
+            assert!(Opt::from_iter_safe(args).is_err(), "{:?}", args);
+        }
+        assert!(Opt::from_iter_safe(["p2", "edit", "1", "Ann"]).is_ok());
+    }
+
+    #[test]
+    fn saves_edits_only_when_something_changed() {
+        let dir = test_dir("edit-nothing");
+        let data_file = dir.join("c.csv");
+        run_p2(&data_file, &["--create", "add", "Ann", "-p", "555"]).unwrap();
+        let before = dir_contents(&dir);
+        run_p2(&data_file, &["edit", "1"]).unwrap();
+        run_p2(&data_file, &["edit", "1", "-p", "555"]).unwrap();
+        assert_eq!(dir_contents(&dir), before);
+
+        run_p2(&data_file, &["edit", "1", "-p", "556"]).unwrap();
+        let recs = load_records(data_file, CsvFormat::default(), true).unwrap();
+        assert_eq!(recs.get(1.into()).unwrap().phone.as_deref(), Some("556"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn generates_man_pages_and_completions() {
+        let mut man = vec![];
+        write_man_page(&mut man, "p2").unwrap();
+        let man = String::from_utf8(man).unwrap();
+        assert!(man.contains(".SS \"p2 group add <group> [ids]...\"\nAdds contacts to a group\n"));
+        assert!(man.contains(".TP\n\\fB\\-t\\fR, \\fB\\-\\-tag\\fR \\fItag...\\fR\n"));
+        assert!(man.contains("[env: CONTACTS_FILE]\n"));
+        assert!(man.contains(".TP\n.B 5\nanother p2 process is using the data file\n"));
+        assert!(!man.contains("\\-\\-help"));
+    }
+
+    #[test]
+    fn completes_contact_ids() {
+        use structopt::clap::Shell;
+        let completions = |shell| {
+            let mut out = vec![];
+            write_completions(&mut out, shell, "p2").unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let bash = completions(Shell::Bash);
+        assert!(bash.contains("p2__group__add)"));
+        assert!(bash.contains("complete -F _p2_ids -o bashdefault -o default p2\n"));
+        let fish = completions(Shell::Fish);
+        assert!(fish.contains("complete -c p2 -n \"__fish_seen_subcommand_from show edit"));
+        assert!(!fish.contains("{bin}"));
+        assert!(completions(Shell::Zsh).contains("#compdef p2"));
+    }
+
+    #[test]
+    fn keeps_a_shell_history_for_each_data_file() {
+        let dir = test_dir("shell-history");
+        let mut histories = vec![];
+        for name in ["work.csv", "home.csv"] {
+            let data_file = dir.join(name);
+            run_p2(&data_file, &["--create", "add", "Ann"]).unwrap();
+            let storage = open_storage(
+                Backend::Csv,
+                data_file.clone(),
+                false,
+                false,
+                CsvFormat::default(),
+                None,
+            );
+            let shell = Shell::new(storage.as_ref(), &data_file, None, false).unwrap();
+            histories.push(shell.history_file());
+        }
+        assert_eq!(histories[0], dir.join("work.csv.shell_history"));
+        assert_eq!(histories[1], dir.join("home.csv.shell_history"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lists_backups_without_changing_anything() {
+        let modifies = |args: &[&str]| {
+            let mut all = vec!["p2"];
+            all.extend(args);
+            Opt::from_iter(all).cmd.modifies_records()
+        };
+        assert!(!modifies(&["restore", "--list"]));
+        assert!(modifies(&["restore", "3"]));
+        assert!(modifies(&["restore", "--from", "2024"]));
+
+        let dir = test_dir("restore-list");
+        let data_file = dir.join("c.csv");
+        run_p2(&data_file, &["--create", "add", "Ann"]).unwrap();
+        run_p2(&data_file, &["--auto-backup", "add", "Bob"]).unwrap();
+        let before = dir_contents(&dir.join("c.csv.backups"));
+        run_p2(
+            &data_file,
+            &["--read-only", "--auto-backup", "restore", "--list"],
+        )
+        .unwrap();
+        assert_eq!(dir_contents(&dir.join("c.csv.backups")), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checks_the_checksum() {
+        let dir = test_dir("checksum");
+        let data_file = dir.join("c.csv");
+        run_p2(&data_file, &["--create", "add", "Ann"]).unwrap();
+        verify_checksum(&data_file, false).unwrap();
+        // Only the data file and the checksum are left, without any
+        // temporary files.
+        let checksum = sidecar_path(&data_file, "sha256");
+        assert!(dir_contents(&dir).iter().any(|(path, _)| *path == checksum));
+        assert!(!dir_contents(&dir)
+            .iter()
+            .any(|(path, _)| path.ends_with(".tmp")));
+
+        let mut data = fs::read_to_string(&data_file).unwrap();
+        data.push_str("2,Bob,,,,,,,,,,,,,,\n");
+        fs::write(&data_file, data).unwrap();
+        for args in [&["list"][..], &["list", "--stream"], &["add", "Cy"]] {
+            assert!(matches!(
+                run_p2(&data_file, args),
+                Err(AppError::ChecksumMismatch(_))
+            ));
+        }
+        run_p2(&data_file, &["--ignore-checksum", "list"]).unwrap();
+        run_p2(&data_file, &["validate"]).unwrap();
+
+        // Files without a checksum, such as ones written by another
+        // program, aren't checked.
+        fs::remove_file(&checksum).unwrap();
+        verify_checksum(&data_file, false).unwrap();
+        run_p2(&data_file, &["list", "--stream"]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_nothing_when_read_only() {
+        let dir = test_dir("read-only");
+        let data_file = dir.join("c.csv");
+        run_p2(&data_file, &["--create", "add", "Ann"]).unwrap();
+        fs::remove_file(sidecar_path(&data_file, "lock")).unwrap();
+        let before = dir_contents(&dir);
+        run_p2(&data_file, &["--read-only", "list"]).unwrap();
+        run_p2(&data_file, &["--read-only", "show", "1"]).unwrap();
+        assert!(matches!(
+            run_p2(&data_file, &["--read-only", "add", "Bob"]),
+            Err(AppError::ReadOnly(_))
+        ));
+        assert_eq!(dir_contents(&dir), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn finds_the_next_birthday() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        let today = date(2023, 6, 15);
+        assert_eq!(next_birthday(date(1990, 6, 15), today), today);
+        assert_eq!(next_birthday(date(1990, 12, 1), today), date(2023, 12, 1));
+        assert_eq!(next_birthday(date(1990, 1, 3), today), date(2024, 1, 3));
+        // Leap day birthdays move to February 28 in other years.
+        assert_eq!(next_birthday(date(2000, 2, 29), today), date(2024, 2, 29));
+        let today = date(2024, 3, 1);
+        assert_eq!(next_birthday(date(2000, 2, 29), today), date(2025, 2, 28));
+    }
+
+    #[test]
+    fn picks_the_closest_matches_first() {
+        let items: Vec<_> = ["1  Bob Rowe", "2  Robert Brown", "3  Ann Lee"]
+            .iter()
+            .map(|item| item.to_string())
+            .collect();
+        assert_eq!(picker::matches("bo", &items), vec![0, 1]);
+        assert_eq!(picker::matches("br", &items), vec![1, 0]);
+        assert_eq!(picker::matches("", &items), vec![0, 1, 2]);
+        assert_eq!(picker::score("xyz", "Ann Lee"), None);
+    }
+
+    #[test]
+    fn round_trips_vcards() {
+        let record = Record {
+            id: 1.into(),
+            name: "Smith, Jr; John".to_string(),
+            emails: vec!["john@work.com".to_string(), "john@home.com".to_string()],
+            phone: Some("555-0100".to_string()),
+            tags: vec!["work".to_string(), "a, b".to_string()],
+            address: Some("1 Main St, Springfield".to_string()),
+            birthday: NaiveDate::from_ymd_opt(1990, 2, 28),
+            notes: Some("met at the conference; likes tea".to_string()),
+            avatar: Some("/home/john/photo.jpg".to_string()),
+            company: Some("Acme; Inc.".to_string()),
+            title: Some("Sales, East".to_string()),
+            nickname: Some("Johnny".to_string()),
+            ..Default::default()
+        };
+        let cards = parse_vcards(&format_vcard(&record));
+        assert_eq!(cards.len(), 1);
+        assert_eq!(cards[0].name, record.name);
+        assert_eq!(cards[0].emails, record.emails);
+        assert_eq!(cards[0].phone, record.phone);
+        assert_eq!(cards[0].tags, record.tags);
+        assert_eq!(cards[0].address, record.address);
+        assert_eq!(cards[0].birthday, record.birthday);
+        assert_eq!(cards[0].notes, record.notes);
+        assert_eq!(cards[0].avatar, record.avatar);
+        assert_eq!(cards[0].company, record.company);
+        assert_eq!(cards[0].title, record.title);
+        assert_eq!(cards[0].nickname, record.nickname);
+    }
+
+    #[test]
+    fn groups_records_by_company() {
+        let record = |id: i64, name: &str, company: Option<&str>| Record {
+            company: company.map(|company| company.to_string()),
+            ..Record::new(id.into(), name.to_string())
+        };
+        let records = [
+            record(1, "Ann", Some("Zeta")),
+            record(2, "Bob", None),
+            record(3, "Cy", Some("acme")),
+            record(4, "Di", Some("ACME ")),
+        ];
+        let groups = group_records(records.iter().collect(), GroupKey::Company);
+        let names: Vec<_> = groups.iter().map(|group| group.name.as_deref()).collect();
+        assert_eq!(names, vec![Some("acme"), Some("Zeta"), None]);
+        assert_eq!(groups[0].records.len(), 2);
+    }
+
+    #[test]
+    fn groups_records_by_letter() {
+        let records: Vec<_> = ["Zoe", "Åsa", "élodie", "'Adam'", "42 Club", "Erik"]
+            .iter()
+            .enumerate()
+            .map(|(id, name)| Record::new((id as i64).into(), name.to_string()))
+            .collect();
+        let letters = |language| -> Vec<String> {
+            group_by_letter(records.iter().collect(), language)
+                .into_iter()
+                .map(|group| {
+                    let names: Vec<_> = group.records.iter().map(|r| r.name.as_str()).collect();
+                    format!("{}: {}", group.name.unwrap(), names.join(", "))
+                })
+                .collect()
+        };
+        assert_eq!(
+            letters(None),
+            vec!["A: 'Adam', Åsa", "E: élodie, Erik", "Z: Zoe", "#: 42 Club"]
+        );
+        assert_eq!(letters(Some("sv"))[2..4], ["Z: Zoe", "Å: Åsa"]);
+    }
+
+    #[test]
+    fn merges_documents() {
+        let record = Record {
+            emails: vec!["ann@example.com".to_string()],
+            ..Record::new(7.into(), "Ann O'Lee".to_string())
+        };
+        let letter =
+            Template::from_document("Dear {{name}} ({{ email }}),\n{not a field}\n").unwrap();
+        assert_eq!(
+            letter.render(&record),
+            "Dear Ann O'Lee (ann@example.com),\n{not a field}\n"
+        );
+        assert!(Template::from_document("{{nmae}}").is_err());
+        assert!(Template::from_document("{{name").is_err());
+        assert_eq!(merge_file_name(&record, Some("txt")), "7-ann-o-lee.txt");
+    }
+
+    #[test]
+    fn truncates_table_columns() {
+        let row = vec!["1".to_string(), "Bartholomew".to_string(), "".to_string()];
+        let plain = |_, text: &str| text.to_string();
+        assert_eq!(format_row(&row, &[3, 6, 5], plain), "1    Barth…");
+        assert_eq!(truncate("héllo", 5), "héllo");
+    }
+
+    #[test]
+    fn renders_templates() {
+        let mut record = Record::new(1.into(), "Bob".to_string());
+        record.emails = vec!["bob@example.com".to_string(), "b@example.com".to_string()];
+        let template: Template = "{{{id}}} {name} <{email}>".parse().unwrap();
+        assert_eq!(template.render(&record), "{1} Bob <bob@example.com>");
+        assert!("{nmae}".parse::<Template>().is_err());
+        assert!("Hi {name".parse::<Template>().is_err());
+        assert!("{name}{".parse::<Template>().is_err());
+    }
+
+    #[test]
+    fn reads_batches() {
+        let data = "name,email\nAnn,ann@example.com\n\n,nobody@example.com\nBob\n";
+        let (records, bad_lines) = read_batch(data.as_bytes()).unwrap();
+        let names: Vec<_> = records.iter().map(|rec| rec.name.as_str()).collect();
+        assert_eq!(names, vec!["Ann", "Bob"]);
+        assert_eq!(records[0].emails, vec!["ann@example.com"]);
+        assert_eq!(bad_lines[0].number, 4);
+    }
+
+    #[test]
+    fn encrypts_with_passphrase() {
+        let data = b"id,name,email\n1,Bob,bob@example.com\n";
+        let encrypted = crypt::encrypt(data, "secret");
+        assert!(encrypted.starts_with(crypt::MAGIC));
+        assert_ne!(encrypted, crypt::encrypt(data, "secret"));
+        assert_eq!(crypt::decrypt(&encrypted, "secret").unwrap(), data);
+        assert_eq!(crypt::decrypt(&encrypted, "guess"), None);
+    }
+
+    #[test]
+    fn diffs_files() {
+        let mut before = Records::new();
+        before.add(Record::new(1.into(), "Ann".to_string()));
+        before.add(Record::new(2.into(), "Bob".to_string()));
+        let mut after = before.clone();
+        after.remove(1.into());
+        let mut bob = after.get(2.into()).unwrap().clone();
+        bob.phone = Some("555".to_string());
+        after.add(bob);
+        after.add(Record::new(3.into(), "Cy".to_string()));
+        let diff = diff_files(&before, &after);
+        assert_eq!(diff.added[0].name, "Cy");
+        assert_eq!(diff.removed[0].name, "Ann");
+        let change = FieldChange {
+            field: "phone".to_string(),
+            before: "".to_string(),
+            after: "555".to_string(),
+        };
+        assert_eq!(diff.changed[0].fields, vec![change]);
+    }
+
+    #[test]
+    fn plans_sync() {
+        let mut base = Records::new();
+        base.add(Record::new(1.into(), "Ann".to_string()));
+        base.add(Record::new(2.into(), "Bob".to_string()));
+        let mut local = base.clone();
+        let mut remote = base.clone();
+        local.remove(1.into());
+        let mut bob = remote.get(2.into()).unwrap().clone();
+        bob.name = "Robert".to_string();
+        remote.add(bob);
+        let plan = plan_sync(&base, &mut local, &remote);
+        assert_eq!(plan.push.remove, vec![RecordId::from(1)]);
+        assert_eq!(plan.pulled, 1);
+        assert_eq!(local.get(2.into()).unwrap().name, "Robert");
+        assert!(plan.conflicts.is_empty());
+    }
+
+    #[test]
+    fn replaces_tags_with_put() {
+        let mut recs = Records::new();
+        recs.add(Record::new(1.into(), "Ann".to_string()));
+        recs.tag(1.into(), "work");
+        recs.tag(1.into(), "family");
+        let input = r#"{"name": "Ann", "tags": ["family", "Family"]}"#;
+        replace_contact(&mut recs, 1.into(), serde_json::from_str(input).unwrap());
+        assert_eq!(recs[1.into()].tags, ["family"]);
+        // Leaving out the tags keeps them.
+        let input = r#"{"name": "Ann Lee"}"#;
+        replace_contact(&mut recs, 1.into(), serde_json::from_str(input).unwrap());
+        assert_eq!(recs[1.into()].tags, ["family"]);
+    }
+
+    #[test]
+    fn keeps_records_added_on_both_sides() {
+        let base = Records::new();
+        let mut local = Records::new();
+        let mut remote = Records::new();
+        local.add(Record::new(1.into(), "Ann".to_string()));
+        remote.add(Record::new(1.into(), "Bob".to_string()));
+        remote.add(Record::new(2.into(), "Cy".to_string()));
+        let plan = plan_sync(&base, &mut local, &remote);
+        assert!(plan.conflicts.is_empty());
+        assert_eq!(plan.renumbered, vec![(1.into(), 3.into())]);
+        let names: Vec<_> = local.sorted().iter().map(|r| r.name.clone()).collect();
+        assert_eq!(names, ["Bob", "Cy", "Ann"]);
+        assert_eq!(plan.push.put.len(), 1);
+        assert_eq!(plan.push.put[0].id, RecordId::from(3));
+    }
+
+    #[test]
+    fn matches_unsynced_cards() {
+        let uid = Uuid::new_v4();
+        let mut recs = Records::new();
+        recs.add(Record::new(1.into(), "Ann Lee".to_string()));
+        recs.edit(1.into(), |rec| rec.emails = vec!["ann@x.org".to_string()]);
+        recs.add(Record::new(RecordId::Uuid(uid), "Bob".to_string()));
+        let known = HashSet::new();
+        let card = "BEGIN:VCARD\r\nFN:ann lee\r\nEMAIL:ANN@x.org\r\nEND:VCARD\r\n";
+        let theirs = parse_vcards(card).remove(0);
+        assert_eq!(
+            matching_record(&recs, &known, card, &theirs),
+            Some(1.into())
+        );
+        // A name alone isn't enough.
+        let card = "BEGIN:VCARD\r\nFN:Ann Lee\r\nEND:VCARD\r\n";
+        let theirs = parse_vcards(card).remove(0);
+        assert_eq!(matching_record(&recs, &known, card, &theirs), None);
+        let card = format!("BEGIN:VCARD\r\nUID:{}\r\nFN:Robert\r\nEND:VCARD\r\n", uid);
+        let theirs = parse_vcards(&card).remove(0);
+        assert_eq!(
+            matching_record(&recs, &known, &card, &theirs),
+            Some(RecordId::Uuid(uid))
+        );
+        // Records that are already synced have their own cards.
+        let known = HashSet::from([RecordId::Uuid(uid)]);
+        assert_eq!(matching_record(&recs, &known, &card, &theirs), None);
+    }
+
+    #[test]
+    fn updates_vcards_in_place() {
+        let card =
+            "BEGIN:VCARD\r\nVERSION:3.0\r\nFN:Ann\r\nTEL:1\r\nTEL:2\r\nPHOTO:x\r\nEND:VCARD\r\n";
+        let base = parse_vcards(card).remove(0);
+        let record = Record {
+            name: "Ann Lee".to_string(),
+            ..base.clone()
+        };
+        let updated = update_vcard(card, Some(&base), &record);
+        assert!(updated.contains("TEL:2\r\nPHOTO:x\r\n"));
+        assert!(updated.contains("FN:Ann Lee\r\nN:Lee;Ann;;;\r\nEND:VCARD"));
+        assert!(!updated.contains("FN:Ann\r\n"));
+    }
+
+    #[test]
+    fn imports_google_contacts() {
+        let data = "Name,E-mail 1 - Value,Labels,Photo\nAnn Lee,a@x.com ::: b@x.com,* myContacts ::: Work,x.jpg\n";
+        let (records, skipped) = read_foreign_csv(data.as_bytes(), &GoogleProfile, true).unwrap();
+        assert_eq!(records[0].name, "Ann Lee");
+        assert_eq!(records[0].emails, vec!["a@x.com", "b@x.com"]);
+        assert_eq!(records[0].tags, vec!["Work"]);
+        assert_eq!(skipped, vec!["Photo"]);
+    }
+
+    #[test]
+    fn imports_outlook_contacts() {
+        let data = "First Name,Last Name,E-mail Address,Categories,Birthday\n\
+                    Ann,Lee,a@x.com,Work;Golf,3/14/1990\n\
+                    Bob,,,,3/14/90\n\
+                    Cy,,,,1/2/05\n\
+                    Di,,,,0/0/00\n";
+        let (records, _) = read_foreign_csv(data.as_bytes(), &OutlookProfile, true).unwrap();
+        assert_eq!(records[0].name, "Ann Lee");
+        assert_eq!(records[0].emails, vec!["a@x.com"]);
+        assert_eq!(records[0].tags, vec!["Work", "Golf"]);
+        let birthdays: Vec<_> = records.iter().map(|record| record.birthday).collect();
+        assert_eq!(
+            birthdays,
+            vec![
+                NaiveDate::from_ymd_opt(1990, 3, 14),
+                NaiveDate::from_ymd_opt(1990, 3, 14),
+                NaiveDate::from_ymd_opt(2005, 1, 2),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn imports_thunderbird_contacts() {
+        let data = "Display Name,Primary Email,Birth Year,Birth Month,Birth Day\n\
+                    Ann Lee,a@x.com,1990,3,14\n\
+                    Bob,,90,3,14\n\
+                    Cy,,,3,14\n";
+        let (records, _) = read_foreign_csv(data.as_bytes(), &ThunderbirdProfile, true).unwrap();
+        assert_eq!(records[0].name, "Ann Lee");
+        assert_eq!(records[0].emails, vec!["a@x.com"]);
+        assert_eq!(records[0].birthday, NaiveDate::from_ymd_opt(1990, 3, 14));
+        assert_eq!(records[1].birthday, NaiveDate::from_ymd_opt(1990, 3, 14));
+        assert_eq!(records[2].birthday, None);
+    }
+
+    #[test]
+    fn expands_two_digit_years() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 30).unwrap();
+        assert_eq!(full_year(24, today), 2024);
+        assert_eq!(full_year(5, today), 2005);
+        assert_eq!(full_year(25, today), 1925);
+        assert_eq!(full_year(99, today), 1999);
+        assert_eq!(full_year(1890, today), 1890);
+    }
+
+    #[test]
+    fn writes_old_birthdays_to_xlsx_as_text() {
+        let mut ann = Record::new(1.into(), "Ann".to_string());
+        ann.birthday = NaiveDate::from_ymd_opt(1890, 5, 1);
+        let mut bob = Record::new(2.into(), "Bob".to_string());
+        bob.birthday = NaiveDate::from_ymd_opt(1990, 5, 1);
+        let spreadsheet = write_xlsx(&[&ann, &bob], &[], &ProgressBar::hidden()).unwrap();
+        assert!(spreadsheet.starts_with(b"PK"));
+    }
+
+    #[test]
+    fn leaves_out_ages_that_make_no_sense() {
+        let date = NaiveDate::from_ymd_opt(2024, 6, 30).unwrap();
+        let born = |year| NaiveDate::from_ymd_opt(year, 6, 30).unwrap();
+        assert_eq!(
+            birthday_line("Ann", born(1990), date, 0),
+            "Sun Jun 30  today        Ann turns 34"
+        );
+        assert_eq!(
+            birthday_line("Bob", born(2024), date, 1),
+            "Sun Jun 30  tomorrow     Bob"
+        );
+        assert_eq!(
+            birthday_line("Cy", born(2030), date, 5),
+            "Sun Jun 30  in 5 days    Cy"
+        );
+    }
+
+    #[test]
+    fn formats_ldif_values() {
+        assert_eq!(ldif_line("cn", "Ann Lee"), "cn: Ann Lee\n");
+        assert_eq!(ldif_line("cn", "Zoë"), "cn:: Wm/Dqw==\n");
+        assert_eq!(ldif_line("cn", " Ann"), "cn:: IEFubg==\n");
+        assert_eq!(escape_dn_value("Lee, Ann"), "Lee\\, Ann");
+    }
+
+    #[test]
+    fn names_duplicate_ldif_entries_by_id() {
+        let ann = Record::new(7.into(), "Ann".to_string());
+        let dn = ldif_dn(&ann, Some("dc=example,dc=com"), true);
+        assert_eq!(dn, "cn=Ann+uid=7,dc=example,dc=com");
+        let entry = format_ldif(&ann, &dn, true);
+        assert!(entry.contains("\ncn: Ann\n"));
+        assert!(entry.contains("\nuid: 7\n"));
+        let dn = ldif_dn(&ann, Some("dc=example,dc=com"), false);
+        assert!(!format_ldif(&ann, &dn, false).contains("uid:"));
+    }
+
+    #[test]
+    fn finds_stale_contacts() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 30).unwrap();
+        let mut ann = Record::new(1.into(), "Ann Lee".to_string());
+        assert!(is_stale(&ann, Some(90), today));
+        ann.last_contacted = NaiveDate::from_ymd_opt(2024, 4, 1);
+        assert!(!is_stale(&ann, Some(90), today));
+        assert!(is_stale(&ann, Some(parse_age("2m").unwrap()), today));
+        assert_eq!(parse_age("90d"), Ok(90));
+        assert_eq!(parse_age("1y"), Ok(365));
+        assert!(parse_age("3 days").is_err());
+    }
+
+    #[test]
+    fn parses_reminders() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 30).unwrap();
+        let due = |args: &[&str]| -> Result<NaiveDate, String> {
+            let mut all = vec!["p2", "remind", "1"];
+            all.extend(args);
+            match Opt::from_iter_safe(all).map_err(|e| e.message)?.cmd {
+                Command::Remind { on, after, .. } => {
+                    due_date(on, after, today).map_err(|e| e.to_string())
+                }
+                _ => unreachable!(),
+            }
+        };
+        assert_eq!(due(&["--in", "2w"]), Ok(today + chrono::Days::new(14)));
+        assert_eq!(
+            due(&["--on", "2030-01-01"]),
+            Ok(NaiveDate::from_ymd_opt(2030, 1, 1).unwrap())
+        );
+        assert!(due(&["--in", "2w", "--on", "2030-01-01"]).is_err());
+        assert_eq!(
+            due(&["--in", "99999999d"]),
+            Err("99999999 days from today is too far in the future".to_string())
+        );
+        let missing = due(&[]).unwrap_err();
+        assert!(missing.contains("--in") && missing.contains("--on"));
+    }
+
+    #[test]
+    fn lists_reminders() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 30).unwrap();
+        let mut recs = Records::new();
+        recs.add(Record::new(1.into(), "Ann".to_string()));
+        let reminder = |number, contact: i64, due| Reminder {
+            number,
+            contact: contact.into(),
+            due,
+            note: Some("call".to_string()),
+        };
+        let row = reminder_row(&reminder(1, 1, today.pred_opt().unwrap()), &recs, today);
+        assert_eq!(row, ["1", "2024-06-29 (overdue)", "Ann (1)", "call"]);
+        let row = reminder_row(&reminder(2, 1, today), &recs, today);
+        assert_eq!(row[1], "2024-06-30 (today)");
+        let row = reminder_row(&reminder(3, 5, today.succ_opt().unwrap()), &recs, today);
+        assert_eq!(row[1..3], ["2024-07-01", "record 5"]);
+    }
+
+    #[test]
+    fn builds_mailto_urls() {
+        assert_eq!(mailto_url("ann@example.com"), "mailto:ann@example.com");
+        assert_eq!(
+            mailto_url("ann+work@example.com"),
+            "mailto:ann%2Bwork@example.com"
+        );
+        assert_eq!(mailto_url("a b"), "mailto:a%20b");
+    }
+
+    #[test]
+    fn copies_business_card() {
+        let mut ann = Record::new(1.into(), "Ann Lee".to_string());
+        ann.emails = vec!["ann@example.com".to_string(), "al@example.com".to_string()];
+        ann.company = Some("Acme".to_string());
+        assert_eq!(CopyField::Email.value(&ann).unwrap(), "ann@example.com");
+        assert_eq!(CopyField::Phone.value(&ann), None);
+        assert_eq!(
+            CopyField::All.value(&ann).unwrap(),
+            "Ann Lee\nAcme\nann@example.com\nal@example.com"
+        );
+    }
+
+    #[test]
+    fn picks_unused_nicknames() {
+        let ann = Record::new(1.into(), "Ann Lee".to_string());
+        let mut taken = HashSet::new();
+        for expected in ["ann", "annl", "ann-lee", "ann-lee2"] {
+            let nickname = nickname(&ann, &taken);
+            assert_eq!(nickname, expected);
+            taken.insert(nickname);
+        }
     }
 }
@@ -0,0 +1,3668 @@
+//! Contacts and the data file they are saved in.
+//!
+//! A [`Record`] is one contact, and [`Records`] holds all of them along with
+//! the indexes used for searching. The data file is a CSV file with a header
+//! line: [`read_records`] and [`load_records`] read it, and [`save_records`]
+//! writes it back out safely by replacing the whole file at once.
+//!
+//! ```no_run
+//! use activities::contacts::{load_records, save_records, CsvFormat, Record};
+//!
+//! let path = std::path::PathBuf::from("contacts.csv");
+//! let mut records = load_records(path.clone(), CsvFormat::default(), false)?;
+//! let id = records.next_id();
+//! records.add(Record::new(id, "Ada Lovelace".to_string()));
+//! save_records(path, &records)?;
+//! # Ok::<(), activities::contacts::Error>(())
+//! ```
+
+use chrono::{DateTime, NaiveDate, SubsecRound, Utc};
+use flate2::bufread::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use rayon::prelude::*;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+use tracing::{debug, info, trace, warn};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use uuid::Uuid;
+
+/// Identifies a record. Ids are normally numbers, but they can also be
+/// random UUIDs, which won't collide when records from files on different
+/// machines are combined.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+// "untagged" saves the id as just the number or UUID, instead of wrapping it
+// in the name of the variant.
+#[serde(untagged)]
+pub enum RecordId {
+    Number(i64),
+    Uuid(Uuid),
+}
+
+impl Default for RecordId {
+    fn default() -> Self {
+        RecordId::Number(0)
+    }
+}
+
+impl From<i64> for RecordId {
+    fn from(id: i64) -> Self {
+        RecordId::Number(id)
+    }
+}
+
+impl std::fmt::Display for RecordId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RecordId::Number(id) => write!(f, "{}", id),
+            RecordId::Uuid(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+// Ids are shown the same way when debugging, so printed records look like
+// "Record { id: 1, ... }" instead of "Record { id: Number(1), ... }".
+impl std::fmt::Debug for RecordId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl FromStr for RecordId {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = s.parse::<i64>() {
+            return Ok(RecordId::Number(id));
+        }
+        match Uuid::parse_str(s) {
+            Ok(id) => Ok(RecordId::Uuid(id)),
+            Err(_) => Err(ParseError::InvalidId(s.to_string())),
+        }
+    }
+}
+
+/// A link from one contact to another. It is written as "spouse:7" in the
+/// data file, for a contact whose spouse is the contact with id 7.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Relation {
+    /// How the other contact is related, such as "spouse" or "manager".
+    pub kind: String,
+    /// The id of the other contact.
+    pub id: RecordId,
+}
+
+impl std::fmt::Display for Relation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.kind, self.id)
+    }
+}
+
+impl FromStr for Relation {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The id comes last and never has a colon in it, so the kind of
+        // relation is everything before the last colon.
+        match s.rsplit_once(':') {
+            Some((kind, id)) if !kind.trim().is_empty() => Ok(Relation {
+                kind: kind.trim().to_string(),
+                id: id.trim().parse()?,
+            }),
+            _ => Err(ParseError::InvalidRelation(s.to_string())),
+        }
+    }
+}
+
+/// How new records are given ids.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdScheme {
+    /// One more than the largest number already in use.
+    Number,
+    /// A random UUID.
+    Uuid,
+}
+
+/// How new records are given ids: which scheme is used, and whether ids of
+/// removed records may be given out again.
+///
+/// By default, ids are never reused. The data file remembers the next number
+/// to use, so removing the newest contact doesn't free up its id. This keeps
+/// the history and trash from mixing up two different contacts. When
+/// "reuse" is set, the next id is one more than the largest id in the data
+/// file, so the ids of removed contacts may be used again the next time the
+/// file is loaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdPolicy {
+    /// Whether new ids are numbers or UUIDs.
+    pub scheme: IdScheme,
+    /// Whether the ids of removed records may be used again.
+    pub reuse: bool,
+}
+
+impl IdPolicy {
+    /// Chooses the next id. "largest" is the largest number in use, and
+    /// "next_unused" is the smallest number that has never been used.
+    pub fn next_id(&self, largest: i64, next_unused: i64) -> RecordId {
+        match self.scheme {
+            // UUIDs are random, so there's no need to look at the other ids.
+            IdScheme::Uuid => RecordId::Uuid(Uuid::new_v4()),
+            IdScheme::Number if self.reuse => RecordId::Number(largest + 1),
+            IdScheme::Number => RecordId::Number(next_unused.max(largest + 1)),
+        }
+    }
+}
+
+impl FromStr for IdScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "number" => Ok(Self::Number),
+            "uuid" => Ok(Self::Uuid),
+            _ => Err(format!(
+                "unknown id scheme: {} (expected number or uuid)",
+                s
+            )),
+        }
+    }
+}
+
+/// The formats that a whole set of records can be written in and read from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataFormat {
+    Csv,
+    Json,
+    /// One JSON object on each line, which can also be used for the data
+    /// file.
+    JsonLines,
+    Yaml,
+}
+
+impl DataFormat {
+    /// Picks the format from a file's extension. Anything other than JSON
+    /// or YAML is read as CSV, since that is what data files use.
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        match extension.to_lowercase().as_str() {
+            "json" => Self::Json,
+            "jsonl" | "ndjson" => Self::JsonLines,
+            "yaml" | "yml" => Self::Yaml,
+            _ => Self::Csv,
+        }
+    }
+}
+
+/// What to do when two lines of the data file have the same id. Whatever
+/// is chosen, every collision is reported along with its line numbers.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Fail to load the file.
+    Error,
+    /// Keep the record from the earlier line and skip the later one.
+    KeepFirst,
+    /// Keep the record from the later line, which replaces the earlier one.
+    #[default]
+    KeepLast,
+    /// Keep both records, giving the later one a new id.
+    Renumber,
+}
+
+/// The order that records are saved in. Anything other than id order is
+/// remembered in the header line as "#sort=name", so that the data file
+/// stays sorted as records are added and changed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SortOrder {
+    /// By id, which is usually the order records were added in.
+    #[default]
+    Id,
+    /// By name, ignoring case and accents, in the order of the language
+    /// that the file is sorted for.
+    Name,
+    /// By first email address, ignoring case. Records without one go last.
+    Email,
+}
+
+impl SortOrder {
+    /// Returns the name used on the command line and in the header line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SortOrder::Id => "id",
+            SortOrder::Name => "name",
+            SortOrder::Email => "email",
+        }
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(Self::Id),
+            "name" => Ok(Self::Name),
+            "email" => Ok(Self::Email),
+            _ => Err(format!(
+                "unknown sort order: {} (expected name, email or id)",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "keep-first" => Ok(Self::KeepFirst),
+            "keep-last" => Ok(Self::KeepLast),
+            "renumber" => Ok(Self::Renumber),
+            _ => Err(format!(
+                "unknown duplicate policy: {} (expected error, keep-first, keep-last or renumber)",
+                s
+            )),
+        }
+    }
+}
+
+/// A contact record.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Unique ID for this record.
+    pub id: RecordId,
+    /// The name of the contact.
+    pub name: String,
+    /// The email addresses of the contact.
+    #[serde(default)]
+    pub emails: Vec<String>,
+    /// The phone number of the contact.
+    pub phone: Option<String>,
+    /// The groups this contact belongs to, such as "family" or "work".
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// When the contact was added. Contacts saved by older versions of the
+    /// program don't have this.
+    pub created_at: Option<DateTime<Utc>>,
+    /// When the contact was last changed.
+    pub updated_at: Option<DateTime<Utc>>,
+    /// The postal address of the contact.
+    pub address: Option<String>,
+    /// The birthday of the contact.
+    pub birthday: Option<NaiveDate>,
+    /// Anything else worth remembering about the contact.
+    pub notes: Option<String>,
+    /// Links to other contacts, such as a spouse or a manager.
+    #[serde(default)]
+    pub relations: Vec<Relation>,
+    /// A picture of the contact: the path of an image file, or its URL.
+    #[serde(default)]
+    pub avatar: Option<String>,
+    /// The company or organization that the contact works for.
+    #[serde(default)]
+    pub company: Option<String>,
+    /// The job title of the contact, such as "Sales Manager".
+    #[serde(default)]
+    pub title: Option<String>,
+    /// What the contact likes to be called, such as "Bob" for "Robert".
+    #[serde(default)]
+    pub nickname: Option<String>,
+    /// The last day the contact was called, written to, or met.
+    #[serde(default)]
+    pub last_contacted: Option<NaiveDate>,
+    /// Values from columns of the data file that this program doesn't know
+    /// about, by column name. They are written back out when saving.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub extra: HashMap<String, String>,
+}
+
+impl Record {
+    /// Creates a new contact with only a name. Both timestamps are set to
+    /// the current time.
+    pub fn new(id: RecordId, name: String) -> Self {
+        let now = now();
+        Self {
+            id,
+            name,
+            created_at: Some(now),
+            updated_at: Some(now),
+            ..Default::default()
+        }
+    }
+
+    /// Marks the contact as changed just now.
+    pub fn touch(&mut self) {
+        self.updated_at = Some(now());
+    }
+
+    /// Returns true if the contact has the given tag. Tags are compared
+    /// without regard to case.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags
+            .iter()
+            .any(|t| t.to_lowercase() == tag.to_lowercase())
+    }
+
+    /// Returns true if the contact has every one of the given tags.
+    pub fn has_tags(&self, tags: &[String]) -> bool {
+        tags.iter().all(|tag| self.has_tag(tag))
+    }
+
+    /// Checks whether two records hold exactly the same contact details.
+    /// The ids are not compared, since they depend on which file the record
+    /// came from.
+    pub fn same_contact(&self, other: &Record) -> bool {
+        self.name == other.name
+            && self.emails == other.emails
+            && self.phone == other.phone
+            && self.tags == other.tags
+            && self.company == other.company
+            && self.title == other.title
+            && self.nickname == other.nickname
+    }
+
+    /// Returns the values of a field as strings. Most fields have at most one
+    /// value, but a contact may have several emails.
+    pub fn field_values(&self, field: Field) -> Vec<String> {
+        match field {
+            Field::Id => vec![self.id.to_string()],
+            Field::Name => vec![self.name.clone()],
+            Field::Email => self.emails.clone(),
+            Field::Phone => self.phone.iter().cloned().collect(),
+            Field::Company => self.company.iter().cloned().collect(),
+            Field::Title => self.title.iter().cloned().collect(),
+            Field::Nickname => self.nickname.iter().cloned().collect(),
+        }
+    }
+}
+
+/// The fields of a record that can be searched.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    Id,
+    Name,
+    Email,
+    Phone,
+    Company,
+    Title,
+    Nickname,
+}
+
+impl Field {
+    /// The name of the field, as used on the command line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Field::Id => "id",
+            Field::Name => "name",
+            Field::Email => "email",
+            Field::Phone => "phone",
+            Field::Company => "company",
+            Field::Title => "title",
+            Field::Nickname => "nickname",
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "id" => Ok(Field::Id),
+            "name" => Ok(Field::Name),
+            "email" => Ok(Field::Email),
+            "phone" => Ok(Field::Phone),
+            "company" => Ok(Field::Company),
+            "title" => Ok(Field::Title),
+            "nickname" => Ok(Field::Nickname),
+            _ => Err(format!(
+                "unknown field: {} (expected id, name, nickname, email, phone, company, or title)",
+                s
+            )),
+        }
+    }
+}
+
+/// How well a value matched a search, from the best kind of match to the
+/// worst, so that search results can be ranked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchKind {
+    /// The whole value, or one of its words, is the query.
+    Exact,
+    /// The value, or one of its words, begins with the query.
+    Prefix,
+    /// The query is somewhere else in the value.
+    Substring,
+    /// The value is only spelled almost like the query, or sounds like it.
+    Fuzzy,
+}
+
+/// Where a search matched one of the values of a record, so that the match
+/// can be highlighted. "start" and "end" are byte positions in the value.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MatchPosition {
+    pub field: Field,
+    /// Which of the field's values matched, since a contact may have
+    /// several emails.
+    pub value: usize,
+    pub start: usize,
+    pub end: usize,
+    pub kind: MatchKind,
+}
+
+/// A record found by a search, along with the fields that matched.
+#[derive(Debug, Serialize)]
+pub struct SearchMatch<'a> {
+    /// "flatten" places the record's fields directly into the JSON object
+    /// instead of nesting them under a "record" key.
+    #[serde(flatten)]
+    pub record: &'a Record,
+    /// The fields that matched, in the order they were searched.
+    pub matched: Vec<Field>,
+    /// The best kind of match of any of the values, which the results are
+    /// ranked by.
+    #[serde(rename = "match")]
+    pub kind: MatchKind,
+    /// Where each value matched. Names found by how they sound have none,
+    /// since no part of them is spelled like the query.
+    pub positions: Vec<MatchPosition>,
+}
+
+/// The number of records needed before searches are done on several
+/// threads. Smaller searches finish faster on one thread, since starting
+/// the others takes longer than the search itself.
+const PARALLEL_SEARCH_MIN: usize = 10_000;
+
+/// Contains all saved records.
+#[derive(Debug, Clone)]
+pub struct Records {
+    /// The records by id. A BTreeMap keeps its keys sorted, so the records
+    /// always come out in order by id without having to sort them.
+    inner: BTreeMap<RecordId, Record>,
+    /// A lowercase copy of every name along with its record's id, sorted by
+    /// name. Searching looks through these instead of the records, so the
+    /// names don't have to be lowercased again for every search.
+    names: Vec<(String, RecordId)>,
+    /// The Soundex code of every word in every name, along with its record's
+    /// id. It is sorted just like "names", so that phonetic searches can
+    /// find every record with a code without looking at the others.
+    sounds: Vec<(String, RecordId)>,
+    /// How "next_id" creates ids for new records.
+    pub id_policy: IdPolicy,
+    /// The largest numeric id that has been added. Removing records doesn't
+    /// make it smaller, so "next_id" never has to look through every id.
+    largest_id: i64,
+    /// The next id as remembered by the data file. It is past the ids of
+    /// any records that were removed.
+    pub saved_next_id: i64,
+    /// The names of unknown columns in the data file, in their original
+    /// order.
+    extra_columns: Vec<String>,
+    /// How the data file was laid out, so that it is saved the same way.
+    /// The delimiter is None for records that weren't loaded from a file.
+    pub format: CsvFormat,
+    /// The schema version of the file the records were loaded from. Saving
+    /// always writes the current version.
+    pub schema: u32,
+}
+
+impl Default for Records {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Records {
+    /// Create a new records collection.
+    pub fn new() -> Self {
+        Self {
+            inner: BTreeMap::new(),
+            names: vec![],
+            sounds: vec![],
+            id_policy: IdPolicy {
+                scheme: IdScheme::Number,
+                reuse: false,
+            },
+            largest_id: 0,
+            saved_next_id: 1,
+            extra_columns: vec![],
+            format: CsvFormat::default(),
+            schema: SCHEMA_VERSION,
+        }
+    }
+
+    /// The names of the columns that p2 doesn't know about, in the order
+    /// they had in the data file.
+    pub fn extra_columns(&self) -> &[String] {
+        &self.extra_columns
+    }
+
+    /// Edit an existing record. Will insert a new record if the id is not found.
+    ///
+    /// The changes are made by the "change" function, which is given the
+    /// record to modify. Fields that it doesn't change, such as tags, are
+    /// kept as-is.
+    pub fn edit<F: FnOnce(&mut Record)>(&mut self, id: RecordId, change: F) {
+        // "entry" gives us the existing record if there is one, and
+        // "or_insert_with" creates a new one when there isn't.
+        self.note_id(id);
+        let record = self
+            .inner
+            .entry(id)
+            .or_insert_with(|| Record::new(id, String::new()));
+        let old_name = record.name.clone();
+        change(record);
+        record.touch();
+        // The name may have been changed, so the index needs updating.
+        let new_name = record.name.clone();
+        self.unindex_name(&old_name, id);
+        self.index_name(&new_name, id);
+    }
+
+    /// Adds a tag to a record. Returns false if the record does not exist.
+    pub fn tag(&mut self, id: RecordId, tag: &str) -> bool {
+        match self.inner.get_mut(&id) {
+            Some(record) => {
+                if !record.has_tag(tag) {
+                    record.tags.push(tag.to_string());
+                    record.touch();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes a tag from a record. Returns false if the record does not exist.
+    pub fn untag(&mut self, id: RecordId, tag: &str) -> bool {
+        match self.inner.get_mut(&id) {
+            Some(record) => {
+                if record.has_tag(tag) {
+                    record
+                        .tags
+                        .retain(|t| t.to_lowercase() != tag.to_lowercase());
+                    record.touch();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns every group along with the number of contacts in it. Groups
+    /// are sorted by name, and names are lowercase since tags ignore case.
+    pub fn groups(&self) -> Vec<(String, usize)> {
+        // A BTreeMap keeps its keys sorted, so the groups come out in order.
+        let mut groups = BTreeMap::new();
+        for record in self.inner.values() {
+            for tag in record.tags.iter() {
+                *groups.entry(tag.to_lowercase()).or_insert(0) += 1;
+            }
+        }
+        groups.into_iter().collect()
+    }
+
+    /// Returns the next available record id. This doesn't need to look at
+    /// the other records, since the largest id is kept track of as records
+    /// are added.
+    pub fn next_id(&self) -> RecordId {
+        self.id_policy.next_id(self.largest_id, self.saved_next_id)
+    }
+
+    /// Returns the smallest number that has never been used as an id. This
+    /// is saved in the data file.
+    pub fn next_unused_id(&self) -> i64 {
+        self.saved_next_id.max(self.largest_id + 1)
+    }
+
+    /// Keeps track of the largest id when a record is added.
+    fn note_id(&mut self, id: RecordId) {
+        if let RecordId::Number(id) = id {
+            self.largest_id = self.largest_id.max(id);
+        }
+    }
+
+    /// Adds a new record to the database.
+    pub fn add(&mut self, record: Record) {
+        self.note_id(record.id);
+        let (name, id) = (record.name.clone(), record.id);
+        // A record with the same id is replaced, so its old name has to be
+        // taken out of the index before the new one is added.
+        if let Some(old) = self.inner.insert(id, record) {
+            self.unindex_name(&old.name, old.id);
+        }
+        self.index_name(&name, id);
+    }
+
+    /// Adds a name to the name index, and the codes of its words to the
+    /// phonetic index. "binary_search" tells us where each entry belongs,
+    /// so the indexes stay sorted without sorting them again.
+    fn index_name(&mut self, name: &str, id: RecordId) {
+        let entry = (fold_case(name), id);
+        if let Err(pos) = self.names.binary_search(&entry) {
+            self.names.insert(pos, entry);
+        }
+        for code in name_sounds(name) {
+            let entry = (code, id);
+            if let Err(pos) = self.sounds.binary_search(&entry) {
+                self.sounds.insert(pos, entry);
+            }
+        }
+    }
+
+    /// Removes a name from the name index and the phonetic index.
+    fn unindex_name(&mut self, name: &str, id: RecordId) {
+        let entry = (fold_case(name), id);
+        if let Ok(pos) = self.names.binary_search(&entry) {
+            self.names.remove(pos);
+        }
+        for code in name_sounds(name) {
+            if let Ok(pos) = self.sounds.binary_search(&(code, id)) {
+                self.sounds.remove(pos);
+            }
+        }
+    }
+
+    /// Returns the ids of every record with a name containing a word with
+    /// the given Soundex code. The entries for a code are next to each
+    /// other in the index, and "partition_point" finds where they start.
+    fn ids_sounding(&self, code: &str) -> BTreeSet<RecordId> {
+        let start = self
+            .sounds
+            .partition_point(|(other, _)| other.as_str() < code);
+        self.sounds[start..]
+            .iter()
+            .take_while(|(other, _)| other == code)
+            .map(|(_, id)| *id)
+            .collect()
+    }
+
+    /// Searches for records whose names sound like the query, so that
+    /// "Jon Smyth" finds "John Smith". Every word of the query has to sound
+    /// like one of the words in the name.
+    pub fn search_phonetic(&self, query: &str) -> Vec<SearchMatch<'_>> {
+        let mut codes = name_sounds(query).into_iter();
+        let mut ids = match codes.next() {
+            Some(code) => self.ids_sounding(&code),
+            None => return vec![],
+        };
+        for code in codes {
+            let others = self.ids_sounding(&code);
+            ids.retain(|id| others.contains(id));
+        }
+        ids.iter()
+            .filter_map(|id| self.get(*id))
+            .map(|record| SearchMatch {
+                record,
+                matched: vec![Field::Name],
+                kind: MatchKind::Fuzzy,
+                positions: vec![],
+            })
+            .collect()
+    }
+
+    /// Returns the ids of every record with a name containing the query,
+    /// which must already be a search key made by "search_key". Only the
+    /// index is looked through, so no record is touched and no name is
+    /// case-folded again.
+    fn ids_named(&self, query: &str, ignore_accents: bool) -> BTreeSet<RecordId> {
+        self.names
+            .iter()
+            .filter(|(name, _)| match ignore_accents {
+                true => strip_accents(name).contains(query),
+                false => name.contains(query),
+            })
+            .map(|(_, id)| *id)
+            .collect()
+    }
+
+    /// Returns the ids of every record with a name containing the words of
+    /// the query as whole words. Like "ids_named", the query must already
+    /// be a search key.
+    fn ids_named_words(&self, query: &str, ignore_accents: bool) -> BTreeSet<RecordId> {
+        self.names
+            .iter()
+            .filter(|(name, _)| match ignore_accents {
+                true => contains_words(&strip_accents(name), query),
+                false => contains_words(name, query),
+            })
+            .map(|(_, id)| *id)
+            .collect()
+    }
+
+    /// Returns the ids of every record with a name spelled almost like the
+    /// query, as "find_similar" decides. Like "ids_named", only the index is
+    /// looked through.
+    fn ids_similar(&self, query: &str, ignore_accents: bool) -> BTreeSet<RecordId> {
+        self.names
+            .iter()
+            .filter(|(name, _)| find_similar(name, query, ignore_accents).is_some())
+            .map(|(_, id)| *id)
+            .collect()
+    }
+
+    /// Returns the record with the given id, if it exists.
+    pub fn get(&self, id: RecordId) -> Option<&Record> {
+        self.inner.get(&id)
+    }
+
+    /// Returns how many records there are.
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns true if there are no records.
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Returns the id of every record, in order.
+    pub fn ids(&self) -> impl Iterator<Item = RecordId> + '_ {
+        self.inner.keys().copied()
+    }
+
+    /// Returns references to every record, in order by id.
+    pub fn sorted(&self) -> Vec<&Record> {
+        self.inner.values().collect()
+    }
+
+    /// Returns references to every record in the given order. Records that
+    /// sort the same stay in order by id.
+    pub fn sorted_by(&self, order: SortOrder) -> Vec<&Record> {
+        let mut records = self.sorted();
+        match order {
+            SortOrder::Id => (),
+            SortOrder::Name => {
+                records.sort_by_cached_key(|record| collation_key(&record.name, self.format.locale))
+            }
+            // "None" sorts before "Some", so records without an email are
+            // moved to the end by sorting on whether they have one first.
+            SortOrder::Email => records.sort_by_cached_key(|record| {
+                let email = record.emails.first().map(|email| fold_case(email));
+                (email.is_none(), email)
+            }),
+        }
+        records
+    }
+
+    /// Converts this structure into a vector of Record, in order by id.
+    /// This is used when saving the data.
+    pub fn into_vec(self) -> Vec<Record> {
+        // "into_values" moves the records out of the map, so we can then
+        // move them into a vector.
+        self.inner.into_values().collect()
+    }
+
+    /// Searches for all records containing the supplied query in either
+    /// the name or any of the email addresses.
+    pub fn search(&self, query: &str) -> Vec<&Record> {
+        self.search_in(query, &[Field::Name, Field::Email], false)
+            .into_iter()
+            .map(|found| found.record)
+            .collect()
+    }
+
+    /// Searches for all records containing the supplied query in any of the
+    /// given fields. Phone numbers only compare digits, so "555-0100" will
+    /// match "(555) 0100". With "ignore_accents", "muller" also matches
+    /// "Müller".
+    pub fn search_in(
+        &self,
+        query: &str,
+        fields: &[Field],
+        ignore_accents: bool,
+    ) -> Vec<SearchMatch<'_>> {
+        self.search_names(query, &[], fields, ignore_accents, false)
+    }
+
+    /// Like "search_in", but names and nicknames are also found by the
+    /// other forms of the name in "NAME_ALIASES", so that "bob" finds
+    /// "Robert". Aliases have to match whole words, since short forms like
+    /// "al" are part of many other names.
+    pub fn search_with_aliases(
+        &self,
+        query: &str,
+        fields: &[Field],
+        ignore_accents: bool,
+    ) -> Vec<SearchMatch<'_>> {
+        let aliases = name_aliases(&search_key(query, ignore_accents));
+        self.search_names(query, &aliases, fields, ignore_accents, false)
+    }
+
+    /// Like "search_with_aliases", but names and nicknames that are spelled
+    /// almost like the query are found too, so that "kathrine" finds
+    /// "Katherine".
+    /// These are the worst kind of match, so they are ranked last.
+    pub fn search_similar(
+        &self,
+        query: &str,
+        fields: &[Field],
+        ignore_accents: bool,
+    ) -> Vec<SearchMatch<'_>> {
+        let aliases = name_aliases(&search_key(query, ignore_accents));
+        self.search_names(query, &aliases, fields, ignore_accents, true)
+    }
+
+    /// Does the work of "search_in", "search_with_aliases", and
+    /// "search_similar". "aliases" must already be search keys.
+    fn search_names(
+        &self,
+        query: &str,
+        aliases: &[String],
+        fields: &[Field],
+        ignore_accents: bool,
+        similar: bool,
+    ) -> Vec<SearchMatch<'_>> {
+        // The search is case-insensitive due to the usage of "search_key",
+        // which folds the case of both the query and the values.
+        let query = search_key(query, ignore_accents);
+        let digits = phone_digits(&query);
+        // Names are looked up in the index, which is already case-folded.
+        let named = if fields.contains(&Field::Name) {
+            let mut ids = self.ids_named(&query, ignore_accents);
+            for alias in aliases {
+                ids.extend(self.ids_named_words(alias, ignore_accents));
+            }
+            if similar {
+                ids.extend(self.ids_similar(&query, ignore_accents));
+            }
+            ids
+        } else {
+            BTreeSet::new()
+        };
+        // Aliases are other names rather than the query itself, so they
+        // never rank above a plain substring.
+        let find_name = |value: &str| {
+            find_key(value, &query, ignore_accents, false)
+                .or_else(|| {
+                    aliases
+                        .iter()
+                        .find_map(|alias| find_key(value, alias, ignore_accents, true))
+                        .map(|(kind, range)| (kind.max(MatchKind::Substring), range))
+                })
+                .or_else(|| match similar {
+                    true => find_similar(value, &query, ignore_accents),
+                    false => None,
+                })
+        };
+        // When only names are searched, the index already knows which
+        // records can match, so the others aren't looked at at all.
+        let only = match fields.iter().all(|field| *field == Field::Name) {
+            true => Some(&named),
+            false => None,
+        };
+        self.search_fields(fields, only, |record, field, value| match field {
+            Field::Name if !named.contains(&record.id) => None,
+            Field::Name | Field::Nickname => find_name(value),
+            Field::Phone if digits.is_empty() => None,
+            Field::Phone => find_digits(value, &digits),
+            _ => find_key(value, &query, ignore_accents, false),
+        })
+    }
+
+    /// Searches for all records with any of the given fields matching the
+    /// supplied regular expression.
+    pub fn search_regex(&self, regex: &Regex, fields: &[Field]) -> Vec<SearchMatch<'_>> {
+        self.search_fields(fields, None, |_, _, value| {
+            let found = regex.find(value)?;
+            Some((match_kind(value, &found.range()), found.range()))
+        })
+    }
+
+    /// Checks the given fields of every record using "matches", which is
+    /// called with the record, each field, and its value, and returns how
+    /// well the value matched and where. The matching records are returned
+    /// in order by id, along with which of their fields matched. With
+    /// "only", just the records with those ids are checked.
+    ///
+    /// Large record sets are split up and searched on several threads at
+    /// once. "Sync" is required for this, since "matches" is shared between
+    /// the threads.
+    fn search_fields<'a, F>(
+        &'a self,
+        fields: &[Field],
+        only: Option<&BTreeSet<RecordId>>,
+        matches: F,
+    ) -> Vec<SearchMatch<'a>>
+    where
+        F: Fn(&Record, Field, &str) -> Option<(MatchKind, Range<usize>)> + Sync,
+    {
+        let search = |rec: &'a Record| {
+            let mut matched = vec![];
+            let mut positions = vec![];
+            for field in fields.iter().copied() {
+                let count = positions.len();
+                for (value, text) in rec.field_values(field).iter().enumerate() {
+                    if let Some((kind, range)) = matches(rec, field, text) {
+                        positions.push(MatchPosition {
+                            field,
+                            value,
+                            start: range.start,
+                            end: range.end,
+                            kind,
+                        });
+                    }
+                }
+                if positions.len() > count {
+                    matched.push(field);
+                }
+            }
+            // Records where nothing matched have no best kind of match,
+            // and "?" drops them.
+            let kind = positions.iter().map(|position| position.kind).min()?;
+            Some(SearchMatch {
+                record: rec,
+                matched,
+                kind,
+                positions,
+            })
+        };
+        // The records are visited in order by id, and rayon puts the results
+        // of each thread back together in the same order, so the results
+        // are always in order by id.
+        if let Some(ids) = only {
+            ids.iter()
+                .filter_map(|id| self.inner.get(id))
+                .filter_map(search)
+                .collect()
+        } else if self.inner.len() < PARALLEL_SEARCH_MIN {
+            self.inner.values().filter_map(search).collect()
+        } else {
+            self.inner
+                .par_iter()
+                .filter_map(|(_, rec)| search(rec))
+                .collect()
+        }
+    }
+
+    /// Removes a record, returning it if it existed. Links from other
+    /// records to it are removed too, since they would point at nothing, or
+    /// at whichever contact is given its id later.
+    pub fn remove(&mut self, id: RecordId) -> Option<Record> {
+        let record = self.take(id)?;
+        for other in self.inner.values_mut() {
+            other.relations.retain(|relation| relation.id != id);
+        }
+        Some(record)
+    }
+
+    /// Removes a record without touching the links to it, for when the
+    /// record is about to be added back.
+    fn take(&mut self, id: RecordId) -> Option<Record> {
+        let record = self.inner.remove(&id)?;
+        self.unindex_name(&record.name, id);
+        Some(record)
+    }
+
+    /// Links a record to another one, replacing the link between them if
+    /// there already was one. Returns false if either record doesn't exist.
+    pub fn link(&mut self, id: RecordId, other: RecordId, kind: &str) -> bool {
+        if !self.inner.contains_key(&other) {
+            return false;
+        }
+        let record = match self.inner.get_mut(&id) {
+            Some(record) => record,
+            None => return false,
+        };
+        record.relations.retain(|relation| relation.id != other);
+        record.relations.push(Relation {
+            kind: kind.to_string(),
+            id: other,
+        });
+        record.touch();
+        true
+    }
+
+    /// Removes the links between two records, in both directions. Returns
+    /// how many links were removed.
+    pub fn unlink(&mut self, id: RecordId, other: RecordId) -> usize {
+        let mut count = 0;
+        for (from, to) in [(id, other), (other, id)] {
+            if let Some(record) = self.inner.get_mut(&from) {
+                let before = record.relations.len();
+                record.relations.retain(|relation| relation.id != to);
+                if record.relations.len() < before {
+                    count += before - record.relations.len();
+                    record.touch();
+                }
+            }
+        }
+        count
+    }
+
+    /// Returns the records that link to the given one, along with the kind
+    /// of each link, in order by id.
+    pub fn linked_from(&self, id: RecordId) -> Vec<(&Record, &str)> {
+        self.inner
+            .values()
+            .flat_map(|record| {
+                record
+                    .relations
+                    .iter()
+                    .filter(move |relation| relation.id == id)
+                    .map(move |relation| (record, relation.kind.as_str()))
+            })
+            .collect()
+    }
+
+    /// Finds groups of records that look like the same contact. Two records
+    /// are duplicates when they share an email address, or when their names
+    /// are at most "max_distance" edits apart once case, punctuation, and
+    /// spacing are ignored. Each group is in order by id, and the groups are
+    /// in order by their first id.
+    pub fn find_duplicates(&self, max_distance: usize) -> Vec<Vec<RecordId>> {
+        let records = self.sorted();
+        let names: Vec<_> = records
+            .iter()
+            .map(|rec| normalize_name(&rec.name))
+            .collect();
+
+        // Each record starts out in its own group. "parents" points each
+        // record at another record in the same group, and following the
+        // pointers always ends at the same record for the whole group.
+        // This is known as a "union-find" structure.
+        let mut parents: Vec<usize> = (0..records.len()).collect();
+        fn find(parents: &mut [usize], mut i: usize) -> usize {
+            while parents[i] != i {
+                parents[i] = parents[parents[i]];
+                i = parents[i];
+            }
+            i
+        }
+
+        // Records sharing an email are joined by remembering the first
+        // record seen with each email.
+        let mut emails: HashMap<String, usize> = HashMap::new();
+        for (i, rec) in records.iter().enumerate() {
+            for email in rec.emails.iter() {
+                let first = *emails.entry(email.to_lowercase()).or_insert(i);
+                let (a, b) = (find(&mut parents, first), find(&mut parents, i));
+                parents[b] = a;
+            }
+        }
+
+        // Every pair of names is compared. Names whose lengths differ by
+        // more than "max_distance" can never be close enough, so they are
+        // skipped without computing the distance.
+        for i in 0..records.len() {
+            for j in (i + 1)..records.len() {
+                let (a, b) = (&names[i], &names[j]);
+                let length_difference = a.chars().count().abs_diff(b.chars().count());
+                if length_difference <= max_distance && edit_distance(a, b) <= max_distance {
+                    let (a, b) = (find(&mut parents, i), find(&mut parents, j));
+                    parents[b] = a;
+                }
+            }
+        }
+
+        let mut groups: BTreeMap<usize, Vec<RecordId>> = BTreeMap::new();
+        for (i, rec) in records.iter().enumerate() {
+            let root = find(&mut parents, i);
+            groups.entry(root).or_default().push(rec.id);
+        }
+        let mut groups: Vec<_> = groups.into_values().filter(|ids| ids.len() > 1).collect();
+        groups.sort();
+        groups
+    }
+
+    /// Merges the records with the given ids into the first one. Emails,
+    /// tags and notes from every record are combined, and the details that
+    /// the first record is missing, such as its phone number or address, are
+    /// filled in from the other records. The other records are removed.
+    /// Returns None if the first record does not exist.
+    pub fn merge(&mut self, ids: &[RecordId]) -> Option<&Record> {
+        let (first, rest) = ids.split_first()?;
+        let mut merged = self.take(*first)?;
+        for id in rest {
+            if let Some(other) = self.take(*id) {
+                for email in other.emails {
+                    if !merged.emails.iter().any(|e| e.eq_ignore_ascii_case(&email)) {
+                        merged.emails.push(email);
+                    }
+                }
+                for tag in other.tags {
+                    if !merged.has_tag(&tag) {
+                        merged.tags.push(tag);
+                    }
+                }
+                merged.phone = merged.phone.or(other.phone);
+                merged.address = merged.address.or(other.address);
+                merged.birthday = merged.birthday.or(other.birthday);
+                merged.company = merged.company.or(other.company);
+                merged.title = merged.title.or(other.title);
+                merged.nickname = merged.nickname.or(other.nickname);
+                merged.avatar = merged.avatar.or(other.avatar);
+                // Any of the duplicates may have been contacted last.
+                merged.last_contacted = merged.last_contacted.max(other.last_contacted);
+                merged.notes = match (merged.notes, other.notes) {
+                    (Some(a), Some(b)) if a != b => Some(format!("{}\n{}", a, b)),
+                    (a, b) => a.or(b),
+                };
+                // The merged contact was added when the oldest of the
+                // duplicates was. "None" sorts first, so it is skipped.
+                merged.created_at = match (merged.created_at, other.created_at) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+                // Unknown columns are kept too, but the first record's values
+                // win when both have one.
+                for (column, value) in other.extra {
+                    merged.extra.entry(column).or_insert(value);
+                }
+                for relation in other.relations {
+                    if !merged.relations.iter().any(|r| r.id == relation.id) {
+                        merged.relations.push(relation);
+                    }
+                }
+            }
+        }
+        // Links to any of the duplicates now point at the merged record,
+        // except for the ones between the duplicates themselves.
+        merged
+            .relations
+            .retain(|relation| !ids.contains(&relation.id));
+        for record in self.inner.values_mut() {
+            for relation in record.relations.iter_mut() {
+                if rest.contains(&relation.id) {
+                    relation.id = *first;
+                }
+            }
+            let mut seen = BTreeSet::new();
+            record.relations.retain(|relation| seen.insert(relation.id));
+        }
+        merged.touch();
+        self.add(merged);
+        self.inner.get(first)
+    }
+
+    /// Gives the records numeric ids counting up from 1, in order by id, so
+    /// that the ids of removed records are no longer skipped. Records with
+    /// UUIDs keep them. Returns the old and new id of every record that was
+    /// renumbered.
+    pub fn compact(&mut self) -> Vec<(RecordId, RecordId)> {
+        let numbered: Vec<RecordId> = self
+            .inner
+            .keys()
+            .filter(|id| matches!(id, RecordId::Number(_)))
+            .copied()
+            .collect();
+        let count = numbered.len() as i64;
+        let changes: Vec<_> = numbered
+            .into_iter()
+            .zip((1..).map(RecordId::Number))
+            .filter(|(old, new)| old != new)
+            .collect();
+        // Every record is taken out before any are added back, so that a new
+        // id never replaces a record that hasn't been moved yet.
+        let moved: Vec<Record> = changes
+            .iter()
+            .filter_map(|(old, _)| self.take(*old))
+            .collect();
+        for ((_, new), mut record) in changes.iter().zip(moved) {
+            record.id = *new;
+            self.add(record);
+        }
+        // Links between records follow them to their new ids.
+        let ids: HashMap<_, _> = changes.iter().copied().collect();
+        for record in self.inner.values_mut() {
+            for relation in record.relations.iter_mut() {
+                if let Some(new) = ids.get(&relation.id) {
+                    relation.id = *new;
+                }
+            }
+        }
+        // The next id is the one right after the last record again.
+        self.largest_id = count;
+        self.saved_next_id = 1;
+        changes
+    }
+
+    /// Writes every record in the given format. CSV is the format of the
+    /// data file, while JSON and YAML hold the same records for other
+    /// programs to use.
+    pub fn to_writer<W: Write>(&self, mut writer: W, format: DataFormat) -> std::io::Result<()> {
+        match format {
+            DataFormat::Csv => write_csv(&mut writer, self, self.format.delimiter.unwrap_or(','))?,
+            DataFormat::Json => serde_json::to_writer_pretty(&mut writer, self)?,
+            DataFormat::JsonLines => write_json_lines(&mut writer, self)?,
+            DataFormat::Yaml => {
+                serde_yaml::to_writer(&mut writer, self).map_err(std::io::Error::other)?
+            }
+        }
+        writer.flush()
+    }
+
+    /// Reads records that were written by "to_writer". Lines of a CSV file
+    /// that can't be parsed are skipped, just like when loading the data
+    /// file.
+    pub fn from_reader<R: BufRead>(reader: R, format: DataFormat) -> Result<Self, Error> {
+        match format {
+            DataFormat::Csv => read_records(reader, CsvFormat::default(), false),
+            DataFormat::Json => Ok(serde_json::from_reader(reader)?),
+            DataFormat::JsonLines => read_json_lines(reader, false),
+            DataFormat::Yaml => Ok(serde_yaml::from_reader(reader)?),
+        }
+    }
+}
+
+/// "records[id]" is a shorter way to get a record that is known to exist.
+/// Like indexing a map, it panics if there is no record with that id.
+impl std::ops::Index<RecordId> for Records {
+    type Output = Record;
+
+    fn index(&self, id: RecordId) -> &Record {
+        &self.inner[&id]
+    }
+}
+
+/// Records are written as a list in order by id, just like the lines of
+/// the data file. Serde can then turn them into JSON, YAML, or any other
+/// format it supports.
+impl Serialize for Records {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.inner.values())
+    }
+}
+
+/// Records are read from a list, and added one at a time so that the name
+/// index is built along the way. A record with the same id as an earlier
+/// one replaces it.
+impl<'de> Deserialize<'de> for Records {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut records = Records::new();
+        for record in Vec::<Record>::deserialize(deserializer)? {
+            records.add(record);
+        }
+        Ok(records)
+    }
+}
+
+/// Prepares a name for comparison by making it lowercase, removing any
+/// punctuation, and collapsing runs of spaces.
+pub fn normalize_name(name: &str) -> String {
+    let name: String = name
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .collect();
+    name.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Computes the edit distance between two strings: the number of characters
+/// that must be inserted, removed, or changed to turn one into the other.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // "previous" holds the distances for the previous character of "a".
+    // Only two rows are needed at a time, instead of a whole table.
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let change = previous[j] + usize::from(ca != *cb);
+            let insert = current[j] + 1;
+            let remove = previous[j + 1] + 1;
+            current.push(change.min(insert).min(remove));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Folds the case of some text, so that it can be compared without caring
+/// about upper and lower case. This goes further than "to_lowercase": "ß"
+/// matches "ss", a final "ς" matches "σ", and the Turkish "İ" matches "i"
+/// instead of becoming an "i" with an extra dot.
+pub fn fold_case(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            'ß' | 'ẞ' => folded.push_str("ss"),
+            'ς' => folded.push('σ'),
+            'İ' => folded.push('i'),
+            _ => folded.extend(c.to_lowercase()),
+        }
+    }
+    folded
+}
+
+/// Removes accents, so that "Müller" becomes "Muller". "nfkd" splits each
+/// accented letter into the plain letter followed by its accent, which is
+/// a "combining mark" that can then be dropped.
+pub fn strip_accents(text: &str) -> String {
+    text.nfkd().filter(|c| !is_combining_mark(*c)).collect()
+}
+
+/// Prepares text for searching by folding its case, and by removing its
+/// accents as well when "ignore_accents" is set.
+pub fn search_key(text: &str, ignore_accents: bool) -> String {
+    match ignore_accents {
+        true => fold_case(&strip_accents(text)),
+        false => fold_case(text),
+    }
+}
+
+/// Letters that some languages treat as letters of their own, rather than
+/// as a letter with an accent, along with the letter they come after. In
+/// Swedish, "Åsa" is listed under "Å", after "Z", instead of under "A".
+const LOCALE_LETTERS: [(&str, &[(char, char)]); 7] = [
+    ("sv", &[('Å', 'Z'), ('Ä', 'Z'), ('Ö', 'Z')]),
+    ("fi", &[('Å', 'Z'), ('Ä', 'Z'), ('Ö', 'Z')]),
+    ("da", &[('Æ', 'Z'), ('Ø', 'Z'), ('Å', 'Z')]),
+    ("nb", &[('Æ', 'Z'), ('Ø', 'Z'), ('Å', 'Z')]),
+    ("nn", &[('Æ', 'Z'), ('Ø', 'Z'), ('Å', 'Z')]),
+    ("no", &[('Æ', 'Z'), ('Ø', 'Z'), ('Å', 'Z')]),
+    ("es", &[('Ñ', 'N')]),
+];
+
+/// Returns the letters of a language from "LOCALE_LETTERS". Languages that
+/// aren't listed there have none.
+pub fn locale_letters(language: Option<&str>) -> &'static [(char, char)] {
+    LOCALE_LETTERS
+        .iter()
+        .find(|(lang, _)| Some(*lang) == language)
+        .map_or(&[], |(_, letters)| letters)
+}
+
+/// Returns the language from "LOCALE_LETTERS", if it is listed there. Other
+/// languages sort names the same way as when no language is given, so they
+/// don't need to be remembered.
+pub fn known_language(language: &str) -> Option<&'static str> {
+    LOCALE_LETTERS
+        .iter()
+        .map(|(lang, _)| *lang)
+        .find(|lang| *lang == language)
+}
+
+/// Returns a key that sorts text the way that people who speak "language"
+/// expect. Case and accents are ignored, so "élodie" comes before "Erik",
+/// unless two names are the same without them. Letters that the language
+/// has of its own come after the letter that "LOCALE_LETTERS" lists them
+/// after, like "Åsa" after "Zoe" in Swedish.
+pub fn collation_key(text: &str, language: Option<&str>) -> (Vec<(char, usize)>, String) {
+    let letters = locale_letters(language);
+    let mut key = vec![];
+    for c in text.chars() {
+        let upper = c.to_uppercase().next().unwrap_or(c);
+        match letters.iter().position(|(own, _)| *own == upper) {
+            Some(i) => key.extend(
+                fold_case(&letters[i].1.to_string())
+                    .chars()
+                    .map(|c| (c, i + 1)),
+            ),
+            None => key.extend(search_key(&c.to_string(), true).chars().map(|c| (c, 0))),
+        }
+    }
+    (key, text.to_string())
+}
+
+/// Returns the Soundex code of a word, which is the same for words that
+/// sound alike in English: "Smith" and "Smyth" are both "S530". The first
+/// letter is kept, and the consonants after it are turned into digits, with
+/// similar sounding ones sharing a digit. Vowels are dropped.
+pub fn soundex(word: &str) -> Option<String> {
+    let code = |c: char| match c {
+        'B' | 'F' | 'P' | 'V' => Some('1'),
+        'C' | 'G' | 'J' | 'K' | 'Q' | 'S' | 'X' | 'Z' => Some('2'),
+        'D' | 'T' => Some('3'),
+        'L' => Some('4'),
+        'M' | 'N' => Some('5'),
+        'R' => Some('6'),
+        _ => None,
+    };
+    let word = strip_accents(word);
+    let mut letters = word
+        .chars()
+        .filter(|c| c.is_ascii_alphabetic())
+        .map(|c| c.to_ascii_uppercase());
+    let first = letters.next()?;
+    let mut result = first.to_string();
+    let mut last = code(first);
+    for c in letters {
+        let digit = code(c);
+        if digit.is_some() && digit != last {
+            result.extend(digit);
+            if result.len() == 4 {
+                break;
+            }
+        }
+        // Letters with the same digit are only written once, even with an
+        // "H" or "W" between them. A vowel between them counts them twice.
+        if !matches!(c, 'H' | 'W') {
+            last = digit;
+        }
+    }
+    Some(format!("{:0<4}", result))
+}
+
+/// Returns the Soundex codes of the words in a name.
+pub fn name_sounds(name: &str) -> Vec<String> {
+    name.split(|c: char| c.is_whitespace() || c == '-')
+        .filter_map(soundex)
+        .collect()
+}
+
+/// Groups of first names that are often used in place of each other, such
+/// as "Bob" for "Robert". A name may be in more than one group, like "Al",
+/// which is short for both "Albert" and "Alexander".
+pub const NAME_ALIASES: &[&[&str]] = &[
+    &["robert", "bob", "bobby", "rob", "robbie"],
+    &["william", "bill", "billy", "will", "willy", "liam"],
+    &["richard", "rick", "ricky", "rich", "dick"],
+    &["james", "jim", "jimmy", "jamie"],
+    &["john", "jack", "johnny", "jon"],
+    &["jonathan", "jon", "jonny"],
+    &["michael", "mike", "mikey", "mick"],
+    &["thomas", "tom", "tommy"],
+    &["christopher", "chris", "kit"],
+    &["joseph", "joe", "joey"],
+    &["charles", "charlie", "chuck"],
+    &["edward", "ed", "eddie", "ted", "ned"],
+    &["daniel", "dan", "danny"],
+    &["anthony", "tony"],
+    &["nicholas", "nick", "nicky"],
+    &["alexander", "alex", "al", "sandy"],
+    &["albert", "al", "bert"],
+    &["benjamin", "ben", "benny"],
+    &["matthew", "matt"],
+    &["andrew", "andy", "drew"],
+    &["steven", "stephen", "steve"],
+    &["david", "dave", "davey"],
+    &["timothy", "tim", "timmy"],
+    &["gregory", "greg"],
+    &["peter", "pete"],
+    &["samuel", "sam", "sammy"],
+    &["elizabeth", "liz", "lizzie", "beth", "betty", "eliza"],
+    &["margaret", "maggie", "meg", "peggy"],
+    &[
+        "katherine",
+        "catherine",
+        "kathryn",
+        "kate",
+        "katie",
+        "kathy",
+        "cathy",
+    ],
+    &["jennifer", "jen", "jenny"],
+    &["patricia", "pat", "patty", "tricia"],
+    &["susan", "sue", "susie"],
+    &["deborah", "deb", "debbie"],
+    &["rebecca", "becky", "becca"],
+    &["samantha", "sam", "sammy"],
+    &["victoria", "vicky", "tori"],
+    &["alexandra", "alex", "lexi", "sandy"],
+    &["abigail", "abby"],
+];
+
+/// Returns the other ways of writing a name, made by swapping its first
+/// word for each of its aliases, so that "bob lee" gives "robert lee". The
+/// name must already be a search key.
+pub fn name_aliases(name: &str) -> Vec<String> {
+    let (first, rest) = match name.split_once(' ') {
+        Some((first, rest)) => (first, Some(rest)),
+        None => (name, None),
+    };
+    let mut aliases = vec![];
+    for group in NAME_ALIASES.iter().filter(|group| group.contains(&first)) {
+        for alias in group.iter().filter(|alias| **alias != first) {
+            let alias = match rest {
+                Some(rest) => format!("{} {}", alias, rest),
+                None => alias.to_string(),
+            };
+            if !aliases.contains(&alias) {
+                aliases.push(alias);
+            }
+        }
+    }
+    aliases
+}
+
+/// Returns whether "words" is found in "text" as whole words, so that "rob"
+/// is found in "rob lee" and "lee, rob", but not in "robin lee".
+fn contains_words(text: &str, words: &str) -> bool {
+    text.match_indices(words).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + words.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+/// Returns the search key of a text, made by "search_key", along with the
+/// part of the text that each byte of the key came from. A match found in
+/// the key can then be found in the text, even where folding the case or
+/// removing accents changed the length of a character.
+fn keyed_text(text: &str, ignore_accents: bool) -> (String, Vec<Range<usize>>) {
+    let mut key = String::new();
+    let mut origins = vec![];
+    for (start, c) in text.char_indices() {
+        let folded = search_key(c.encode_utf8(&mut [0; 4]), ignore_accents);
+        key.push_str(&folded);
+        origins.extend(std::iter::repeat_n(
+            start..start + c.len_utf8(),
+            folded.len(),
+        ));
+    }
+    (key, origins)
+}
+
+/// Turns a range of a search key made by "keyed_text" into the range of
+/// the text it came from.
+fn text_range(origins: &[Range<usize>], range: Range<usize>) -> Range<usize> {
+    match (origins.get(range.start), range.end.checked_sub(1)) {
+        (Some(first), Some(last)) if !range.is_empty() => first.start..origins[last].end,
+        _ => 0..0,
+    }
+}
+
+/// Returns how well the part of a text in "range" matched a search.
+fn match_kind(text: &str, range: &Range<usize>) -> MatchKind {
+    let before = text[..range.start].chars().next_back();
+    let after = text[range.end..].chars().next();
+    if before.is_some_and(char::is_alphanumeric) {
+        MatchKind::Substring
+    } else if !after.is_some_and(char::is_alphanumeric) {
+        MatchKind::Exact
+    } else {
+        MatchKind::Prefix
+    }
+}
+
+/// Finds the query, which must already be a search key, in a value, and
+/// returns the best kind of match along with where it is in the value.
+/// With "whole_words", only whole words match, like for aliases.
+fn find_key(
+    value: &str,
+    query: &str,
+    ignore_accents: bool,
+    whole_words: bool,
+) -> Option<(MatchKind, Range<usize>)> {
+    let (key, origins) = keyed_text(value, ignore_accents);
+    key.match_indices(query)
+        .map(|(start, _)| start..start + query.len())
+        .filter(|range| {
+            let before = key[..range.start].chars().next_back();
+            let after = key[range.end..].chars().next();
+            !whole_words
+                || !before.is_some_and(char::is_alphanumeric)
+                    && !after.is_some_and(char::is_alphanumeric)
+        })
+        .map(|range| (match_kind(&key, &range), range))
+        .min_by_key(|(kind, _)| *kind)
+        .map(|(kind, range)| (kind, text_range(&origins, range)))
+}
+
+/// Finds the words of a name that are spelled almost like the query, such
+/// as "Katherine" for "kathrine", allowing one typo for every four letters of the
+/// query. Queries of several words are compared with the whole name.
+fn find_similar(
+    value: &str,
+    query: &str,
+    ignore_accents: bool,
+) -> Option<(MatchKind, Range<usize>)> {
+    let length = query.chars().count();
+    let max_distance = length / 4;
+    if max_distance == 0 {
+        return None;
+    }
+    let (key, origins) = keyed_text(value, ignore_accents);
+    let mut words = vec![];
+    if query.contains(' ') {
+        words.push(0..key.len());
+    } else {
+        // The end of the key is treated like a space, to finish the last
+        // word.
+        let mut start = None;
+        for (i, c) in key.char_indices().chain([(key.len(), ' ')]) {
+            match (c.is_alphanumeric(), start) {
+                (true, None) => start = Some(i),
+                (false, Some(first)) => {
+                    words.push(first..i);
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+    }
+    words
+        .into_iter()
+        .find(|range| {
+            let word = &key[range.clone()];
+            word.chars().count().abs_diff(length) <= max_distance
+                && edit_distance(word, query) <= max_distance
+        })
+        .map(|range| (MatchKind::Fuzzy, text_range(&origins, range)))
+}
+
+/// Finds the digits of a phone number in a value, skipping any other
+/// characters in it, so that "5550100" is found in "(555) 0100".
+fn find_digits(value: &str, digits: &str) -> Option<(MatchKind, Range<usize>)> {
+    let positions: Vec<_> = value
+        .char_indices()
+        .filter(|(_, c)| c.is_ascii_digit())
+        .map(|(i, _)| i)
+        .collect();
+    let all_digits = phone_digits(value);
+    let start = all_digits.find(digits)?;
+    let end = start + digits.len();
+    let kind = match (start, end == all_digits.len()) {
+        (0, true) => MatchKind::Exact,
+        (0, false) => MatchKind::Prefix,
+        _ => MatchKind::Substring,
+    };
+    Some((kind, positions[start]..positions[end - 1] + 1))
+}
+
+/// Strips everything except the digits from a phone number.
+pub fn phone_digits(phone: &str) -> String {
+    phone.chars().filter(|c| c.is_ascii_digit()).collect()
+}
+
+/// Splits a semicolon-delimited list, such as emails or tags, dropping any
+/// empty entries.
+pub fn split_list(list: &str) -> Vec<String> {
+    list.split(';')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| item.to_string())
+        .collect()
+}
+
+/// Errors that may occur while parsing the data file.
+#[derive(Error, Debug)]
+pub enum ParseError {
+    #[error("id must be a number or UUID: {0}")]
+    InvalidId(String),
+    #[error("relation must look like spouse:7: {0}")]
+    InvalidRelation(String),
+    #[error("empty record")]
+    EmptyRecord,
+    #[error("missing field: {0}")]
+    MissingField(String),
+    #[error("unterminated quoted field")]
+    UnterminatedQuote,
+    #[error("id {0} was already used on line {1}")]
+    DuplicateId(RecordId, usize),
+    #[error("missing END:VCARD")]
+    UnterminatedCard,
+    #[error("invalid timestamp: {0}")]
+    InvalidTimestamp(#[from] chrono::ParseError),
+    #[error("invalid date (expected YYYY-MM-DD): {0}")]
+    InvalidDate(String),
+    #[error("invalid JSON: {0}")]
+    InvalidJson(serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// Parses a date such as a birthday, which is written as "YYYY-MM-DD".
+pub fn parse_date(date: &str) -> Result<NaiveDate, ParseError> {
+    NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d")
+        .map_err(|_| ParseError::InvalidDate(date.to_string()))
+}
+
+/// Returns the current time. Timestamps are saved to the nearest second,
+/// so the fraction of a second is dropped to match what will be loaded.
+pub fn now() -> DateTime<Utc> {
+    Utc::now().trunc_subsecs(0)
+}
+
+/// Parses a timestamp written by "format_timestamp". An empty string means
+/// there is no timestamp.
+pub fn parse_timestamp(timestamp: &str) -> Result<Option<DateTime<Utc>>, chrono::ParseError> {
+    if timestamp.is_empty() {
+        return Ok(None);
+    }
+    let timestamp = DateTime::parse_from_rfc3339(timestamp)?;
+    Ok(Some(timestamp.with_timezone(&Utc)))
+}
+
+/// Formats a timestamp in the RFC 3339 format, such as
+/// "2024-01-31T12:00:00Z". Missing timestamps become an empty string.
+pub fn format_timestamp(timestamp: Option<DateTime<Utc>>) -> String {
+    timestamp
+        .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+        .unwrap_or_default()
+}
+
+/// Splits a single record into its fields, which are separated by
+/// "delimiter". This is usually a comma, but tabs and semicolons are common
+/// too.
+///
+/// Fields follow the quoting rules from RFC 4180: a field may be wrapped in
+/// double quotes, in which case it can contain delimiters, line breaks, and
+/// quotes. Quotes inside of a quoted field are escaped by doubling them ("").
+pub fn parse_fields(record: &str, delimiter: char) -> Result<Vec<String>, ParseError> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    // Tracks whether we are inside of a quoted field. Delimiters found while
+    // this is true are part of the data instead of being separators.
+    let mut in_quotes = false;
+    // "Peekable" lets us look at the next character without consuming it.
+    // This is needed to tell an escaped quote ("") apart from a closing quote.
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c != '"' {
+                field.push(c);
+            } else if chars.peek() == Some(&'"') {
+                chars.next();
+                field.push('"');
+            } else {
+                in_quotes = false;
+            }
+        } else {
+            match c {
+                // "mem::take" moves the finished field into the vector and
+                // leaves an empty string behind for the next field.
+                _ if c == delimiter => fields.push(std::mem::take(&mut field)),
+                '"' if field.is_empty() => in_quotes = true,
+                _ => field.push(c),
+            }
+        }
+    }
+    if in_quotes {
+        return Err(ParseError::UnterminatedQuote);
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+/// Formats a single field for writing. Fields containing the delimiter, a
+/// quote, or a line break are wrapped in quotes, and any quotes inside are
+/// doubled.
+pub fn escape_field(field: &str, delimiter: char) -> String {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Joins fields into a single CSV line, quoting them where needed.
+pub fn format_fields(fields: &[&str], delimiter: char) -> String {
+    let fields: Vec<_> = fields
+        .iter()
+        .map(|field| escape_field(field, delimiter))
+        .collect();
+    fields.join(&delimiter.to_string())
+}
+
+/// The delimiters that are recognized in the header line of a data file.
+const DELIMITERS: [char; 4] = [',', '\t', ';', '|'];
+
+/// Returns the delimiter that a file's name calls for, if any. Files named
+/// like "contacts.tsv" are separated by tabs.
+pub fn default_delimiter(file_name: &Path) -> Option<char> {
+    let name = file_name.to_string_lossy().to_lowercase();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    name.ends_with(".tsv").then_some('\t')
+}
+
+/// How a data file is laid out. Unless they are given, the delimiter and
+/// the order of the columns are found from the header line.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CsvFormat {
+    /// The character between fields, or None to find it from the header.
+    pub delimiter: Option<char>,
+    /// Set when the file has no header line, so that every line is a record
+    /// with its fields in the usual order.
+    pub no_header: bool,
+    /// What to do with records that use an id from an earlier line.
+    pub on_duplicate: DuplicatePolicy,
+    /// The order that records are saved in, found from the header line.
+    pub sort: SortOrder,
+    /// The language that names are sorted for, from "LOCALE_LETTERS", when
+    /// the file is sorted by name. It is remembered in the header line as
+    /// "#locale=sv".
+    pub locale: Option<&'static str>,
+}
+
+/// Parses a single record line. "columns" are the names of the columns from
+/// the header line, which may be in any order. When there is no header, it
+/// is empty and the fields are in the usual order.
+pub fn parse_record(
+    record: &str,
+    columns: &[String],
+    delimiter: char,
+) -> Result<Record, ParseError> {
+    // "parse_fields" breaks the record up on each delimiter (ignoring the
+    // ones inside of quotes) and gives us a vector containing every field.
+    let fields = parse_fields(record, delimiter)?;
+    record_from_fields(&fields, columns)
+}
+
+/// Builds a record from the fields of a line of the data file, which are
+/// found by the names of the "columns" just like in "parse_record".
+fn record_from_fields(fields: &[String], columns: &[String]) -> Result<Record, ParseError> {
+    // Looks up a field by the name of its column. Files written by other
+    // programs may have moved the columns around, so the header says where
+    // each one is. Missing columns are treated just like empty fields.
+    let field = |name: &str| {
+        let index = match columns.is_empty() {
+            true => CSV_COLUMNS.iter().position(|column| *column == name),
+            false => columns.iter().position(|column| is_column(column, name)),
+        };
+        index.and_then(|index| fields.get(index))
+    };
+
+    // The id and name fields are required, so a match expression is used
+    // in order to extract the data (if possible) and place it into the
+    // appropriate variables.
+
+    // Here we try to get the "id" portion of the record, which is usually
+    // the first entry (which is index 0). We then use "parse" to convert
+    // the string id into a number or UUID. We abort the function if this
+    // conversion fails, or if we do not find the id.
+    let id = match field("id") {
+        Some(id) => id.parse::<RecordId>()?,
+        None => return Err(ParseError::EmptyRecord),
+    };
+
+    // Here we try to get the "name" portion of the record, which is usually
+    // the second entry (which is index 1). We also ensure that a name
+    // actually exists by using filter on the name and checking that it
+    // is not an empty string.
+    let name = match field("name").filter(|name| !name.is_empty()) {
+        Some(name) => name.to_string(),
+        None => return Err(ParseError::MissingField("name".to_owned())),
+    };
+
+    // The email field is usually the third piece of data (index 2). A
+    // contact may have several emails, which are separated by semicolons (;)
+    // inside of the field. Emails are optional, so a missing or empty field
+    // simply results in an empty vector.
+    let emails = match field("email") {
+        Some(emails) => split_list(emails),
+        None => vec![],
+    };
+
+    // The phone field was added after the original data file format, so
+    // older files will not have a phone column at all. "field" will return
+    // None in that case, which works out to the contact having no phone.
+    let phone = field("phone")
+        .map(|phone| phone.to_string())
+        .filter(|phone| !phone.is_empty());
+
+    // Tags come after the phone number, separated by semicolons just like
+    // emails. Older files won't have this field either.
+    let tags = match field("tags") {
+        Some(tags) => split_list(tags),
+        None => vec![],
+    };
+
+    // The timestamps were added later, so they may be missing as well.
+    let created_at = parse_timestamp(field("created_at").map_or("", |t| t.as_str()))?;
+    let updated_at = parse_timestamp(field("updated_at").map_or("", |t| t.as_str()))?;
+
+    // The address, birthday, and notes are the newest fields. Just like the
+    // phone, an empty field means there is no value.
+    let optional = |name: &str| {
+        field(name)
+            .map(|field| field.to_string())
+            .filter(|field| !field.is_empty())
+    };
+    let address = optional("address");
+    let birthday = match optional("birthday") {
+        Some(birthday) => Some(parse_date(&birthday)?),
+        None => None,
+    };
+    let notes = optional("notes");
+
+    // Links to other contacts are a list like the tags, where each item is
+    // the kind of relation and the id of the other contact.
+    let relations = parse_relations(field("relations").map_or("", |r| r.as_str()))?;
+    let avatar = optional("avatar");
+    let company = optional("company");
+    let title = optional("title");
+    let nickname = optional("nickname");
+    let last_contacted = match optional("last_contacted") {
+        Some(date) => Some(parse_date(&date)?),
+        None => None,
+    };
+
+    // Any fields in columns that we don't know about are kept along with
+    // the name of their column, so they can be written back out when saving.
+    let extra = columns
+        .iter()
+        .zip(fields.iter())
+        .filter(|(column, value)| !is_known_column(column) && !value.is_empty())
+        .map(|(column, value)| (column.clone(), value.clone()))
+        .collect();
+
+    Ok(Record {
+        id,
+        name,
+        emails,
+        phone,
+        tags,
+        created_at,
+        updated_at,
+        address,
+        birthday,
+        notes,
+        relations,
+        avatar,
+        company,
+        title,
+        nickname,
+        last_contacted,
+        extra,
+    })
+}
+
+/// Parses a list of relations, such as "spouse:7;manager:12".
+pub fn parse_relations(list: &str) -> Result<Vec<Relation>, ParseError> {
+    split_list(list).iter().map(|item| item.parse()).collect()
+}
+
+/// The columns of the data file, in the order they are written.
+pub const CSV_COLUMNS: [&str; 16] = [
+    "id",
+    "name",
+    "email",
+    "phone",
+    "tags",
+    "created_at",
+    "updated_at",
+    "address",
+    "birthday",
+    "notes",
+    "relations",
+    "avatar",
+    "company",
+    "title",
+    "nickname",
+    "last_contacted",
+];
+
+/// Returns whether a column from a header line is the one called "name".
+/// Other programs may capitalize the names or pad them with spaces.
+fn is_column(column: &str, name: &str) -> bool {
+    column.trim().eq_ignore_ascii_case(name)
+}
+
+/// Returns whether a column from a header line is one that p2 knows about.
+fn is_known_column(column: &str) -> bool {
+    CSV_COLUMNS.iter().any(|name| is_column(column, name))
+}
+
+/// The version of the data file format. It is written at the end of the
+/// header line as "#schema=7", so that files written by newer versions of
+/// the program can be recognized. Files without it are version 1.
+pub const SCHEMA_VERSION: u32 = 7;
+
+/// What changed in one version of the data file format.
+pub struct SchemaChange {
+    pub version: u32,
+    /// Columns that were added. Older files simply don't have them.
+    pub added: &'static [&'static str],
+    /// Columns that were renamed, as (old name, new name). The header of an
+    /// older file is read with the new names.
+    pub renamed: &'static [(&'static str, &'static str)],
+    /// Any other change to how the file is written.
+    pub note: Option<&'static str>,
+}
+
+/// Every change to the data file format since version 1, oldest first. A
+/// change that renames a column must be listed here, or the values of that
+/// column would be kept as an unknown column instead.
+pub const SCHEMA_CHANGES: [SchemaChange; 6] = [
+    SchemaChange {
+        version: 2,
+        added: &["address", "birthday", "notes"],
+        renamed: &[],
+        note: None,
+    },
+    SchemaChange {
+        version: 3,
+        added: &["relations", "avatar"],
+        renamed: &[],
+        note: None,
+    },
+    SchemaChange {
+        version: 4,
+        added: &["company", "title"],
+        renamed: &[],
+        note: None,
+    },
+    SchemaChange {
+        version: 5,
+        added: &["nickname"],
+        renamed: &[],
+        note: None,
+    },
+    SchemaChange {
+        version: ESCAPED_FORMULAS_SCHEMA,
+        added: &[],
+        renamed: &[],
+        note: Some("values that spreadsheets would run as formulas are escaped"),
+    },
+    SchemaChange {
+        version: 7,
+        added: &["last_contacted"],
+        renamed: &[],
+        note: None,
+    },
+];
+
+/// Gives the columns of a file with the given schema version the names they
+/// have now, using the renames of every later change. The changes are
+/// applied in order, so a column renamed twice ends up with its newest name.
+fn rename_columns(columns: &mut [String], schema: u32, changes: &[SchemaChange]) {
+    let changes = changes.iter().filter(|change| change.version > schema);
+    for (old, new) in changes.flat_map(|change| change.renamed.iter()) {
+        for column in columns.iter_mut().filter(|column| is_column(column, old)) {
+            *column = new.to_string();
+        }
+    }
+}
+
+/// The schema version since which values in CSV files are escaped by
+/// "escape_formula".
+pub const ESCAPED_FORMULAS_SCHEMA: u32 = 6;
+
+/// The characters that make spreadsheets like Excel treat a value as a
+/// formula, when it begins with one of them.
+const FORMULA_CHARS: [char; 6] = ['=', '+', '-', '@', '\t', '\r'];
+
+/// Escapes a value that a spreadsheet would treat as a formula by putting a
+/// "'" in front of it, so that opening the file in a spreadsheet can't run
+/// a formula hidden in a contact's name. Values that already begin with
+/// "'" and then such a character get another one, so that "unescape_formula"
+/// can always tell the two apart.
+fn escape_formula(value: &str) -> Cow<'_, str> {
+    match value.trim_start_matches('\'').starts_with(FORMULA_CHARS) {
+        true => Cow::Owned(format!("'{}", value)),
+        false => Cow::Borrowed(value),
+    }
+}
+
+/// Undoes "escape_formula".
+pub fn unescape_formula(value: &str) -> &str {
+    match value.strip_prefix('\'') {
+        Some(rest) if rest.trim_start_matches('\'').starts_with(FORMULA_CHARS) => rest,
+        _ => value,
+    }
+}
+
+/// Reads records one at a time from a CSV file, without loading the whole
+/// file into memory first. Each call to "next" reads just enough lines to
+/// parse the next record.
+pub struct RecordReader<R: BufRead> {
+    lines: std::io::Lines<R>,
+    /// Lines that were read but given back, to be read again. The last one
+    /// is read first.
+    unread: Vec<String>,
+    /// How many lines have been read so far.
+    line_number: usize,
+    /// The line number that the last record started on.
+    start: usize,
+    /// The text of the last record, used when reporting errors.
+    pub record: String,
+    /// The names of every column, in the order they appear in the header.
+    /// It is empty until a header is found.
+    columns: Vec<String>,
+    /// The names of unknown columns, found in the header.
+    extra_columns: Vec<String>,
+    /// The next id remembered in the header, or 1 if there isn't one.
+    pub saved_next_id: i64,
+    /// The line numbers of the blank lines that were skipped.
+    blank_lines: Vec<usize>,
+    /// Set once reading fails, so that we don't keep trying.
+    failed: bool,
+    /// The character between fields. Unless it was given, it is found from
+    /// the header line, which has both an "id" and a "name" column.
+    pub delimiter: char,
+    /// Whether the delimiter was given instead of being found.
+    fixed_delimiter: bool,
+    /// Whether to read every line as a record, without looking for a header.
+    no_header: bool,
+    /// The order the file is kept in, remembered in the header.
+    sort: SortOrder,
+    /// The language the names are sorted for, remembered in the header.
+    locale: Option<&'static str>,
+    /// The schema version remembered in the header.
+    pub schema: u32,
+}
+
+impl<R: BufRead> RecordReader<R> {
+    /// Creates a reader that finds the delimiter and columns on its own.
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+            unread: vec![],
+            line_number: 0,
+            start: 0,
+            record: String::new(),
+            columns: vec![],
+            extra_columns: vec![],
+            saved_next_id: 1,
+            blank_lines: vec![],
+            failed: false,
+            delimiter: ',',
+            fixed_delimiter: false,
+            no_header: false,
+            sort: SortOrder::Id,
+            locale: None,
+            schema: 1,
+        }
+    }
+
+    /// Reads the file in the given format. A given delimiter is used instead
+    /// of finding it from the header line.
+    pub fn with_format(mut self, format: CsvFormat) -> Self {
+        if let Some(delimiter) = format.delimiter {
+            self.delimiter = delimiter;
+            self.fixed_delimiter = true;
+        }
+        self.no_header = format.no_header;
+        // Without a header, there is nothing to say which version wrote the
+        // file. Just like the columns are taken to be in the usual order, it
+        // is taken to be written by this version.
+        if format.no_header {
+            self.schema = SCHEMA_VERSION;
+        }
+        self
+    }
+
+    /// Parses the record that was read last. Its values were escaped by
+    /// "escape_formula" if the file is new enough.
+    fn parse_record(&self) -> Result<Record, ParseError> {
+        let mut fields = parse_fields(&self.record, self.delimiter)?;
+        if self.schema >= ESCAPED_FORMULAS_SCHEMA {
+            for field in fields.iter_mut() {
+                *field = unescape_formula(field).to_string();
+            }
+        }
+        record_from_fields(&fields, &self.columns)
+    }
+
+    /// Returns whether the columns are in the usual order, so that a record
+    /// can be added to the end of the file without rewriting it.
+    pub fn has_usual_columns(&self) -> bool {
+        self.columns
+            .iter()
+            .zip(CSV_COLUMNS.iter())
+            .all(|(column, name)| is_column(column, name))
+    }
+
+    /// Returns the format that the file turned out to have, so that it can
+    /// be saved the same way.
+    pub fn format(&self) -> CsvFormat {
+        CsvFormat {
+            delimiter: Some(self.delimiter),
+            no_header: self.no_header,
+            sort: self.sort,
+            locale: self.locale,
+            ..CsvFormat::default()
+        }
+    }
+
+    /// Returns the delimiter if the last record is the header line. Only the
+    /// first line can be the header, and it must have both an "id" and a
+    /// "name" column.
+    fn header_delimiter(&self) -> Option<char> {
+        if self.no_header || self.start != 1 {
+            return None;
+        }
+        let fixed = [self.delimiter];
+        let candidates = match self.fixed_delimiter {
+            true => &fixed[..],
+            false => &DELIMITERS[..],
+        };
+        candidates.iter().copied().find(|delimiter| {
+            let columns = parse_fields(&self.record, *delimiter).unwrap_or_default();
+            ["id", "name"]
+                .iter()
+                .all(|name| columns.iter().any(|column| is_column(column, name)))
+        })
+    }
+
+    /// Returns the line number and text of the last record that was read.
+    pub fn last_record(&self) -> (usize, &str) {
+        (self.start, &self.record)
+    }
+
+    /// Reads the text of the next record into "self.record". Line breaks
+    /// inside of quoted fields are part of the data, so a single record may
+    /// span multiple lines. Returns false at the end of the file.
+    pub fn read_record(&mut self) -> std::io::Result<bool> {
+        self.record.clear();
+        self.start = self.line_number + 1;
+        let mut lines = vec![];
+        let mut in_quotes = false;
+        let mut field_start = true;
+        loop {
+            let line = match self.next_line()? {
+                Some(line) => line,
+                // A quote that is never closed would take in the rest of the
+                // file, so only its first line is returned, for the parser
+                // to report, and the others are read again as records of
+                // their own.
+                None if lines.len() > 1 => {
+                    self.unread.extend(lines.drain(1..).rev());
+                    self.line_number = self.start;
+                    self.record = lines.remove(0);
+                    return Ok(true);
+                }
+                None => return Ok(in_quotes),
+            };
+            if in_quotes {
+                self.record.push('\n');
+            }
+            self.record.push_str(&line);
+            // Just like in "parse_fields", a quote only starts a quoted field
+            // at the start of a field, so a name such as O"Brien doesn't
+            // make the record go on into the next line. Escaped quotes ("")
+            // inside of a quoted field don't end it.
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match (in_quotes, c) {
+                    (true, '"') if chars.peek() == Some(&'"') => {
+                        chars.next();
+                    }
+                    (true, '"') => in_quotes = false,
+                    (true, _) => (),
+                    (false, '"') if field_start => in_quotes = true,
+                    (false, _) => (),
+                }
+                field_start = !in_quotes && c == self.delimiter;
+            }
+            if !in_quotes {
+                return Ok(true);
+            }
+            lines.push(line);
+        }
+    }
+
+    /// Returns the next line, without the byte order mark and line ending,
+    /// or None at the end of the file.
+    fn next_line(&mut self) -> std::io::Result<Option<String>> {
+        let line = match self.unread.pop() {
+            Some(line) => line,
+            None => match self.lines.next() {
+                Some(line) => line?,
+                None => return Ok(None),
+            },
+        };
+        // Files from Windows programs often start with a byte order mark,
+        // which would otherwise end up in the first field. They also end
+        // lines with "\r\n". "lines" removes both characters, but the
+        // "\r" is left behind on a last line that has no "\n".
+        let mut line = line.as_str();
+        if self.line_number == 0 {
+            line = line.strip_prefix('\u{feff}').unwrap_or(line);
+        }
+        let line = line.strip_suffix('\r').unwrap_or(line).to_string();
+        self.line_number += 1;
+        Ok(Some(line))
+    }
+
+    /// Reads the header line, which contains the field names. It ends with
+    /// settings such as "#schema=2" which aren't real columns.
+    fn read_header(&mut self) {
+        let mut columns = parse_fields(&self.record, self.delimiter).unwrap_or_default();
+        while let Some(setting) = columns.pop_if(|column| column.starts_with('#')) {
+            match setting[1..].split_once('=') {
+                Some(("schema", value)) => self.schema = value.parse().unwrap_or(self.schema),
+                Some(("next_id", value)) => {
+                    self.saved_next_id = value.parse().unwrap_or(self.saved_next_id)
+                }
+                Some(("sort", value)) => self.sort = value.parse().unwrap_or_default(),
+                Some(("locale", value)) => self.locale = known_language(value),
+                _ => (),
+            }
+        }
+        // Newer versions may have added fields that we don't know about,
+        // and those would be lost when saving.
+        if self.schema > SCHEMA_VERSION {
+            warn!(
+                "the data file uses schema version {}, but only version {} is supported",
+                self.schema, SCHEMA_VERSION
+            );
+        }
+        rename_columns(&mut columns, self.schema, &SCHEMA_CHANGES);
+        // Columns that we don't know about were added by someone else. Their
+        // names are remembered so that their values can be kept.
+        self.extra_columns = columns
+            .iter()
+            .filter(|column| !is_known_column(column))
+            .cloned()
+            .collect();
+        // Older files only have some of the columns, and records added since
+        // then have every field in the usual order. Those are read by
+        // position, so that the fields past the end of the header are kept.
+        let usual_order = columns
+            .iter()
+            .zip(CSV_COLUMNS.iter())
+            .all(|(column, name)| is_column(column, name));
+        self.columns = match usual_order && columns.len() < CSV_COLUMNS.len() {
+            true => vec![],
+            false => columns,
+        };
+    }
+}
+
+impl<R: BufRead> Iterator for RecordReader<R> {
+    type Item = Result<Record, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            match self.read_record() {
+                Ok(true) => (),
+                Ok(false) => return None,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e.into()));
+                }
+            }
+            // The first line contains the field names, so we skip over it.
+            if let Some(delimiter) = self.header_delimiter() {
+                self.delimiter = delimiter;
+                self.read_header();
+                continue;
+            }
+            if !self.record.is_empty() {
+                return Some(self.parse_record());
+            }
+            self.blank_lines.push(self.start);
+        }
+        None
+    }
+}
+
+/// A line of the data file that couldn't be parsed.
+#[derive(Debug)]
+pub struct BadLine {
+    /// The line number that the record started on, counting from 1.
+    pub number: usize,
+    /// Why the line couldn't be parsed.
+    pub error: ParseError,
+    /// The text of the record, which may span several lines.
+    pub text: String,
+}
+
+impl BadLine {
+    /// Creates a bad line from the last record that the reader read.
+    pub fn new<R: BufRead>(reader: &RecordReader<R>, error: ParseError) -> Self {
+        let (number, text) = reader.last_record();
+        Self {
+            number,
+            error,
+            text: text.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for BadLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "error on line number {}: {}\n  > \"{}\"",
+            self.number, self.error, self.text
+        )
+    }
+}
+
+/// Lists bad lines one after another, separated by blank lines.
+pub fn format_bad_lines(lines: &[BadLine]) -> String {
+    lines.iter().map(|line| format!("\n\n{}", line)).collect()
+}
+
+/// Errors that may occur while reading records.
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid YAML: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    /// Returned in strict mode, with every line that couldn't be parsed.
+    #[error("found {} lines that can't be read:{}", .0.len(), format_bad_lines(.0))]
+    Malformed(Vec<BadLine>),
+    /// Returned when the duplicate policy is "error", with every line that
+    /// used an id again.
+    #[error(
+        "found {} contacts with ids that were already used:{}",
+        .0.len(),
+        format_bad_lines(.0)
+    )]
+    DuplicateIds(Vec<BadLine>),
+}
+
+/// Reads every record. Records that can't be parsed are logged, and skipped
+/// unless "strict" is true, in which case an error listing every one of
+/// them is returned instead. Skipping them would mean that they are lost the
+/// next time the records are saved.
+///
+/// The delimiter and columns are found from the header line, unless the
+/// format says otherwise.
+pub fn read_records<R: BufRead>(
+    reader: R,
+    format: CsvFormat,
+    strict: bool,
+) -> Result<Records, Error> {
+    let mut recs = Records::new();
+    let mut reader = RecordReader::new(reader).with_format(format);
+    let mut bad_lines = vec![];
+    // The line that each id was last seen on, so that records with the same
+    // id can be reported along with the line they collided with.
+    let mut lines = HashMap::new();
+    let (mut duplicates, mut renumbered) = (vec![], vec![]);
+    while let Some(result) = reader.next() {
+        let number = reader.last_record().0;
+        match result {
+            Ok(rec) => match lines.get(&rec.id).copied() {
+                None => {
+                    trace!(id = %rec.id, line = number, "read a record");
+                    lines.insert(rec.id, number);
+                    recs.add(rec);
+                }
+                Some(first) => match format.on_duplicate {
+                    DuplicatePolicy::Error => {
+                        let error = ParseError::DuplicateId(rec.id, first);
+                        duplicates.push(BadLine::new(&reader, error));
+                    }
+                    DuplicatePolicy::KeepFirst => warn!(
+                        "line {} uses id {} just like line {}, so it was skipped",
+                        number, rec.id, first
+                    ),
+                    DuplicatePolicy::KeepLast => {
+                        warn!(
+                            "line {} uses id {} just like line {}, which it replaces",
+                            number, rec.id, first
+                        );
+                        lines.insert(rec.id, number);
+                        recs.add(rec);
+                    }
+                    // New ids are only given out once every line has been
+                    // read, so that they can't collide with a later line.
+                    DuplicatePolicy::Renumber => renumbered.push((number, first, rec)),
+                },
+            },
+            // Problems reading the file itself can't be skipped.
+            Err(ParseError::Io(e)) => return Err(e.into()),
+            Err(e) => {
+                let bad_line = BadLine::new(&reader, e);
+                info!("skipped a record: {}", bad_line);
+                if strict {
+                    bad_lines.push(bad_line);
+                }
+            }
+        }
+    }
+    if !bad_lines.is_empty() {
+        return Err(Error::Malformed(bad_lines));
+    }
+    if !duplicates.is_empty() {
+        return Err(Error::DuplicateIds(duplicates));
+    }
+    debug!(
+        records = recs.inner.len(),
+        lines = reader.line_number,
+        delimiter = ?reader.delimiter,
+        "read the records"
+    );
+    recs.format = reader.format();
+    recs.extra_columns = reader.extra_columns;
+    recs.saved_next_id = reader.saved_next_id;
+    recs.schema = reader.schema;
+    for (number, first, mut rec) in renumbered {
+        let id = recs.next_id();
+        warn!(
+            "line {} uses id {} just like line {}, so it was given id {}",
+            number, rec.id, first, id
+        );
+        rec.id = id;
+        recs.add(rec);
+    }
+    Ok(recs)
+}
+
+/// Reads records written by "write_json_lines", one JSON object on each
+/// line. Lines that can't be parsed are handled just like in "read_records".
+/// A later record with the same id as an earlier one replaces it, so a
+/// changed record can simply be added to the end of the file.
+pub fn read_json_lines<R: BufRead>(reader: R, strict: bool) -> Result<Records, Error> {
+    let mut recs = Records::new();
+    let mut bad_lines = vec![];
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str::<serde_json::Value>(&line).and_then(|value| {
+            // Settings are on a line of their own, like "#next_id" in the
+            // header line of a CSV file.
+            if let Some(next_id) = value.get(JSON_NEXT_ID).and_then(|id| id.as_i64()) {
+                recs.saved_next_id = next_id;
+                if let Some(schema) = value.get(JSON_SCHEMA).and_then(|schema| schema.as_u64()) {
+                    recs.schema = schema as u32;
+                }
+                return Ok(None);
+            }
+            serde_json::from_value::<Record>(value).map(Some)
+        });
+        match record {
+            Ok(Some(record)) => {
+                trace!(id = %record.id, line = index + 1, "read a record");
+                recs.add(record);
+            }
+            Ok(None) => (),
+            Err(e) => {
+                let bad_line = BadLine {
+                    number: index + 1,
+                    error: ParseError::InvalidJson(e),
+                    text: line,
+                };
+                info!("skipped a record: {}", bad_line);
+                if strict {
+                    bad_lines.push(bad_line);
+                }
+            }
+        }
+    }
+    if !bad_lines.is_empty() {
+        return Err(Error::Malformed(bad_lines));
+    }
+    debug!(records = recs.inner.len(), "read the records");
+    Ok(recs)
+}
+
+/// The settings of a JSON Lines file, which are on its first line.
+const JSON_SCHEMA: &str = "#schema";
+const JSON_NEXT_ID: &str = "#next_id";
+
+/// Writes the records as JSON Lines: a line with the settings, and then
+/// one line for each record, in order by id.
+pub fn write_json_lines<W: Write>(writer: &mut W, records: &Records) -> std::io::Result<()> {
+    let settings = serde_json::json!({
+        JSON_SCHEMA: SCHEMA_VERSION,
+        JSON_NEXT_ID: records.next_unused_id(),
+    });
+    writeln!(writer, "{}", settings)?;
+    for record in records.inner.values() {
+        writeln!(writer, "{}", format_json_line(record)?)?;
+    }
+    Ok(())
+}
+
+/// Formats a record as a single line of JSON, without the line break.
+pub fn format_json_line(record: &Record) -> std::io::Result<String> {
+    Ok(serde_json::to_string(record)?)
+}
+
+/// Loads the raw records from a file. The file is read through a
+/// "BufReader", which reads the file in large pieces instead of one line at
+/// a time, but never holds the whole file in memory.
+pub fn load_records(file_name: PathBuf, format: CsvFormat, strict: bool) -> Result<Records, Error> {
+    let start = std::time::Instant::now();
+    let file = File::open(&file_name)?;
+    let recs = read_records(decompress(BufReader::new(file))?, format, strict)?;
+    info!(
+        path = %file_name.display(),
+        records = recs.inner.len(),
+        elapsed = ?start.elapsed(),
+        "loaded the records"
+    );
+    Ok(recs)
+}
+
+/// The results of repairing a data file.
+pub struct Repair {
+    /// Every record that could be read, with duplicate ids replaced.
+    pub records: Records,
+    /// Records that couldn't be fixed.
+    pub rejects: Vec<BadLine>,
+    /// What was done to each of the records that were fixed.
+    pub fixes: Vec<String>,
+    /// How many blank lines, or lines with only empty fields, were dropped.
+    pub blank_lines: usize,
+}
+
+/// Reads every record of a data file, fixing whatever can be fixed.
+/// Whitespace around fields is trimmed, blank lines are dropped, and records
+/// with an id that was already used are given a new one by "id_policy".
+/// Records that still can't be parsed are rejected.
+pub fn repair_records<R: BufRead>(
+    reader: R,
+    format: CsvFormat,
+    id_policy: IdPolicy,
+) -> Result<Repair, Error> {
+    let mut reader = RecordReader::new(reader).with_format(format);
+    let mut repair = Repair {
+        records: Records::new(),
+        rejects: vec![],
+        fixes: vec![],
+        blank_lines: 0,
+    };
+    let mut repaired = vec![];
+    while let Some(result) = reader.next() {
+        // The reader's own attempt at parsing is ignored, since the fields
+        // are parsed again once they have been trimmed.
+        if let Err(ParseError::Io(e)) = result {
+            return Err(e.into());
+        }
+        let (number, text) = reader.last_record();
+        match repair_record(text, &reader.columns, reader.delimiter, reader.schema) {
+            Ok(Some((record, trimmed))) => {
+                if trimmed {
+                    repair
+                        .fixes
+                        .push(format!("line {}: trimmed whitespace", number));
+                }
+                repaired.push((number, record));
+            }
+            Ok(None) => repair.blank_lines += 1,
+            Err(error) => repair.rejects.push(BadLine {
+                number,
+                error,
+                text: text.to_string(),
+            }),
+        }
+    }
+    repair.blank_lines += reader.blank_lines.len();
+
+    // Every record that keeps its id is added first, so that the new ids
+    // given to the duplicates can't be used by a record later in the file.
+    let records = &mut repair.records;
+    records.id_policy = id_policy;
+    records.format = reader.format();
+    records.extra_columns = reader.extra_columns;
+    records.saved_next_id = reader.saved_next_id;
+    let mut duplicates = vec![];
+    for (number, record) in repaired {
+        if records.get(record.id).is_some() {
+            duplicates.push((number, record));
+        } else {
+            records.add(record);
+        }
+    }
+    for (number, mut record) in duplicates {
+        let id = records.next_id();
+        repair.fixes.push(format!(
+            "line {}: id {} was already used, changed it to {}",
+            number, record.id, id
+        ));
+        record.id = id;
+        records.add(record);
+    }
+    Ok(repair)
+}
+
+/// Parses a record after trimming the whitespace around each of its fields.
+/// Returns None when every field is empty. Otherwise, the record is returned
+/// along with whether anything had to be trimmed. "schema" is the schema
+/// version of the file, which says whether the values are escaped.
+fn repair_record(
+    record: &str,
+    columns: &[String],
+    delimiter: char,
+    schema: u32,
+) -> Result<Option<(Record, bool)>, ParseError> {
+    let fields = parse_fields(record, delimiter)?;
+    let trimmed: Vec<_> = fields.iter().map(|field| field.trim()).collect();
+    if trimmed.iter().all(|field| field.is_empty()) {
+        return Ok(None);
+    }
+    let changed = fields.iter().zip(&trimmed).any(|(a, b)| a != b);
+    let values: Vec<_> = trimmed
+        .iter()
+        .map(|field| match schema >= ESCAPED_FORMULAS_SCHEMA {
+            true => unescape_formula(field).to_string(),
+            false => field.to_string(),
+        })
+        .collect();
+    let record = record_from_fields(&values, columns)?;
+    Ok(Some((record, changed)))
+}
+
+/// The kinds of problems that can be found in a data file.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    BlankLine,
+    MalformedRecord,
+    MissingName,
+    DuplicateId,
+    InvalidEmail,
+}
+
+/// A problem found in a data file by "validate_records".
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    /// The line number that the record started on, counting from 1.
+    pub line: usize,
+    /// What kind of problem it is, for programs reading the JSON report.
+    pub kind: ProblemKind,
+    /// A description of the problem for people to read.
+    pub message: String,
+}
+
+/// Checks every record of a data file without changing anything. Returns
+/// every problem that was found, in order by line number.
+pub fn validate_records<R: BufRead>(reader: R, format: CsvFormat) -> Result<Vec<Problem>, Error> {
+    let mut reader = RecordReader::new(reader).with_format(format);
+    let mut problems = vec![];
+    // The line that each id was first seen on.
+    let mut ids = HashMap::new();
+    while let Some(result) = reader.next() {
+        let line = reader.last_record().0;
+        let mut problem = |kind, message| {
+            problems.push(Problem {
+                line,
+                kind,
+                message,
+            })
+        };
+        match result {
+            Ok(record) => {
+                if let Some(first) = ids.insert(record.id, line) {
+                    // The first line is put back, so that later duplicates
+                    // refer to it as well.
+                    ids.insert(record.id, first);
+                    problem(
+                        ProblemKind::DuplicateId,
+                        format!("id {} was already used on line {}", record.id, first),
+                    );
+                }
+                for email in record.emails.iter().filter(|email| !is_valid_email(email)) {
+                    problem(
+                        ProblemKind::InvalidEmail,
+                        format!("invalid email address: {}", email),
+                    );
+                }
+            }
+            Err(ParseError::Io(e)) => return Err(e.into()),
+            Err(ParseError::MissingField(field)) if field == "name" => {
+                problem(ProblemKind::MissingName, "missing name".to_string())
+            }
+            Err(e) => problem(ProblemKind::MalformedRecord, e.to_string()),
+        }
+    }
+    for line in reader.blank_lines {
+        problems.push(Problem {
+            line,
+            kind: ProblemKind::BlankLine,
+            message: "blank line".to_string(),
+        });
+    }
+    // "sort_by_key" is stable, so problems on the same line stay in the
+    // order they were found.
+    problems.sort_by_key(|problem| problem.line);
+    Ok(problems)
+}
+
+/// Checks that an email address looks like "name@example.com". This only
+/// catches obvious mistakes, since the only way to know for sure that an
+/// address works is to send an email to it.
+pub fn is_valid_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((name, domain)) => {
+            !name.is_empty()
+                && !domain.contains('@')
+                && !email.contains(char::is_whitespace)
+                && domain
+                    .split_once('.')
+                    .is_some_and(|(host, rest)| !host.is_empty() && !rest.is_empty())
+                && !domain.ends_with('.')
+        }
+        None => false,
+    }
+}
+
+/// The first two bytes of every gzip file. No CSV file starts with them.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Decompresses gzip data while it is read, or reads anything else as it
+/// is. "fill_buf" lets us look at the start of the data without using it up.
+pub fn decompress<'a, R: BufRead + 'a>(mut reader: R) -> std::io::Result<Box<dyn BufRead + 'a>> {
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        // "MultiGzDecoder" also reads files made by joining gzip files.
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Checks whether a data file is compressed: either it is already gzip, or
+/// its name ends with ".gz" so that it should be. A missing file isn't.
+pub fn is_compressed(file_name: &Path) -> std::io::Result<bool> {
+    if file_name.extension().is_some_and(|ext| ext == "gz") {
+        return Ok(true);
+    }
+    let mut start = vec![];
+    match File::open(file_name) {
+        Ok(file) => file.take(GZIP_MAGIC.len() as u64).read_to_end(&mut start)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    Ok(start == GZIP_MAGIC)
+}
+
+/// Formats a record as a line of the data file, ending with a line break.
+/// "extra_columns" are the names of the unknown columns that come after the
+/// ones we know about.
+pub fn format_record(record: &Record, extra_columns: &[String], delimiter: char) -> String {
+    // Multiple emails and tags are joined with semicolons. When we do
+    // not have an email or phone, we just use an empty string ("").
+    let email = record.emails.join(";");
+    let tags = record.tags.join(";");
+    let phone = record.phone.as_deref().unwrap_or_default();
+    // This creates a new string that is properly formatted to CSV.
+    // Names, emails, and phones may contain commas or quotes, so they are
+    // escaped by "format_fields" before being written.
+    let id = record.id.to_string();
+    let created_at = format_timestamp(record.created_at);
+    let updated_at = format_timestamp(record.updated_at);
+    let address = record.address.as_deref().unwrap_or_default();
+    let birthday = record
+        .birthday
+        .map(|date| date.to_string())
+        .unwrap_or_default();
+    let notes = record.notes.as_deref().unwrap_or_default();
+    let relations: Vec<_> = record.relations.iter().map(|r| r.to_string()).collect();
+    let relations = relations.join(";");
+    let avatar = record.avatar.as_deref().unwrap_or_default();
+    let company = record.company.as_deref().unwrap_or_default();
+    let title = record.title.as_deref().unwrap_or_default();
+    let nickname = record.nickname.as_deref().unwrap_or_default();
+    let last_contacted = record
+        .last_contacted
+        .map(|date| date.to_string())
+        .unwrap_or_default();
+    let mut fields = vec![
+        id.as_str(),
+        &record.name,
+        &email,
+        phone,
+        &tags,
+        &created_at,
+        &updated_at,
+        address,
+        &birthday,
+        notes,
+        &relations,
+        avatar,
+        company,
+        title,
+        nickname,
+        &last_contacted,
+    ];
+    for column in extra_columns.iter() {
+        fields.push(record.extra.get(column).map_or("", |value| value.as_str()));
+    }
+    // Data files are often opened in spreadsheets, which would run any
+    // value that looks like a formula.
+    let fields: Vec<_> = fields.into_iter().map(escape_formula).collect();
+    let fields: Vec<&str> = fields.iter().map(|field| field.as_ref()).collect();
+    format!("{}\n", format_fields(&fields, delimiter))
+}
+
+/// Adds a single record to the end of the data file, without rewriting the
+/// records that are already in it.
+pub fn append_record(file_name: &Path, record: &Record, delimiter: char) -> std::io::Result<()> {
+    use std::io::{Seek, SeekFrom};
+    let mut file = OpenOptions::new().read(true).append(true).open(file_name)?;
+    // The last line might not end with a line break, in which case the new
+    // record would be joined onto it. We check the last byte to be sure.
+    let length = file.metadata()?.len();
+    if length > 0 {
+        let mut last = [0];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            file.write_all(b"\n")?;
+        }
+    }
+    file.write_all(format_record(record, &[], delimiter).as_bytes())?;
+    file.sync_all()
+}
+
+/// Saves the records to disk.
+///
+/// The records are first written to a temporary file in the same directory,
+/// which is then renamed over the original file. Renaming is atomic, so the
+/// data file always contains either all of the old records or all of the
+/// new ones, even if the program crashes partway through saving.
+///
+/// Files that are compressed stay compressed, and so are new files whose
+/// names end with ".gz". Records keep the format of the file they were
+/// loaded from, and new files get the delimiter that their name calls for.
+pub fn save_records(file_name: PathBuf, records: &Records) -> std::io::Result<()> {
+    let start = std::time::Instant::now();
+    let path = file_name.display().to_string();
+    let compress = is_compressed(&file_name)?;
+    let delimiter = records
+        .format
+        .delimiter
+        .or_else(|| default_delimiter(&file_name))
+        .unwrap_or(',');
+    save_file(file_name, |file| {
+        if !compress {
+            return write_csv(file, records, delimiter);
+        }
+        let mut encoder = GzEncoder::new(file, flate2::Compression::default());
+        write_csv(&mut encoder, records, delimiter)?;
+        encoder.finish()?;
+        Ok(())
+    })?;
+    info!(
+        path,
+        records = records.inner.len(),
+        compress,
+        elapsed = ?start.elapsed(),
+        "saved the records"
+    );
+    Ok(())
+}
+
+/// Replaces a file with whatever "write" writes, in the same safe way as
+/// "save_records".
+pub fn save_file<F>(file_name: PathBuf, write: F) -> std::io::Result<()>
+where
+    F: FnOnce(&mut File) -> std::io::Result<()>,
+{
+    let tmp_name = temp_path(&file_name);
+    // If anything goes wrong while writing, we remove the temporary file so
+    // it doesn't get left behind. The original file is untouched.
+    if let Err(e) = write_file(&tmp_name, &file_name, write) {
+        let _ = fs::remove_file(&tmp_name);
+        return Err(e);
+    }
+    fs::rename(&tmp_name, &file_name)?;
+    sync_parent_dir(&file_name)
+}
+
+/// Returns the path of the temporary file used while saving. It lives in the
+/// same directory as the data file, since renaming only works within a
+/// single filesystem. The process id keeps two running programs apart.
+pub fn temp_path(file_name: &Path) -> PathBuf {
+    let name = file_name.file_name().unwrap_or_default().to_string_lossy();
+    file_name.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
+}
+
+/// Makes a rename durable by syncing the directory containing the file.
+#[cfg(unix)]
+fn sync_parent_dir(file_name: &Path) -> std::io::Result<()> {
+    let dir = match file_name.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Directories cannot be opened for syncing on this platform.
+#[cfg(not(unix))]
+fn sync_parent_dir(_file_name: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Writes to a new file at "tmp_name". The permissions of the original file
+/// are copied over, so saving doesn't change who can read it.
+fn write_file<F>(tmp_name: &Path, file_name: &Path, write: F) -> std::io::Result<()>
+where
+    F: FnOnce(&mut File) -> std::io::Result<()>,
+{
+    // "create_new" fails if the file already exists, so we never write
+    // into a file that some other program happens to be using.
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(tmp_name)?;
+    if let Ok(metadata) = fs::metadata(file_name) {
+        file.set_permissions(metadata.permissions())?;
+    }
+    write(&mut file)?;
+
+    // "Flushing" the data ensures that everything is written to disk before
+    // continuing. Without this line, it is possible for the program to
+    // terminate before the system is done writing to the file, and this
+    // can result in corrupted data. "sync_all" goes one step further and
+    // waits until the operating system has actually stored the data.
+    file.flush()?;
+    file.sync_all()
+}
+
+/// Writes the records as CSV, starting with the line of field names unless
+/// the file didn't have one. Any "Write" can be used, such as a file or
+/// stdout.
+pub fn write_csv<W: Write>(
+    file: &mut W,
+    records: &Records,
+    delimiter: char,
+) -> std::io::Result<()> {
+    // Unknown columns are written after the ones we know about, in the same
+    // order they were loaded in. Records may also have come from another
+    // file with different columns, so those are added at the end.
+    let mut extra_columns = records.extra_columns.clone();
+    let mut other_columns: Vec<_> = records
+        .inner
+        .values()
+        .flat_map(|record| record.extra.keys())
+        .filter(|column| !extra_columns.contains(column))
+        .cloned()
+        .collect();
+    other_columns.sort();
+    other_columns.dedup();
+    extra_columns.extend(other_columns);
+
+    // First we write the field names.
+    let mut header = CSV_COLUMNS.to_vec();
+    header.extend(extra_columns.iter().map(|column| column.as_str()));
+    let mut header = format!(
+        "{}{d}#schema={}{d}#next_id={}",
+        format_fields(&header, delimiter),
+        SCHEMA_VERSION,
+        records.next_unused_id(),
+        d = delimiter
+    );
+    let sort = records.format.sort;
+    if sort != SortOrder::Id {
+        header.push_str(&format!("{}#sort={}", delimiter, sort.as_str()));
+    }
+    if let (SortOrder::Name, Some(locale)) = (sort, records.format.locale) {
+        header.push_str(&format!("{}#locale={}", delimiter, locale));
+    }
+    header.push('\n');
+    if !records.format.no_header {
+        file.write_all(header.as_bytes())?;
+    }
+
+    // Then we iterate through each record and write it to the file.
+    // "sorted_by" gives us references to the records in the file's order,
+    // so we don't have to make copies of the data before saving it to disk.
+    for record in records.sorted_by(sort) {
+        let line = format_record(record, &extra_columns, delimiter);
+        // We then write the string to the file. "write_all" works with bytes,
+        // so we just access the bytes of the string with "as_bytes".
+        file.write_all(line.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_quoted_fields() {
+        let fields = parse_fields(r#"1,"Smith, Jr","say ""hi""""#, ',').unwrap();
+        assert_eq!(fields, vec!["1", "Smith, Jr", "say \"hi\""]);
+    }
+
+    #[test]
+    fn maps_columns_by_header_name() {
+        let data = "Email,pronouns,Name,ID\nann@example.com,she/her,Ann,4\n";
+        let recs = read_records(data.as_bytes(), CsvFormat::default(), false).unwrap();
+        assert_eq!(recs.extra_columns, vec!["pronouns"]);
+        let rec = recs.get(RecordId::from(4)).unwrap();
+        assert_eq!(rec.name, "Ann");
+        assert_eq!(rec.emails, vec!["ann@example.com"]);
+        assert_eq!(rec.extra["pronouns"], "she/her");
+
+        let format = CsvFormat {
+            no_header: true,
+            ..CsvFormat::default()
+        };
+        assert!(read_records(data.as_bytes(), format, true).is_err());
+    }
+
+    #[test]
+    fn reads_windows_line_endings() {
+        let data = "\u{feff}id,name,email\r\n1,Ann,ann@example.com\r\n2,\"Bo\r\nB\",\r";
+        let recs = read_records(data.as_bytes(), CsvFormat::default(), true).unwrap();
+        let ann = recs.get(RecordId::from(1)).unwrap();
+        assert_eq!(ann.emails, vec!["ann@example.com"]);
+        assert_eq!(recs.get(RecordId::from(2)).unwrap().name, "Bo\nB");
+    }
+
+    #[test]
+    fn round_trips_tab_separated_records() {
+        let data = "id\tname\temail\n1\tSmith, Bob\tbob@example.com\n";
+        let recs = read_records(data.as_bytes(), CsvFormat::default(), false).unwrap();
+        assert_eq!(recs.format.delimiter, Some('\t'));
+        let rec = recs.get(RecordId::from(1)).unwrap();
+        assert_eq!(rec.name, "Smith, Bob");
+        assert!(format_record(rec, &[], '\t').starts_with("1\tSmith, Bob\tbob@example.com\t"));
+        assert_eq!(escape_field("a;b", ';'), "\"a;b\"");
+    }
+
+    #[test]
+    fn keeps_the_file_sorted() {
+        let data = "id,name,email\n1,bob,b@example.com\n2,Ann,\n3,Cy,a@example.com\n";
+        let mut recs = read_records(data.as_bytes(), CsvFormat::default(), false).unwrap();
+        let emails: Vec<_> = recs
+            .sorted_by(SortOrder::Email)
+            .iter()
+            .map(|r| r.id)
+            .collect();
+        assert_eq!(emails, vec![3.into(), 1.into(), 2.into()]);
+
+        recs.format.sort = SortOrder::Name;
+        let mut saved = vec![];
+        write_csv(&mut saved, &recs, ',').unwrap();
+        let saved = String::from_utf8(saved).unwrap();
+        assert!(saved.lines().next().unwrap().ends_with(",#sort=name"));
+        let recs = read_records(saved.as_bytes(), CsvFormat::default(), false).unwrap();
+        assert_eq!(recs.format.sort, SortOrder::Name);
+        let ids: Vec<_> = saved.lines().skip(1).map(|line| &line[..1]).collect();
+        assert_eq!(ids, vec!["2", "1", "3"]);
+    }
+
+    #[test]
+    fn sorts_names_for_a_language() {
+        let mut recs = Records::new();
+        for name in ["Zoe", "Åsa", "Erik", "élodie", "anna", "Ann"] {
+            recs.add(Record::new(recs.next_id(), name.to_string()));
+        }
+        recs.format.sort = SortOrder::Name;
+        let names = |recs: &Records| -> Vec<String> {
+            let records = recs.sorted_by(SortOrder::Name);
+            records.iter().map(|r| r.name.clone()).collect()
+        };
+        assert_eq!(
+            names(&recs),
+            ["Ann", "anna", "Åsa", "élodie", "Erik", "Zoe"]
+        );
+
+        recs.format.locale = known_language("sv");
+        let mut saved = vec![];
+        write_csv(&mut saved, &recs, ',').unwrap();
+        let saved = String::from_utf8(saved).unwrap();
+        assert!(saved
+            .lines()
+            .next()
+            .unwrap()
+            .ends_with(",#sort=name,#locale=sv"));
+        let recs = read_records(saved.as_bytes(), CsvFormat::default(), false).unwrap();
+        assert_eq!(
+            names(&recs),
+            ["Ann", "anna", "élodie", "Erik", "Zoe", "Åsa"]
+        );
+    }
+
+    #[test]
+    fn ranks_search_results() {
+        let mut recs = Records::new();
+        for name in ["Joanne", "Annette", "Anne", "Anna Lee", "Bob", "Zoë"] {
+            recs.add(Record::new(recs.next_id(), name.to_string()));
+        }
+        let mut found = recs.search_similar("anne", &[Field::Name], false);
+        found.sort_by_key(|found| found.kind);
+        let ids: Vec<RecordId> = found.iter().map(|found| found.record.id).collect();
+        assert_eq!(ids, vec![3.into(), 2.into(), 1.into(), 4.into()]);
+        let position = &found[2].positions[0];
+        assert_eq!((position.start, position.end), (2, 6));
+        assert_eq!(found[3].kind, MatchKind::Fuzzy);
+        assert!(recs.search_in("anne", &[Field::Name], false).len() == 3);
+
+        // The position is in the name as it was written, accents and all.
+        let found = recs.search_in("zoe", &[Field::Name], true);
+        let position = &found[0].positions[0];
+        assert_eq!(
+            (position.start, position.end, position.kind),
+            (0, 4, MatchKind::Exact)
+        );
+    }
+
+    #[test]
+    fn round_trips_special_characters() {
+        let fields = ["7", "O'Neil, \"Ace\"", "line\nbreak"];
+        let line = format_fields(&fields, ',');
+        let mut reader = RecordReader::new(line.as_bytes());
+        assert!(reader.read_record().unwrap());
+        assert_eq!(reader.last_record().0, 1);
+        assert_eq!(parse_fields(reader.last_record().1, ',').unwrap(), fields);
+    }
+
+    #[test]
+    fn parses_records_without_phone_column() {
+        let recs = read_records(
+            "id,name,email\n1,Bob,bob@example.com\n".as_bytes(),
+            CsvFormat::default(),
+            false,
+        )
+        .unwrap();
+        let recs = recs.into_vec();
+        assert_eq!(recs.len(), 1);
+        assert_eq!(recs[0].phone, None);
+    }
+
+    #[test]
+    fn parses_multiple_emails() {
+        let rec = parse_record("1,Bob,bob@work.com; bob@home.com;,", &[], ',').unwrap();
+        assert_eq!(rec.emails, vec!["bob@work.com", "bob@home.com"]);
+    }
+
+    #[test]
+    fn finds_and_merges_duplicates() {
+        let data = "1,Jon Smith,jon@example.com,,\n2,John Smith,,555,friends\n\
+                    3,J. Smith,JON@example.com,,\n4,Someone Else,,,\n";
+        let mut recs = read_records(data.as_bytes(), CsvFormat::default(), false).unwrap();
+        let groups = recs.find_duplicates(1);
+        let expected: Vec<RecordId> = vec![1.into(), 2.into(), 3.into()];
+        assert_eq!(groups, vec![expected]);
+
+        let merged = recs.merge(&groups[0]).unwrap();
+        assert_eq!(merged.emails, vec!["jon@example.com"]);
+        assert_eq!(merged.phone.as_deref(), Some("555"));
+        assert_eq!(merged.tags, vec!["friends"]);
+        assert_eq!(recs.sorted().len(), 2);
+    }
+
+    #[test]
+    fn merges_every_field() {
+        let extra = |columns: &[(&str, &str)]| {
+            columns
+                .iter()
+                .map(|(column, value)| (column.to_string(), value.to_string()))
+                .collect()
+        };
+        let mut recs = Records::new();
+        recs.add(Record {
+            notes: Some("met at work".to_string()),
+            last_contacted: NaiveDate::from_ymd_opt(2024, 1, 1),
+            extra: extra(&[("pager", "123")]),
+            ..Record::new(1.into(), "Ann Lee".to_string())
+        });
+        recs.add(Record {
+            address: Some("1 Main St".to_string()),
+            birthday: NaiveDate::from_ymd_opt(1990, 5, 1),
+            notes: Some("likes tea".to_string()),
+            company: Some("Acme".to_string()),
+            title: Some("Engineer".to_string()),
+            nickname: Some("Annie".to_string()),
+            avatar: Some("/photos/ann.png".to_string()),
+            last_contacted: NaiveDate::from_ymd_opt(2025, 3, 1),
+            extra: extra(&[("pager", "456"), ("fax", "789")]),
+            ..Record::new(2.into(), "Ann Lee".to_string())
+        });
+        let merged = recs.merge(&[1.into(), 2.into()]).unwrap().clone();
+        assert_eq!(merged.address.as_deref(), Some("1 Main St"));
+        assert_eq!(merged.birthday, NaiveDate::from_ymd_opt(1990, 5, 1));
+        assert_eq!(merged.notes.as_deref(), Some("met at work\nlikes tea"));
+        assert_eq!(merged.company.as_deref(), Some("Acme"));
+        assert_eq!(merged.title.as_deref(), Some("Engineer"));
+        assert_eq!(merged.nickname.as_deref(), Some("Annie"));
+        assert_eq!(merged.avatar.as_deref(), Some("/photos/ann.png"));
+        assert_eq!(merged.last_contacted, NaiveDate::from_ymd_opt(2025, 3, 1));
+        assert_eq!(merged.extra, extra(&[("pager", "123"), ("fax", "789")]));
+        assert_eq!(recs.sorted().len(), 1);
+    }
+
+    #[test]
+    fn compares_every_detail_of_contacts() {
+        let ann = Record::new(1.into(), "Ann".to_string());
+        let copy = Record::new(2.into(), "Ann".to_string());
+        assert!(ann.same_contact(&copy));
+        let details: [fn(&mut Record); 3] = [
+            |rec| rec.company = Some("Acme".to_string()),
+            |rec| rec.title = Some("Engineer".to_string()),
+            |rec| rec.nickname = Some("Annie".to_string()),
+        ];
+        for detail in details {
+            let mut other = copy.clone();
+            detail(&mut other);
+            assert!(!ann.same_contact(&other));
+        }
+    }
+
+    #[test]
+    fn escapes_formulas() {
+        let mut recs = Records::new();
+        let names = ["=HYPERLINK(\"x\")", "'=1", "-Ann", "Bob's", "'"];
+        for name in names {
+            recs.add(Record::new(recs.next_id(), name.to_string()));
+        }
+        let mut out = vec![];
+        recs.to_writer(&mut out, DataFormat::Csv).unwrap();
+        let text = String::from_utf8(out.clone()).unwrap();
+        assert!(text.contains("\n1,\"'=HYPERLINK(\"\"x\"\")\",") && text.contains("\n2,''=1,"));
+        let read = read_records(out.as_slice(), CsvFormat::default(), true).unwrap();
+        let read_names: Vec<_> = read.sorted().iter().map(|r| r.name.clone()).collect();
+        assert_eq!(read_names, names);
+        // Files from before the values were escaped are read as they are.
+        let old = "id,name,#schema=5\n1,'=1\n";
+        let read = read_records(old.as_bytes(), CsvFormat::default(), true).unwrap();
+        assert_eq!(read.get(1.into()).unwrap().name, "'=1");
+    }
+
+    #[test]
+    fn renames_columns_from_older_schemas() {
+        let changes = [
+            SchemaChange {
+                version: 2,
+                added: &[],
+                renamed: &[("mail", "e-mail"), ("mobile", "phone")],
+                note: None,
+            },
+            SchemaChange {
+                version: 3,
+                added: &[],
+                renamed: &[("e-mail", "email")],
+                note: None,
+            },
+        ];
+        let header = |columns: &[&str]| -> Vec<String> {
+            columns.iter().map(|column| column.to_string()).collect()
+        };
+        let mut columns = header(&["id", "name", "Mail", "mobile"]);
+        rename_columns(&mut columns, 1, &changes);
+        assert_eq!(columns, header(&["id", "name", "email", "phone"]));
+        // Files that already have a change keep their names from it.
+        let mut columns = header(&["id", "name", "e-mail", "mobile"]);
+        rename_columns(&mut columns, 2, &changes);
+        assert_eq!(columns, header(&["id", "name", "email", "mobile"]));
+        let mut columns = header(&["id", "name", "mail"]);
+        rename_columns(&mut columns, 3, &changes);
+        assert_eq!(columns, header(&["id", "name", "mail"]));
+    }
+
+    #[test]
+    fn keeps_unknown_columns() {
+        let data = "id,name,email,phone,tags,created_at,updated_at,address,birthday,notes,\
+                    pronouns,#schema=2\n1,Robert,,,,,,,,,he/him\n";
+        let recs = read_records(data.as_bytes(), CsvFormat::default(), false).unwrap();
+        assert_eq!(recs.extra_columns, vec!["pronouns"]);
+        let rec = recs.get(1.into()).unwrap();
+        assert_eq!(
+            rec.extra.get("pronouns").map(|s| s.as_str()),
+            Some("he/him")
+        );
+    }
+
+    #[test]
+    fn does_not_reuse_removed_ids() {
+        let data = "id,name,#schema=2,#next_id=10\n1,Ann\n2,Bob\n";
+        let mut recs = read_records(data.as_bytes(), CsvFormat::default(), false).unwrap();
+        assert_eq!(recs.next_id(), 10.into());
+        recs.id_policy.reuse = true;
+        assert_eq!(recs.next_id(), 3.into());
+    }
+
+    #[test]
+    fn compacts_ids() {
+        let data = "id,name,#schema=2,#next_id=10\n2,Ann\n5,Bob\n7,Cy\n";
+        let mut recs = read_records(data.as_bytes(), CsvFormat::default(), false).unwrap();
+        let changes = recs.compact();
+        assert_eq!(
+            changes,
+            vec![
+                (2.into(), 1.into()),
+                (5.into(), 2.into()),
+                (7.into(), 3.into())
+            ]
+        );
+        assert_eq!(recs.get(2.into()).unwrap().name, "Bob");
+        assert_eq!(recs.search("bob")[0].id, 2.into());
+        assert_eq!(recs.next_id(), 4.into());
+    }
+
+    #[test]
+    fn keeps_links_pointing_at_records() {
+        let data = "id,name,relations\n2,Ann,spouse:5\n5,Bob\n7,Cy,friend:2;boss:5\n";
+        let mut recs = read_records(data.as_bytes(), CsvFormat::default(), false).unwrap();
+        recs.compact();
+        let kinds = |recs: &Records, id: i64| -> Vec<String> {
+            let relations = &recs.get(id.into()).unwrap().relations;
+            relations.iter().map(|r| r.to_string()).collect()
+        };
+        assert_eq!(kinds(&recs, 1), vec!["spouse:2"]);
+        assert_eq!(kinds(&recs, 3), vec!["friend:1", "boss:2"]);
+        recs.remove(2.into());
+        assert!(kinds(&recs, 1).is_empty());
+        assert_eq!(kinds(&recs, 3), vec!["friend:1"]);
+    }
+
+    #[test]
+    fn searches_renamed_records() {
+        let mut recs =
+            read_records("1,Ann\n2,Bob\n".as_bytes(), CsvFormat::default(), false).unwrap();
+        recs.edit(2.into(), |rec| rec.name = "Annabel".to_string());
+        recs.remove(1.into());
+        let found: Vec<_> = recs.search("ANN").iter().map(|rec| rec.id).collect();
+        assert_eq!(found, vec![2.into()]);
+    }
+
+    #[test]
+    fn round_trips_records_with_serde() {
+        let data = "id,name,email,pronouns\n1,Ann,ann@example.com,she/her\n2,Bob\n";
+        let recs = read_records(data.as_bytes(), CsvFormat::default(), false).unwrap();
+        let formats = [
+            DataFormat::Csv,
+            DataFormat::Json,
+            DataFormat::JsonLines,
+            DataFormat::Yaml,
+        ];
+        for format in formats.iter() {
+            let mut out = vec![];
+            recs.to_writer(&mut out, *format).unwrap();
+            let read = Records::from_reader(out.as_slice(), *format).unwrap();
+            assert_eq!(read.into_vec(), recs.clone().into_vec());
+        }
+    }
+
+    #[test]
+    fn reads_json_lines() {
+        let data = r##"{"#schema":2,"#next_id":9}
+{"id":1,"name":"Ann"}
+not json
+{"id":2,"name":"Bob"}
+{"id":1,"name":"Annabel"}
+"##;
+        let recs = read_json_lines(data.as_bytes(), false).unwrap();
+        assert_eq!(recs.get(1.into()).unwrap().name, "Annabel");
+        assert_eq!(recs.inner.len(), 2);
+        assert_eq!(recs.next_unused_id(), 9);
+        assert!(matches!(
+            read_json_lines(data.as_bytes(), true),
+            Err(Error::Malformed(lines)) if lines[0].number == 3
+        ));
+    }
+
+    #[test]
+    fn handles_duplicate_ids() {
+        let data = "1,Ann\n2,Bob\n1,Cid\n";
+        let load = |on_duplicate| {
+            let format = CsvFormat {
+                on_duplicate,
+                ..CsvFormat::default()
+            };
+            read_records(data.as_bytes(), format, false)
+        };
+        let name = |recs: &Records, id: i64| recs.get(id.into()).unwrap().name.clone();
+        assert!(matches!(
+            load(DuplicatePolicy::Error),
+            Err(Error::DuplicateIds(lines)) if lines[0].number == 3
+        ));
+        assert_eq!(name(&load(DuplicatePolicy::KeepFirst).unwrap(), 1), "Ann");
+        assert_eq!(name(&load(DuplicatePolicy::KeepLast).unwrap(), 1), "Cid");
+        let recs = load(DuplicatePolicy::Renumber).unwrap();
+        assert_eq!(
+            (name(&recs, 1), name(&recs, 3)),
+            ("Ann".into(), "Cid".into())
+        );
+    }
+
+    #[test]
+    fn searches_by_sound() {
+        assert_eq!(soundex("Robert").unwrap(), "R163");
+        assert_eq!(soundex("Ashcraft").unwrap(), "A261");
+        assert_eq!(soundex("Tymczak").unwrap(), "T522");
+        assert_eq!(soundex("Lee").unwrap(), "L000");
+        let mut recs = read_records(
+            "1,John Smith\n2,Jane Smythe\n3,Joan Smart\n".as_bytes(),
+            CsvFormat::default(),
+            false,
+        )
+        .unwrap();
+        let ids = |recs: &Records| -> Vec<RecordId> {
+            recs.search_phonetic("jon smyth")
+                .iter()
+                .map(|found| found.record.id)
+                .collect()
+        };
+        assert_eq!(ids(&recs), vec![1.into(), 2.into()]);
+        recs.edit(2.into(), |rec| rec.name = "Jane Doe".to_string());
+        assert_eq!(ids(&recs), vec![1.into()]);
+    }
+
+    #[test]
+    fn searches_names_by_alias() {
+        let data = "id,name,nickname\n1,Robert Lee,\n2,Robin Hood,\n3,Ann Bobson,Bobby\n";
+        let recs = read_records(data.as_bytes(), CsvFormat::default(), false).unwrap();
+        let ids = |query: &str| -> Vec<RecordId> {
+            recs.search_with_aliases(query, &[Field::Name, Field::Nickname], false)
+                .iter()
+                .map(|found| found.record.id)
+                .collect()
+        };
+        assert_eq!(ids("Bob"), vec![1.into(), 3.into()]);
+        assert_eq!(ids("rob lee"), vec![1.into()]);
+        assert_eq!(ids("Rob"), vec![1.into(), 2.into(), 3.into()]);
+        assert_eq!(
+            name_aliases("al"),
+            vec!["alexander", "alex", "sandy", "albert", "bert"]
+        );
+    }
+
+    #[test]
+    fn searches_names_through_the_index() {
+        let mut recs = Records::new();
+        recs.add(Record::new(1.into(), "Ann Lee".to_string()));
+        recs.add(Record::new(2.into(), "Bob Lee".to_string()));
+        recs.add(Record::new(3.into(), "Cy".to_string()));
+        recs.edit(3.into(), |record| record.name = "Cy Lee".to_string());
+        recs.remove(1.into());
+        let ids: Vec<RecordId> = recs
+            .search_in("LEE", &[Field::Name], false)
+            .iter()
+            .map(|found| found.record.id)
+            .collect();
+        assert_eq!(ids, vec![2.into(), 3.into()]);
+        assert!(recs.search_in("ann", &[Field::Name], false).is_empty());
+
+        // Names spelled almost like the query are found through the index
+        // too, so the old name of a renamed record no longer matches.
+        recs.add(Record::new(4.into(), "Katherine Lee".to_string()));
+        let found = recs.search_similar("kathrine", &[Field::Name, Field::Email], false);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].record.id, 4.into());
+        recs.edit(4.into(), |record| record.name = "Kay Lee".to_string());
+        assert!(recs
+            .search_similar("kathrine", &[Field::Name], false)
+            .is_empty());
+    }
+
+    #[test]
+    fn folds_case_and_accents() {
+        let recs = read_records(
+            "1,Jürgen Strauß
+2,İlker
+"
+            .as_bytes(),
+            CsvFormat::default(),
+            false,
+        )
+        .unwrap();
+        let ids = |query, ignore_accents| -> Vec<RecordId> {
+            recs.search_in(query, &[Field::Name], ignore_accents)
+                .iter()
+                .map(|found| found.record.id)
+                .collect()
+        };
+        assert_eq!(ids("STRAUSS", false), vec![1.into()]);
+        assert_eq!(ids("ilker", false), vec![2.into()]);
+        assert!(ids("jurgen", false).is_empty());
+        assert_eq!(ids("jurgen", true), vec![1.into()]);
+    }
+
+    #[test]
+    fn strict_mode_reports_every_bad_line() {
+        let data = "1,Ann\n2,\nx,Bob\n3,Cid\n";
+        assert_eq!(
+            read_records(data.as_bytes(), CsvFormat::default(), false)
+                .unwrap()
+                .sorted()
+                .len(),
+            2
+        );
+        match read_records(data.as_bytes(), CsvFormat::default(), true) {
+            Err(Error::Malformed(lines)) => {
+                let numbers: Vec<_> = lines.iter().map(|line| line.number).collect();
+                assert_eq!(numbers, vec![2, 3]);
+            }
+            other => panic!("expected malformed lines, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn repairs_records() {
+        let data = "1,Ann\n\n 2 , Bob \n1,Cid\n,,\nx,Dee\n";
+        let id_policy = IdPolicy {
+            scheme: IdScheme::Number,
+            reuse: false,
+        };
+        let repair = repair_records(data.as_bytes(), CsvFormat::default(), id_policy).unwrap();
+        let names: Vec<_> = repair
+            .records
+            .sorted()
+            .iter()
+            .map(|rec| rec.name.clone())
+            .collect();
+        assert_eq!(names, vec!["Ann", "Bob", "Cid"]);
+        assert_eq!(repair.records.get(3.into()).unwrap().name, "Cid");
+        assert_eq!(repair.blank_lines, 2);
+        assert_eq!(repair.rejects.len(), 1);
+        assert_eq!(repair.rejects[0].number, 6);
+    }
+
+    #[test]
+    fn validates_records() {
+        let data = "1,Ann,ann@example.com\n\n1,Bob,bob@example\n2,\n";
+        let problems = validate_records(data.as_bytes(), CsvFormat::default()).unwrap();
+        let found: Vec<_> = problems.iter().map(|p| (p.line, p.kind)).collect();
+        assert_eq!(
+            found,
+            vec![
+                (2, ProblemKind::BlankLine),
+                (3, ProblemKind::DuplicateId),
+                (3, ProblemKind::InvalidEmail),
+                (4, ProblemKind::MissingName),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unterminated_quotes() {
+        assert!(parse_record("1,\"Bob,bob@example.com", &[], ',').is_err());
+    }
+
+    #[test]
+    fn reads_stray_quotes_inside_of_fields() {
+        let data = "id,name,email\n1,O\"Brien,o@x.com\n2,Ann,a@x.com\n3,\"Bob\n4,Cid\n";
+        let recs = read_records(data.as_bytes(), CsvFormat::default(), false).unwrap();
+        let names: Vec<_> = recs.sorted().iter().map(|rec| rec.name.clone()).collect();
+        assert_eq!(names, vec!["O\"Brien", "Ann", "Cid"]);
+        // The quote that is never closed only makes its own line bad.
+        match read_records(data.as_bytes(), CsvFormat::default(), true) {
+            Err(Error::Malformed(lines)) => {
+                let numbers: Vec<_> = lines.iter().map(|line| line.number).collect();
+                assert_eq!(numbers, vec![4]);
+            }
+            other => panic!("expected malformed lines, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,7 @@
+//! The code shared by the activities. The contact manager's data model and
+//! file handling live in [`contacts`], so other programs can read and write
+//! the same data file as the "p2" command. [`query`] is the language its
+//! commands use to pick out contacts.
+
+pub mod contacts;
+pub mod query;
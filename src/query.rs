@@ -0,0 +1,397 @@
+//! A small language for picking out contacts, such as
+//! `name ~ "smith" and email ends "@gmail.com"`.
+//!
+//! A query is made of comparisons between a field and a value, which can be
+//! combined with "and", "or", "not", and parentheses. "and" binds tighter
+//! than "or", so `a or b and c` is the same as `a or (b and c)`.
+//!
+//! | Operator   | Matches when the field...                  |
+//! |------------|--------------------------------------------|
+//! | `=`, `!=`  | is (or isn't) the value                    |
+//! | `~`, `!~`  | contains (or doesn't contain) the value    |
+//! | `starts`   | starts with the value                      |
+//! | `ends`     | ends with the value                        |
+//! | `<`, `<=`, `>`, `>=` | sorts before or after the value  |
+//! | `has`      | has any value at all, as in `has phone`    |
+//!
+//! Case is ignored. Fields with several values, like "email" and "tag",
+//! match when any one of their values does. Numbers are compared as
+//! numbers, and everything else as text, which puts dates in order too.
+//!
+//! ```
+//! use activities::contacts::Record;
+//! use activities::query::Query;
+//!
+//! let query: Query = r#"name ~ "smith" and email ends "@gmail.com""#.parse()?;
+//! let mut record = Record::new(1.into(), "Ann Smith".to_string());
+//! record.emails.push("ann@gmail.com".to_string());
+//! assert!(query.matches(&record));
+//! # Ok::<(), activities::query::QueryError>(())
+//! ```
+
+use crate::contacts::{fold_case, format_timestamp, Record};
+use std::cmp::Ordering;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A query that couldn't be parsed, along with where the problem is.
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{message} at column {column} of the query")]
+pub struct QueryError {
+    /// What went wrong.
+    pub message: String,
+    /// Where it went wrong, counting characters from 1.
+    pub column: usize,
+}
+
+/// How a field is compared with a value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Equals,
+    Contains,
+    StartsWith,
+    EndsWith,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// A parsed query. "!=" and "!~" become a "Not" around "Equals" and
+/// "Contains", so a field without any values matches them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    /// Compares a field with a value.
+    Compare {
+        field: String,
+        op: Op,
+        value: String,
+    },
+    /// Matches records where the field has a value.
+    Has(String),
+    Not(Box<Query>),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+}
+
+impl Query {
+    /// Returns whether a record matches the query.
+    pub fn matches(&self, record: &Record) -> bool {
+        match self {
+            Query::Compare { field, op, value } => {
+                let value = fold_case(value);
+                field_values(record, field)
+                    .iter()
+                    .any(|field| compare(&fold_case(field), *op, &value))
+            }
+            Query::Has(field) => field_values(record, field)
+                .iter()
+                .any(|value| !value.is_empty()),
+            Query::Not(query) => !query.matches(record),
+            Query::And(left, right) => left.matches(record) && right.matches(record),
+            Query::Or(left, right) => left.matches(record) || right.matches(record),
+        }
+    }
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            position: 0,
+            end: s.chars().count() + 1,
+        };
+        let query = parser.parse_or()?;
+        match parser.peek() {
+            None => Ok(query),
+            Some(_) => Err(parser.error("expected \"and\", \"or\", or the end of the query")),
+        }
+    }
+}
+
+/// Returns the values of a field by its name, which is the same as its
+/// column in the data file. Names that aren't one of the usual columns
+/// look up the unknown columns that were kept from the file.
+fn field_values(record: &Record, field: &str) -> Vec<String> {
+    match field.to_ascii_lowercase().as_str() {
+        "id" => vec![record.id.to_string()],
+        "name" => vec![record.name.clone()],
+        "email" | "emails" => record.emails.clone(),
+        "phone" => record.phone.iter().cloned().collect(),
+        "tag" | "tags" => record.tags.clone(),
+        "created" | "created_at" => record
+            .created_at
+            .map(|t| format_timestamp(Some(t)))
+            .into_iter()
+            .collect(),
+        "updated" | "updated_at" => record
+            .updated_at
+            .map(|t| format_timestamp(Some(t)))
+            .into_iter()
+            .collect(),
+        "address" => record.address.iter().cloned().collect(),
+        "birthday" => record
+            .birthday
+            .iter()
+            .map(|date| date.to_string())
+            .collect(),
+        "notes" => record.notes.iter().cloned().collect(),
+        "relation" | "relations" => record.relations.iter().map(|r| r.kind.clone()).collect(),
+        "avatar" => record.avatar.iter().cloned().collect(),
+        "company" => record.company.iter().cloned().collect(),
+        "title" => record.title.iter().cloned().collect(),
+        "nickname" => record.nickname.iter().cloned().collect(),
+        "last_contacted" => record
+            .last_contacted
+            .iter()
+            .map(|date| date.to_string())
+            .collect(),
+        _ => record
+            .extra
+            .iter()
+            .filter(|(column, _)| column.trim().eq_ignore_ascii_case(field))
+            .map(|(_, value)| value.clone())
+            .collect(),
+    }
+}
+
+/// Compares a single value of a field, which have both been case folded.
+fn compare(field: &str, op: Op, value: &str) -> bool {
+    // Numbers such as ids would be out of order if they were compared as
+    // text, since "10" comes before "9".
+    let ordering = || match (field.parse::<f64>(), value.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => field.cmp(value),
+    };
+    match op {
+        Op::Equals => field == value,
+        Op::Contains => field.contains(value),
+        Op::StartsWith => field.starts_with(value),
+        Op::EndsWith => field.ends_with(value),
+        Op::Less => ordering() == Ordering::Less,
+        Op::LessOrEqual => ordering() != Ordering::Greater,
+        Op::Greater => ordering() == Ordering::Greater,
+        Op::GreaterOrEqual => ordering() != Ordering::Less,
+    }
+}
+
+/// A piece of a query, such as a word or an operator.
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A field name, a keyword, or a value without quotes.
+    Word(String),
+    /// A value in double quotes, which is never a keyword.
+    Quoted(String),
+    Symbol(&'static str),
+}
+
+/// The operators made of symbols. Longer ones come first, so that "<=" isn't
+/// read as "<" followed by "=".
+const SYMBOLS: [&str; 10] = ["!=", "!~", "<=", ">=", "=", "~", "<", ">", "(", ")"];
+
+/// Splits a query into tokens, each with the column that it starts at.
+fn tokenize(query: &str) -> Result<Vec<(Token, usize)>, QueryError> {
+    let chars: Vec<char> = query.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let column = i + 1;
+        let rest: String = chars[i..].iter().take(2).collect();
+        if chars[i].is_whitespace() {
+            i += 1;
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            tokens.push((Token::Symbol(symbol), column));
+            i += symbol.len();
+        } else if chars[i] == '"' {
+            // A backslash lets a value contain quotes and backslashes.
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    Some('"') => break,
+                    Some('\\') if i + 1 < chars.len() => {
+                        value.push(chars[i + 1]);
+                        i += 2;
+                    }
+                    Some(&c) => {
+                        value.push(c);
+                        i += 1;
+                    }
+                    None => {
+                        return Err(QueryError {
+                            message: "unterminated quote".to_string(),
+                            column,
+                        })
+                    }
+                }
+            }
+            tokens.push((Token::Quoted(value), column));
+            i += 1;
+        } else {
+            let start = i;
+            while i < chars.len()
+                && !chars[i].is_whitespace()
+                && !matches!(chars[i], '"' | '(' | ')' | '=' | '~' | '<' | '>' | '!')
+            {
+                i += 1;
+            }
+            if i == start {
+                return Err(QueryError {
+                    message: format!("unexpected \"{}\"", chars[i]),
+                    column,
+                });
+            }
+            tokens.push((Token::Word(chars[start..i].iter().collect()), column));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Builds a query from its tokens, with one function for each level of
+/// precedence: "or" is made of "and"s, which are made of "not"s.
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    /// The column just past the end of the query, for errors at the end.
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    /// Takes the next token if it is the given keyword.
+    fn keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword) => {
+                self.position += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns an error at the next token.
+    fn error(&self, message: &str) -> QueryError {
+        QueryError {
+            message: message.to_string(),
+            column: self
+                .tokens
+                .get(self.position)
+                .map_or(self.end, |(_, column)| *column),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Query, QueryError> {
+        let mut query = self.parse_and()?;
+        while self.keyword("or") {
+            query = Query::Or(Box::new(query), Box::new(self.parse_and()?));
+        }
+        Ok(query)
+    }
+
+    fn parse_and(&mut self) -> Result<Query, QueryError> {
+        let mut query = self.parse_not()?;
+        while self.keyword("and") {
+            query = Query::And(Box::new(query), Box::new(self.parse_not()?));
+        }
+        Ok(query)
+    }
+
+    fn parse_not(&mut self) -> Result<Query, QueryError> {
+        if self.keyword("not") {
+            return Ok(Query::Not(Box::new(self.parse_not()?)));
+        }
+        if self.peek() == Some(&Token::Symbol("(")) {
+            self.position += 1;
+            let query = self.parse_or()?;
+            if self.peek() != Some(&Token::Symbol(")")) {
+                return Err(self.error("expected \")\""));
+            }
+            self.position += 1;
+            return Ok(query);
+        }
+        if self.keyword("has") {
+            return Ok(Query::Has(self.parse_field()?));
+        }
+        let field = self.parse_field()?;
+        let (op, negated) = match self.next() {
+            Some(Token::Symbol("=")) => (Op::Equals, false),
+            Some(Token::Symbol("!=")) => (Op::Equals, true),
+            Some(Token::Symbol("~")) => (Op::Contains, false),
+            Some(Token::Symbol("!~")) => (Op::Contains, true),
+            Some(Token::Symbol("<")) => (Op::Less, false),
+            Some(Token::Symbol("<=")) => (Op::LessOrEqual, false),
+            Some(Token::Symbol(">")) => (Op::Greater, false),
+            Some(Token::Symbol(">=")) => (Op::GreaterOrEqual, false),
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("starts") => {
+                (Op::StartsWith, false)
+            }
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("ends") => (Op::EndsWith, false),
+            _ => {
+                self.position -= 1;
+                return Err(self.error("expected an operator such as =, ~, starts, or ends"));
+            }
+        };
+        let value = match self.next() {
+            Some(Token::Word(value)) | Some(Token::Quoted(value)) => value,
+            _ => {
+                self.position -= 1;
+                return Err(self.error("expected a value"));
+            }
+        };
+        let query = Query::Compare { field, op, value };
+        Ok(match negated {
+            true => Query::Not(Box::new(query)),
+            false => query,
+        })
+    }
+
+    fn parse_field(&mut self) -> Result<String, QueryError> {
+        match self.next() {
+            Some(Token::Word(field)) => Ok(field),
+            _ => {
+                self.position -= 1;
+                Err(self.error("expected a field name such as name or email"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evaluates_queries() {
+        let mut record = Record::new(12.into(), "Bob Smith".to_string());
+        record.emails = vec!["bob@work.com".to_string(), "bob@gmail.com".to_string()];
+        record.tags = vec!["friends".to_string()];
+        let matches = |query: &str| query.parse::<Query>().unwrap().matches(&record);
+        assert!(matches(r#"name ~ "SMITH" and email ends "@gmail.com""#));
+        assert!(matches("tag = friends and not has phone"));
+        assert!(matches("id > 9 and id <= 12"));
+        assert!(matches(
+            "name starts alice or (email != x@y.com and name !~ ann)"
+        ));
+        assert!(!matches("tag = work or phone ~ 555"));
+    }
+
+    #[test]
+    fn reports_where_queries_are_wrong() {
+        let error = |query: &str| query.parse::<Query>().unwrap_err();
+        assert_eq!(error("name smith").column, 6);
+        assert_eq!(error("name = \"smith").column, 8);
+        assert_eq!(error("(name = a").column, 10);
+        assert_eq!(error("name = a email = b").column, 10);
+    }
+}